@@ -27,6 +27,10 @@ pub const MSG_ID_PTZ_CONTROL: u32 = 18;
 pub const MSG_ID_PTZ_CONTROL_PRESET: u32 = 19;
 /// Reboot messages have this ID
 pub const MSG_ID_REBOOT: u32 = 23;
+/// Set the image settings such as flip and mirror
+pub const MSG_ID_SET_ISP: u32 = 25;
+/// Get the image settings such as flip and mirror
+pub const MSG_ID_GET_ISP: u32 = 26;
 /// Request motion detection messages
 pub const MSG_ID_MOTION_REQUEST: u32 = 31;
 /// Motion detection messages
@@ -35,8 +39,14 @@ pub const MSG_ID_MOTION: u32 = 33;
 pub const MSG_ID_SET_SERVICE_PORTS: u32 = 36;
 /// Get service ports
 pub const MSG_ID_GET_SERVICE_PORTS: u32 = 37;
+/// Get the encoder settings of the streams
+pub const MSG_ID_GET_COMPRESSION: u32 = 56;
+/// Set the encoder settings of the streams
+pub const MSG_ID_SET_COMPRESSION: u32 = 57;
 /// Version messages have this ID
 pub const MSG_ID_VERSION: u32 = 80;
+/// Get the recording schedule
+pub const MSG_ID_GET_RECORD: u32 = 81;
 /// Ping messages have this ID
 pub const MSG_ID_PING: u32 = 93;
 /// General system info messages have this ID
@@ -1,7 +1,7 @@
 #![allow(non_snake_case)]
 
 use serde::{Deserialize, Serialize};
-use std::{io::BufRead, io::Write};
+use std::{collections::BTreeMap, io::BufRead, io::Write};
 
 #[cfg(test)]
 use indoc::indoc;
@@ -132,6 +132,15 @@ pub struct BcXml {
     /// For changing rtmp server port
     #[serde(rename = "OnvifPort", skip_serializing_if = "Option::is_none")]
     pub onvif_port: Option<OnvifPort>,
+    /// The recording schedule
+    #[serde(rename = "Record", skip_serializing_if = "Option::is_none")]
+    pub record: Option<Record>,
+    /// The encoder settings of the streams
+    #[serde(rename = "Compression", skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// The image settings such as flip and mirror
+    #[serde(rename = "Isp", skip_serializing_if = "Option::is_none")]
+    pub isp: Option<Isp>,
 }
 
 impl BcXml {
@@ -1475,11 +1484,130 @@ pub struct OnvifPort {
     pub enable: Option<u32>,
 }
 
+/// The recording schedule of the camera
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct Record {
+    /// XML Version
+    #[serde(rename = "@version")]
+    pub version: String,
+    /// The channel the schedule is for
+    #[serde(rename = "channelId")]
+    pub channel_id: u8,
+    /// Whether recording is enabled known values are `1`, `0`
+    pub enable: u32,
+    /// The list of schedules, one per alarm type
+    #[serde(rename = "ScheduleList")]
+    pub schedule_list: RecordScheduleList,
+}
+
+/// The list of recording schedules
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct RecordScheduleList {
+    /// The schedules
+    #[serde(rename = "Schedule", default)]
+    pub schedules: Vec<RecordSchedule>,
+}
+
+/// A recording schedule for a single alarm type
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct RecordSchedule {
+    /// The alarm type that triggers recording. Known values are `"MD"` or `"none"`
+    #[serde(rename = "alarmType")]
+    pub alarm_type: String,
+    /// The time blocks of the schedule
+    #[serde(rename = "timeBlockList")]
+    pub time_block_list: TimeBlockList,
+}
+
 /// Convience function to return the xml version used throughout the library
 pub fn xml_ver() -> String {
     "1.1".to_string()
 }
 
+/// The encoder settings of the streams
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct Compression {
+    /// XML Version
+    #[serde(rename = "@version")]
+    pub version: String,
+    /// The channel the settings are for
+    #[serde(rename = "channelId")]
+    pub channel_id: u8,
+    /// Unknown, observed values `1`. Sent by the camera but not written back
+    #[serde(rename = "isNoTranslateFrame", skip_serializing_if = "Option::is_none")]
+    pub is_no_translate_frame: Option<u32>,
+    /// The settings of the main stream
+    #[serde(rename = "mainStream")]
+    pub main_stream: CompressionStream,
+    /// The settings of the sub stream
+    #[serde(rename = "subStream")]
+    pub sub_stream: CompressionStream,
+    /// The settings of the third (extern) stream
+    #[serde(rename = "thirdStream", skip_serializing_if = "Option::is_none")]
+    pub third_stream: Option<CompressionStream>,
+}
+
+/// The image settings of the camera
+///
+/// The camera sends many more settings such as exposure and white balance,
+/// these are kept in `other` so that the xml can be sent back whole with
+/// only the wanted fields changed
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct Isp {
+    /// XML Version
+    #[serde(rename = "@version")]
+    pub version: String,
+    /// The channel the settings are for
+    #[serde(rename = "channelId")]
+    pub channel_id: u8,
+    /// Image flipped upside down, known values `0`, `1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<u32>,
+    /// Image mirrored left to right, known values `0`, `1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirroring: Option<u32>,
+    /// The rest of the settings as they were sent by the camera
+    #[serde(flatten)]
+    pub other: BTreeMap<String, XmlValue>,
+}
+
+/// An element that is not otherwise parsed
+///
+/// Text is held under the `$text` key of `Nested`
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum XmlValue {
+    /// The text of an element
+    Text(String),
+    /// The children of an element
+    Nested(BTreeMap<String, XmlValue>),
+}
+
+/// The encoder settings of a single stream
+#[derive(PartialEq, Eq, Default, Debug, Deserialize, Serialize)]
+pub struct CompressionStream {
+    /// If audio is included in the stream known values `1`, `0`
+    pub audio: u32,
+    /// Resolution name is in the format "width*height" i.e. "2304*1296"
+    #[serde(rename = "resolutionName")]
+    pub resolution_name: String,
+    /// Width of the stream in pixels
+    pub width: u32,
+    /// Height of the stream in pixels
+    pub height: u32,
+    /// The bitrate control mode known values `"cbr"`, `"vbr"`
+    #[serde(rename = "encoderType")]
+    pub encoder_type: String,
+    /// The framerate
+    pub frame: u32,
+    /// The bitrate in kbps
+    #[serde(rename = "bitRate")]
+    pub bit_rate: u32,
+    /// The encoder profile known values `"high"`, `"main"`, `"base"`, `"default"`
+    #[serde(rename = "encoderProfile")]
+    pub encoder_profile: String,
+}
+
 #[test]
 fn test_encryption_deser() {
     let sample = indoc!(
@@ -1577,6 +1705,58 @@ fn test_login_ser() {
     assert_eq!(b2, b3);
 }
 
+#[test]
+fn test_isp_keeps_other_settings() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <Isp version="1.1">
+        <channelId>0</channelId>
+        <antiFlick>0</antiFlick>
+        <exposure>0</exposure>
+        <gain>
+        <min>1</min>
+        <max>62</max>
+        </gain>
+        <dayNight>0</dayNight>
+        <rotation>0</rotation>
+        <mirroring>0</mirroring>
+        </Isp>
+        </body>"#
+    );
+    let mut b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let isp = b.isp.as_mut().unwrap();
+    assert_eq!(isp.rotation, Some(0));
+    assert_eq!(isp.mirroring, Some(0));
+    assert_eq!(isp.other.len(), 4);
+    isp.rotation = Some(1);
+
+    let b2 = BcXml::try_parse(b.serialize(vec![]).unwrap().as_ref()).unwrap();
+    assert_eq!(b, b2);
+    let isp = b2.isp.unwrap();
+    assert_eq!(isp.rotation, Some(1));
+    assert_eq!(
+        isp.other.get("gain"),
+        Some(&XmlValue::Nested(BTreeMap::from([
+            (
+                "min".to_string(),
+                XmlValue::Nested(BTreeMap::from([(
+                    "$text".to_string(),
+                    XmlValue::Text("1".to_string())
+                )]))
+            ),
+            (
+                "max".to_string(),
+                XmlValue::Nested(BTreeMap::from([(
+                    "$text".to_string(),
+                    XmlValue::Text("62".to_string())
+                )]))
+            ),
+        ])))
+    );
+}
+
 #[test]
 fn test_deviceinfo_partial_deser() {
     let sample = indoc!(
@@ -1737,3 +1917,104 @@ fn test_binary_deser() {
         _ => panic!(),
     }
 }
+
+#[test]
+fn test_compression_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <Compression version="1.1">
+        <channelId>0</channelId>
+        <isNoTranslateFrame>1</isNoTranslateFrame>
+        <mainStream>
+        <audio>1</audio>
+        <resolutionName>2304*1296</resolutionName>
+        <width>2304</width>
+        <height>1296</height>
+        <encoderType>cbr</encoderType>
+        <frame>15</frame>
+        <bitRate>2560</bitRate>
+        <encoderProfile>high</encoderProfile>
+        </mainStream>
+        <subStream>
+        <audio>1</audio>
+        <resolutionName>896*512</resolutionName>
+        <width>896</width>
+        <height>512</height>
+        <encoderType>vbr</encoderType>
+        <frame>15</frame>
+        <bitRate>512</bitRate>
+        <encoderProfile>high</encoderProfile>
+        </subStream>
+        <thirdStream>
+        <audio>0</audio>
+        <resolutionName></resolutionName>
+        <width>0</width>
+        <height>0</height>
+        <encoderType>vbr</encoderType>
+        <frame>0</frame>
+        <bitRate>0</bitRate>
+        <encoderProfile>default</encoderProfile>
+        </thirdStream>
+        </Compression>
+        </body>
+    "#
+    );
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    match b {
+        BcXml {
+            compression:
+                Some(Compression {
+                    main_stream:
+                        CompressionStream {
+                            encoder_type: ref main_type,
+                            bit_rate: 2560,
+                            ..
+                        },
+                    sub_stream:
+                        CompressionStream {
+                            encoder_type: ref sub_type,
+                            ..
+                        },
+                    third_stream: Some(CompressionStream { width: 0, .. }),
+                    ..
+                }),
+            ..
+        } if main_type == "cbr" && sub_type == "vbr" => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_isp_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <Isp version="1.1">
+        <channelId>0</channelId>
+        <antiFlick>outdoor</antiFlick>
+        <exposure>auto</exposure>
+        <dayNight>auto</dayNight>
+        <rotation>1</rotation>
+        <mirroring>0</mirroring>
+        </Isp>
+        </body>
+    "#
+    );
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    match b {
+        BcXml {
+            isp:
+                Some(Isp {
+                    channel_id: 0,
+                    rotation: Some(1),
+                    mirroring: Some(0),
+                    ..
+                }),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
@@ -14,10 +14,12 @@ use Md5Trunc::*;
 
 mod abilityinfo;
 mod battery;
+mod compression;
 mod connection;
 mod credentials;
 mod errors;
 mod floodlight;
+mod isp;
 mod keepalive;
 mod ledstate;
 mod link;
@@ -29,6 +31,7 @@ mod pirstate;
 mod ptz;
 mod pushinfo;
 mod reboot;
+mod record;
 mod resolution;
 mod services;
 mod siren;
@@ -41,6 +44,7 @@ mod time;
 mod uid;
 mod version;
 
+pub use battery::PowerStatus;
 pub(crate) use connection::*;
 pub use credentials::*;
 pub use errors::Error;
@@ -48,7 +52,7 @@ pub use ledstate::LightState;
 pub use login::MaxEncryption;
 pub use motion::{MotionData, MotionStatus};
 pub use pirstate::PirState;
-pub use ptz::Direction;
+pub use ptz::{Direction, PtzInfo};
 pub use pushinfo::PhoneType;
 pub use resolution::*;
 use std::sync::Arc;
@@ -70,12 +74,17 @@ pub struct BcCamera {
     channel_id: u8,
     connection: Arc<BcConnection>,
     logged_in: AtomicBool,
-    message_num: AtomicU16,
+    // Shared between all channels using the same connection
+    message_num: Arc<AtomicU16>,
     // Certain commands such as logout require the username/pass in plain text.... why....???
     credentials: Credentials,
     abilities: RwLock<HashMap<String, ReadKind>>,
+    ptz_info: RwLock<Option<PtzInfo>>,
+    transport: TransportInfo,
     #[allow(dead_code)]
     cancel: CancellationToken,
+    // Keeps the logged in connection alive while a channel uses it
+    parent: Option<Arc<BcCamera>>,
 }
 
 /// Options used to construct a camera
@@ -102,6 +111,9 @@ pub struct BcCameraOpt {
     pub credentials: Credentials,
     /// Toggle debug print of underlying data
     pub debug: bool,
+    /// Local address to bind to when connecting to the camera
+    /// When not given the OS will choose based on its routing
+    pub bind: Option<IpAddr>,
 }
 
 /// Used to choose the print format of various status messages like battery levels
@@ -130,16 +142,46 @@ pub enum ConnectionProtocol {
     Udp,
 }
 
+/// How the connection to the camera was established
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMethod {
+    /// Direct TCP connection to a known address
+    Tcp,
+    /// UDP connection found by local broadcast discovery
+    Local,
+    /// UDP connection found via the reolink servers
+    Remote,
+    /// UDP connection mapped through the reolink servers
+    Map,
+    /// UDP connection relayed through the reolink servers
+    Relay,
+}
+
+/// Details of how the camera is connected
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransportInfo {
+    /// The method used to connect
+    pub method: ConnectionMethod,
+    /// The address of the camera or relay that we are connected to
+    pub remote_addr: SocketAddr,
+    /// The local address of the connection if known
+    pub local_addr: Option<SocketAddr>,
+}
+
 enum CameraLocation {
     Tcp(SocketAddr),
-    Udp(DiscoveryResult),
+    Udp(DiscoveryResult, ConnectionMethod),
 }
 
 impl BcCamera {
     /// Try to connect to the camera via appropaite methods and return
     /// the location that should be used
     async fn find_camera(options: &BcCameraOpt) -> Result<CameraLocation> {
-        let discovery = Discovery::new().await?;
+        if let Some(bind) = options.bind.as_ref() {
+            info!("{}: Binding to source address {}", options.name, bind);
+        }
+        let discovery = Discovery::new(options.bind).await?;
         if let ConnectionProtocol::Tcp | ConnectionProtocol::TcpUdp = options.protocol {
             let mut sockets = vec![];
             match options.port {
@@ -211,13 +253,13 @@ impl BcCamera {
                                 uid_local,
                                 disc.get_addr()
                             );
-                            Ok(CameraLocation::Udp(disc))
+                            Ok(CameraLocation::Udp(disc, ConnectionMethod::Local))
                         },
                         Err(e) => Err(e)
                     }
                 }, if allow_local => Ok(v),
                 Ok(v) = async {
-                    let mut discovery = Discovery::new().await?;
+                    let mut discovery = Discovery::new(options.bind).await?;
                     let reg_result;
                     // Registration is looped as it seems that reolink
                     // only updates the registration lazily when someone attempts
@@ -241,7 +283,7 @@ impl BcCamera {
                         retry += 1;
                         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
                         // New discovery to get new client IDs
-                        discovery = Discovery::new().await?;
+                        discovery = Discovery::new(options.bind).await?;
                     };
                     tokio::select! {
                         Ok(v) = async {
@@ -258,7 +300,7 @@ impl BcCamera {
                                         uid_remote,
                                         disc.get_addr()
                                     );
-                                    Ok(CameraLocation::Udp(disc))
+                                    Ok(CameraLocation::Udp(disc, ConnectionMethod::Remote))
                                 },
                                 Err(e) => Err(e)
                             }
@@ -276,7 +318,7 @@ impl BcCamera {
                                         uid_map,
                                         disc.get_addr()
                                     );
-                                    Ok(CameraLocation::Udp(disc))
+                                    Ok(CameraLocation::Udp(disc, ConnectionMethod::Map))
                                 },
                                 Err(e) => Err(e),
                             }
@@ -294,7 +336,7 @@ impl BcCamera {
                                         uid_relay,
                                         disc.get_addr()
                                     );
-                                    Ok(CameraLocation::Udp(disc))
+                                    Ok(CameraLocation::Udp(disc, ConnectionMethod::Relay))
                                 },
                                 Err(e) => Err(e),
                             }
@@ -328,15 +370,39 @@ impl BcCamera {
         let username: String = options.credentials.username.clone();
         let passwd: Option<String> = options.credentials.password.clone();
 
-        let (sink, source): (BcConnSink, BcConnSource) = {
+        let (sink, source, transport): (BcConnSink, BcConnSource, TransportInfo) = {
             match BcCamera::find_camera(options).await? {
                 CameraLocation::Tcp(addr) => {
-                    let (x, r) = TcpSource::new(addr, &username, passwd.as_ref(), options.debug)
-                        .await?
-                        .split();
-                    (Box::new(x), Box::new(r))
+                    let source = TcpSource::new(
+                        addr,
+                        options.bind,
+                        &username,
+                        passwd.as_ref(),
+                        options.debug,
+                    )
+                    .await?;
+                    let local_addr = source.local_addr().ok();
+                    if let Some(local_addr) = local_addr {
+                        info!("{}: Connected from {}", options.name, local_addr);
+                    }
+                    let transport = TransportInfo {
+                        method: ConnectionMethod::Tcp,
+                        remote_addr: addr,
+                        local_addr,
+                    };
+                    let (x, r) = source.split();
+                    (Box::new(x), Box::new(r), transport)
                 }
-                CameraLocation::Udp(discovery) => {
+                CameraLocation::Udp(discovery, method) => {
+                    let local_addr = discovery.get_local_addr().ok();
+                    if let Some(local_addr) = local_addr {
+                        info!("{}: Connected from {}", options.name, local_addr);
+                    }
+                    let transport = TransportInfo {
+                        method,
+                        remote_addr: *discovery.get_addr(),
+                        local_addr,
+                    };
                     let (x, r) = UdpSource::new_from_discovery(
                         discovery,
                         &username,
@@ -345,7 +411,7 @@ impl BcCamera {
                     )
                     .await?
                     .split();
-                    (Box::new(x), Box::new(r))
+                    (Box::new(x), Box::new(r), transport)
                 }
             }
         };
@@ -355,17 +421,56 @@ impl BcCamera {
         trace!("Success");
         let me = Self {
             connection: Arc::new(conn),
-            message_num: AtomicU16::new(0),
+            message_num: Arc::new(AtomicU16::new(0)),
             channel_id: options.channel_id,
             logged_in: AtomicBool::new(false),
             credentials: Credentials::new(username, passwd),
             abilities: Default::default(),
+            ptz_info: Default::default(),
+            transport,
             cancel: CancellationToken::new(),
+            parent: None,
         };
         me.keepalive().await?;
         Ok(me)
     }
 
+    /// Use another channel over this connection
+    ///
+    /// This is used with NVRs where each channel is a different camera.
+    /// The returned camera shares the connection and login with this one
+    /// which is kept alive for as long as the channel is in use.
+    ///
+    /// Returns [`Error::NoCameraOnChannel`] if the NVR does not report any
+    /// streams for the channel
+    pub async fn channel(self: &Arc<Self>, channel_id: u8) -> Result<BcCamera> {
+        let me = Self {
+            connection: self.connection.clone(),
+            message_num: self.message_num.clone(),
+            channel_id,
+            // The login belongs to the parent, we must not log it out
+            logged_in: AtomicBool::new(false),
+            credentials: self.credentials.clone(),
+            abilities: RwLock::new(self.abilities.read().await.clone()),
+            ptz_info: Default::default(),
+            transport: self.transport,
+            cancel: self.cancel.child_token(),
+            parent: Some(self.clone()),
+        };
+        match me.get_stream_info().await {
+            Ok(info) if !info.stream_infos.is_empty() => Ok(me),
+            Ok(_) | Err(Error::CameraServiceUnavailable(_)) => {
+                Err(Error::NoCameraOnChannel(channel_id))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the details of how the camera is connected
+    pub fn transport(&self) -> TransportInfo {
+        self.transport
+    }
+
     /// This method will get a new message number and increment the message count atomically
     pub fn new_message_num(&self) -> u16 {
         self.message_num.fetch_add(1, Ordering::Relaxed)
@@ -427,7 +532,13 @@ impl BcCamera {
 
     /// Disconnect from the camera. This is done by sending cancel to
     /// all threads then waiting for the join
+    ///
+    /// A channel of an NVR shares the connection with the other channels and
+    /// so does nothing here, the connection closes when the last is dropped
     pub async fn shutdown(&self) -> Result<()> {
+        if self.parent.is_some() {
+            return Ok(());
+        }
         self.connection.shutdown().await?;
         Ok(())
     }
@@ -10,6 +10,38 @@ use crate::{
     bc::{model::*, xml::BatteryInfo},
     Error,
 };
+use serde::Serialize;
+
+/// The power details of the camera
+///
+/// These come from the battery info so are only available on models
+/// that answer it. The PoE or DC status of wired cameras is not reported
+/// by the camera
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PowerStatus {
+    /// What is attached to the charging port, known values "solarPanel", "none"
+    pub adapter: String,
+    /// Known values "chargeComplete", "charging", "none"
+    pub charge: String,
+    /// Voltage as reported by the camera
+    pub voltage: i32,
+    /// Current as reported by the camera
+    pub current: i32,
+    /// The camera is warning that its power is low
+    pub low_power: bool,
+}
+
+impl From<&BatteryInfo> for PowerStatus {
+    fn from(info: &BatteryInfo) -> Self {
+        Self {
+            adapter: info.adapter_status.clone(),
+            charge: info.charge_status.clone(),
+            voltage: info.voltage,
+            current: info.current,
+            low_power: info.low_power != 0,
+        }
+    }
+}
 
 impl BcCamera {
     /// Create a handller to respond to battery messages
@@ -0,0 +1,139 @@
+use super::{BcCamera, Error, Result, StreamKind};
+use crate::bc::{model::*, xml::*};
+
+impl BcCamera {
+    /// Get the [Compression] xml which contains the encoder settings of the streams
+    pub async fn get_compression(&self) -> Result<Compression> {
+        let connection = self.get_connection();
+        let msg_num = self.new_message_num();
+        let mut sub_get = connection
+            .subscribe(MSG_ID_GET_COMPRESSION, msg_num)
+            .await?;
+        let get = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_GET_COMPRESSION,
+                channel_id: self.channel_id,
+                msg_num,
+                response_code: 0,
+                stream_type: 0,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(Extension {
+                    channel_id: Some(self.channel_id),
+                    ..Default::default()
+                }),
+                payload: None,
+            }),
+        };
+
+        sub_get.send(get).await?;
+        let msg = sub_get.recv().await?;
+        if msg.meta.response_code != 200 {
+            return Err(Error::CameraServiceUnavailable(msg.meta.response_code));
+        }
+
+        if let BcBody::ModernMsg(ModernMsg {
+            payload:
+                Some(BcPayloads::BcXml(BcXml {
+                    compression: Some(compression),
+                    ..
+                })),
+            ..
+        }) = msg.body
+        {
+            Ok(compression)
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: std::sync::Arc::new(Box::new(msg)),
+                why: "Expected Compression xml but it was not recieved",
+            })
+        }
+    }
+
+    /// Set the encoder settings of the streams using the [Compression] xml
+    pub async fn set_compression(&self, mut compression: Compression) -> Result<()> {
+        let connection = self.get_connection();
+        let msg_num = self.new_message_num();
+        let mut sub_set = connection
+            .subscribe(MSG_ID_SET_COMPRESSION, msg_num)
+            .await?;
+
+        // This is recieved from the camera but not sent back
+        compression.is_no_translate_frame = None;
+        // Cameras without a third stream still report an empty one
+        // we do not send that back either
+        if compression
+            .third_stream
+            .as_ref()
+            .is_some_and(|third| third.width == 0)
+        {
+            compression.third_stream = None;
+        }
+        let set = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_SET_COMPRESSION,
+                channel_id: self.channel_id,
+                msg_num,
+                response_code: 0,
+                stream_type: 0,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(Extension {
+                    channel_id: Some(self.channel_id),
+                    ..Default::default()
+                }),
+                payload: Some(BcPayloads::BcXml(BcXml {
+                    compression: Some(compression),
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        sub_set.send(set).await?;
+        let msg = sub_set.recv().await?;
+        if let BcMeta {
+            response_code: 200, ..
+        } = msg.meta
+        {
+            Ok(())
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: std::sync::Arc::new(Box::new(msg)),
+                why: "The camera did not accept the Compression xml",
+            })
+        }
+    }
+
+    /// Get the bitrate control mode of a stream, either `"cbr"` or `"vbr"`
+    pub async fn get_encoder_type(&self, stream: StreamKind) -> Result<String> {
+        let mut compression = self.get_compression().await?;
+        Ok(std::mem::take(
+            &mut compression_stream(&mut compression, stream)?.encoder_type,
+        ))
+    }
+
+    /// This is a convience function to set the bitrate control mode of a stream
+    ///
+    /// Valid values are `"cbr"` and `"vbr"`
+    pub async fn set_encoder_type(&self, stream: StreamKind, encoder_type: &str) -> Result<()> {
+        let mut compression = self.get_compression().await?;
+        compression_stream(&mut compression, stream)?.encoder_type = encoder_type.to_string();
+        self.set_compression(compression).await
+    }
+}
+
+fn compression_stream(
+    compression: &mut Compression,
+    stream: StreamKind,
+) -> Result<&mut CompressionStream> {
+    match stream {
+        StreamKind::Main => Ok(&mut compression.main_stream),
+        StreamKind::Sub => Ok(&mut compression.sub_stream),
+        StreamKind::Extern => compression
+            .third_stream
+            .as_mut()
+            .ok_or(Error::Other("Camera does not have a third stream")),
+    }
+}
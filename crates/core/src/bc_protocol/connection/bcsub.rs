@@ -5,7 +5,7 @@ use futures::stream::{Stream, TryStreamExt};
 use std::io::{Error as IoError, ErrorKind, Result as IoResult};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tokio_util::codec::FramedRead;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
@@ -94,11 +94,22 @@ impl<'a> BcSubscription<'a> {
         })
     }
 
-    pub fn bcmedia_stream(&'_ mut self, strict: bool) -> impl Stream<Item = Result<BcMedia>> + '_ {
+    /// Decode the binary payloads into BcMedia
+    ///
+    /// If `dump` is given each payload is also sent to it as it came from the camera
+    pub fn bcmedia_stream(
+        &'_ mut self,
+        strict: bool,
+        dump: Option<UnboundedSender<Vec<u8>>>,
+    ) -> impl Stream<Item = Result<BcMedia>> + '_ {
         let async_read = self
             .payload_stream()
-            .map(|frame| {
+            .map(move |frame| {
                 log::debug!("- BcMedia Payloader got packet");
+                if let (Ok(data), Some(dump)) = (&frame, dump.as_ref()) {
+                    // The dump is dropped once it is full
+                    let _ = dump.send(data.clone());
+                }
                 frame
             })
             .into_async_read()
@@ -18,7 +18,7 @@ use log::*;
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use std::collections::{btree_map::Entry, BTreeMap, HashSet};
 use std::convert::TryInto;
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use tokio::time::MissedTickBehavior;
 use tokio::{
@@ -117,8 +117,8 @@ fn valid_addr(ip_port: &IpPort) -> bool {
 }
 
 impl Discoverer {
-    async fn new() -> Result<Discoverer> {
-        let socket = Arc::new(connect().await?);
+    async fn new(bind: Option<IpAddr>) -> Result<Discoverer> {
+        let socket = Arc::new(connect(bind).await?);
         let local_addr = socket.local_addr()?;
         let inner: ArcFramedSocket = UdpFramed::new(socket.clone(), BcUdpCodex::new());
         let cancel = CancellationToken::new();
@@ -1006,13 +1006,15 @@ impl Drop for Discoverer {
 pub(crate) struct Discovery {
     discoverer: Discoverer,
     client_id: i32,
+    bind: Option<IpAddr>,
 }
 
 impl Discovery {
-    pub(crate) async fn new() -> Result<Self> {
+    pub(crate) async fn new(bind: Option<IpAddr>) -> Result<Self> {
         Ok(Self {
-            discoverer: Discoverer::new().await?,
+            discoverer: Discoverer::new(bind).await?,
             client_id: generate_cid(),
+            bind,
         })
     }
 
@@ -1057,8 +1059,11 @@ impl Discovery {
     pub(crate) async fn check_tcp(&self, addr: SocketAddr, channel_id: u8) -> Result<()> {
         let username = "admin";
         let password = Some("123456");
-        let mut tcp_source =
-            timeout(*TCP_WAIT, TcpSource::new(addr, username, password, false)).await??;
+        let mut tcp_source = timeout(
+            *TCP_WAIT,
+            TcpSource::new(addr, self.bind, username, password, false),
+        )
+        .await??;
 
         let md5_username = md5_string(username, Md5Trunc::ZeroLast);
         let md5_password = password
@@ -1251,16 +1256,17 @@ fn generate_cid() -> i32 {
     rng.gen()
 }
 
-async fn connect() -> Result<UdpSocket> {
+async fn connect(bind: Option<IpAddr>) -> Result<UdpSocket> {
     let mut ports: Vec<u16> = (53500..54000).collect();
     {
         let mut rng = thread_rng();
         ports.shuffle(&mut rng);
     }
 
+    let bind = bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
     let addrs: Vec<_> = ports
         .iter()
-        .map(|&port| SocketAddr::from(([0, 0, 0, 0], port)))
+        .map(|&port| SocketAddr::new(bind, port))
         .collect();
     let socket = UdpSocket::bind(&addrs[..]).await?;
     socket.set_broadcast(true)?;
@@ -31,4 +31,9 @@ impl DiscoveryResult {
     pub(crate) fn get_addr(&self) -> &SocketAddr {
         &self.addr
     }
+
+    /// Get the local address the discovery socket is bound to
+    pub(crate) fn get_local_addr(&self) -> crate::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
 }
@@ -3,7 +3,7 @@ use crate::Result;
 use crate::{bc::codex::BcCodex, Credentials};
 use delegate::delegate;
 use futures::{sink::Sink, stream::Stream};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::net::{TcpSocket, TcpStream};
@@ -16,11 +16,12 @@ pub(crate) struct TcpSource {
 impl TcpSource {
     pub(crate) async fn new<T: Into<String>, U: Into<String>>(
         addr: SocketAddr,
+        bind: Option<IpAddr>,
         username: T,
         password: Option<U>,
         debug: bool,
     ) -> Result<TcpSource> {
-        let stream = connect_to(addr).await?;
+        let stream = connect_to(addr, bind).await?;
 
         let codex = if debug {
             BcCodex::new_with_debug(Credentials::new(username, password))
@@ -31,6 +32,11 @@ impl TcpSource {
             inner: Framed::new(stream, codex),
         })
     }
+
+    /// The local address that the connection is bound to
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.get_ref().local_addr()?)
+    }
 }
 
 impl Stream for TcpSource {
@@ -63,11 +69,17 @@ impl Sink<Bc> for TcpSource {
 }
 
 /// Helper to create a TcpStream with a connect timeout
-async fn connect_to(addr: SocketAddr) -> Result<TcpStream> {
+///
+/// If `bind` is given then the socket is bound to that local
+/// address before connecting otherwise the OS chooses based on its routing
+async fn connect_to(addr: SocketAddr, bind: Option<IpAddr>) -> Result<TcpStream> {
     let socket = match addr {
         SocketAddr::V4(_) => TcpSocket::new_v4()?,
         SocketAddr::V6(_) => TcpSocket::new_v6()?,
     };
+    if let Some(bind) = bind {
+        socket.bind(SocketAddr::new(bind, 0))?;
+    }
 
     Ok(socket.connect(addr).await?)
 }
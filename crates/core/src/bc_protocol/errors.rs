@@ -52,6 +52,10 @@ pub enum Error {
     #[error("Camera responded with Err during login")]
     CameraLoginFail,
 
+    /// Raised when a NVR has no camera attached to the requested channel
+    #[error("No camera is attached to channel {0}")]
+    NoCameraOnChannel(u8),
+
     /// Raised when a connection is dropped.
     #[error("Dropped connection")]
     DroppedConnection,
@@ -201,6 +201,22 @@ impl BcCamera {
         Ok(())
     }
 
+    /// Convience function: Set the brightness of the Flood Light in %
+    ///
+    /// The value is kept inside the range the camera reports and the
+    /// brightness that was set is returned
+    pub async fn set_floodlight_brightness(&self, brightness: u32) -> Result<u32> {
+        let mut curr_state = self.get_flightlight_tasks().await?;
+        let brightness = brightness
+            .max(curr_state.brightness_min.unwrap_or(1))
+            .min(curr_state.brightness_max.unwrap_or(100));
+        if curr_state.brightness_cur != brightness {
+            curr_state.brightness_cur = brightness;
+            self.set_flightlight_tasks(curr_state).await?;
+        }
+        Ok(brightness)
+    }
+
     /// Convience function: Check if Flood Light tasks are enbabled
     pub async fn is_flightlight_tasks_enabled(&self) -> Result<bool> {
         let curr_state = self.get_flightlight_tasks().await?;
@@ -0,0 +1,116 @@
+use super::{BcCamera, Error, Result};
+use crate::bc::{model::*, xml::*};
+
+impl BcCamera {
+    /// Get the [Isp] xml which contains the image settings of the camera
+    pub async fn get_isp(&self) -> Result<Isp> {
+        let connection = self.get_connection();
+        let msg_num = self.new_message_num();
+        let mut sub_get = connection.subscribe(MSG_ID_GET_ISP, msg_num).await?;
+        let get = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_GET_ISP,
+                channel_id: self.channel_id,
+                msg_num,
+                response_code: 0,
+                stream_type: 0,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(Extension {
+                    channel_id: Some(self.channel_id),
+                    ..Default::default()
+                }),
+                payload: None,
+            }),
+        };
+
+        sub_get.send(get).await?;
+        let msg = sub_get.recv().await?;
+        if msg.meta.response_code != 200 {
+            return Err(Error::CameraServiceUnavailable(msg.meta.response_code));
+        }
+
+        if let BcBody::ModernMsg(ModernMsg {
+            payload: Some(BcPayloads::BcXml(BcXml { isp: Some(isp), .. })),
+            ..
+        }) = msg.body
+        {
+            Ok(isp)
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: std::sync::Arc::new(Box::new(msg)),
+                why: "Expected Isp xml but it was not recieved",
+            })
+        }
+    }
+
+    /// Set the image settings of the camera using the [Isp] xml
+    ///
+    /// The camera may reset any setting that is left out so this should be
+    /// the [Isp] from [`BcCamera::get_isp`] with only the wanted fields changed
+    pub async fn set_isp(&self, isp: Isp) -> Result<()> {
+        let connection = self.get_connection();
+        let msg_num = self.new_message_num();
+        let mut sub_set = connection.subscribe(MSG_ID_SET_ISP, msg_num).await?;
+        let set = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_SET_ISP,
+                channel_id: self.channel_id,
+                msg_num,
+                response_code: 0,
+                stream_type: 0,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(Extension {
+                    channel_id: Some(self.channel_id),
+                    ..Default::default()
+                }),
+                payload: Some(BcPayloads::BcXml(BcXml {
+                    isp: Some(isp),
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        sub_set.send(set).await?;
+        let msg = sub_set.recv().await?;
+        if let BcMeta {
+            response_code: 200, ..
+        } = msg.meta
+        {
+            Ok(())
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: std::sync::Arc::new(Box::new(msg)),
+                why: "The camera did not accept the Isp xml",
+            })
+        }
+    }
+
+    /// Get if the image is flipped upside down and mirrored left to right
+    ///
+    /// Returns an error if the camera does not support changing these
+    pub async fn get_flip_mirror(&self) -> Result<(bool, bool)> {
+        let isp = self.get_isp().await?;
+        match (isp.rotation, isp.mirroring) {
+            (Some(rotation), Some(mirroring)) => Ok((rotation != 0, mirroring != 0)),
+            _ => Err(Error::Other("Camera does not report flip and mirror")),
+        }
+    }
+
+    /// This is a convience function to flip the image upside down
+    pub async fn set_flip(&self, flip: bool) -> Result<()> {
+        let mut isp = self.get_isp().await?;
+        isp.rotation = Some(flip as u32);
+        self.set_isp(isp).await
+    }
+
+    /// This is a convience function to mirror the image left to right
+    pub async fn set_mirror(&self, mirror: bool) -> Result<()> {
+        let mut isp = self.get_isp().await?;
+        isp.mirroring = Some(mirror as u32);
+        self.set_isp(isp).await
+    }
+}
@@ -5,6 +5,10 @@ use std::sync::atomic::Ordering;
 impl BcCamera {
     /// Logout from the camera
     pub async fn logout(&self) -> Result<()> {
+        // The login of a channel belongs to the NVR connection
+        if self.parent.is_some() {
+            return Ok(());
+        }
         if self.logged_in.load(Ordering::Relaxed) {
             let credentials = self.get_credentials();
             let connection = self.get_connection();
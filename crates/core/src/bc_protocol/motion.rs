@@ -22,8 +22,9 @@ pub enum MotionStatus {
 pub struct MotionData {
     handle: JoinSet<Result<()>>,
     cancel: CancellationToken,
-    rx: Receiver<Result<MotionStatus>>,
+    rx: Receiver<Result<(MotionStatus, Vec<String>)>>,
     last_update: MotionStatus,
+    last_ai: Vec<String>,
 }
 
 impl MotionData {
@@ -53,6 +54,14 @@ impl MotionData {
         })
     }
 
+    /// The AI types reported with the last motion event such as `people`,
+    /// `vehicle`, `dog_cat` or `face`
+    ///
+    /// Empty if nothing was detected or the camera has no AI
+    pub fn ai_detected(&self) -> &[String] {
+        &self.last_ai
+    }
+
     /// Consume the motion events diretly
     ///
     /// An error is raised if the motion connection to the camera is dropped
@@ -60,7 +69,11 @@ impl MotionData {
         let mut results: Vec<MotionStatus> = vec![];
         loop {
             match self.rx.try_recv() {
-                Ok(motion) => results.push(motion?),
+                Ok(motion) => {
+                    let (motion, ai) = motion?;
+                    results.push(motion);
+                    self.last_ai = ai;
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(e) => return Err(Error::from(e)),
             }
@@ -79,8 +92,9 @@ impl MotionData {
         if let Some(last) = motions.last() {
             Ok(*last)
         } else if let Some(moition) = self.rx.recv().await {
-            let moition = moition?;
+            let (moition, ai) = moition?;
             self.last_update = moition;
+            self.last_ai = ai;
             Ok(moition)
         } else {
             Err(Error::Other("Motion dropped"))
@@ -237,15 +251,22 @@ impl BcCamera {
                                 }) = motion_msg.body
                                 {
                                     let mut result = MotionStatus::NoChange(Instant::now());
+                                    let mut ai = vec![];
                                     for alarm_event in &alarm_event_list.alarm_events {
                                         if alarm_event.channel_id == channel_id {
-                                            if alarm_event.status != "none"
-                                                || alarm_event
-                                                    .ai_type
-                                                    .as_ref()
-                                                    .map(|ai_type| ai_type != "none")
-                                                    .unwrap_or(false)
-                                            {
+                                            // Newer firmwares list several types such as `people,vehicle`
+                                            ai = alarm_event
+                                                .ai_type
+                                                .as_deref()
+                                                .unwrap_or("none")
+                                                .split(',')
+                                                .map(|ai_type| ai_type.trim())
+                                                .filter(|ai_type| {
+                                                    !ai_type.is_empty() && *ai_type != "none"
+                                                })
+                                                .map(|ai_type| ai_type.to_string())
+                                                .collect();
+                                            if alarm_event.status != "none" || !ai.is_empty() {
                                                 result = MotionStatus::Start(Instant::now());
                                                 break;
                                             } else {
@@ -254,9 +275,9 @@ impl BcCamera {
                                             }
                                         }
                                     }
-                                    Ok(result)
+                                    Ok((result, ai))
                                 } else {
-                                    Ok(MotionStatus::NoChange(Instant::now()))
+                                    Ok((MotionStatus::NoChange(Instant::now()), vec![]))
                                 }
                             }
                             // On connection drop we stop
@@ -278,6 +299,7 @@ impl BcCamera {
             cancel,
             rx,
             last_update: MotionStatus::NoChange(Instant::now()),
+            last_ai: vec![],
         })
     }
 }
@@ -1,5 +1,6 @@
 use super::{BcCamera, Error, Result};
 use crate::bc::{model::*, xml::*};
+use serde::Serialize;
 
 /// Directions used for Ptz
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -16,7 +17,103 @@ pub enum Direction {
     Stop,
 }
 
+/// The PTZ capabilities of the camera
+///
+/// The camera only reports which axes can move, not the angles they can
+/// move through. Zoom and focus report their range of positions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PtzInfo {
+    /// True if the camera can move in any way
+    pub supported: bool,
+    /// The camera can pan left and right
+    pub pan: bool,
+    /// The camera can tilt up and down
+    pub tilt: bool,
+    /// The camera can zoom
+    pub zoom: bool,
+    /// The min and max zoom positions
+    pub zoom_range: Option<[u32; 2]>,
+    /// The min and max focus positions
+    pub focus_range: Option<[u32; 2]>,
+    /// Number of saved presets, None if presets are not supported
+    pub presets: Option<usize>,
+}
+
 impl BcCamera {
+    /// Get the PTZ capabilities of the camera
+    ///
+    /// The axes are only queried once per connection but the presets are
+    /// counted each time as they change when one is saved
+    pub async fn get_ptz_info(&self) -> Result<PtzInfo> {
+        let cached = self.ptz_info.read().await.clone();
+        let mut info = match cached {
+            Some(info) => info,
+            None => self.query_ptz_info().await?,
+        };
+        if info.presets.is_some() {
+            info.presets = self
+                .get_ptz_preset()
+                .await
+                .ok()
+                .map(|preset| preset.preset_list.preset.len());
+        }
+        Ok(info)
+    }
+
+    /// The PTZ capabilities with `presets` as `Some(0)` if they are supported
+    async fn query_ptz_info(&self) -> Result<PtzInfo> {
+        // Fixed cameras may not answer at all, treat that as no ptz
+        let support = match self.get_support().await {
+            Ok(support) => support,
+            Err(Error::CameraServiceUnavailable(_)) => Default::default(),
+            Err(e) => return Err(e),
+        };
+        let mode = support.ptz_mode.clone().unwrap_or_default().to_lowercase();
+        let item = support
+            .items
+            .iter()
+            .find(|item| item.chn_id == self.channel_id as u32);
+        let pan = mode.contains('p');
+        let tilt = mode.contains('t');
+        let has_control = item
+            .and_then(|item| item.ptz_control.or(item.ptz_type))
+            .is_some_and(|v| v > 0);
+
+        // Not all cameras report zoom in the ptz mode so also check
+        // for a usable zoom range
+        let zoom_focus = if pan || tilt || has_control || mode.contains('z') {
+            self.get_zoom().await.ok()
+        } else {
+            None
+        };
+        let zoom_range = zoom_focus
+            .as_ref()
+            .filter(|zf| zf.zoom.max_pos > zf.zoom.min_pos)
+            .map(|zf| [zf.zoom.min_pos, zf.zoom.max_pos]);
+        let focus_range = zoom_focus
+            .as_ref()
+            .filter(|zf| zf.focus.max_pos > zf.focus.min_pos)
+            .map(|zf| [zf.focus.min_pos, zf.focus.max_pos]);
+        let zoom = mode.contains('z') || zoom_range.is_some();
+
+        let presets = item
+            .and_then(|item| item.ptz_preset)
+            .is_some_and(|v| v > 0)
+            .then_some(0);
+
+        let info = PtzInfo {
+            supported: pan || tilt || zoom,
+            pan,
+            tilt,
+            zoom,
+            zoom_range,
+            focus_range,
+            presets,
+        };
+        self.ptz_info.write().await.replace(info.clone());
+        Ok(info)
+    }
+
     /// Send a PTZ message to the camera
     pub async fn send_ptz(&self, direction: Direction, amount: f32) -> Result<()> {
         self.has_ability_rw("control").await?;
@@ -239,7 +336,7 @@ impl BcCamera {
     /// The camera will zoom to a given zoom amount.
     /// Not sure what the units for this are, seems to be 1000 is 1x and 2000 is 2x
     pub async fn zoom_to(&self, zoom_pos: u32) -> Result<()> {
-        log::debug!("Setting to {}", zoom_pos);
+        log::debug!("Setting zoom to {}", zoom_pos);
         let current = self.get_zoom().await?;
         log::debug!(
             "   curr: {}, min: {}, max: {}",
@@ -249,7 +346,27 @@ impl BcCamera {
         );
         let zoom_pos = zoom_pos.clamp(current.zoom.min_pos, current.zoom.max_pos);
         log::debug!("Clamped to {}", zoom_pos);
+        self.set_zoom_focus("zoomPos", zoom_pos).await
+    }
+
+    /// The camera will focus to a given position
+    ///
+    /// The position is clamped to the range from `get_zoom`
+    pub async fn focus_to(&self, focus_pos: u32) -> Result<()> {
+        log::debug!("Setting focus to {}", focus_pos);
+        let current = self.get_zoom().await?;
+        log::debug!(
+            "   curr: {}, min: {}, max: {}",
+            current.focus.cur_pos,
+            current.focus.min_pos,
+            current.focus.max_pos
+        );
+        let focus_pos = focus_pos.clamp(current.focus.min_pos, current.focus.max_pos);
+        log::debug!("Clamped to {}", focus_pos);
+        self.set_zoom_focus("focusPos", focus_pos).await
+    }
 
+    async fn set_zoom_focus(&self, command: &str, move_pos: u32) -> Result<()> {
         self.has_ability_rw("control").await?;
         let connection = self.get_connection();
         let msg_num = self.new_message_num();
@@ -273,8 +390,8 @@ impl BcCamera {
                     start_zoom_focus: Some(StartZoomFocus {
                         version: xml_ver(),
                         channel_id: self.channel_id,
-                        command: "zoomPos".to_string(),
-                        move_pos: zoom_pos,
+                        command: command.to_string(),
+                        move_pos,
                     }),
                     ..Default::default()
                 })),
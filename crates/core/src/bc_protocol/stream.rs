@@ -4,7 +4,7 @@ use crate::{
     bcmedia::model::*,
 };
 use futures::stream::StreamExt;
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::mpsc::{channel, Receiver, UnboundedSender};
 use tokio::task::{self, JoinHandle};
 use tokio_util::sync::CancellationToken;
 
@@ -69,6 +69,11 @@ impl StreamData {
                 // debug!("StreamData: Drop");
                 log::debug!("SteamData::get_data Cancel3");
                 self.abort_handle.cancel();
+                // The sender is only dropped once the task has ended, pass
+                // on why it ended such as the camera refusing the stream
+                if let Some(handle) = self.handle.take() {
+                    handle.await??;
+                }
                 Err(Error::DroppedConnection)
             }
         }
@@ -115,10 +120,27 @@ impl BcCamera {
     /// A value of scrict=true will mean that the stream will error if the underlying stream is not
     /// as expected
     pub async fn start_video(
+        &self,
+        stream: StreamKind,
+        buffer_size: usize,
+        strict: bool,
+    ) -> Result<StreamData> {
+        self.start_video_with_dump(stream, buffer_size, strict, None)
+            .await
+    }
+
+    ///
+    /// Starts the video stream in the same way as `start_video`
+    ///
+    /// The bytes of the stream are also sent to `dump` exactly as the camera
+    /// sent them, before they are decoded. These can be read back with
+    /// [`crate::bcmedia::dump::read_dump`]
+    pub async fn start_video_with_dump(
         &self,
         stream: StreamKind,
         mut buffer_size: usize,
         strict: bool,
+        dump: Option<UnboundedSender<Vec<u8>>>,
     ) -> Result<StreamData> {
         if let Err(e) = self.has_ability_rw("preview").await {
             if self.has_ability_ro("streamTable").await.is_err() {
@@ -200,19 +222,13 @@ impl BcCamera {
             sub_video.send(start_video).await?;
 
             let msg = sub_video.recv().await?;
-            if let BcMeta {
-                response_code: 200, ..
-            } = msg.meta
-            {
-            } else {
-                return Err(Error::UnintelligibleReply {
-                    reply: std::sync::Arc::new(Box::new(msg)),
-                    why: "The camera did not accept the stream start command.",
-                });
+            if msg.meta.response_code != 200 {
+                // Some cameras refuse a second stream while one is running
+                return Err(Error::CameraServiceUnavailable(msg.meta.response_code));
             }
 
             {
-                let mut media_sub = sub_video.bcmedia_stream(strict);
+                let mut media_sub = sub_video.bcmedia_stream(strict, dump);
 
                 tokio::select! {
                     _ = abort_handle_thread.cancelled() => {},
@@ -0,0 +1,59 @@
+//! Dumps of a BcMedia stream
+//!
+//! A dump is the bytes of a stream exactly as the camera sent them, see
+//! [`crate::bc_protocol::BcCamera::start_video_with_dump`]. Replaying one
+//! with [`read_dump`] reproduces decoding problems without needing the camera
+use super::{codex::BcMediaCodex, model::BcMedia};
+use crate::Error;
+use futures::stream::Stream;
+use tokio::io::AsyncRead;
+use tokio_util::codec::FramedRead;
+
+/// Read back the packets of a dump
+///
+/// If `strict` is false corrupt data is skipped the same way it is for
+/// a camera
+pub fn read_dump<R: AsyncRead>(
+    reader: R,
+    strict: bool,
+) -> impl Stream<Item = Result<BcMedia, Error>> {
+    FramedRead::new(reader, BcMediaCodex::new(strict))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+
+    #[tokio::test]
+    // A dump of the camera's bytes should read back as all of its packets
+    async fn test_read_dump() {
+        let sample = [
+            include_bytes!("samples/video_stream_swan_00.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_01.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_02.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_03.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_04.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_05.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_06.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_07.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_08.raw").as_ref(),
+            include_bytes!("samples/video_stream_swan_09.raw").as_ref(),
+        ]
+        .concat();
+
+        let packets = read_dump(sample.as_slice(), true)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!packets.is_empty());
+        assert!(packets
+            .iter()
+            .any(|packet| matches!(packet, BcMedia::Adpcm(_))));
+        assert!(packets
+            .iter()
+            .any(|packet| matches!(packet, BcMedia::Iframe(_))));
+    }
+}
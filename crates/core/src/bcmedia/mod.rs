@@ -1,6 +1,8 @@
 pub(crate) mod codex;
 /// Deserlizer for BCMedia
 pub mod de;
+/// Dumping and replaying a BCMedia stream
+pub mod dump;
 /// Structure model for BCMedia
 pub mod model;
 /// Serlizer for BCMedia
@@ -57,7 +57,8 @@ impl BcMedia {
                 )?
             }
             BcMedia::Adpcm(payload) => {
-                let pad_size = match payload.data.len() as u32 % PAD_SIZE {
+                // Padded on the payload size, which includes the 4 byte sub header, as it is parsed
+                let pad_size = match (payload.data.len() + 4) as u32 % PAD_SIZE {
                     0 => 0,
                     n => PAD_SIZE - n,
                 };
@@ -189,3 +190,36 @@ fn bcmedia_adpcm<W: Write>(payload: &BcMediaAdpcm) -> impl SerializeFn<W> {
         le_u16(((payload.data.len() - 4) / 2) as u16), // Block size without the header halved
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::bcmedia::model::*;
+    use bytes::BytesMut;
+
+    #[test]
+    // Serialised adpcm should parse back to the same data with the padding consumed
+    fn test_adpcm_roundtrip() {
+        // 244 needs no padding on either size, the others pad differently
+        // on the data size than on the payload size
+        for len in [244, 246, 250] {
+            let media = BcMedia::Adpcm(BcMediaAdpcm {
+                data: (0..len).map(|i| i as u8).collect(),
+            });
+            let buf = media.serialize(vec![]).unwrap();
+            let mut buf = BytesMut::from(&buf[..]);
+
+            match BcMedia::deserialize(&mut buf) {
+                Ok(BcMedia::Adpcm(BcMediaAdpcm { data })) => {
+                    assert_eq!(data.len(), len);
+                    assert_eq!(data, (0..len).map(|i| i as u8).collect::<Vec<_>>());
+                }
+                e => panic!("Adpcm of {} bytes did not parse back: {:?}", len, e),
+            }
+            assert!(
+                buf.is_empty(),
+                "Adpcm of {len} bytes left {} bytes",
+                buf.len()
+            );
+        }
+    }
+}
@@ -94,6 +94,7 @@ impl AddressOrUid {
                 password: camera_config.password.clone(),
             },
             debug: false,
+            bind: None,
         };
 
         trace!("Camera Info: {:?}", options);
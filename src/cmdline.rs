@@ -28,4 +28,5 @@ pub enum Command {
     Image(super::image::Opt),
     Battery(super::battery::Opt),
     Services(super::services::Opt),
+    Template(super::template::Opt),
 }
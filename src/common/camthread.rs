@@ -1,12 +1,27 @@
-use std::sync::{Arc, Weak};
+use anyhow::anyhow;
+use std::{
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
 use tokio::{
     sync::watch::{Receiver as WatchReceiver, Sender as WatchSender},
-    time::{interval, sleep, timeout, Duration, Instant},
+    time::{interval, sleep, timeout, Duration, Instant, MissedTickBehavior},
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::{config::CameraConfig, utils::connect_and_login, AnyResult};
-use neolink_core::bc_protocol::BcCamera;
+use super::{camera_metrics, ConnectQueue, ConnectionState};
+use crate::{
+    config::{
+        BitrateMode, CameraConfig, EncoderProfile, PowerCycleConfig, ProfileConfig,
+        ReconnectStrategy,
+    },
+    utils::{connect_and_login, now_local},
+    AnyResult,
+};
+use neolink_core::bc_protocol::{BcCamera, StreamKind};
+
+/// Time the power cycle command has to finish
+const POWER_CYCLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Eq, PartialEq, Copy, Clone)]
 pub(crate) enum NeoCamThreadState {
@@ -19,6 +34,15 @@ pub(crate) struct NeoCamThread {
     config: WatchReceiver<CameraConfig>,
     cancel: CancellationToken,
     camera_watch: WatchSender<Weak<BcCamera>>,
+    users: WatchReceiver<u32>,
+    error: WatchSender<Option<String>>,
+    /// Changed when the streams have stalled and the connection should be dropped
+    reconnect: WatchReceiver<()>,
+    connect_queue: Arc<ConnectQueue>,
+    is_battery: bool,
+    /// Only used for streaming so the camera's settings are left to the
+    /// main connection
+    stream_only: bool,
 }
 
 impl NeoCamThread {
@@ -26,6 +50,10 @@ impl NeoCamThread {
         watch_state_rx: WatchReceiver<NeoCamThreadState>,
         watch_config_rx: WatchReceiver<CameraConfig>,
         camera_watch_tx: WatchSender<Weak<BcCamera>>,
+        users: WatchReceiver<u32>,
+        error_tx: WatchSender<Option<String>>,
+        reconnect_rx: WatchReceiver<()>,
+        connect_queue: Arc<ConnectQueue>,
         cancel: CancellationToken,
     ) -> Self {
         Self {
@@ -33,19 +61,81 @@ impl NeoCamThread {
             config: watch_config_rx,
             cancel,
             camera_watch: camera_watch_tx,
+            users,
+            error: error_tx,
+            reconnect: reconnect_rx,
+            connect_queue,
+            is_battery: false,
+            stream_only: false,
         }
     }
+
+    /// Make this a connection for the streams alone
+    pub(crate) fn stream_only(mut self) -> Self {
+        self.stream_only = true;
+        self
+    }
+
     async fn run_camera(&mut self, config: &CameraConfig) -> AnyResult<()> {
         let name = config.name.clone();
+        let permit = self.connect_queue.acquire(&name).await;
         let camera = Arc::new(connect_and_login(config).await?);
+        drop(permit);
+        self.error.send_replace(None);
+
+        // Only battery cameras reply to the battery info, this is used to
+        // pick the reconnect strategy when it is set to auto
+        if config.reconnect_strategy == ReconnectStrategy::Auto {
+            self.is_battery = matches!(
+                timeout(Duration::from_secs(2), camera.battery_info()).await,
+                Ok(Ok(_))
+            );
+        }
+
+        if self.stream_only {
+            log::debug!("{name}: Stream connection logged in");
+            self.camera_watch.send_replace(Arc::downgrade(&camera));
+            return self.wait_for_disconnect(&camera, config).await;
+        }
 
         sleep(Duration::from_secs(2)).await; // Delay a little since some calls will error if camera is waking up
-        update_camera_time(&camera, &name, config.update_time).await?;
+        update_camera_time(
+            &camera,
+            &name,
+            config.update_time,
+            Duration::from_secs(config.max_time_drift),
+        )
+        .await?;
+        let profile = config.profile.as_ref();
+        if let Some(bitrate_mode) = profile
+            .and_then(|profile| profile.bitrate_mode)
+            .or(config.bitrate_mode)
+        {
+            update_bitrate_mode(&camera, &name, bitrate_mode).await;
+        }
+        let flip = profile.and_then(|profile| profile.flip).or(config.flip);
+        let mirror = profile.and_then(|profile| profile.mirror).or(config.mirror);
+        if flip.is_some() || mirror.is_some() {
+            update_flip_mirror(&camera, &name, flip, mirror).await;
+        }
+        if let Some(profile) = profile {
+            apply_profile(&camera, &name, profile).await;
+        }
         sleep(Duration::from_secs(2)).await; // Delay a little since some calls will error if camera is waking up
 
         self.camera_watch.send_replace(Arc::downgrade(&camera));
+        camera_metrics(&name).set_state(ConnectionState::Connected);
+        self.wait_for_disconnect(&camera, config).await
+    }
 
+    async fn wait_for_disconnect(&self, camera: &BcCamera, config: &CameraConfig) -> AnyResult<()> {
+        let name = &config.name;
         let cancel_check = self.cancel.clone();
+        // Only requests made while connected count
+        let mut reconnect = self.reconnect.clone();
+        reconnect.borrow_and_update();
+        // With a separate stream login only that one carries the streams
+        let carries_streams = self.stream_only || !config.stream_session;
         // Now we wait for a disconnect
         tokio::select! {
             _ = cancel_check.cancelled() => {
@@ -57,6 +147,10 @@ impl NeoCamThread {
                 v?;
                 Ok(())
             },
+            v = reconnect.changed(), if carries_streams => {
+                v?;
+                Err(anyhow!("The camera stopped sending video"))
+            },
             v = async {
                 let mut interval = interval(Duration::from_secs(5));
                 let mut missed_pings = 0;
@@ -87,6 +181,31 @@ impl NeoCamThread {
                     }
                 }
             } => v,
+            v = async {
+                // Some firmwares close the session if it is idle, the
+                // version request is cheap and supported by all cameras
+                let secs = match config.keepalive {
+                    Some(secs) => secs,
+                    None => futures::future::pending().await,
+                };
+                let mut interval = interval(Duration::from_secs(secs));
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    match timeout(Duration::from_secs(10), camera.version()).await {
+                        Ok(Ok(_)) => log::trace!("{name}: Keepalive sent"),
+                        Ok(Err(e)) => {
+                            log::warn!("{name}: Keepalive failed, reconnecting: {e:?}");
+                            break Err(e.into());
+                        }
+                        Err(_) => {
+                            log::warn!("{name}: Keepalive timed out, reconnecting");
+                            break Err(anyhow!("Keepalive timed out"));
+                        }
+                    }
+                }
+            } => v,
         }?;
 
         let _ = camera.logout().await;
@@ -100,16 +219,34 @@ impl NeoCamThread {
     // A watch sender is used to send the new camera
     // whenever it changes
     pub(crate) async fn run(&mut self) -> AnyResult<()> {
-        const MAX_BACKOFF: Duration = Duration::from_secs(5);
         const MIN_BACKOFF: Duration = Duration::from_millis(50);
+        const NO_CAMERA_RETRY: Duration = Duration::from_secs(60);
+        // Refused logins in a row before the camera is taken to be locking us out
+        const LOGIN_THROTTLE_AFTER: u32 = 3;
 
         let mut backoff = MIN_BACKOFF;
+        // Reconnects that have failed in a row
+        let mut failures = 0u32;
+        // Logins that have been refused in a row
+        let mut login_failures = 0u32;
+        let mut last_power_cycle: Option<Instant> = None;
+        // The stream connection is not counted, it follows the main one
+        let metrics = (!self.stream_only).then(|| camera_metrics(&self.config.borrow().name));
+        let set_state = |state| {
+            if let Some(metrics) = metrics.as_ref() {
+                metrics.set_state(state);
+            }
+        };
 
         loop {
+            if matches!(*self.state.borrow(), NeoCamThreadState::Disconnected) {
+                set_state(ConnectionState::Disconnected);
+            }
             self.state
                 .clone()
                 .wait_for(|state| matches!(state, NeoCamThreadState::Connected))
                 .await?;
+            set_state(ConnectionState::Connecting);
             let mut config_rec = self.config.clone();
 
             let config = config_rec.borrow_and_update().clone();
@@ -125,28 +262,45 @@ impl NeoCamThread {
                 Ok(_) = state.wait_for(|state| matches!(state, NeoCamThreadState::Disconnected)) => {
                     None
                 }
+                _ = session_expired(&name, config.max_session_duration, self.users.clone()) => {
+                    log::info!("{name}: Reconnecting to refresh the session");
+                    None
+                }
+                jump = clock_jump(config.clock_jump) => {
+                    // The session and stream timings are stale, start again
+                    log::warn!("{name}: The clock jumped by {:?}, the host may have been suspended. Reconnecting", jump);
+                    None
+                }
                 v = self.run_camera(&config) => {
                     Some(v)
                 }
             };
             self.camera_watch.send_replace(Weak::new());
+            set_state(ConnectionState::Connecting);
 
             if res.is_none() {
                 // If None go back and reload NOW
                 //
-                // This occurs if there was a config change
+                // This occurs if there was a config change, a clock jump or
+                // the session reached its max duration
                 continue;
             }
 
             // Else we see what the result actually was
             let result = res.unwrap();
+            if let (Err(_), Some(metrics)) = (&result, metrics.as_ref()) {
+                metrics.reconnected();
+            }
 
             if now.elapsed() > Duration::from_secs(60) {
                 // Command ran long enough to be considered a success
                 backoff = MIN_BACKOFF;
+                failures = 0;
+                login_failures = 0;
             }
-            if backoff > MAX_BACKOFF {
-                backoff = MAX_BACKOFF;
+            let max_backoff = Duration::from_secs(config.max_backoff);
+            if backoff > max_backoff {
+                backoff = max_backoff;
             }
 
             match result {
@@ -160,20 +314,106 @@ impl NeoCamThread {
                     // An error
                     // Check if it is non-retry
                     let e_inner = e.downcast_ref::<neolink_core::Error>();
+                    let login_failed = matches!(
+                        e_inner,
+                        Some(neolink_core::Error::AuthFailed)
+                            | Some(neolink_core::Error::CameraLoginFail)
+                    );
+                    if login_failed {
+                        login_failures += 1;
+                    } else {
+                        login_failures = 0;
+                    }
                     match e_inner {
-                        Some(neolink_core::Error::CameraLoginFail) => {
-                            // Fatal
-                            log::error!("{name}: Login credentials were not accepted");
-                            log::debug!("NeoCamThread::run Login Cancel");
-                            self.cancel.cancel();
-                            return Err(e);
+                        Some(neolink_core::Error::AuthFailed)
+                        | Some(neolink_core::Error::CameraLoginFail)
+                            if login_failures >= LOGIN_THROTTLE_AFTER =>
+                        {
+                            // After too many failed logins the camera bans this host
+                            // for a while and retrying now would only extend it
+                            let throttle = Duration::from_secs(config.login_throttle_backoff);
+                            log::error!(
+                                "{name}: Camera has refused {login_failures} logins in a row and may be locking out this host. Waiting {:?} before trying again",
+                                throttle
+                            );
+                            self.error.send_replace(Some("throttled".to_string()));
+                            tokio::select! {
+                                _ = sleep(throttle) => {}
+                                _ = config_rec.changed() => {
+                                    // The credentials may have been fixed
+                                    login_failures = 0;
+                                }
+                            }
+                            backoff = MIN_BACKOFF;
+                        }
+                        Some(neolink_core::Error::NoCameraOnChannel(channel)) => {
+                            // Nothing to do until a camera is plugged into the NVR
+                            log::warn!(
+                                "{name}: The NVR has no camera attached on channel {channel}. Checking again in {:?}",
+                                NO_CAMERA_RETRY
+                            );
+                            self.error.send_replace(Some("no camera".to_string()));
+                            tokio::select! {
+                                _ = sleep(NO_CAMERA_RETRY) => {}
+                                _ = config_rec.changed() => {}
+                            }
+                            backoff = MIN_BACKOFF;
                         }
                         _ => {
                             // Non fatal
-                            log::warn!("{name}: Connection Lost: {:?}", e);
-                            log::info!("{name}: Attempt reconnect in {:?}", backoff);
-                            sleep(backoff).await;
-                            backoff *= 2;
+                            if login_failed {
+                                log::error!(
+                                    "{name}: Login failed, check the username and password"
+                                );
+                            } else {
+                                log::warn!("{name}: Connection Lost: {:?}", e);
+                            }
+                            failures += 1;
+                            if let Some(hook) =
+                                config.power_cycle.as_ref().filter(|_| !self.stream_only)
+                            {
+                                if failures >= hook.after {
+                                    match last_power_cycle {
+                                        Some(last)
+                                            if last.elapsed()
+                                                < Duration::from_secs(hook.cooldown) =>
+                                        {
+                                            log::debug!(
+                                                "{name}: Not power cycling again so soon, {failures} reconnects have failed"
+                                            );
+                                        }
+                                        _ => {
+                                            log::warn!(
+                                                "{name}: {failures} reconnects have failed in a row, power cycling the camera"
+                                            );
+                                            self.error
+                                                .send_replace(Some("power cycle".to_string()));
+                                            power_cycle(&name, hook).await;
+                                            last_power_cycle = Some(Instant::now());
+                                            failures = 0;
+                                        }
+                                    }
+                                }
+                            }
+                            let lazy = match config.reconnect_strategy {
+                                ReconnectStrategy::Eager => false,
+                                ReconnectStrategy::Lazy => true,
+                                ReconnectStrategy::Auto => self.is_battery,
+                            };
+                            if lazy {
+                                log::info!("{name}: Waiting for the camera to be needed before reconnecting");
+                                let mut users = self.users.clone();
+                                tokio::select! {
+                                    v = users.wait_for(|users| *users > 0) => {
+                                        v?;
+                                    }
+                                    _ = config_rec.changed() => {}
+                                }
+                            }
+                            let delay = jitter(backoff);
+                            log::debug!("{name}: Attempt reconnect in {:?}", delay);
+                            sleep(delay).await;
+                            backoff = (backoff * 2).min(max_backoff);
                         }
                     }
                 }
@@ -182,6 +422,103 @@ impl NeoCamThread {
     }
 }
 
+/// Add up to a quarter again to the backoff
+///
+/// Stops cameras that dropped together, such as when a switch restarts,
+/// from all reconnecting at the same moment
+fn jitter(backoff: Duration) -> Duration {
+    backoff + backoff.mul_f64(rand::random::<f64>() * 0.25)
+}
+
+/// Completes once the session is older than `max` seconds and no one is
+/// using the camera
+///
+/// If it is in use the whole time the reconnect is forced after a grace
+/// period so that a camera that is always watched is still refreshed
+async fn session_expired(name: &str, max: Option<u64>, mut users: WatchReceiver<u32>) {
+    const QUIET_WAIT: Duration = Duration::from_secs(60 * 60);
+    let max = match max {
+        Some(max) => Duration::from_secs(max),
+        None => return futures::future::pending().await,
+    };
+    sleep(max).await;
+    if *users.borrow() > 0 {
+        log::info!(
+            "{name}: Session is {:?} old, reconnecting once the streams are idle or in {:?}",
+            max,
+            QUIET_WAIT
+        );
+        let _ = timeout(QUIET_WAIT, users.wait_for(|users| *users == 0)).await;
+    }
+}
+
+/// Completes when the clock jumps by more than `threshold` seconds
+///
+/// The monotonic clock stops while the host is suspended but the wall clock
+/// does not so a gap between them shows a resume. A late tick shows the same
+/// on hosts where the monotonic clock keeps counting through a suspend
+async fn clock_jump(threshold: u64) -> Duration {
+    const PERIOD: Duration = Duration::from_secs(5);
+    if threshold == 0 {
+        return futures::future::pending().await;
+    }
+    let threshold = Duration::from_secs(threshold);
+    let mut last_mono = Instant::now();
+    let mut last_wall = SystemTime::now();
+    loop {
+        sleep(PERIOD).await;
+        let mono = last_mono.elapsed();
+        let wall = SystemTime::now()
+            .duration_since(last_wall)
+            .unwrap_or_else(|e| e.duration());
+        let jump = std::cmp::max(
+            if wall > mono {
+                wall - mono
+            } else {
+                mono - wall
+            },
+            mono.saturating_sub(PERIOD),
+        );
+        if jump > threshold {
+            return jump;
+        }
+        last_mono = Instant::now();
+        last_wall = SystemTime::now();
+    }
+}
+
+/// Run the power cycle command and wait for it to finish
+///
+/// It is killed if it takes longer than `POWER_CYCLE_TIMEOUT`
+async fn power_cycle(name: &str, hook: &PowerCycleConfig) {
+    let command = match hook.command.as_ref() {
+        Some(command) => command,
+        None => return,
+    };
+    log::info!("{name}: Running power cycle command: {command}");
+    #[cfg(windows)]
+    let mut shell = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    #[cfg(not(windows))]
+    let mut shell = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+    shell.arg(command).kill_on_drop(true);
+    let res = timeout(POWER_CYCLE_TIMEOUT, async { shell.spawn()?.wait().await }).await;
+    match res {
+        Ok(Ok(status)) if status.success() => {
+            log::info!("{name}: Power cycle command finished")
+        }
+        Ok(Ok(status)) => log::warn!("{name}: Power cycle command failed: {status}"),
+        Ok(Err(e)) => log::warn!("{name}: Could not run the power cycle command: {e:?}"),
+        Err(_) => log::warn!(
+            "{name}: Power cycle command did not finish within {}s, killed it",
+            POWER_CYCLE_TIMEOUT.as_secs()
+        ),
+    }
+}
+
 impl Drop for NeoCamThread {
     fn drop(&mut self) {
         log::debug!("Cancel:: NeoCamThread::drop");
@@ -189,12 +526,29 @@ impl Drop for NeoCamThread {
     }
 }
 
-async fn update_camera_time(camera: &BcCamera, name: &str, update_time: bool) -> AnyResult<()> {
+/// Set the camera's clock to the host's local time
+///
+/// The camera shows its clock on the OSD in the offset it is given so the
+/// local offset of the host is sent rather than utc. With `update_time` it is
+/// only set when it has drifted more than `max_drift`
+async fn update_camera_time(
+    camera: &BcCamera,
+    name: &str,
+    update_time: bool,
+    max_drift: Duration,
+) -> AnyResult<()> {
     let cam_time = camera.get_time().await?;
+    let now = now_local();
     let mut update = false;
     if let Some(time) = cam_time {
-        log::info!("{}: Camera time is already set: {}", name, time);
-        if update_time {
+        let drift = (time - now).abs();
+        log::info!(
+            "{}: Camera time is already set: {} ({:.0}s from the host)",
+            name,
+            time,
+            drift.as_seconds_f64()
+        );
+        if update_time && drift > max_drift {
             update = true;
         }
     } else {
@@ -202,11 +556,8 @@ async fn update_camera_time(camera: &BcCamera, name: &str, update_time: bool) ->
         log::warn!("{}: Camera has no time set, Updating", name);
     }
     if update {
-        use std::time::SystemTime;
-        let new_time = SystemTime::now();
-
-        log::info!("{}: Setting time to {:?}", name, new_time);
-        match camera.set_time(new_time.into()).await {
+        log::info!("{}: Setting time to {}", name, now);
+        match camera.set_time(now).await {
             Ok(_) => {
                 let cam_time = camera.get_time().await?;
                 if let Some(time) = cam_time {
@@ -224,3 +575,132 @@ async fn update_camera_time(camera: &BcCamera, name: &str, update_time: bool) ->
     }
     Ok(())
 }
+
+async fn update_flip_mirror(
+    camera: &BcCamera,
+    name: &str,
+    flip: Option<bool>,
+    mirror: Option<bool>,
+) {
+    let (curr_flip, curr_mirror) = match camera.get_flip_mirror().await {
+        Ok(current) => current,
+        Err(e) => {
+            log::warn!("{}: Camera does not support flip or mirror: {:?}", name, e);
+            return;
+        }
+    };
+    if let Some(flip) = flip.filter(|flip| *flip != curr_flip) {
+        match camera.set_flip(flip).await {
+            Ok(()) => log::info!("{}: Set flip to {}", name, flip),
+            Err(e) => log::warn!("{}: Camera did not accept flip: Error: {:?}", name, e),
+        }
+    }
+    if let Some(mirror) = mirror.filter(|mirror| *mirror != curr_mirror) {
+        match camera.set_mirror(mirror).await {
+            Ok(()) => log::info!("{}: Set mirror to {}", name, mirror),
+            Err(e) => log::warn!("{}: Camera did not accept mirror: Error: {:?}", name, e),
+        }
+    }
+}
+
+async fn update_bitrate_mode(camera: &BcCamera, name: &str, bitrate_mode: BitrateMode) {
+    for stream in [StreamKind::Main, StreamKind::Sub] {
+        let mode = bitrate_mode.as_camera_str();
+        if matches!(camera.get_encoder_type(stream).await, Ok(current) if current == mode) {
+            continue;
+        }
+        match camera.set_encoder_type(stream, mode).await {
+            Ok(()) => log::info!("{}: Set {} bitrate mode to {}", name, stream, mode),
+            Err(e) => log::warn!(
+                "{}: Camera did not accept bitrate mode {} for {}: Error: {:?}",
+                name,
+                mode,
+                stream,
+                e
+            ),
+        }
+    }
+}
+
+/// Apply the rest of a profile, the bitrate mode, flip and mirror are
+/// applied with the camera's own settings
+async fn apply_profile(camera: &BcCamera, name: &str, profile: &ProfileConfig) {
+    let profile_name = profile.name.as_deref().unwrap_or("profile");
+    let mut applied = vec![];
+    let mut rejected = vec![];
+
+    if let Some(ir) = profile.ir {
+        match camera.irled_light_set(ir.light_state()).await {
+            Ok(()) => applied.push("ir"),
+            Err(e) => {
+                log::debug!("{name}: Camera did not accept ir: {e:?}");
+                rejected.push("ir");
+            }
+        }
+    }
+    if let Some(on) = profile.status_led {
+        match camera.led_light_set(on).await {
+            Ok(()) => applied.push("status_led"),
+            Err(e) => {
+                log::debug!("{name}: Camera did not accept status_led: {e:?}");
+                rejected.push("status_led");
+            }
+        }
+    }
+    if let Some(on) = profile.pir {
+        match camera.pir_set(on).await {
+            Ok(()) => applied.push("pir"),
+            Err(e) => {
+                log::debug!("{name}: Camera did not accept pir: {e:?}");
+                rejected.push("pir");
+            }
+        }
+    }
+    if profile.main.is_some() || profile.sub.is_some() {
+        match update_encoder(camera, profile.main.as_ref(), profile.sub.as_ref()).await {
+            Ok(()) => applied.push("encoder"),
+            Err(e) => {
+                log::debug!("{name}: Camera did not accept encoder: {e:?}");
+                rejected.push("encoder");
+            }
+        }
+    }
+
+    if !applied.is_empty() {
+        log::info!("{name}: Applied {profile_name}: {}", applied.join(", "));
+    }
+    if !rejected.is_empty() {
+        log::warn!(
+            "{name}: Camera rejected {profile_name}: {}",
+            rejected.join(", ")
+        );
+    }
+}
+
+async fn update_encoder(
+    camera: &BcCamera,
+    main: Option<&EncoderProfile>,
+    sub: Option<&EncoderProfile>,
+) -> AnyResult<()> {
+    let mut compression = camera.get_compression().await?;
+    let mut changed = false;
+    for (stream, profile) in [
+        (&mut compression.main_stream, main),
+        (&mut compression.sub_stream, sub),
+    ] {
+        if let Some(profile) = profile {
+            if let Some(bitrate) = profile.bitrate.filter(|b| *b != stream.bit_rate) {
+                stream.bit_rate = bitrate;
+                changed = true;
+            }
+            if let Some(fps) = profile.fps.filter(|f| *f != stream.frame) {
+                stream.frame = fps;
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        camera.set_compression(compression).await?;
+    }
+    Ok(())
+}
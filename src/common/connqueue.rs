@@ -0,0 +1,247 @@
+//! Limits how many cameras may be connecting at once
+//!
+//! Cameras wait here before connecting and are let through in
+//! order of their `connect_order` then their position in the config
+//! while no more than `max_connecting` are connecting at once
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{watch::Receiver as WatchReceiver, Notify};
+
+use crate::config::Config;
+
+/// The place of a waiter: its `connect_order`, its position in the config then
+/// a ticket so that two waits for the same camera each have their own place
+type QueueKey = (u32, usize, u64);
+
+#[derive(Default)]
+struct QueueState {
+    connecting: usize,
+    waiting: BTreeSet<QueueKey>,
+    next_ticket: u64,
+}
+
+/// Shared between all cameras of a reactor
+pub(crate) struct ConnectQueue {
+    config: WatchReceiver<Config>,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+impl ConnectQueue {
+    pub(crate) fn new(config: WatchReceiver<Config>) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            state: Default::default(),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Wait for our turn to connect
+    ///
+    /// The slot is held until the returned permit is dropped
+    pub(crate) async fn acquire(self: &Arc<Self>, name: &str) -> ConnectPermit {
+        let (order, index) = {
+            let config = self.config.borrow();
+            let index = config
+                .cameras
+                .iter()
+                .position(|cam| cam.name == name)
+                .unwrap_or(config.cameras.len());
+            let order = config
+                .cameras
+                .get(index)
+                .map(|cam| cam.connect_order)
+                .unwrap_or(u32::MAX);
+            (order, index)
+        };
+        let key = {
+            let mut state = self.state.lock().unwrap();
+            let key = (order, index, state.next_ticket);
+            state.next_ticket += 1;
+            state.waiting.insert(key);
+            key
+        };
+        // Removes us from the queue if the wait is cancelled
+        let mut permit = ConnectPermit {
+            queue: self.clone(),
+            key: Some(key),
+        };
+
+        let mut reported = None;
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let limit = self.config.borrow().max_connecting;
+            {
+                let mut state = self.state.lock().unwrap();
+                let ahead = state.waiting.range(..&key).count();
+                let has_slot = limit == 0 || state.connecting < limit;
+                if has_slot && ahead == 0 {
+                    state.waiting.remove(&key);
+                    state.connecting += 1;
+                    permit.key = None;
+                    if limit > 0 {
+                        log::info!(
+                            "{}: Connecting ({}/{} connection slots in use, {} waiting)",
+                            name,
+                            state.connecting,
+                            limit,
+                            state.waiting.len()
+                        );
+                    }
+                    return permit;
+                }
+                if reported != Some(ahead) {
+                    log::info!(
+                        "{}: Waiting to connect, {} camera(s) ahead in the queue",
+                        name,
+                        ahead
+                    );
+                    reported = Some(ahead);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Holds a connection slot or a place in the queue
+pub(crate) struct ConnectPermit {
+    queue: Arc<ConnectQueue>,
+    // Some while still waiting in the queue
+    key: Option<QueueKey>,
+}
+
+impl Drop for ConnectPermit {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        match self.key.take() {
+            Some(key) => {
+                state.waiting.remove(&key);
+            }
+            None => state.connecting -= 1,
+        }
+        drop(state);
+        self.queue.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        sync::watch::channel as watch,
+        time::{timeout, Duration},
+    };
+
+    fn queue(max_connecting: usize, cameras: &[(&str, u32)]) -> Arc<ConnectQueue> {
+        let mut toml = format!("max_connecting = {max_connecting}\n");
+        for (name, order) in cameras {
+            toml.push_str(&format!(
+                "[[cameras]]\nname = \"{name}\"\nusername = \"admin\"\naddress = \"127.0.0.1\"\nconnect_order = {order}\n"
+            ));
+        }
+        let config: Config = toml::from_str(&toml).unwrap();
+        let (_, config_rx) = watch(config);
+        ConnectQueue::new(config_rx)
+    }
+
+    async fn wait_for_waiting(queue: &Arc<ConnectQueue>, count: usize) {
+        while queue.state.lock().unwrap().waiting.len() != count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    // Waiters go by connect_order then by their place in the config
+    async fn test_order() {
+        let queue = queue(1, &[("Cam1", 1), ("Cam2", 0), ("Cam3", 1)]);
+        let first = queue.acquire("Cam1").await;
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        for name in ["Cam3", "Cam1", "Cam2"] {
+            let queue = queue.clone();
+            let order_tx = order_tx.clone();
+            tokio::spawn(async move {
+                let permit = queue.acquire(name).await;
+                order_tx.send(name).unwrap();
+                tokio::task::yield_now().await;
+                drop(permit);
+            });
+        }
+        wait_for_waiting(&queue, 3).await;
+        drop(first);
+
+        let mut order = vec![];
+        for _ in 0..3 {
+            order.push(order_rx.recv().await.unwrap());
+        }
+        assert_eq!(order, vec!["Cam2", "Cam1", "Cam3"]);
+    }
+
+    #[tokio::test]
+    // No more than max_connecting hold a slot at once
+    async fn test_slot_limit() {
+        let queue = queue(2, &[("Cam1", 0), ("Cam2", 0), ("Cam3", 0)]);
+        let first = queue.acquire("Cam1").await;
+        let _second = queue.acquire("Cam2").await;
+        assert!(
+            timeout(Duration::from_millis(50), queue.acquire("Cam3"))
+                .await
+                .is_err(),
+            "A third camera got a slot"
+        );
+
+        drop(first);
+        let _third = timeout(Duration::from_secs(1), queue.acquire("Cam3"))
+            .await
+            .unwrap();
+        assert_eq!(queue.state.lock().unwrap().connecting, 2);
+    }
+
+    #[tokio::test]
+    // A waiter that gives up leaves the queue and does not hold up the rest
+    async fn test_cancelled_waiter() {
+        let queue = queue(1, &[("Cam1", 0), ("Cam2", 0), ("Cam3", 0)]);
+        let first = queue.acquire("Cam1").await;
+        assert!(timeout(Duration::from_millis(50), queue.acquire("Cam2"))
+            .await
+            .is_err());
+        assert!(queue.state.lock().unwrap().waiting.is_empty());
+
+        drop(first);
+        let _third = timeout(Duration::from_secs(1), queue.acquire("Cam3"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    // The main and stream connections of a camera wait in the queue together,
+    // one of them giving up must not take the other's place
+    async fn test_same_camera_waiters() {
+        let queue = queue(1, &[("Cam1", 0), ("Cam2", 0)]);
+        let first = queue.acquire("Cam1").await;
+
+        let waiter = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let _permit = queue.acquire("Cam2").await;
+            })
+        };
+        wait_for_waiting(&queue, 1).await;
+        assert!(timeout(Duration::from_millis(50), queue.acquire("Cam2"))
+            .await
+            .is_err());
+        assert_eq!(queue.state.lock().unwrap().waiting.len(), 1);
+
+        drop(first);
+        timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
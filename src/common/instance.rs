@@ -12,14 +12,45 @@ use tokio::{
         mpsc::Sender as MpscSender, oneshot::channel as oneshot, watch::channel as watch,
         watch::Receiver as WatchReceiver,
     },
-    time::{sleep, Duration},
+    time::{sleep, timeout, Duration},
 };
 use tokio_util::sync::CancellationToken;
 
-use super::{MdState, NeoCamCommand, NeoCamThreadState, Permit, PushNoti, StreamInstance};
+use super::{
+    MdState, NeoCamCommand, NeoCamThreadState, Permit, PushNoti, Snapshot, StreamInstance,
+};
 use crate::{config::CameraConfig, AnyResult, Result};
 use neolink_core::bc_protocol::{BcCamera, StreamKind};
 
+/// The state of the connection to the camera
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub(crate) enum ConnectionState {
+    /// Logged in and ready for commands
+    Connected,
+    /// Wanted but not logged in, such as while reconnecting
+    Connecting,
+    /// Not wanted, such as after an idle disconnect
+    Disconnected,
+}
+
+impl ConnectionState {
+    fn new(camera: &Weak<BcCamera>, thread: NeoCamThreadState) -> Self {
+        match (camera.strong_count() > 0, thread) {
+            (true, _) => Self::Connected,
+            (false, NeoCamThreadState::Connected) => Self::Connecting,
+            (false, NeoCamThreadState::Disconnected) => Self::Disconnected,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connected => "connected",
+            Self::Connecting => "connecting",
+            Self::Disconnected => "disconnected",
+        }
+    }
+}
+
 /// This instance is the primary interface used throughout the app
 ///
 /// It uses channels to run all tasks on the actual shared `[NeoCam]`
@@ -57,6 +88,20 @@ impl NeoInstance {
         instance_rx.await?
     }
 
+    /// Create a new instance that runs its tasks on the connection used
+    /// for streaming
+    ///
+    /// This is the same as [`NeoInstance::subscribe`] unless the camera
+    /// has `stream_session` set, in which case the streams have their own
+    /// login so that they cannot upset the control session
+    pub(crate) async fn stream_session(&self) -> Result<Self> {
+        let (instance_tx, instance_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::StreamInstance(instance_tx))
+            .await?;
+        instance_rx.await?
+    }
+
     /// This is a helpful convience function
     ///
     /// Given an async task it will:
@@ -234,7 +279,46 @@ impl NeoInstance {
         Ok(instance_rx.await?)
     }
 
-    #[allow(dead_code)]
+    /// Get a stream that will be decoded by neolink
+    ///
+    /// If the stream is larger than `max_decode_resolution` the sub stream
+    /// is used instead to protect low powered hosts
+    pub(crate) async fn decode_stream(&self, kind: StreamKind) -> Result<StreamInstance> {
+        let (name, cap) = {
+            let config = self.config().await?;
+            let config = config.borrow();
+            (config.name.clone(), config.max_decode_resolution)
+        };
+        let stream = self.stream(kind).await?;
+        let cap = match cap {
+            Some(cap) => cap,
+            None => return Ok(stream),
+        };
+        let [width, height] = stream.config.borrow().resolution;
+        if width <= cap[0] && height <= cap[1] {
+            return Ok(stream);
+        }
+        if kind != StreamKind::Sub {
+            match self.stream(StreamKind::Sub).await {
+                Ok(sub) => {
+                    log::info!(
+                        "{name}: The {kind} stream is {width}x{height} which is over the max_decode_resolution of {}x{}. Decoding the sub stream instead",
+                        cap[0],
+                        cap[1]
+                    );
+                    return Ok(sub);
+                }
+                Err(e) => log::debug!("{name}: No sub stream to decode: {e:?}"),
+            }
+        }
+        log::warn!(
+            "{name}: The {kind} stream is {width}x{height} which is over the max_decode_resolution of {}x{} but there is no smaller stream to use",
+            cap[0],
+            cap[1]
+        );
+        Ok(stream)
+    }
+
     pub(crate) async fn low_stream(&self) -> Result<Option<StreamInstance>> {
         let (instance_tx, instance_rx) = oneshot();
         self.camera_control
@@ -261,6 +345,17 @@ impl NeoInstance {
         Ok(instance_rx.await?)
     }
 
+    /// Allow or prevent a stream from pulling data from the camera
+    ///
+    /// Disabling a stream stops it even if there are clients using it
+    pub(crate) async fn set_stream_enabled(&self, name: StreamKind, enabled: bool) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::EnableStream(name, enabled, reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
     pub(crate) async fn uid(&self) -> Result<String> {
         let (reply_tx, reply_rx) = oneshot();
         self.camera_control
@@ -269,6 +364,16 @@ impl NeoInstance {
         Ok(reply_rx.await?)
     }
 
+    /// Why the camera cannot currently connect, if it is something other
+    /// than an ordinary dropped connection
+    pub(crate) async fn error(&self) -> Result<WatchReceiver<Option<String>>> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Error(reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
     pub(crate) async fn push_notifications(&self) -> Result<WatchReceiver<Option<PushNoti>>> {
         let uid = self.uid().await?;
         let (instance_tx, instance_rx) = oneshot();
@@ -303,6 +408,66 @@ impl NeoInstance {
         Ok(fwatch_rx)
     }
 
+    /// Start or stop recording to disk
+    pub(crate) async fn set_recording(&self, on: bool) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Record(on, reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Whether the camera is recording to disk
+    pub(crate) async fn recording(&self) -> Result<WatchReceiver<bool>> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Recording(reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Keep the IR as set by hand until the next change of the IR schedule
+    pub(crate) async fn override_ir(&self) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::IrOverride(reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Pause or resume the camera's streams
+    ///
+    /// While paused the rtsp mounts show the splash rather than the camera
+    pub(crate) async fn set_paused(&self, paused: bool) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Pause(paused, reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Whether the camera's streams are paused
+    pub(crate) async fn paused(&self) -> Result<WatchReceiver<bool>> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Paused(reply_tx))
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// The latest jpeg from the snapshot cache
+    ///
+    /// This is `None` unless `snapshot_cache` is enabled and
+    /// a keyframe has been decoded
+    pub(crate) async fn cached_snapshot(&self) -> Result<Snapshot> {
+        let (instance_tx, instance_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Snapshot(instance_tx))
+            .await?;
+        let snapshot = instance_rx.await?.borrow().clone();
+        Ok(snapshot)
+    }
+
     pub(crate) async fn motion(&self) -> Result<WatchReceiver<MdState>> {
         let (instance_tx, instance_rx) = oneshot();
         self.camera_control
@@ -339,6 +504,72 @@ impl NeoInstance {
         Ok(instance_rx.await?)
     }
 
+    /// Drop the connection that carries the streams and log in again
+    ///
+    /// Used when the connection is open but the camera has stopped sending
+    pub(crate) async fn reconnect(&self) -> Result<()> {
+        let (instance_tx, instance_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::Reconnect(instance_tx))
+            .await?;
+        Ok(instance_rx.await?)
+    }
+
+    /// Watch the state of the connection to the camera
+    ///
+    /// A change away from `Connected` is only passed on once it has lasted
+    /// for `debounce` so that a quick reconnect does not show as a drop
+    pub(crate) async fn connection_state(
+        &self,
+        debounce: Duration,
+    ) -> Result<WatchReceiver<ConnectionState>> {
+        let (instance_tx, instance_rx) = oneshot();
+        self.camera_control
+            .send(NeoCamCommand::StateWatch(instance_tx))
+            .await?;
+        let mut thread_state = instance_rx.await?;
+        let mut camera_watch = self.camera_watch.clone();
+        let (state_tx, state_rx) = watch(ConnectionState::new(
+            &camera_watch.borrow_and_update(),
+            *thread_state.borrow_and_update(),
+        ));
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = state_tx.closed() => break,
+                    v = camera_watch.changed() => if v.is_err() { break },
+                    v = thread_state.changed() => if v.is_err() { break },
+                }
+                let was_connected = matches!(*state_tx.borrow(), ConnectionState::Connected);
+                if was_connected && camera_watch.borrow().strong_count() == 0 {
+                    match timeout(
+                        debounce,
+                        camera_watch.wait_for(|camera| camera.strong_count() > 0),
+                    )
+                    .await
+                    {
+                        Ok(Ok(_)) => continue, // Back before the debounce
+                        Ok(Err(_)) => break,
+                        Err(_) => {}
+                    }
+                }
+                let next = ConnectionState::new(
+                    &camera_watch.borrow_and_update(),
+                    *thread_state.borrow_and_update(),
+                );
+                state_tx.send_if_modified(|state| {
+                    if *state != next {
+                        *state = next;
+                        true
+                    } else {
+                        false
+                    }
+                });
+            }
+        });
+        Ok(state_rx)
+    }
+
     #[allow(dead_code)]
     pub(crate) async fn get_state(&self) -> Result<NeoCamThreadState> {
         let (instance_tx, instance_rx) = oneshot();
@@ -0,0 +1,145 @@
+//! This thread sets the IR by the time of day
+//!
+//! Inside the `ir_schedule` window the IR is set to `mode` and outside of it
+//! to `otherwise`. The local time is checked every minute and the IR is only
+//! set when the window is entered or left, so a mode set by hand with
+//! `NeoInstance::override_ir` is kept until the next boundary
+
+use tokio::{
+    sync::{mpsc::Receiver as MpscReceiver, oneshot::Sender as OneshotSender},
+    time::{interval, timeout, Duration, MissedTickBehavior},
+};
+
+use super::NeoInstance;
+use crate::{config::IrScheduleConfig, utils::now_local, AnyResult, Result};
+
+/// How often the time is checked
+const CHECK_PERIOD: Duration = Duration::from_secs(60);
+/// Time allowed to reach the camera, it is tried again on the next check
+const SET_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) enum IrRequest {
+    /// The IR was set by hand
+    Override { sender: OneshotSender<()> },
+}
+
+pub(crate) struct NeoCamIrThread {
+    ir_request_rx: MpscReceiver<IrRequest>,
+    instance: NeoInstance,
+}
+
+impl NeoCamIrThread {
+    pub(crate) async fn new(
+        ir_request_rx: MpscReceiver<IrRequest>,
+        instance: NeoInstance,
+    ) -> Result<Self> {
+        Ok(Self {
+            ir_request_rx,
+            instance,
+        })
+    }
+
+    pub(crate) async fn run(&mut self) -> Result<()> {
+        let mut config = self.instance.config().await?;
+        let name = config.borrow().name.clone();
+        // Whether it was inside the window when last checked, None to set the IR
+        // on the next check without it counting as a boundary
+        let mut inside_window: Option<bool> = None;
+        let mut overridden = false;
+        let mut current = config.borrow().ir_schedule.clone();
+        let mut ticker = interval(CHECK_PERIOD);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                request = self.ir_request_rx.recv() => match request {
+                    Some(IrRequest::Override { sender }) => {
+                        if config.borrow().ir_schedule.is_some() {
+                            log::info!("{name}: IR schedule paused until the next change");
+                        }
+                        overridden = true;
+                        let _ = sender.send(());
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+                v = config.wait_for(|config| config.ir_schedule != current) => {
+                    current = v?.ir_schedule.clone();
+                    inside_window = None;
+                    overridden = false;
+                },
+                _ = ticker.tick() => {},
+            }
+
+            let schedule = match current.clone() {
+                Some(schedule) => schedule,
+                None => {
+                    inside_window = None;
+                    continue;
+                }
+            };
+            let inside = in_window(&schedule, local_minute());
+            if inside_window == Some(inside) {
+                continue;
+            }
+            if inside_window.is_some() {
+                // A boundary ends any manual setting
+                overridden = false;
+            }
+            if overridden {
+                inside_window = Some(inside);
+                continue;
+            }
+
+            let mode = if inside {
+                schedule.mode
+            } else {
+                schedule.otherwise
+            };
+            let res = timeout(
+                SET_TIMEOUT,
+                self.instance.run_task(|cam| {
+                    Box::pin(async move {
+                        cam.irled_light_set(mode.light_state()).await?;
+                        AnyResult::Ok(())
+                    })
+                }),
+            )
+            .await;
+            match res {
+                Ok(Ok(())) => {
+                    log::info!("{name}: IR set to {mode:?} by the schedule");
+                    inside_window = Some(inside);
+                }
+                Ok(Err(e)) => {
+                    log::warn!("{name}: Could not set the IR by the schedule: {e:?}");
+                }
+                Err(_) => {
+                    log::debug!("{name}: Camera not ready to set the IR by the schedule");
+                }
+            }
+        }
+    }
+}
+
+/// Minutes since local midnight
+fn local_minute() -> u16 {
+    let now = now_local();
+    now.hour() as u16 * 60 + now.minute() as u16
+}
+
+/// Parse HH:MM into minutes since midnight, the config has been validated
+fn minute_of_day(time: &str) -> u16 {
+    let (hour, minute) = time.split_once(':').unwrap_or((time, "0"));
+    hour.parse::<u16>().unwrap_or(0) * 60 + minute.parse::<u16>().unwrap_or(0)
+}
+
+/// Whether the minute is inside the window, which may wrap past midnight
+fn in_window(schedule: &IrScheduleConfig, minute: u16) -> bool {
+    let start = minute_of_day(&schedule.start);
+    let end = minute_of_day(&schedule.end);
+    if start <= end {
+        start <= minute && minute < end
+    } else {
+        minute >= start || minute < end
+    }
+}
@@ -18,8 +18,9 @@ use crate::{AnyResult, Result};
 use neolink_core::bc_protocol::MotionStatus;
 
 pub(crate) enum MdState {
+    /// Motion started at this time with any AI types that were detected
     #[allow(dead_code)]
-    Start(Instant),
+    Start(Instant, Vec<String>),
     Stop(Instant),
     Unknown,
 }
@@ -78,7 +79,7 @@ impl NeoCamMdThread {
                                 match event {
                                     MotionStatus::Start(at) => {
                                         watcher.send_replace(
-                                            MdState::Start(at.into())
+                                            MdState::Start(at.into(), md.ai_detected().to_vec())
                                         );
                                     }
                                     MotionStatus::Stop(at) => {
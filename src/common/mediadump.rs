@@ -0,0 +1,56 @@
+//! Writes the BcMedia of a stream to disk for offline debugging
+//!
+//! The bytes are written exactly as the camera sent them. Each stream is
+//! appended to `{path}/{camera}-{stream}.bcmedia` until the
+//! file reaches `max_size` MB. The dump can be read back with
+//! `neolink_core::bcmedia::dump::read_dump` to replay it without the camera
+use std::path::PathBuf;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::{config::DumpConfig, AnyResult};
+use neolink_core::bc_protocol::StreamKind;
+
+pub(crate) struct MediaDump {
+    file: tokio::fs::File,
+    path: PathBuf,
+    written: u64,
+    max_size: u64,
+}
+
+impl MediaDump {
+    pub(crate) async fn open(name: &str, kind: StreamKind, config: &DumpConfig) -> AnyResult<Self> {
+        let mut path = PathBuf::from(&config.path);
+        tokio::fs::create_dir_all(&path).await?;
+        path.push(format!("{name}-{kind}.bcmedia"));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let written = file.metadata().await?.len();
+        log::info!("{name}: Dumping the {kind} stream to {}", path.display());
+        Ok(Self {
+            file,
+            path,
+            written,
+            max_size: config.max_size * 1024 * 1024,
+        })
+    }
+
+    /// Append the camera's bytes, returns false once the dump is full
+    pub(crate) async fn write(&mut self, buf: &[u8]) -> AnyResult<bool> {
+        if self.written >= self.max_size {
+            return Ok(false);
+        }
+        self.file.write_all(buf).await?;
+        self.file.flush().await?;
+        self.written += buf.len() as u64;
+        if self.written >= self.max_size {
+            log::info!(
+                "Dump {} is full, no longer writing to it",
+                self.path.display()
+            );
+        }
+        Ok(true)
+    }
+}
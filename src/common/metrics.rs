@@ -0,0 +1,370 @@
+//! Counters of the health of the cameras for prometheus
+//!
+//! The camera, stream and rtsp threads look up their counters by name once
+//! and then only touch atomics so that counting never waits on a lock.
+//! When `[metrics]` is set the counters are served in the prometheus text
+//! format at `http://{bind}:{bind_port}/metrics`
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch::Receiver as WatchReceiver,
+    time::{interval, timeout, Duration, Instant, MissedTickBehavior},
+};
+
+use super::{ConnectionState, NeoReactor};
+use crate::{
+    config::{Config, MetricsConfig},
+    AnyResult,
+};
+use neolink_core::bc_protocol::StreamKind;
+
+/// How often the bitrate is worked out from the byte count
+const BITRATE_PERIOD: Duration = Duration::from_secs(5);
+/// Time a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request that is read
+const MAX_REQUEST: usize = 8 * 1024;
+
+static CAMERAS: Lazy<Mutex<BTreeMap<String, Arc<CameraMetrics>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+static STREAMS: Lazy<Mutex<BTreeMap<(String, String), Arc<StreamMetrics>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The counters of a camera's connection
+pub(crate) struct CameraMetrics {
+    state: AtomicU8,
+    reconnects: AtomicU64,
+}
+
+impl CameraMetrics {
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        let code = match state {
+            ConnectionState::Disconnected => 0,
+            ConnectionState::Connecting => 1,
+            ConnectionState::Connected => 2,
+        };
+        self.state.store(code, Ordering::Relaxed);
+    }
+
+    fn state(&self) -> ConnectionState {
+        match self.state.load(Ordering::Relaxed) {
+            2 => ConnectionState::Connected,
+            1 => ConnectionState::Connecting,
+            _ => ConnectionState::Disconnected,
+        }
+    }
+
+    /// Count a lost connection
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters of one of a camera's streams
+#[derive(Default)]
+pub(crate) struct StreamMetrics {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    /// Bits per second over the last `BITRATE_PERIOD`
+    bitrate: AtomicU64,
+    clients: AtomicI64,
+}
+
+impl StreamMetrics {
+    /// Count a video frame from the camera
+    pub(crate) fn frame(&self, len: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count an rtsp client until the returned guard is dropped
+    pub(crate) fn client(self: &Arc<Self>) -> ClientGuard {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self.clone())
+    }
+}
+
+/// Counts a client for as long as it is held
+pub(crate) struct ClientGuard(Arc<StreamMetrics>);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The frames and bytes received so far on each of a camera's streams
+///
+/// Only the streams that have been started are listed
+pub(crate) fn stream_totals(name: &str) -> Vec<(String, u64, u64)> {
+    STREAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((camera, _), _)| camera == name)
+        .map(|((_, stream), metrics)| {
+            (
+                stream.clone(),
+                metrics.frames.load(Ordering::Relaxed),
+                metrics.bytes.load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+/// The connection counters of a camera
+pub(crate) fn camera_metrics(name: &str) -> Arc<CameraMetrics> {
+    CAMERAS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| {
+            Arc::new(CameraMetrics {
+                state: AtomicU8::new(0),
+                reconnects: AtomicU64::new(0),
+            })
+        })
+        .clone()
+}
+
+/// The counters of a camera's stream
+pub(crate) fn stream_metrics(name: &str, kind: StreamKind) -> Arc<StreamMetrics> {
+    STREAMS
+        .lock()
+        .unwrap()
+        .entry((name.to_string(), stream_label(kind).to_string()))
+        .or_default()
+        .clone()
+}
+
+fn stream_label(kind: StreamKind) -> &'static str {
+    match kind {
+        StreamKind::Main => "main",
+        StreamKind::Sub => "sub",
+        StreamKind::Extern => "extern",
+    }
+}
+
+/// Serve the metrics while `[metrics]` is in the config
+pub(crate) async fn metrics_main(reactor: NeoReactor) -> AnyResult<()> {
+    let mut config = reactor.config().await?;
+    loop {
+        let metrics = config.borrow_and_update().metrics.clone();
+        let serve_config = config.clone();
+        tokio::select! {
+            v = config.wait_for(|config| config.metrics != metrics) => {
+                v?;
+            },
+            v = async {
+                match metrics.as_ref() {
+                    Some(metrics) => serve(metrics, serve_config).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                if let Err(e) = v {
+                    // Wait for the user to fix the config
+                    log::error!("Metrics server stopped: {e:?}");
+                    config.wait_for(|config| config.metrics != metrics).await?;
+                }
+            },
+        }
+    }
+}
+
+async fn serve(metrics: &MetricsConfig, config: WatchReceiver<Config>) -> AnyResult<()> {
+    let addr = format!("{}:{}", metrics.bind_addr, metrics.bind_port);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("Unable to serve the metrics on {addr}"))?;
+    log::info!("Metrics available at http://{addr}/metrics");
+    tokio::select! {
+        v = async {
+            loop {
+                let (socket, _) = listener.accept().await?;
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = reply(socket, &config).await {
+                        log::debug!("Metrics request failed: {e:?}");
+                    }
+                });
+            }
+        } => v,
+        _ = sample_bitrates() => Ok(()),
+    }
+}
+
+async fn reply(mut socket: TcpStream, config: &WatchReceiver<Config>) -> AnyResult<()> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    timeout(REQUEST_TIMEOUT, async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let read = socket.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+        AnyResult::Ok(())
+    })
+    .await??;
+
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or_default().split(' ');
+    let response = match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(&config.borrow());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Work out the bitrate of every stream from its byte count
+async fn sample_bitrates() {
+    let mut last_bytes: BTreeMap<(String, String), u64> = Default::default();
+    let mut last = Instant::now();
+    let mut ticker = interval(BITRATE_PERIOD);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let secs = last.elapsed().as_secs_f64().max(1.0);
+        last = Instant::now();
+        let streams = STREAMS.lock().unwrap().clone();
+        for (key, stream) in streams {
+            let bytes = stream.bytes.load(Ordering::Relaxed);
+            let prev = last_bytes.insert(key, bytes).unwrap_or(bytes);
+            let bitrate = (bytes.saturating_sub(prev) as f64 * 8.0 / secs) as u64;
+            stream.bitrate.store(bitrate, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The metrics of the cameras in the config in the prometheus text format
+fn render(config: &Config) -> String {
+    let names = config
+        .cameras
+        .iter()
+        .filter(|camera| camera.enabled)
+        .map(|camera| camera.name.as_str())
+        .collect::<Vec<_>>();
+    let cameras = CAMERAS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| names.contains(&name.as_str()))
+        .map(|(name, metrics)| (name.clone(), metrics.clone()))
+        .collect::<Vec<_>>();
+    let streams = STREAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((name, _), _)| names.contains(&name.as_str()))
+        .map(|(key, metrics)| (key.clone(), metrics.clone()))
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    header(
+        &mut out,
+        "neolink_camera_state",
+        "gauge",
+        "The state of the connection to the camera",
+    );
+    for (name, metrics) in cameras.iter() {
+        let current = metrics.state();
+        for state in [
+            ConnectionState::Connected,
+            ConnectionState::Connecting,
+            ConnectionState::Disconnected,
+        ] {
+            let _ = writeln!(
+                out,
+                "neolink_camera_state{{camera=\"{}\",state=\"{}\"}} {}",
+                escape(name),
+                state.as_str(),
+                u8::from(state == current)
+            );
+        }
+    }
+    header(
+        &mut out,
+        "neolink_camera_reconnects_total",
+        "counter",
+        "Times the connection to the camera was lost",
+    );
+    for (name, metrics) in cameras.iter() {
+        let _ = writeln!(
+            out,
+            "neolink_camera_reconnects_total{{camera=\"{}\"}} {}",
+            escape(name),
+            metrics.reconnects.load(Ordering::Relaxed)
+        );
+    }
+
+    let stream_values: [(&str, &str, &str, fn(&StreamMetrics) -> i64); 4] = [
+        (
+            "neolink_stream_frames_total",
+            "counter",
+            "Video frames received from the camera",
+            |m| m.frames.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "neolink_stream_bytes_total",
+            "counter",
+            "Bytes of video received from the camera",
+            |m| m.bytes.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "neolink_stream_bitrate_bps",
+            "gauge",
+            "Video bitrate in bits per second over the last few seconds",
+            |m| m.bitrate.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "neolink_stream_clients",
+            "gauge",
+            "Rtsp clients watching the stream",
+            |m| m.clients.load(Ordering::Relaxed),
+        ),
+    ];
+    for (metric, kind, help, value) in stream_values {
+        header(&mut out, metric, kind, help);
+        for ((name, stream), metrics) in streams.iter() {
+            let _ = writeln!(
+                out,
+                "{metric}{{camera=\"{}\",stream=\"{stream}\"}} {}",
+                escape(name),
+                value(metrics)
+            );
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {metric} {help}");
+    let _ = writeln!(out, "# TYPE {metric} {kind}");
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
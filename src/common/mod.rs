@@ -1,17 +1,29 @@
 mod camthread;
+mod connqueue;
 mod instance;
+mod irthread;
 mod mdthread;
+mod mediadump;
+mod metrics;
 mod neocam;
 mod pushnoti;
 mod reactor;
+mod recordthread;
+mod snapthread;
 mod streamthread;
 mod usecounter;
 
 pub(crate) use camthread::*;
+pub(crate) use connqueue::*;
 pub(crate) use instance::*;
+pub(crate) use irthread::*;
 pub(crate) use mdthread::*;
+pub(crate) use mediadump::*;
+pub(crate) use metrics::*;
 pub(crate) use neocam::*;
 pub(crate) use pushnoti::*;
 pub(crate) use reactor::*;
+pub(crate) use recordthread::*;
+pub(crate) use snapthread::*;
 pub(crate) use streamthread::*;
 pub(crate) use usecounter::*;
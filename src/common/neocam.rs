@@ -6,7 +6,7 @@
 //!    Clonable interface to share amongst threadsanyhow::anyhow;
 use anyhow::Context;
 use futures::{stream::StreamExt, TryFutureExt};
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
 use tokio::{
     sync::{
         mpsc::{channel as mpsc, Sender as MpscSender},
@@ -14,34 +14,49 @@ use tokio::{
         watch::{channel as watch, Receiver as WatchReceiver, Sender as WatchSender},
     },
     task::JoinSet,
-    time::{sleep, Duration},
+    time::{sleep, timeout, Duration},
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
 
 use super::{
-    MdRequest, MdState, NeoCamMdThread, NeoCamStreamThread, NeoCamThread, NeoCamThreadState,
-    NeoInstance, Permit, PnRequest, PushNoti, StreamInstance, StreamRequest, UseCounter,
+    ConnectQueue, MdRequest, MdState, NeoCamMdThread, NeoCamRecordThread, NeoCamSnapThread,
+    NeoCamStreamThread, NeoCamThread, NeoCamThreadState, NeoInstance, Permit, PnRequest, PushNoti,
+    RecordRequest, SnapRequest, Snapshot, StreamInstance, StreamRequest, UseCounter,
 };
 use crate::{config::CameraConfig, AnyResult, Result};
 use neolink_core::bc_protocol::{BcCamera, StreamKind};
 
+/// Time the tasks of a camera or the reactor have to stop before they are aborted
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[allow(dead_code)]
 pub(crate) enum NeoCamCommand {
     HangUp,
     Instance(OneshotSender<Result<NeoInstance>>),
+    StreamInstance(OneshotSender<Result<NeoInstance>>),
     Stream(StreamKind, OneshotSender<StreamInstance>),
     HighStream(OneshotSender<Option<StreamInstance>>),
     LowStream(OneshotSender<Option<StreamInstance>>),
     Streams(OneshotSender<Vec<StreamInstance>>),
+    EnableStream(StreamKind, bool, OneshotSender<()>),
     Motion(OneshotSender<WatchReceiver<MdState>>),
+    Snapshot(OneshotSender<WatchReceiver<Snapshot>>),
+    Record(bool, OneshotSender<()>),
+    Recording(OneshotSender<WatchReceiver<bool>>),
+    IrOverride(OneshotSender<()>),
+    Pause(bool, OneshotSender<()>),
+    Paused(OneshotSender<WatchReceiver<bool>>),
     Config(OneshotSender<WatchReceiver<CameraConfig>>),
     Disconnect(OneshotSender<()>),
+    Reconnect(OneshotSender<()>),
     Connect(OneshotSender<()>),
     State(OneshotSender<NeoCamThreadState>),
+    StateWatch(OneshotSender<WatchReceiver<NeoCamThreadState>>),
     GetPermit(OneshotSender<Permit>),
     PushNoti(OneshotSender<WatchReceiver<Option<PushNoti>>>),
     GetUid(OneshotSender<String>),
+    Error(OneshotSender<WatchReceiver<Option<String>>>),
 }
 /// The underlying camera binding
 pub(crate) struct NeoCam {
@@ -56,17 +71,26 @@ impl NeoCam {
     pub(crate) async fn new(
         config: CameraConfig,
         pn_request_tx: MpscSender<PnRequest>,
+        connect_queue: Arc<ConnectQueue>,
     ) -> Result<NeoCam> {
         let (commander_tx, commander_rx) = mpsc(100);
         let (watch_config_tx, watch_config_rx) = watch(config.clone());
         let (camera_watch_tx, camera_watch_rx) = watch(Weak::new());
+        let (stream_camera_watch_tx, stream_camera_watch_rx) = watch(Weak::new());
         let (stream_request_tx, stream_request_rx) = mpsc(100);
         let (md_request_tx, md_request_rx) = mpsc(100);
+        let (snap_request_tx, snap_request_rx) = mpsc(100);
+        let (record_request_tx, record_request_rx) = mpsc(100);
+        let (ir_request_tx, ir_request_rx) = mpsc(100);
         let (state_tx, state_rx) = watch(NeoCamThreadState::Connected);
         let (uid_tx, uid_rx) = watch(config.camera_uid.clone());
+        let (error_tx, error_rx) = watch(None);
+        let (paused_tx, paused_rx) = watch(false);
+        let (reconnect_tx, reconnect_rx) = watch(());
 
         let set = JoinSet::new();
         let users = UseCounter::new().await;
+        let users_rx = users.get_counter();
 
         let mut me = Self {
             cancel: CancellationToken::new(),
@@ -110,6 +134,14 @@ impl NeoCam {
                                 );
                                 let _ = result.send(instance);
                             }
+                            NeoCamCommand::StreamInstance(result) => {
+                                let instance = NeoInstance::new(
+                                    stream_camera_watch_rx.clone(),
+                                    thread_commander_tx.clone(),
+                                    thread_cancel.clone(),
+                                );
+                                let _ = result.send(instance);
+                            }
                             NeoCamCommand::Stream(name, sender) => {
                                 stream_request_tx.send(
                                     StreamRequest::GetOrInsert {
@@ -140,6 +172,15 @@ impl NeoCam {
                                     }
                                 ).await?;
                             },
+                            NeoCamCommand::EnableStream(name, enabled, sender) => {
+                                stream_request_tx.send(
+                                    StreamRequest::Enable {
+                                        name,
+                                        enabled,
+                                        sender,
+                                    }
+                                ).await?;
+                            },
                             NeoCamCommand::Motion(sender) => {
                                 md_request_tx.send(
                                     MdRequest::Get {
@@ -147,6 +188,42 @@ impl NeoCam {
                                     }
                                 ).await?;
                             },
+                            NeoCamCommand::Snapshot(sender) => {
+                                snap_request_tx.send(
+                                    SnapRequest::Get {
+                                        sender,
+                                    }
+                                ).await?;
+                            },
+                            NeoCamCommand::Record(on, sender) => {
+                                record_request_tx.send(
+                                    RecordRequest::Set {
+                                        on,
+                                        sender,
+                                    }
+                                ).await?;
+                            },
+                            NeoCamCommand::Recording(sender) => {
+                                record_request_tx.send(
+                                    RecordRequest::Get {
+                                        sender,
+                                    }
+                                ).await?;
+                            },
+                            NeoCamCommand::IrOverride(sender) => {
+                                ir_request_tx.send(
+                                    IrRequest::Override {
+                                        sender,
+                                    }
+                                ).await?;
+                            },
+                            NeoCamCommand::Pause(paused, sender) => {
+                                paused_tx.send_replace(paused);
+                                let _ = sender.send(());
+                            },
+                            NeoCamCommand::Paused(sender) => {
+                                let _ = sender.send(paused_rx.clone());
+                            },
                             NeoCamCommand::Config(sender) => {
                                 let _ = sender.send(thread_watch_config_rx.clone());
                             },
@@ -164,9 +241,17 @@ impl NeoCam {
                                 }
                                 let _ = sender.send(());
                             }
+                            NeoCamCommand::Reconnect(sender) => {
+                                log::debug!("{}: Reconnect On Request", thread_watch_config_rx.borrow().name);
+                                reconnect_tx.send_replace(());
+                                let _ = sender.send(());
+                            }
                             NeoCamCommand::State(sender) => {
                                 let _ = sender.send(*state_tx.borrow());
                             }
+                            NeoCamCommand::StateWatch(sender) => {
+                                let _ = sender.send(state_tx.subscribe());
+                            }
                             NeoCamCommand::GetPermit(sender) => {
                                 let _ = sender.send(users.create_activated().await?);
                             }
@@ -177,6 +262,9 @@ impl NeoCam {
                                     }
                                 ).await?;
                             },
+                            NeoCamCommand::Error(sender) => {
+                                let _ = sender.send(error_rx.clone());
+                            }
                             NeoCamCommand::GetUid(sender) => {
                                 let mut uid_rx = uid_rx.clone();
                                 tokio::task::spawn(async move {
@@ -210,9 +298,13 @@ impl NeoCam {
         // It will keep it logged and reconnect
         let thread_watch_config_rx = watch_config_rx.clone();
         let mut cam_thread = NeoCamThread::new(
-            state_rx,
+            state_rx.clone(),
             thread_watch_config_rx,
             camera_watch_tx,
+            users_rx.clone(),
+            error_tx,
+            reconnect_rx.clone(),
+            connect_queue.clone(),
             me.cancel.clone(),
         )
         .await;
@@ -222,8 +314,60 @@ impl NeoCam {
             v
         });
 
+        // This thread provides the connection used by the streams
+        //
+        // Usually this is just the main connection but with `stream_session`
+        // a second login is kept just for the streams
+        let session_cancel = me.cancel.clone();
+        let mut session_config_rx = watch_config_rx.clone();
+        let session_camera_watch_rx = me.camera_watch.clone();
+        me.set.spawn(async move {
+            let name = session_config_rx.borrow().name.clone();
+            loop {
+                let separate = session_config_rx.borrow_and_update().stream_session;
+                let mut changed_config_rx = session_config_rx.clone();
+                tokio::select! {
+                    _ = session_cancel.cancelled() => return AnyResult::Ok(()),
+                    v = changed_config_rx.wait_for(|config| config.stream_session != separate) => {
+                        v?;
+                    },
+                    v = async {
+                        if separate {
+                            log::info!("{name}: Using a separate login for the streams");
+                            let (session_watch_tx, session_watch_rx) = watch(Weak::new());
+                            let (session_error_tx, _) = watch(None);
+                            let mut session_thread = NeoCamThread::new(
+                                state_rx.clone(),
+                                session_config_rx.clone(),
+                                session_watch_tx,
+                                users_rx.clone(),
+                                session_error_tx,
+                                reconnect_rx.clone(),
+                                connect_queue.clone(),
+                                session_cancel.child_token(),
+                            )
+                            .await
+                            .stream_only();
+                            tokio::select! {
+                                v = session_thread.run() => v,
+                                v = forward_camera(session_watch_rx, &stream_camera_watch_tx) => v,
+                            }
+                        } else {
+                            forward_camera(session_camera_watch_rx.clone(), &stream_camera_watch_tx).await
+                        }
+                    } => {
+                        if let Err(e) = v {
+                            log::error!("{name}: Stream connection stopped: {e:?}");
+                        }
+                        stream_camera_watch_tx.send_replace(Weak::new());
+                        changed_config_rx.wait_for(|config| config.stream_session != separate).await?;
+                    },
+                }
+            }
+        });
+
         // This thread maintains the streams
-        let stream_instance = instance.subscribe().await?;
+        let stream_instance = instance.stream_session().await?;
         let stream_cancel = me.cancel.clone();
         let mut stream_thread = NeoCamStreamThread::new(stream_request_rx, stream_instance).await?;
         me.set.spawn(async move {
@@ -250,6 +394,48 @@ impl NeoCam {
             }
         });
 
+        // This thread keeps the snapshot cache up to date
+        let snap_instance = instance.subscribe().await?;
+        let snap_cancel = me.cancel.clone();
+        let mut snap_thread = NeoCamSnapThread::new(snap_request_rx, snap_instance).await?;
+        me.set.spawn(async move {
+            tokio::select! {
+                _ = snap_cancel.cancelled() => AnyResult::Ok(()),
+                v = snap_thread.run() => {
+                    log::debug!("Snapshot thread ended; {:?}", v);
+                    v
+                },
+            }
+        });
+
+        // This thread records to disk on request
+        let record_instance = instance.subscribe().await?;
+        let record_cancel = me.cancel.clone();
+        let mut record_thread = NeoCamRecordThread::new(record_request_rx, record_instance).await?;
+        me.set.spawn(async move {
+            tokio::select! {
+                _ = record_cancel.cancelled() => AnyResult::Ok(()),
+                v = record_thread.run() => {
+                    log::debug!("Record thread ended; {:?}", v);
+                    v
+                },
+            }
+        });
+
+        // This thread sets the IR by the time of day
+        let ir_instance = instance.subscribe().await?;
+        let ir_cancel = me.cancel.clone();
+        let mut ir_thread = NeoCamIrThread::new(ir_request_rx, ir_instance).await?;
+        me.set.spawn(async move {
+            tokio::select! {
+                _ = ir_cancel.cancelled() => AnyResult::Ok(()),
+                v = ir_thread.run() => {
+                    log::debug!("IR thread ended; {:?}", v);
+                    v
+                },
+            }
+        });
+
         // This thread just does a one time report on camera info
         let report_instance = instance.subscribe().await?;
         let report_cancel = me.cancel.clone();
@@ -368,7 +554,7 @@ impl NeoCam {
                 v = async {
                     let mut md = md_permit_instance.motion().await.with_context(|| "Unable to acquire motion watcher")?;
                     loop{
-                        md.wait_for(|md| matches!(md, MdState::Start(_))).await.with_context(|| "MD Watcher lost")?;
+                        md.wait_for(|md| matches!(md, MdState::Start(..))).await.with_context(|| "MD Watcher lost")?;
                         let _permit = md_permit_instance.permit().await.with_context(|| "Unuable to acquire motion permit")?;
                         md.wait_for(|md| matches!(md, MdState::Stop(_))).await.with_context(|| "MD Watcher lost")?;
                         // Try waiting for 30s
@@ -376,7 +562,7 @@ impl NeoCam {
                         // loop early to reaquire the permit
                         tokio::select!{
                             _ = sleep(Duration::from_secs(30)) => {},
-                            v = md.wait_for(|md| matches!(md, MdState::Start(_))) => {v.with_context(|| "MD Watcher lost")?;},
+                            v = md.wait_for(|md| matches!(md, MdState::Start(..))) => {v.with_context(|| "MD Watcher lost")?;},
                         }
                     }
                 } => {
@@ -456,7 +642,16 @@ impl NeoCam {
     }
 
     pub(crate) async fn update_config(&self, config: CameraConfig) -> Result<()> {
-        self.config_watch.send_replace(config);
+        // Only wake the camera if its own settings changed so that a change to
+        // another camera does not restart this one's streams
+        self.config_watch.send_if_modified(|current| {
+            if *current != config {
+                *current = config;
+                true
+            } else {
+                false
+            }
+        });
         Ok(())
     }
 }
@@ -464,13 +659,58 @@ impl NeoCam {
 impl Drop for NeoCam {
     fn drop(&mut self) {
         log::trace!("Drop NeoCam");
-        let mut set = std::mem::take(&mut self.set);
+        let set = std::mem::take(&mut self.set);
         let commander = self.commander.clone();
+        let cancel = self.cancel.clone();
+        let name = self.config_watch.borrow().name.clone();
         let _gt = tokio::runtime::Handle::current().enter();
         tokio::task::spawn(async move {
-            let _ = commander.send(NeoCamCommand::HangUp).await;
-            while set.join_next().await.is_some() {}
-            log::trace!("Dropped NeoCam");
+            let hangup = async move {
+                let _ = commander.send(NeoCamCommand::HangUp).await;
+            };
+            if shutdown(set, hangup, cancel, SHUTDOWN_TIMEOUT).await {
+                log::trace!("Dropped NeoCam");
+            } else {
+                log::warn!(
+                    "{name}: Camera did not stop within {}s, aborted its tasks",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                );
+            }
         });
     }
 }
+
+/// Hang up and wait for the tasks to finish, aborting them after `limit`
+///
+/// The hang up is part of the wait as its command loop may be the task that
+/// is stuck. Returns false if the tasks had to be aborted
+pub(crate) async fn shutdown<T: 'static, F: std::future::Future<Output = ()>>(
+    mut set: JoinSet<T>,
+    hangup: F,
+    cancel: CancellationToken,
+    limit: Duration,
+) -> bool {
+    let stopped = timeout(limit, async {
+        hangup.await;
+        cancel.cancel();
+        while set.join_next().await.is_some() {}
+    })
+    .await
+    .is_ok();
+    if !stopped {
+        cancel.cancel();
+        set.abort_all();
+    }
+    stopped
+}
+
+/// Copy the camera from one watch to another until the sender is dropped
+async fn forward_camera(
+    mut from: WatchReceiver<Weak<BcCamera>>,
+    to: &WatchSender<Weak<BcCamera>>,
+) -> AnyResult<()> {
+    loop {
+        to.send_replace(from.borrow_and_update().clone());
+        from.changed().await?;
+    }
+}
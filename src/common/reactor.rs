@@ -16,7 +16,7 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 
-use super::{NeoCam, NeoInstance};
+use super::{shutdown, ConnectQueue, NeoCam, NeoInstance, SHUTDOWN_TIMEOUT};
 use crate::{common::PushNotiThread, config::Config, AnyResult, Result};
 
 #[allow(clippy::large_enum_variant)]
@@ -47,6 +47,7 @@ impl NeoReactor {
         let cancel1 = cancel.clone();
         let cancel2 = cancel.clone();
         let config_tx = Arc::new(config_tx);
+        let connect_queue = ConnectQueue::new(config_tx.subscribe());
         set.spawn(async move {
             let mut instances: HashMap<String, NeoCam> = Default::default();
 
@@ -74,7 +75,7 @@ impl NeoReactor {
                                         log::debug!("Inserting new insance");
                                         let current_config: Config = (*config_tx.borrow()).clone();
                                         if let Some(config) = current_config.cameras.iter().find(|cam| cam.name == name).cloned() {
-                                            let cam = NeoCam::new(config, push_noti.clone()).await?;
+                                            let cam = NeoCam::new(config, push_noti.clone(), connect_queue.clone()).await?;
                                             log::debug!("New instance created");
                                             Result::Ok(Some(
                                                 vac.insert(
@@ -179,15 +180,23 @@ impl NeoReactor {
 impl Drop for NeoReactor {
     fn drop(&mut self) {
         if let Some(set) = self.set.take() {
-            if let Ok(mut set) = Arc::try_unwrap(set) {
+            if let Ok(set) = Arc::try_unwrap(set) {
                 log::trace!("Drop NeoReactor");
-                self.cancel.cancel();
                 let commander = self.commander.clone();
+                let cancel = self.cancel.clone();
                 let _gt = tokio::runtime::Handle::current().enter();
                 tokio::task::spawn(async move {
-                    let _ = commander.send(NeoReactorCommand::HangUp).await;
-                    while set.join_next().await.is_some() {}
-                    log::trace!("Dropped NeoReactor");
+                    let hangup = async move {
+                        let _ = commander.send(NeoReactorCommand::HangUp).await;
+                    };
+                    if shutdown(set, hangup, cancel, SHUTDOWN_TIMEOUT).await {
+                        log::trace!("Dropped NeoReactor");
+                    } else {
+                        log::warn!(
+                            "Cameras did not stop within {}s, aborted them",
+                            SHUTDOWN_TIMEOUT.as_secs()
+                        );
+                    }
                 });
             }
         }
@@ -0,0 +1,194 @@
+//! This thread records a stream to disk on request
+//!
+//! Recording is started and stopped with `NeoInstance::set_recording`.
+//! Each recording is written to a new file in the `recording.path`
+//! directory as the raw h264/h265 stream starting from a keyframe
+
+use futures::Future;
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::{
+        mpsc::Receiver as MpscReceiver,
+        oneshot::Sender as OneshotSender,
+        watch::{channel as watch, Receiver as WatchReceiver, Sender as WatchSender},
+    },
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use super::{NeoInstance, VidFormat};
+use crate::{config::RecordConfig, utils::now_local, AnyResult, Result};
+
+pub(crate) struct NeoCamRecordThread {
+    record_watcher: Arc<WatchSender<bool>>,
+    record_request_rx: MpscReceiver<RecordRequest>,
+    cancel: CancellationToken,
+    instance: NeoInstance,
+}
+
+impl NeoCamRecordThread {
+    pub(crate) async fn new(
+        record_request_rx: MpscReceiver<RecordRequest>,
+        instance: NeoInstance,
+    ) -> Result<Self> {
+        let (record_watcher, _) = watch(false);
+        let record_watcher = Arc::new(record_watcher);
+        Ok(Self {
+            record_watcher,
+            record_request_rx,
+            cancel: CancellationToken::new(),
+            instance,
+        })
+    }
+
+    pub(crate) async fn run(&mut self) -> Result<()> {
+        let thread_cancel = self.cancel.clone();
+        let watcher = self.record_watcher.clone();
+        let mut recording = self.record_watcher.subscribe();
+        let record_instance = self.instance.clone();
+        let mut config = self.instance.config().await?;
+        tokio::select! {
+            _ = thread_cancel.cancelled() => {
+                Ok(())
+            },
+            v = async {
+                while let Some(request) = self.record_request_rx.recv().await {
+                    match request {
+                        RecordRequest::Set {
+                            on,
+                            sender
+                        } => {
+                            self.record_watcher.send_if_modified(|state| {
+                                let changed = *state != on;
+                                *state = on;
+                                changed
+                            });
+                            let _ = sender.send(());
+                        },
+                        RecordRequest::Get {
+                            sender
+                        } => {
+                            let _ = sender.send(self.record_watcher.subscribe());
+                        },
+                    }
+                }
+                Ok(())
+            } => v,
+            v = async {
+                loop {
+                    recording.wait_for(|on| *on).await?;
+                    let (name, settings) = {
+                        let config = config.borrow_and_update();
+                        (config.name.clone(), config.recording.clone())
+                    };
+                    let mut stop_recording = recording.clone();
+                    let mut stop_config = config.clone();
+                    let stop_settings = settings.clone();
+                    let stop = async move {
+                        tokio::select! {
+                            v = stop_recording.wait_for(|on| !*on) => v.map(|_| ()),
+                            // Start a new file with the new settings
+                            v = stop_config.wait_for(|config| config.recording != stop_settings) => v.map(|_| ()),
+                        }
+                    };
+                    match record(&name, &record_instance, &settings, stop).await {
+                        Ok(()) => {
+                            if !*recording.borrow() {
+                                log::info!("{name}: Recording stopped");
+                            }
+                        }
+                        Err(e) => {
+                            log::warn!("{name}: Recording failed: {:?}", e);
+                            // Wait for the next request rather than retrying a bad path
+                            watcher.send_replace(false);
+                        }
+                    }
+                }
+            } => v,
+        }
+    }
+}
+
+/// Write the stream to a new file until `stop` completes
+async fn record<F, E>(
+    name: &str,
+    instance: &NeoInstance,
+    settings: &RecordConfig,
+    stop: F,
+) -> AnyResult<()>
+where
+    F: Future<Output = std::result::Result<(), E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let kind = settings
+        .stream
+        .as_stream_kinds()
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("No stream selected for recording"))?;
+    let mut stream = instance.stream(kind).await?;
+    stream.activate().await?;
+    let format = stream
+        .config
+        .wait_for(|config| config.vid_ready())
+        .await?
+        .vid_format
+        .clone();
+    let extension = match format {
+        VidFormat::H265 => "h265",
+        _ => "h264",
+    };
+
+    let now = now_local();
+    let time = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    let mut path = PathBuf::from(settings.path.as_deref().unwrap_or("."));
+    tokio::fs::create_dir_all(&path).await?;
+    path.push(format!("{name}-{time}.{extension}"));
+    let mut file = BufWriter::new(File::create(&path).await?);
+    log::info!("{name}: Recording {kind} stream to {}", path.display());
+
+    let mut frames = BroadcastStream::new(stream.vid.resubscribe())
+        .filter_map(|frame| frame.ok())
+        .skip_while(|frame| !frame.keyframe);
+    let r = tokio::select! {
+        v = stop => v.map_err(|e| e.into()),
+        v = async {
+            while let Some(frame) = frames.next().await {
+                file.write_all(&frame.data).await?;
+            }
+            AnyResult::Ok(())
+        } => v,
+    };
+    file.flush().await?;
+    stream.deactivate().await?;
+    r
+}
+
+impl Drop for NeoCamRecordThread {
+    fn drop(&mut self) {
+        log::trace!("Drop NeoCamRecordThread");
+        self.cancel.cancel();
+        log::trace!("Dropped NeoCamRecordThread");
+    }
+}
+
+/// Used to pass messages to the RecordThread
+pub(crate) enum RecordRequest {
+    Set {
+        on: bool,
+        sender: OneshotSender<()>,
+    },
+    Get {
+        sender: OneshotSender<WatchReceiver<bool>>,
+    },
+}
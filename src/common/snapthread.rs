@@ -0,0 +1,312 @@
+//! This thread keeps a jpeg of the latest keyframe
+//! so that snapshots can be served without decoding
+//! the stream for every request
+//!
+//! It also writes the time-lapse jpegs to disk when `timelapse` is set
+
+use anyhow::anyhow;
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{
+        mpsc::Receiver as MpscReceiver,
+        oneshot::Sender as OneshotSender,
+        watch::{channel as watch, Receiver as WatchReceiver, Sender as WatchSender},
+    },
+    time::{interval, sleep, timeout, Duration, Instant, MissedTickBehavior},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use super::NeoInstance;
+use crate::{
+    config::{SnapshotCacheConfig, TimelapseConfig},
+    image::keyframe_to_jpeg,
+    utils::now_local,
+    AnyResult, Result,
+};
+use neolink_core::bc_protocol::StreamKind;
+
+pub(crate) type Snapshot = Option<Arc<Vec<u8>>>;
+
+/// The cached jpeg is dropped once no new keyframe has come for this long
+/// or three refreshes, whichever is longer
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+pub(crate) struct NeoCamSnapThread {
+    snap_watcher: Arc<WatchSender<Snapshot>>,
+    snap_request_rx: MpscReceiver<SnapRequest>,
+    cancel: CancellationToken,
+    instance: NeoInstance,
+}
+
+impl NeoCamSnapThread {
+    pub(crate) async fn new(
+        snap_request_rx: MpscReceiver<SnapRequest>,
+        instance: NeoInstance,
+    ) -> Result<Self> {
+        let (snap_watcher, _) = watch(None);
+        let snap_watcher = Arc::new(snap_watcher);
+        Ok(Self {
+            snap_watcher,
+            snap_request_rx,
+            cancel: CancellationToken::new(),
+            instance,
+        })
+    }
+
+    pub(crate) async fn run(&mut self) -> Result<()> {
+        let thread_cancel = self.cancel.clone();
+        let watcher = self.snap_watcher.clone();
+        let snap_instance = self.instance.clone();
+        let mut config = self.instance.config().await?;
+        let mut timelapse_config = config.clone();
+        let timelapse_instance = self.instance.clone();
+        let cache = self.snap_watcher.subscribe();
+        tokio::select! {
+            _ = thread_cancel.cancelled() => {
+                Ok(())
+            },
+            v = async {
+                while let Some(request) = self.snap_request_rx.recv().await {
+                    match request {
+                        SnapRequest::Get {
+                            sender
+                        } => {
+                          let _ = sender.send(self.snap_watcher.subscribe());
+                        },
+                    }
+                }
+                Ok(())
+            } => v,
+            v = async {
+                loop {
+                    let (settings, quality) = {
+                        let config = config.borrow_and_update();
+                        (config.snapshot_cache.clone(), config.snapshot_quality)
+                    };
+                    let r = tokio::select! {
+                        v = config.wait_for(|config| config.snapshot_cache != settings || config.snapshot_quality != quality) => {
+                            v?;
+                            continue;
+                        },
+                        v = refresh_snapshot(&snap_instance, &settings, quality, &watcher) => v,
+                    };
+                    // The stream is gone, do not serve its last image
+                    watcher.send_replace(None);
+                    log::debug!("Error in snapshot task Restarting: {:?}", r);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            } => v,
+            v = async {
+                loop {
+                    let (name, settings) = {
+                        let config = timelapse_config.borrow_and_update();
+                        (config.name.clone(), config.timelapse.clone())
+                    };
+                    let r = tokio::select! {
+                        v = timelapse_config.wait_for(|config| config.timelapse != settings) => {
+                            v?;
+                            continue;
+                        },
+                        v = save_snapshots(&name, &timelapse_instance, settings.as_ref(), &cache) => v,
+                    };
+                    log::warn!("{name}: Time-lapse snapshot failed: {:?}", r);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            } => v,
+        }
+    }
+}
+
+/// Decode the latest keyframe into the cache at the refresh rate
+async fn refresh_snapshot(
+    instance: &NeoInstance,
+    settings: &SnapshotCacheConfig,
+    quality: u8,
+    watcher: &WatchSender<Snapshot>,
+) -> AnyResult<()> {
+    if !settings.enabled {
+        watcher.send_replace(None);
+        futures::future::pending::<()>().await;
+    }
+
+    let kind = settings
+        .stream
+        .as_stream_kinds()
+        .first()
+        .copied()
+        .unwrap_or(StreamKind::Sub);
+    let mut stream = instance.decode_stream(kind).await?;
+    stream.activate().await?;
+
+    let refresh = Duration::from_millis(settings.refresh);
+    let stale_after = std::cmp::max(refresh * 3, STALE_AFTER);
+    let mut wait = interval(refresh);
+    wait.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last_ts = None;
+    let mut last_new = Instant::now();
+    loop {
+        wait.tick().await;
+        if last_new.elapsed() > stale_after && watcher.borrow().is_some() {
+            log::debug!("No new keyframe for the snapshot cache, clearing it");
+            watcher.send_replace(None);
+        }
+        let format =
+            match timeout(refresh, stream.config.wait_for(|config| config.vid_ready())).await {
+                Ok(config) => config?.vid_format.clone(),
+                // Not streaming, check again on the next tick
+                Err(_) => continue,
+            };
+        let keyframe = stream
+            .vid_history
+            .borrow()
+            .iter()
+            .rev()
+            .find(|frame| frame.keyframe)
+            .cloned();
+        let keyframe = match keyframe {
+            Some(keyframe) if last_ts != Some(keyframe.ts) => keyframe,
+            // Nothing new to decode
+            _ => continue,
+        };
+        last_ts = Some(keyframe.ts);
+        last_new = Instant::now();
+        let jpeg =
+            tokio::task::spawn_blocking(move || keyframe_to_jpeg(format, &keyframe.data, quality))
+                .await??;
+        watcher.send_replace(Some(Arc::new(jpeg)));
+    }
+}
+
+/// Write a jpeg to the time-lapse directory at its interval
+///
+/// The cached jpeg is used when the snapshot cache is on, otherwise a
+/// keyframe of the stream is decoded for each one
+async fn save_snapshots(
+    name: &str,
+    instance: &NeoInstance,
+    settings: Option<&TimelapseConfig>,
+    cache: &WatchReceiver<Snapshot>,
+) -> AnyResult<()> {
+    let settings = match settings {
+        Some(settings) => settings,
+        None => futures::future::pending().await,
+    };
+    let dir = PathBuf::from(&settings.path);
+    tokio::fs::create_dir_all(&dir).await?;
+    log::info!(
+        "{name}: Writing a snapshot to {} every {}s",
+        dir.display(),
+        settings.interval
+    );
+
+    let mut wait = interval(Duration::from_secs(settings.interval));
+    wait.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        wait.tick().await;
+        let cached = cache.borrow().clone();
+        let jpeg = match cached {
+            Some(jpeg) => jpeg.to_vec(),
+            None => stream_snapshot(instance).await?,
+        };
+
+        let now = now_local();
+        let time = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let path = dir.join(format!("{name}-{time}.jpg"));
+        tokio::fs::write(&path, jpeg).await?;
+        log::debug!("{name}: Wrote snapshot {}", path.display());
+    }
+}
+
+/// How long to wait for a keyframe to make a snapshot from
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decode the next keyframe of the stream into a jpeg
+///
+/// This uses its own short lived pipeline so the rtsp clients of the
+/// stream are not disturbed. `max_decode_resolution` limits the size
+pub(crate) async fn stream_snapshot(camera: &NeoInstance) -> AnyResult<Vec<u8>> {
+    let (kind, quality) = {
+        let config = camera.config().await?;
+        let config = config.borrow();
+        (
+            config
+                .snapshot_cache
+                .stream
+                .as_stream_kinds()
+                .first()
+                .copied()
+                .unwrap_or(StreamKind::Sub),
+            config.snapshot_quality,
+        )
+    };
+    let mut stream = camera.decode_stream(kind).await?;
+    stream.activate().await?;
+    let mut frames = BroadcastStream::new(stream.vid.resubscribe());
+    let res = timeout(SNAPSHOT_TIMEOUT, async {
+        let format = stream
+            .config
+            .wait_for(|config| config.vid_ready())
+            .await?
+            .vid_format
+            .clone();
+        // Use the last keyframe if the stream is already running
+        let keyframe = stream
+            .vid_history
+            .borrow()
+            .iter()
+            .rev()
+            .find(|frame| frame.keyframe)
+            .cloned();
+        let keyframe = match keyframe {
+            Some(keyframe) => keyframe,
+            None => loop {
+                match frames.next().await {
+                    Some(Ok(frame)) if frame.keyframe => break frame,
+                    Some(_) => continue,
+                    None => return Err(anyhow!("Stream ended before a keyframe")),
+                }
+            },
+        };
+        AnyResult::Ok((format, keyframe))
+    })
+    .await;
+    stream.deactivate().await?;
+    let (format, keyframe) = match res {
+        Ok(v) => v?,
+        Err(_) => {
+            log::warn!(
+                "No keyframe within {}s to make a snapshot from",
+                SNAPSHOT_TIMEOUT.as_secs()
+            );
+            return Err(anyhow!("No keyframe"));
+        }
+    };
+    let jpeg =
+        tokio::task::spawn_blocking(move || keyframe_to_jpeg(format, &keyframe.data, quality))
+            .await??;
+    Ok(jpeg)
+}
+
+impl Drop for NeoCamSnapThread {
+    fn drop(&mut self) {
+        log::trace!("Drop NeoCamSnapThread");
+        self.cancel.cancel();
+        log::trace!("Dropped NeoCamSnapThread");
+    }
+}
+
+/// Used to pass messages to the SnapThread
+pub(crate) enum SnapRequest {
+    Get {
+        sender: OneshotSender<WatchReceiver<Snapshot>>,
+    },
+}
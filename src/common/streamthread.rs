@@ -6,28 +6,29 @@
 use futures::stream::{FuturesUnordered, StreamExt};
 use std::{
     collections::{hash_map::Entry, HashMap, VecDeque},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 use tokio::{
     sync::{
         broadcast::{
             channel as broadcast, Receiver as BroadcastReceiver, Sender as BroadcastSender,
         },
-        mpsc::{channel as mpsc, Receiver as MpscReceiver},
+        mpsc::{channel as mpsc, unbounded_channel, Receiver as MpscReceiver},
         oneshot::{channel as oneshot, Sender as OneshotSender},
         watch::{channel as watch, Receiver as WatchReceiver, Sender as WatchSender},
     },
     task::JoinHandle,
-    time::{sleep, timeout, Duration},
+    time::{sleep, timeout, Duration, Instant},
 };
 use tokio_util::sync::CancellationToken;
 
-use super::{NeoInstance, Permit, UseCounter};
+use super::{stream_metrics, MediaDump, NeoInstance, Permit, UseCounter};
 use crate::{AnyResult, Result};
 use neolink_core::{bc_protocol::StreamKind, bcmedia::model::*};
 
 pub(crate) struct NeoCamStreamThread {
     streams: HashMap<StreamKind, StreamData>,
+    enabled: HashMap<StreamKind, WatchSender<bool>>,
     stream_request_rx: MpscReceiver<StreamRequest>,
     cancel: CancellationToken,
     instance: NeoInstance,
@@ -40,6 +41,7 @@ impl NeoCamStreamThread {
     ) -> Result<Self> {
         Ok(Self {
             streams: Default::default(),
+            enabled: Default::default(),
             stream_request_rx,
             cancel: CancellationToken::new(),
             instance,
@@ -80,8 +82,9 @@ impl NeoCamStreamThread {
 
                                     let data = StreamData::new(
                                         name,
-                                        self.instance.subscribe().await?,
+                                        self.instance.stream_session().await?,
                                         strict,
+                                        enabled_rx(&mut self.enabled, name),
                                     ).await?;
                                     let data = vac.insert(data);
 
@@ -105,7 +108,7 @@ impl NeoCamStreamThread {
                                     // Fill it in
                                     if let Entry::Vacant(vac) = self.streams.entry(name) {
                                         vac.insert(
-                                            StreamData::new(name, self.instance.subscribe().await?, config.strict)
+                                            StreamData::new(name, self.instance.stream_session().await?, config.strict, enabled_rx(&mut self.enabled, name))
                                                 .await?,
                                         );
                                     }
@@ -136,7 +139,7 @@ impl NeoCamStreamThread {
                                     // Fill it in
                                     if let Entry::Vacant(vac) = self.streams.entry(name) {
                                         vac.insert(
-                                            StreamData::new(name, self.instance.subscribe().await?, config.strict)
+                                            StreamData::new(name, self.instance.stream_session().await?, config.strict, enabled_rx(&mut self.enabled, name))
                                                 .await?,
                                         );
                                     }
@@ -160,7 +163,7 @@ impl NeoCamStreamThread {
                             for stream in streams.iter().copied() {
                                 if let Entry::Vacant(vac) = self.streams.entry(stream) {
                                     vac.insert(
-                                        StreamData::new(stream, self.instance.subscribe().await?, config.strict)
+                                        StreamData::new(stream, self.instance.stream_session().await?, config.strict, enabled_rx(&mut self.enabled, stream))
                                             .await?,
                                     );
                                 }
@@ -175,6 +178,12 @@ impl NeoCamStreamThread {
                             ).collect::<FuturesUnordered<_>>().collect::<Vec<_>>().await;
                             let _ = sender.send(streams.drain(..).flatten().collect());
                         }
+                        StreamRequest::Enable {
+                            name, enabled, sender
+                        } => {
+                            enabled_tx(&mut self.enabled, name).send_replace(enabled);
+                            let _ = sender.send(());
+                        }
                     }
                 }
                 Ok(())
@@ -183,6 +192,20 @@ impl NeoCamStreamThread {
     }
 }
 
+fn enabled_tx(
+    enabled: &mut HashMap<StreamKind, WatchSender<bool>>,
+    name: StreamKind,
+) -> &WatchSender<bool> {
+    enabled.entry(name).or_insert_with(|| watch(true).0)
+}
+
+fn enabled_rx(
+    enabled: &mut HashMap<StreamKind, WatchSender<bool>>,
+    name: StreamKind,
+) -> WatchReceiver<bool> {
+    enabled_tx(enabled, name).subscribe()
+}
+
 impl Drop for NeoCamStreamThread {
     fn drop(&mut self) {
         log::debug!("NeoCamStreamThread::drop Cancel");
@@ -221,6 +244,12 @@ pub(crate) enum StreamRequest {
     All {
         sender: OneshotSender<Vec<StreamInstance>>,
     },
+    /// Allow or prevent a stream from pulling data from the camera
+    Enable {
+        name: StreamKind,
+        enabled: bool,
+        sender: OneshotSender<()>,
+    },
 }
 
 /// The data of a running stream
@@ -316,8 +345,15 @@ impl StreamInstance {
 }
 
 impl StreamData {
-    async fn new(name: StreamKind, instance: NeoInstance, strict: bool) -> Result<Self> {
+    async fn new(
+        name: StreamKind,
+        instance: NeoInstance,
+        strict: bool,
+        mut enabled: WatchReceiver<bool>,
+    ) -> Result<Self> {
         const BUFFER_DURATION: Duration = Duration::from_secs(15);
+        // Wait before asking again for a stream that the camera refused
+        const REFUSED_RETRY: Duration = Duration::from_secs(30);
         // At 30fps for 15s with audio is is about 900 frames
         // Therefore we set this buffer to a rather large 2000
         let (vid, _) = broadcast::<StampedData>(2000);
@@ -399,16 +435,28 @@ impl StreamData {
         let cancel = me.cancel.clone();
         let vid = me.vid.clone();
         let aud = me.aud.clone();
-        let instance = me.instance.subscribe().await?;
+        let instance = me.instance.stream_session().await?;
         let name = me.name;
-        let cam_name = instance.config().await?.borrow().name.clone();
+        let (cam_name, dump_config, loss_recovery, configured_block_size) = {
+            let config = instance.config().await?;
+            let config = config.borrow();
+            (
+                config.name.clone(),
+                config.dump_stream.clone(),
+                config.loss_recovery.clone(),
+                config.adpcm_block_size,
+            )
+        };
         let print_name = format!("{cam_name}::{name}");
+        let metrics = stream_metrics(&cam_name, name);
         let strict = me.strict;
         let config = me.config.clone();
         let thread_inuse = me.users.create_deactivated().await?;
         let vid_history = me.vid_history.clone();
         let aud_history = me.aud_history.clone();
         let mut permit = instance.permit().await?;
+        // When the last frame arrived, only counted while the stream is wanted
+        let last_frame = Arc::new(Mutex::new(Instant::now()));
         me.handle = Some(tokio::task::spawn(async move {
             let r = tokio::select! {
                 _ = cancel.cancelled() => {
@@ -423,6 +471,7 @@ impl StreamData {
                         // This should stop one branch of the select from waking the other
                         // too often
                         let watchdog_print_name = print_name.clone();
+                        let watchdog_last_frame = last_frame.clone();
                         tokio::task::spawn(async move {
                             let mut check_timeout = timeout(Duration::from_secs(15), watchdog_rx.recv()).await; // Wait longer for the first feed
                             loop {
@@ -439,6 +488,7 @@ impl StreamData {
                                     }
                                     Ok(_) => {
                                         // log::debug!("{print_name}: Good Doggo");
+                                        *watchdog_last_frame.lock().unwrap() = Instant::now();
                                         check_timeout = timeout(Duration::from_secs(10), watchdog_rx.recv()).await;
                                     }
                                 }
@@ -453,12 +503,44 @@ impl StreamData {
                                 log::debug!("{print_name}: Streaming STOP");
                                 permit.deactivate().await?;
                                 v?;
+                                let stopped_at = Instant::now();
                                 thread_inuse.aquired_users().await?; // Wait for new users of the stream
+                                let idle_flush = Duration::from_secs(instance.config().await?.borrow().idle_flush);
+                                if stopped_at.elapsed() >= idle_flush {
+                                    // The history is from before we went idle, clear it so that
+                                    // new clients start from the fresh keyframe of the restarted
+                                    // stream rather than the stale frames
+                                    log::debug!("{print_name}: Idle for {:?} flushing buffers", stopped_at.elapsed());
+                                    vid_history.send_replace(VecDeque::new());
+                                    aud_history.send_replace(VecDeque::new());
+                                }
                                 permit.activate().await?;
+                                *last_frame.lock().unwrap() = Instant::now();
                                 log::debug!("{print_name}: Streaming START");
                                 AnyResult::Ok(())
                             },
+                            v = enabled.wait_for(|enabled| !*enabled) => {
+                                // Handles the stop and restart when the stream is disabled
+                                log::debug!("{print_name}: Streaming DISABLED");
+                                permit.deactivate().await?;
+                                v?;
+                                enabled.wait_for(|enabled| *enabled).await?;
+                                thread_inuse.aquired_users().await?; // Wait for new users of the stream
+                                permit.activate().await?;
+                                *last_frame.lock().unwrap() = Instant::now();
+                                log::debug!("{print_name}: Streaming ENABLED");
+                                AnyResult::Ok(())
+                            },
                             _ = watchdog_eat_rx => {
+                                // Restarting the stream has not brought the video back,
+                                // the connection is open but stuck so make a new one
+                                let starved = last_frame.lock().unwrap().elapsed();
+                                let frame_timeout = Duration::from_secs(instance.config().await?.borrow().frame_timeout);
+                                if starved >= frame_timeout {
+                                    log::warn!("{print_name}: No video for {:?}, reconnecting to the camera", starved);
+                                    instance.reconnect().await?;
+                                    *last_frame.lock().unwrap() = Instant::now();
+                                }
                                 sleep(Duration::from_secs(1)).await;
                                 AnyResult::Ok(())
                             },
@@ -471,6 +553,11 @@ impl StreamData {
                                     let watchdog_tx = watchdog_tx.clone();
                                     let fps_table = fps_table.clone();
                                     let print_name = print_name.clone();
+                                    let cam_name = cam_name.clone();
+                                    let metrics = metrics.clone();
+                                    let dump_config = dump_config.clone();
+                                    let loss_recovery = loss_recovery.clone();
+                                    let configured_block_size = configured_block_size;
 
                                     log::debug!("{print_name}: Running Stream Instance Task");
                                     Box::pin(async move {
@@ -478,10 +565,30 @@ impl StreamData {
                                         // let mut file = std::fs::File::create("reference.h264")?;
                                         let mut recieved_iframe = false;
                                         let mut aud_keyframe = false;
+                                        // Set while the frames are dropped after lost video
+                                        let mut frozen_at: Option<Instant> = None;
 
                                         let res = async {
                                             let mut prev_ts = Duration::ZERO;
-                                            let mut stream_data = camera.start_video(name, 0, strict).await?;
+                                            let dump = match dump_config.as_ref() {
+                                                Some(dump_config) => match MediaDump::open(&cam_name, name, dump_config).await {
+                                                    Ok(dump) => Some(dump),
+                                                    Err(e) => {
+                                                        log::warn!("{print_name}: Could not open the stream dump: {e:?}");
+                                                        None
+                                                    }
+                                                },
+                                                None => None,
+                                            };
+                                            // The camera's bytes are passed back to be written as they were sent
+                                            let (dump_tx, mut dump) = match dump {
+                                                Some(dump) => {
+                                                    let (dump_tx, dump_rx) = unbounded_channel();
+                                                    (Some(dump_tx), Some((dump, dump_rx)))
+                                                }
+                                                None => (None, None),
+                                            };
+                                            let mut stream_data = camera.start_video_with_dump(name, 0, strict, dump_tx).await?;
                                             loop {
                                                 log::debug!("{print_name}:   Waiting for frame");
                                                 let data = stream_data.get_data().await??;
@@ -489,62 +596,95 @@ impl StreamData {
                                                 watchdog_tx.send(()).await?;  // Feed the watchdog
                                                 log::debug!("{print_name}:   Got frame");
 
+                                                // The bytes of this packet were sent before it was decoded
+                                                if let Some((writer, dump_rx)) = dump.as_mut() {
+                                                    let mut result = Ok(true);
+                                                    while let Ok(bytes) = dump_rx.try_recv() {
+                                                        result = writer.write(&bytes).await;
+                                                        if !matches!(result, Ok(true)) {
+                                                            break;
+                                                        }
+                                                    }
+                                                    match result {
+                                                        Ok(true) => {}
+                                                        Ok(false) => dump = None,
+                                                        Err(e) => {
+                                                            log::warn!("{print_name}: Stopped dumping the stream: {e:?}");
+                                                            dump = None;
+                                                        }
+                                                    }
+                                                }
+
                                                 // Update the stream config with any information
                                                 match &data {
-                                                    BcMedia::InfoV1(info) => {
+                                                    BcMedia::InfoV1(BcMediaInfoV1{video_width, video_height, fps, ..}) | BcMedia::InfoV2(BcMediaInfoV2{video_width, video_height, fps, ..}) => {
+                                                        let new_fps = fps_table.get(*fps as usize).copied().unwrap_or(*fps as u32);
+                                                        let mut old_resolution = None;
                                                         stream_config.send_if_modified(|state| {
-                                                            let new_fps = fps_table.get(info.fps as usize).copied().unwrap_or(info.fps as u32);
-                                                            if state.resolution[0] != info.video_width || state.resolution[1] != info.video_height || new_fps != state.fps  {
-                                                                state.resolution[0] = info.video_width;
-                                                                state.resolution[1] = info.video_height;
-                                                                state.fps = new_fps;
-                                                                true
-                                                            } else {
-                                                                false
-                                                            }
-                                                        });
-                                                    },
-                                                    BcMedia::InfoV2(info) => {
-                                                        stream_config.send_if_modified(|state| {
-                                                            let new_fps = fps_table.get(info.fps as usize).copied().unwrap_or(info.fps as u32);
-                                                            if state.resolution[0] != info.video_width || state.resolution[1] != info.video_height || new_fps != state.fps  {
-                                                                state.resolution[0] = info.video_width;
-                                                                state.resolution[1] = info.video_height;
+                                                            let resolution = [*video_width, *video_height];
+                                                            if state.resolution != resolution || new_fps != state.fps  {
+                                                                if state.resolution != resolution && state.resolution != [0, 0] {
+                                                                    old_resolution = Some(state.resolution);
+                                                                }
+                                                                state.resolution = resolution;
                                                                 state.fps = new_fps;
                                                                 true
                                                             } else {
                                                                 false
                                                             }
                                                         });
+                                                        if let Some([old_width, old_height]) = old_resolution {
+                                                            // The sdp of the rtsp clients is now wrong, the stream config
+                                                            // change will rebuild the factory and the clients must reconnect
+                                                            log::info!("{print_name}: Resolution changed from {old_width}x{old_height} to {video_width}x{video_height}");
+                                                            // Frames of the old resolution must not be sent to the new clients
+                                                            clear_history(&vid_history, &aud_history);
+                                                        }
                                                     },
                                                     BcMedia::Iframe(frame) => {
+                                                        let mut old_format = None;
                                                         stream_config.send_if_modified(|state| {
                                                             let expected = match frame.video_type {
                                                                 VideoType::H264 => VidFormat::H264,
                                                                 VideoType::H265 => VidFormat::H265,
                                                             };
                                                             if state.vid_format != expected {
+                                                                if !matches!(state.vid_format, VidFormat::None) {
+                                                                    old_format = Some(state.vid_format.clone());
+                                                                }
                                                                 state.vid_format = expected;
                                                                 true
                                                             } else {
                                                                 false
                                                             }
                                                         });
+                                                        if let Some(old_format) = old_format {
+                                                            log::info!("{print_name}: Video format changed from {old_format:?} to {:?}", frame.video_type);
+                                                            clear_history(&vid_history, &aud_history);
+                                                        }
                                                         // let _ = file.write(&frame.data);
                                                     }
                                                     BcMedia::Pframe(frame) => {
+                                                        let mut old_format = None;
                                                         stream_config.send_if_modified(|state| {
                                                             let expected = match frame.video_type {
                                                                 VideoType::H264 => VidFormat::H264,
                                                                 VideoType::H265 => VidFormat::H265,
                                                             };
                                                             if state.vid_format != expected {
+                                                                if !matches!(state.vid_format, VidFormat::None) {
+                                                                    old_format = Some(state.vid_format.clone());
+                                                                }
                                                                 state.vid_format = expected;
                                                                 true
                                                             } else {
                                                                 false
                                                             }
                                                         });
+                                                        if let Some(old_format) = old_format {
+                                                            log::info!("{print_name}: Video format changed from {old_format:?} to {:?}", frame.video_type);
+                                                            clear_history(&vid_history, &aud_history);
+                                                        }
                                                         // let _ = file.write(&frame.data);
                                                     },
                                                     BcMedia::Aac(_) => {
@@ -558,8 +698,11 @@ impl StreamData {
                                                         });
                                                     }
                                                     BcMedia::Adpcm(aud) => {
+                                                        let block_size = adpcm_block_size(&aud.data)
+                                                            .or(configured_block_size)
+                                                            .unwrap_or(aud.data.len() as u32);
                                                         stream_config.send_if_modified(|state| {
-                                                            let expected = AudFormat::Adpcm(aud.data.len() as u32 - 4);
+                                                            let expected = AudFormat::Adpcm(block_size);
                                                             if state.aud_format != expected {
                                                                 state.aud_format = expected;
                                                                 true
@@ -570,15 +713,35 @@ impl StreamData {
                                                     }
                                                 }
 
+                                                // A large gap between frames means video was lost and the
+                                                // frames until the next keyframe will decode with corruption
+                                                if let (Some(recovery), BcMedia::Pframe(BcMediaPframe{microseconds, ..})) = (loss_recovery.as_ref(), &data) {
+                                                    let gap = Duration::from_micros(*microseconds as u64).saturating_sub(prev_ts);
+                                                    if recieved_iframe && frozen_at.is_none() && gap > Duration::from_millis(recovery.gap) {
+                                                        if recovery.keyframe {
+                                                            // The camera starts a new stream with a keyframe
+                                                            log::info!("{print_name}: Lost {gap:?} of video, restarting the stream for a keyframe");
+                                                            return Err(anyhow::anyhow!("Lost {gap:?} of video"));
+                                                        } else if recovery.freeze {
+                                                            log::info!("{print_name}: Lost {gap:?} of video, holding the last good frame until the next keyframe");
+                                                            frozen_at = Some(Instant::now());
+                                                        }
+                                                    }
+                                                }
+
                                                 match data {
                                                     BcMedia::Iframe(BcMediaIframe{data, microseconds, ..}) => {
                                                         prev_ts = Duration::from_micros(microseconds as u64);
+                                                        if let Some(frozen_at) = frozen_at.take() {
+                                                            log::info!("{print_name}: Recovered from lost video after {:?}", frozen_at.elapsed());
+                                                        }
                                                         // log::debug!("IFrame: {prev_ts:?}");
                                                         let d = StampedData{
                                                                 keyframe: true,
                                                                 data: Arc::new(data),
                                                                 ts: prev_ts
                                                         };
+                                                        metrics.frame(d.data.len());
                                                         let _ = vid_tx.send(d.clone());
                                                         vid_history.send_modify(|history| {
                                                            let drop_time = d.ts.saturating_sub(BUFFER_DURATION);
@@ -587,11 +750,14 @@ impl StreamData {
                                                                history.pop_front();
                                                            }
                                                         });
+                                                        if !recieved_iframe {
+                                                            log::debug!("{print_name}: First keyframe at {prev_ts:?}");
+                                                        }
                                                         recieved_iframe = true;
                                                         aud_keyframe = true;
                                                         log::trace!("Sent Vid Key Frame");
                                                     },
-                                                    BcMedia::Pframe(BcMediaPframe{data, microseconds,..}) if recieved_iframe => {
+                                                    BcMedia::Pframe(BcMediaPframe{data, microseconds,..}) if recieved_iframe && frozen_at.is_none() => {
                                                         prev_ts = Duration::from_micros(microseconds as u64);
                                                         // log::debug!("PFrame: {prev_ts:?}");
                                                         // log::debug!("data: {data:02X?}");
@@ -600,6 +766,7 @@ impl StreamData {
                                                             data: Arc::new(data),
                                                             ts: prev_ts
                                                         };
+                                                        metrics.frame(d.data.len());
                                                         let _ = vid_tx.send(d.clone());
                                                         vid_history.send_modify(|history| {
                                                            let drop_time = d.ts.saturating_sub(BUFFER_DURATION);
@@ -641,7 +808,17 @@ impl StreamData {
                                         break Ok(());
                                     },
                                     Ok(Err(e)) => {
-                                        log::debug!("{print_name}: Video Stream Restarting Due to Error: {:?}", e);
+                                        if let Some(neolink_core::Error::CameraServiceUnavailable(code)) = e.downcast_ref::<neolink_core::Error>() {
+                                            // Retrying straight away would only be refused again and
+                                            // may upset the stream that the camera is already sending
+                                            log::error!(
+                                                "{print_name}: The camera refused the stream ({code}). It may not be able to send this stream while another one is running. Trying again in {:?}",
+                                                REFUSED_RETRY
+                                            );
+                                            sleep(REFUSED_RETRY).await;
+                                        } else {
+                                            log::debug!("{print_name}: Video Stream Restarting Due to Error: {:?}", e);
+                                        }
                                         AnyResult::Ok(())
                                     },
                                     Err(e) => {
@@ -671,6 +848,30 @@ impl StreamData {
     }
 }
 
+/// Work out the block size of the adpcm from one of its frames
+///
+/// Each frame holds a single DVI block, a 4 byte header of the predictor
+/// and step index followed by the samples, and the block size includes the
+/// header. None if the frame does not look like such a block
+fn adpcm_block_size(data: &[u8]) -> Option<u32> {
+    match data {
+        [_, _, step_index, 0, samples @ ..] if *step_index <= 88 && !samples.is_empty() => {
+            Some(data.len() as u32)
+        }
+        _ => None,
+    }
+}
+
+/// Forget the buffered frames, used when the stream changes so much that
+/// the old frames cannot be decoded with the new ones
+fn clear_history(
+    vid_history: &WatchSender<VecDeque<StampedData>>,
+    aud_history: &WatchSender<VecDeque<StampedData>>,
+) {
+    vid_history.send_modify(|history| history.clear());
+    aud_history.send_modify(|history| history.clear());
+}
+
 impl Drop for StreamData {
     fn drop(&mut self) {
         log::trace!("Drop StreamData");
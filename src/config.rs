@@ -1,5 +1,9 @@
-use crate::mqtt::Discoveries;
-use neolink_core::bc_protocol::{DiscoveryMethods, PrintFormat, StreamKind};
+use crate::{
+    mqtt::{Discoveries, EventFormat, PublishConfig},
+    AnyResult,
+};
+use anyhow::anyhow;
+use neolink_core::bc_protocol::{DiscoveryMethods, LightState, PrintFormat, StreamKind};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -8,14 +12,22 @@ use std::collections::HashSet;
 use validator::ValidationError;
 use validator_derive::Validate;
 
+static RE_ENV_VAR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$(\$?)\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap());
 static RE_TLS_CLIENT_AUTH: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^(none|request|require)$").unwrap());
 static RE_PAUSE_MODE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(black|still|test|none)$").unwrap());
+static RE_VALIGN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(top|center|bottom|baseline)$").unwrap());
+static RE_HALIGN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(left|center|right)$").unwrap());
+static RE_TIME_OF_DAY: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^([01]?\d|2[0-3]):[0-5]\d$").unwrap());
 static RE_MAXENC_SRC: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^([nN]one|[Aa][Ee][Ss]|[Bb][Cc][Ee][Nn][Cc][Rr][Yy][Pp][Tt])$").unwrap()
 });
 
 #[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+#[validate(schema(function = "validate_config", skip_on_field_errors = false))]
 pub(crate) struct Config {
     #[validate]
     pub(crate) cameras: Vec<CameraConfig>,
@@ -47,6 +59,198 @@ pub(crate) struct Config {
     #[validate]
     #[serde(default)]
     pub(crate) users: Vec<UserConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub(crate) mosaics: Vec<MosaicConfig>,
+
+    /// Most cameras that may be connecting at once, 0 for no limit
+    #[serde(default, alias = "parallel_connects")]
+    pub(crate) max_connecting: usize,
+
+    /// Total kbps the main streams of all cameras should stay under, the
+    /// lowest priority cameras have their bitrate lowered to fit
+    #[validate(range(
+        min = 1,
+        message = "Invalid bandwidth budget",
+        code = "bandwidth_budget"
+    ))]
+    #[serde(default, alias = "bandwidth_limit")]
+    pub(crate) bandwidth_budget: Option<u32>,
+
+    /// Where the original bitrates of the lowered cameras are kept so they
+    /// can be restored after a restart
+    #[serde(default = "default_bandwidth_state")]
+    pub(crate) bandwidth_state: std::path::PathBuf,
+
+    /// What to do when two cameras have the same name
+    #[serde(default, alias = "duplicate_camera_names")]
+    pub(crate) duplicate_names: DuplicateNames,
+
+    /// Serve prometheus metrics over http
+    #[validate]
+    #[serde(default, alias = "prometheus")]
+    pub(crate) metrics: Option<MetricsConfig>,
+}
+
+impl Config {
+    /// Rename cameras that share a name when `duplicate_names = "suffix"`
+    ///
+    /// The first camera keeps the name and the suffix skips any name that
+    /// is in the config so that a later `Cam-2` keeps its own name
+    ///
+    /// This should be called before the config is validated
+    pub(crate) fn resolve_names(&mut self) {
+        if self.duplicate_names != DuplicateNames::Suffix {
+            return;
+        }
+        let configured = self
+            .cameras
+            .iter()
+            .map(|camera| camera.name.clone())
+            .collect::<HashSet<_>>();
+        let mut taken = HashSet::new();
+        for camera in self.cameras.iter_mut() {
+            if taken.insert(camera.name.clone()) {
+                continue;
+            }
+            let new_name = (2..)
+                .map(|i| format!("{}-{}", camera.name, i))
+                .find(|name| !taken.contains(name) && !configured.contains(name))
+                .expect("There is always a free suffix");
+            log::warn!(
+                "Camera name {} is already used, renaming it to {}",
+                camera.name,
+                new_name
+            );
+            camera.name = new_name.clone();
+            taken.insert(new_name);
+        }
+    }
+}
+
+/// Replace each `${NAME}` in the strings of the config with the environment variable
+///
+/// This keeps secrets such as passwords out of the file. `$${NAME}` is kept as
+/// the text `${NAME}`. A variable that is not set is an error rather than an
+/// empty value. This should be called before the config is deserialized
+pub(crate) fn expand_env(value: &mut toml::Value) -> AnyResult<()> {
+    let mut missing = vec![];
+    expand_env_value(value, &mut missing);
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        missing.sort();
+        missing.dedup();
+        Err(anyhow!(
+            "The environment variables {} used in the config are not set",
+            missing.join(", ")
+        ))
+    }
+}
+
+fn expand_env_value(value: &mut toml::Value, missing: &mut Vec<String>) {
+    match value {
+        toml::Value::String(text) => {
+            *text = RE_ENV_VAR
+                .replace_all(text, |caps: &regex::Captures| {
+                    let name = &caps[2];
+                    if !caps[1].is_empty() {
+                        format!("${{{name}}}")
+                    } else {
+                        std::env::var(name).unwrap_or_else(|_| {
+                            missing.push(name.to_string());
+                            String::new()
+                        })
+                    }
+                })
+                .to_string();
+        }
+        toml::Value::Array(values) => {
+            for value in values.iter_mut() {
+                expand_env_value(value, missing);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_env_value(value, missing);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// How cameras with the same name are handled
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+pub(crate) enum DuplicateNames {
+    /// Refuse to load the config
+    #[default]
+    #[serde(alias = "error")]
+    Error,
+    /// Add `-2`, `-3`, ... to the later cameras
+    #[serde(alias = "suffix", alias = "rename")]
+    Suffix,
+}
+
+/// Which way the camera is connected to
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConnectionMode {
+    /// Tcp to the `address` then the `discovery` methods with the `uid`
+    #[default]
+    Auto,
+    /// Only tcp to the `address`
+    #[serde(alias = "direct")]
+    Tcp,
+    /// Only the local udp discovery of the `uid`
+    #[serde(alias = "udp")]
+    Local,
+    /// Only the reolink relay to the `uid`
+    Relay,
+}
+
+impl ConnectionMode {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionMode::Auto => "auto",
+            ConnectionMode::Tcp => "tcp",
+            ConnectionMode::Local => "local",
+            ConnectionMode::Relay => "relay",
+        }
+    }
+}
+
+/// A grid of several cameras served as a single rtsp stream
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct MosaicConfig {
+    pub(crate) name: String,
+
+    /// Names of the cameras to include, in order left to right, top to bottom
+    pub(crate) cameras: Vec<String>,
+
+    #[validate(range(min = 1, max = 8, message = "Invalid mosaic columns", code = "columns"))]
+    #[serde(default = "default_mosaic_columns")]
+    pub(crate) columns: u32,
+
+    #[validate(range(
+        min = 16,
+        max = 3840,
+        message = "Invalid tile width",
+        code = "tile_width"
+    ))]
+    #[serde(default = "default_mosaic_tile_width", alias = "width")]
+    pub(crate) tile_width: u32,
+
+    #[validate(range(
+        min = 16,
+        max = 2160,
+        message = "Invalid tile height",
+        code = "tile_height"
+    ))]
+    #[serde(default = "default_mosaic_tile_height", alias = "height")]
+    pub(crate) tile_height: u32,
+
+    pub(crate) permitted_users: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Validate, PartialEq, Eq)]
@@ -60,11 +264,270 @@ pub(crate) struct MqttServerConfig {
     #[serde(default)]
     pub(crate) credentials: Option<(String, String)>,
 
+    /// Connect with TLS, trusting the system's certificates unless `ca` is set
+    #[serde(default, alias = "tls")]
+    pub(crate) use_tls: bool,
+
     #[serde(default)]
     pub(crate) ca: Option<std::path::PathBuf>,
 
     #[serde(default)]
     pub(crate) client_auth: Option<(std::path::PathBuf, std::path::PathBuf)>,
+
+    /// Maximum number of outgoing messages waiting on the broker
+    #[validate(range(
+        min = 1,
+        message = "Queue must hold at least one message",
+        code = "max_queue"
+    ))]
+    #[serde(default = "default_max_queue")]
+    pub(crate) max_queue: usize,
+
+    /// Which messages to drop when the queue is full
+    #[serde(default = "default_queue_policy")]
+    pub(crate) queue_policy: MqttQueuePolicy,
+
+    /// Use only the one connection to the broker for all cameras
+    ///
+    /// Each camera's last will needs a connection of its own, with this
+    /// they are not set and `neolink/status` going `offline` stands for all
+    #[serde(default, alias = "single_connection")]
+    pub(crate) shared_connection: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum MqttQueuePolicy {
+    #[serde(alias = "drop_oldest", alias = "oldest")]
+    DropOldest,
+    #[serde(alias = "drop_newest", alias = "newest")]
+    DropNewest,
+}
+
+/// The bitrate control mode of the camera's encoder
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum BitrateMode {
+    #[serde(alias = "cbr", alias = "CBR")]
+    Cbr,
+    #[serde(alias = "vbr", alias = "VBR")]
+    Vbr,
+}
+
+impl BitrateMode {
+    /// The value used by the camera for this mode
+    pub(crate) fn as_camera_str(&self) -> &'static str {
+        match self {
+            BitrateMode::Cbr => "cbr",
+            BitrateMode::Vbr => "vbr",
+        }
+    }
+}
+
+/// The mode of the IR lights
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum IrMode {
+    #[serde(alias = "auto")]
+    Auto,
+    #[serde(alias = "on", alias = "open")]
+    On,
+    #[serde(alias = "off", alias = "close")]
+    Off,
+}
+
+impl IrMode {
+    /// The state the camera is sent for this mode
+    pub(crate) fn light_state(&self) -> LightState {
+        match self {
+            IrMode::Auto => LightState::Auto,
+            IrMode::On => LightState::On,
+            IrMode::Off => LightState::Off,
+        }
+    }
+}
+
+/// The desired state of a camera, applied on every connect so that it
+/// survives the camera being reset or rebooted. Unset values are left alone
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct ProfileConfig {
+    /// Used in the logs
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+
+    #[serde(default, alias = "irled")]
+    pub(crate) ir: Option<IrMode>,
+
+    /// The blue status light
+    #[serde(default, alias = "led")]
+    pub(crate) status_led: Option<bool>,
+
+    #[serde(default)]
+    pub(crate) pir: Option<bool>,
+
+    /// Overrides the camera's `flip`
+    #[serde(default)]
+    pub(crate) flip: Option<bool>,
+
+    /// Overrides the camera's `mirror`
+    #[serde(default)]
+    pub(crate) mirror: Option<bool>,
+
+    /// Overrides the camera's `bitrate_mode`
+    #[serde(default, alias = "rate_mode")]
+    pub(crate) bitrate_mode: Option<BitrateMode>,
+
+    #[validate]
+    #[serde(default)]
+    pub(crate) main: Option<EncoderProfile>,
+
+    #[validate]
+    #[serde(default)]
+    pub(crate) sub: Option<EncoderProfile>,
+}
+
+/// Encoder settings of one stream of a profile
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct EncoderProfile {
+    /// In kbps, it must be one the camera offers
+    #[validate(range(min = 1, message = "Invalid bitrate", code = "bitrate"))]
+    #[serde(default)]
+    pub(crate) bitrate: Option<u32>,
+
+    /// Frames per second, it must be one the camera offers
+    #[validate(range(min = 1, message = "Invalid fps", code = "fps"))]
+    #[serde(default, alias = "framerate")]
+    pub(crate) fps: Option<u32>,
+}
+
+/// How the rtsp pipeline of a stream trades latency against smoothness
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+pub(crate) enum StreamPreset {
+    /// Small buffers for the least delay, may stutter on poor networks
+    #[serde(alias = "low-latency", alias = "low_latency", alias = "lowlatency")]
+    LowLatency,
+    /// The default
+    #[default]
+    #[serde(alias = "balanced")]
+    Balanced,
+    /// Large buffers to ride out network problems at the cost of delay
+    #[serde(alias = "high-quality", alias = "high_quality", alias = "highquality")]
+    HighQuality,
+}
+
+/// The codecs that the audio of the rtsp streams can be sent as
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum AudioCodec {
+    /// Uncompressed 16 bit pcm
+    #[serde(alias = "l16", alias = "pcm")]
+    L16,
+    #[serde(alias = "opus")]
+    Opus,
+    #[serde(alias = "aac")]
+    Aac,
+    /// G.711 mu-law
+    #[serde(alias = "pcmu", alias = "g711u", alias = "mulaw")]
+    Pcmu,
+    /// G.711 a-law
+    #[serde(alias = "pcma", alias = "g711a", alias = "alaw")]
+    Pcma,
+}
+
+/// The preset of each stream with optional overrides
+#[derive(Debug, Deserialize, Serialize, Clone, Validate, PartialEq, Eq, Default)]
+pub(crate) struct StreamPresetsConfig {
+    #[serde(default)]
+    pub(crate) main: StreamPreset,
+    #[serde(default)]
+    pub(crate) sub: StreamPreset,
+    #[serde(default, rename = "extern")]
+    pub(crate) extern_: StreamPreset,
+
+    /// Overrides the seconds of stream that the buffers hold
+    #[validate(range(
+        min = 1,
+        max = 120,
+        message = "Invalid buffer seconds",
+        code = "buffer_secs"
+    ))]
+    #[serde(default)]
+    pub(crate) buffer_secs: Option<u32>,
+
+    /// Overrides the max time in ms of data held in the queues
+    #[validate(range(
+        min = 100,
+        max = 120000,
+        message = "Invalid queue time",
+        code = "queue_ms"
+    ))]
+    #[serde(default, alias = "latency_ms")]
+    pub(crate) queue_ms: Option<u64>,
+
+    /// Overrides the smallest size in bytes of the buffers
+    #[validate(range(
+        min = 65536,
+        max = 268435456,
+        message = "Invalid buffer bytes",
+        code = "buffer_bytes"
+    ))]
+    #[serde(default)]
+    pub(crate) buffer_bytes: Option<u32>,
+
+    /// Drop the oldest data from the queues when they are full rather
+    /// than let the delay build up
+    #[serde(default, alias = "leaky", alias = "drop_stale")]
+    pub(crate) leaky_queues: bool,
+}
+
+/// How the camera is reconnected after the connection is lost
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ReconnectStrategy {
+    /// Eager for wired cameras and lazy for battery cameras
+    #[serde(alias = "auto")]
+    Auto,
+    /// Retry straight away with a short backoff
+    #[serde(alias = "eager", alias = "aggressive")]
+    Eager,
+    /// Wait until something needs the camera such as a client, motion
+    /// or a push notification before reconnecting
+    #[serde(alias = "lazy", alias = "on_demand")]
+    Lazy,
+}
+
+/// What happens to a PTZ move that arrives while another is running
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+pub(crate) enum PtzOverlap {
+    /// Wait for the running move to finish
+    #[default]
+    #[serde(alias = "queue", alias = "wait")]
+    Queue,
+    /// Stop the running move and start the new one
+    #[serde(alias = "cancel", alias = "replace")]
+    Cancel,
+}
+
+/// Where the presentation times of the rtsp buffers come from
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+pub(crate) enum TimestampSource {
+    /// The time each frame arrives at neolink
+    #[default]
+    #[serde(alias = "arrival", alias = "local")]
+    Arrival,
+    /// The time the camera put on the frame, when it is usable
+    #[serde(alias = "camera", alias = "hardware")]
+    Camera,
+}
+
+/// Which transports the rtsp clients may receive the rtp on
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default)]
+pub(crate) enum RtspTransport {
+    /// Let the client choose
+    #[default]
+    #[serde(alias = "both", alias = "any")]
+    Both,
+    /// Only rtp interleaved in the rtsp tcp connection
+    #[serde(alias = "tcp")]
+    Tcp,
+    /// Only rtp over udp
+    #[serde(alias = "udp")]
+    Udp,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
@@ -134,6 +597,10 @@ pub(crate) struct CameraConfig {
     #[serde(rename = "uid")]
     pub(crate) camera_uid: Option<String>,
 
+    /// Local address to connect to the camera from, by default the OS routing decides
+    #[serde(rename = "bind", default)]
+    pub(crate) bind_addr: Option<std::net::IpAddr>,
+
     pub(crate) username: String,
     pub(crate) password: Option<String>,
 
@@ -142,10 +609,23 @@ pub(crate) struct CameraConfig {
 
     pub(crate) permitted_users: Option<Vec<String>>,
 
+    /// Logins that may only watch this camera
+    #[validate]
+    #[serde(default, alias = "rtsp_users")]
+    pub(crate) users: Vec<UserConfig>,
+
     #[validate(range(min = 0, max = 31, message = "Invalid channel", code = "channel_id"))]
     #[serde(default = "default_channel_id", alias = "channel")]
     pub(crate) channel_id: u8,
 
+    /// Cameras with a lower value connect first when `max_connecting` is set
+    #[serde(default)]
+    pub(crate) connect_order: u32,
+
+    /// Share one connection to the NVR between all of its channels
+    #[serde(default, alias = "nvr")]
+    pub(crate) nvr_relay: bool,
+
     #[validate]
     #[serde(default = "default_mqtt")]
     pub(crate) mqtt: MqttConfig,
@@ -154,9 +634,76 @@ pub(crate) struct CameraConfig {
     #[serde(default = "default_pause")]
     pub(crate) pause: PauseConfig,
 
+    #[validate]
+    #[serde(default = "default_snapshot_cache", alias = "snapshot")]
+    pub(crate) snapshot_cache: SnapshotCacheConfig,
+
+    #[validate]
+    #[serde(default, alias = "uds")]
+    pub(crate) unix_socket: Option<UnixSocketConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub(crate) srt: Option<SrtConfig>,
+
+    /// Record the stream to mp4 files of a fixed length
+    #[validate]
+    #[serde(default, alias = "mp4")]
+    pub(crate) segments: Option<SegmentConfig>,
+
+    /// Recover from lost video quickly rather than showing corrupt
+    /// frames until the next keyframe
+    #[validate]
+    #[serde(default, alias = "gop_recovery")]
+    pub(crate) loss_recovery: Option<LossRecoveryConfig>,
+
+    /// Dump the BcMedia of the streams to disk for debugging
+    #[validate]
+    #[serde(default, alias = "dump")]
+    pub(crate) dump_stream: Option<DumpConfig>,
+
+    /// Block size in bytes of the adpcm audio, only used when it cannot
+    /// be worked out from the camera's audio
+    #[validate(range(
+        min = 8,
+        max = 8192,
+        message = "Invalid adpcm block size",
+        code = "adpcm_block_size"
+    ))]
+    #[serde(default, alias = "block_size")]
+    pub(crate) adpcm_block_size: Option<u32>,
+
+    #[validate]
+    #[serde(default = "default_recording", alias = "record")]
+    pub(crate) recording: RecordConfig,
+
+    /// Write a jpeg to disk at an interval
+    #[validate]
+    #[serde(default, alias = "snapshot_dir")]
+    pub(crate) timelapse: Option<TimelapseConfig>,
+
+    /// Quality of the jpegs made from the stream 0-100
+    #[validate(range(
+        min = 0,
+        max = 100,
+        message = "Invalid snapshot quality",
+        code = "snapshot_quality"
+    ))]
+    #[serde(default = "default_snapshot_quality", alias = "jpeg_quality")]
+    pub(crate) snapshot_quality: u8,
+
+    /// Largest `[width, height]` of stream that neolink will decode, for the
+    /// snapshots and mosaic. Larger streams are swapped for the sub stream
+    #[serde(default, alias = "max_decode")]
+    pub(crate) max_decode_resolution: Option<[u32; 2]>,
+
     #[serde(default = "default_discovery")]
     pub(crate) discovery: DiscoveryMethods,
 
+    /// Pin the camera to one way of connecting rather than trying each in turn
+    #[serde(default, alias = "connection_mode")]
+    pub(crate) connection: ConnectionMode,
+
     #[serde(default = "default_maxenc")]
     #[validate(regex(
         path = *RE_MAXENC_SRC,
@@ -175,6 +722,11 @@ pub(crate) struct CameraConfig {
     #[serde(default = "default_update_time", alias = "time")]
     pub(crate) update_time: bool,
 
+    /// With `update_time` the camera's clock is only set when it is more
+    /// than this many seconds out
+    #[serde(default = "default_max_time_drift", alias = "time_drift")]
+    pub(crate) max_time_drift: u64,
+
     #[validate(range(
         min = 0,
         max = 500,
@@ -196,6 +748,19 @@ pub(crate) struct CameraConfig {
     #[serde(default = "default_splash", alias = "pattern")]
     pub(crate) splash_pattern: SplashPattern,
 
+    /// Text of the splash, "Stream not Ready" if not set
+    #[serde(default)]
+    pub(crate) splash_text: Option<String>,
+
+    /// Pango font of the splash text, "Sans, 16" if not set
+    #[serde(default)]
+    pub(crate) splash_font: Option<String>,
+
+    /// `[width, height]` of the splash, `[896, 512]` if not set
+    #[validate(custom(function = "validate_splash_size"))]
+    #[serde(default)]
+    pub(crate) splash_size: Option<[u32; 2]>,
+
     #[serde(
         default = "default_max_discovery_retries",
         alias = "retries",
@@ -208,6 +773,182 @@ pub(crate) struct CameraConfig {
 
     #[serde(default = "default_false", alias = "idle", alias = "idle_disc")]
     pub(crate) idle_disconnect: bool,
+
+    /// Time in seconds that a stream must be idle before its buffered
+    /// frames are considered stale and flushed when a new client arrives
+    #[serde(default = "default_idle_flush", alias = "stale_after")]
+    pub(crate) idle_flush: u64,
+
+    /// Time in seconds without any video from a stream that is wanted
+    /// before the connection is assumed stuck and reconnected
+    #[validate(range(min = 5, message = "Invalid frame timeout", code = "frame_timeout"))]
+    #[serde(default = "default_frame_timeout", alias = "watchdog_timeout")]
+    pub(crate) frame_timeout: u64,
+
+    #[serde(default = "default_reconnect_strategy", alias = "reconnect")]
+    pub(crate) reconnect_strategy: ReconnectStrategy,
+
+    /// Time in seconds to wait before logging in again when the camera
+    /// has refused several logins in a row and may be locking us out
+    #[serde(default = "default_login_throttle_backoff", alias = "throttle_backoff")]
+    pub(crate) login_throttle_backoff: u64,
+
+    /// Longest time in seconds to wait between reconnects. The wait
+    /// doubles with each failed reconnect up to this
+    #[validate(range(min = 1, message = "Invalid max backoff", code = "max_backoff"))]
+    #[serde(default = "default_max_backoff", alias = "reconnect_max_backoff")]
+    pub(crate) max_backoff: u64,
+
+    /// Seconds between the keepalive requests that stop some firmwares
+    /// closing an idle session. Off if not set
+    #[validate(range(min = 1, message = "Invalid keepalive", code = "keepalive"))]
+    #[serde(default, alias = "keepalive_interval")]
+    pub(crate) keepalive: Option<u64>,
+
+    /// Reconnect when the clock jumps by more than this many seconds, as
+    /// it does when the host wakes from suspend. 0, the default, to turn off
+    #[serde(default = "default_clock_jump", alias = "clock_jump_threshold")]
+    pub(crate) clock_jump: u64,
+
+    /// Seconds after which the session is replaced by a fresh one, for
+    /// firmwares that degrade over days. Off if not set
+    #[validate(range(
+        min = 60,
+        message = "Invalid max session duration",
+        code = "max_session_duration"
+    ))]
+    #[serde(default, alias = "max_session")]
+    pub(crate) max_session_duration: Option<u64>,
+
+    /// Cameras with a higher priority keep their bitrate longer when
+    /// over the `bandwidth_budget`
+    #[serde(default)]
+    pub(crate) priority: u8,
+
+    /// Log in a second time for the streams, for cameras that drop the
+    /// events of a session once it starts streaming
+    #[serde(default, alias = "separate_stream_session")]
+    pub(crate) stream_session: bool,
+
+    /// What to do when the camera cannot be reconnected, such as
+    /// power cycling its smart plug
+    #[validate]
+    #[serde(default, alias = "reconnect_hook")]
+    pub(crate) power_cycle: Option<PowerCycleConfig>,
+
+    /// Default speed of PTZ movements when none is given
+    #[validate(range(min = 1, max = 100, message = "Invalid ptz speed", code = "ptz_speed"))]
+    #[serde(default = "default_ptz_speed")]
+    pub(crate) ptz_speed: u32,
+
+    /// Seconds after a reboot during which further mqtt reboot commands are refused
+    #[serde(default = "default_reboot_cooldown")]
+    pub(crate) reboot_cooldown: u64,
+
+    /// How a PTZ move is handled while another is still running
+    #[serde(default)]
+    pub(crate) ptz_overlap: PtzOverlap,
+
+    /// Seconds after which a continuous PTZ move stops if no stop is sent
+    #[validate(range(
+        min = 1,
+        max = 300,
+        message = "Invalid ptz timeout",
+        code = "ptz_timeout"
+    ))]
+    #[serde(default = "default_ptz_timeout")]
+    pub(crate) ptz_timeout: u64,
+
+    /// Use the camera's own frame times for the rtsp stream rather
+    /// than the time they arrive
+    #[serde(default, alias = "timestamp_source")]
+    pub(crate) timestamps: TimestampSource,
+
+    /// Time in ms that the stream format must be stable for before
+    /// it is served to clients, 0 to serve straight away
+    #[serde(default, alias = "warmup")]
+    pub(crate) stream_warmup: u64,
+
+    /// Fill gaps in the aac audio with silence, when off the audio just gaps
+    #[serde(default = "default_true")]
+    pub(crate) audio_fallback: bool,
+
+    /// Offer rtsp clients an ONVIF audio backchannel to the camera's speaker
+    #[serde(default, alias = "backchannel")]
+    pub(crate) talk_back: bool,
+
+    /// Also serve the audio without the video at `/audio` under each
+    /// rtsp path of the camera
+    #[serde(default, alias = "audio_only")]
+    pub(crate) audio_mount: bool,
+
+    /// Restrict the rtsp clients to receiving the rtp over tcp or udp
+    #[serde(default, alias = "rtsp_protocols")]
+    pub(crate) rtsp_transport: RtspTransport,
+
+    /// Only pull the main stream while it has rtsp clients. The sub stream is
+    /// pulled instead and a new client starts on it until the main stream's
+    /// first keyframe
+    #[serde(default, alias = "auto_main")]
+    pub(crate) adaptive_stream: bool,
+
+    /// Seconds the main stream is kept after its last client leaves
+    #[validate(range(
+        max = 3600,
+        message = "Invalid adaptive linger",
+        code = "adaptive_linger"
+    ))]
+    #[serde(default = "default_adaptive_linger")]
+    pub(crate) adaptive_linger: u64,
+
+    #[validate]
+    #[serde(default, alias = "preset")]
+    pub(crate) presets: StreamPresetsConfig,
+
+    /// The audio codecs to try in order, the first with all of its
+    /// gstreamer elements installed is used
+    #[serde(default = "default_audio_codecs", alias = "audio_codec")]
+    pub(crate) audio_codecs: Vec<AudioCodec>,
+
+    /// Decode H265 streams and send them to the rtsp clients as H264
+    #[serde(default, alias = "h265_to_h264")]
+    pub(crate) transcode_h265: bool,
+
+    /// Burn a clock into the video, only for streams that are transcoded
+    #[validate]
+    #[serde(default, alias = "timestamp_overlay")]
+    pub(crate) clock_overlay: Option<ClockOverlayConfig>,
+
+    /// Set the IR by the time of day
+    #[validate]
+    #[serde(default, alias = "ir_times")]
+    pub(crate) ir_schedule: Option<IrScheduleConfig>,
+
+    /// Max size of the rtp video packets, lower this for networks with a small mtu
+    #[validate(range(min = 256, max = 9000, message = "Invalid rtp mtu", code = "rtp_mtu"))]
+    #[serde(default, alias = "mtu")]
+    pub(crate) rtp_mtu: Option<u32>,
+
+    /// Streams to serve in order if the requested stream cannot be built
+    #[serde(default = "default_stream_fallback", alias = "fallback")]
+    pub(crate) stream_fallback: Vec<StreamConfig>,
+
+    /// If set the bitrate control mode of the main and sub streams is set to this on connect
+    #[serde(default, alias = "rate_mode")]
+    pub(crate) bitrate_mode: Option<BitrateMode>,
+
+    /// If set the image is flipped upside down (or not) on the camera on connect
+    #[serde(default)]
+    pub(crate) flip: Option<bool>,
+
+    /// If set the image is mirrored left to right (or not) on the camera on connect
+    #[serde(default)]
+    pub(crate) mirror: Option<bool>,
+
+    /// Settings that are applied to the camera on every connect
+    #[validate]
+    #[serde(default)]
+    pub(crate) profile: Option<ProfileConfig>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq, Hash)]
@@ -224,6 +965,9 @@ pub(crate) struct UserConfig {
 pub(crate) struct MqttConfig {
     #[serde(default = "default_true")]
     pub(crate) enable_motion: bool,
+    /// Publish each AI type to `status/ai/<type>`, needs `enable_motion`
+    #[serde(default = "default_true")]
+    pub(crate) enable_ai: bool,
     #[serde(default = "default_true")]
     pub(crate) enable_light: bool,
     #[serde(default = "default_true")]
@@ -261,25 +1005,95 @@ pub(crate) struct MqttConfig {
     #[serde(default = "default_2000")]
     pub(crate) floodlight_update: u64,
 
+    /// Publish the fps and bitrate received from the camera
+    #[serde(default = "default_true")]
+    pub(crate) enable_stats: bool,
+    /// Update time in ms
+    #[validate(range(
+        min = 1000,
+        message = "Update ms should be > 1000",
+        code = "stats_update"
+    ))]
+    #[serde(default = "default_10000")]
+    pub(crate) stats_update: u64,
+
     #[serde(default)]
     pub(crate) discovery: Option<MqttDiscoveryConfig>,
+
+    /// Time in ms that the camera must be disconnected before
+    /// `disconnected` is published to `/status`
+    #[serde(default = "default_5000", alias = "grace")]
+    pub(crate) disconnect_grace: u64,
+
+    /// Clear the retained topics of the camera when it is removed from the config
+    #[serde(default = "default_false")]
+    pub(crate) cleanup_on_shutdown: bool,
+
+    /// The format that events such as motion are published in
+    #[serde(default)]
+    pub(crate) event_format: EventFormat,
+
+    /// Offset from utc in minutes of the midnight that the daily event
+    /// counts reset at. Uses the local time of the host if not set
+    #[validate(range(min = -720, max = 840, message = "Invalid utc offset", code = "utc_offset"))]
+    #[serde(default)]
+    pub(crate) utc_offset: Option<i32>,
+
+    /// Whether each polled status topic is published every poll or only on change
+    #[serde(default)]
+    pub(crate) publish: PublishConfig,
+
+    /// Seconds after which a siren turned on over mqtt is turned off
+    #[validate(range(
+        min = 1,
+        max = 600,
+        message = "Invalid siren duration",
+        code = "siren_duration"
+    ))]
+    #[serde(default = "default_siren_duration")]
+    pub(crate) siren_duration: u64,
+
+    /// Time in ms that motion must stay stopped before the stop is
+    /// published. Motion that starts again within it keeps `/status/motion` on
+    #[serde(default, alias = "motion_debounce")]
+    pub(crate) motion_cooldown: u64,
+
+    /// Publish a snapshot to `/status/motion/snapshot` when motion starts
+    #[serde(default = "default_false", alias = "snapshot_on_motion")]
+    pub(crate) motion_snapshot: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Validate, PartialEq, Eq)]
 pub(crate) struct MqttDiscoveryConfig {
+    #[serde(default = "default_discovery_topic")]
     pub(crate) topic: String,
 
+    /// Entities to register, by default those that every camera has
+    #[serde(default = "default_discovery_features")]
     pub(crate) features: HashSet<Discoveries>,
 }
 
 fn validate_mqtt_server(config: &MqttServerConfig) -> Result<(), ValidationError> {
-    if config.ca.is_some() && config.client_auth.is_some() {
-        Err(ValidationError::new(
-            "Cannot have both ca and client_auth set",
-        ))
-    } else {
-        Ok(())
+    if config.client_auth.is_some() && config.ca.is_none() {
+        return Err(ValidationError::new(
+            "client_auth needs the ca of the mqtt server to be set",
+        ));
+    }
+    // Fail now rather than on connect
+    let paths = config.ca.iter().chain(
+        config
+            .client_auth
+            .iter()
+            .flat_map(|(cert, key)| [cert, key]),
+    );
+    for path in paths {
+        if !path.is_file() {
+            let mut error = ValidationError::new("mqtt tls file not found");
+            error.message = Some(format!("{:?} is not a file", path).into());
+            return Err(error);
+        }
     }
+    Ok(())
 }
 
 const fn default_true() -> bool {
@@ -293,6 +1107,7 @@ const fn default_false() -> bool {
 fn default_mqtt() -> MqttConfig {
     MqttConfig {
         enable_motion: true,
+        enable_ai: true,
         enable_light: true,
         enable_battery: true,
         battery_update: 2000,
@@ -300,10 +1115,28 @@ fn default_mqtt() -> MqttConfig {
         preview_update: 2000,
         enable_floodlight: true,
         floodlight_update: 2000,
+        enable_stats: true,
+        stats_update: 10000,
         discovery: Default::default(),
+        disconnect_grace: 5000,
+        cleanup_on_shutdown: false,
+        event_format: Default::default(),
+        utc_offset: None,
+        publish: Default::default(),
+        siren_duration: 10,
+        motion_cooldown: 0,
+        motion_snapshot: false,
     }
 }
 
+fn default_max_queue() -> usize {
+    1000
+}
+
+fn default_queue_policy() -> MqttQueuePolicy {
+    MqttQueuePolicy::DropOldest
+}
+
 fn default_print() -> PrintFormat {
     PrintFormat::None
 }
@@ -336,6 +1169,200 @@ pub(crate) struct PauseConfig {
     pub(crate) mode: String,
 }
 
+/// Keeps a jpeg of the latest keyframe for snapshots
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct SnapshotCacheConfig {
+    #[serde(default = "default_false")]
+    pub(crate) enabled: bool,
+
+    /// Time in ms between refreshes of the cached jpeg
+    #[validate(range(min = 100, message = "Invalid snapshot refresh", code = "refresh"))]
+    #[serde(default = "default_snapshot_refresh", alias = "interval")]
+    pub(crate) refresh: u64,
+
+    /// The stream to decode the jpeg from
+    #[serde(default = "default_snapshot_stream")]
+    pub(crate) stream: StreamConfig,
+}
+
+/// Serves the raw video on a unix socket
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct UnixSocketConfig {
+    /// Where to create the socket
+    pub(crate) path: String,
+
+    /// The stream to serve on the socket
+    #[serde(default = "default_unix_socket_stream")]
+    pub(crate) stream: StreamConfig,
+}
+
+/// Run when a camera has failed to reconnect too many times in a row
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct PowerCycleConfig {
+    /// Failed reconnects in a row before the hook is run
+    #[validate(range(min = 1, message = "Invalid power cycle threshold", code = "after"))]
+    #[serde(default = "default_power_cycle_after", alias = "threshold")]
+    pub(crate) after: u32,
+
+    /// Shell command to run, such as a curl to the smart plug
+    #[serde(default)]
+    pub(crate) command: Option<String>,
+
+    /// Seconds after a run that the hook won't run again, this stops a
+    /// dead camera being power cycled over and over
+    #[serde(default = "default_power_cycle_cooldown")]
+    pub(crate) cooldown: u64,
+}
+
+/// What to do when video is lost from a stream
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct LossRecoveryConfig {
+    /// Milliseconds between two frames that is taken as lost video
+    #[validate(range(min = 1, message = "Invalid loss gap", code = "gap"))]
+    #[serde(default = "default_loss_gap")]
+    pub(crate) gap: u64,
+
+    /// Restart the stream so that the camera sends a keyframe straight away
+    #[serde(default = "default_true")]
+    pub(crate) keyframe: bool,
+
+    /// Hold the last good frame by dropping the frames until the next keyframe
+    #[serde(default)]
+    pub(crate) freeze: bool,
+}
+
+/// Where the BcMedia dumps are written
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct DumpConfig {
+    /// Directory for the dumps
+    pub(crate) path: String,
+
+    /// Size in MB that each stream's dump stops growing at
+    #[validate(range(min = 1, message = "Invalid dump size", code = "max_size"))]
+    #[serde(default = "default_dump_max_size")]
+    pub(crate) max_size: u64,
+}
+
+/// Serves a stream over SRT as mpeg-ts
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct SrtConfig {
+    /// Full srt uri such as `srt://example.com:8890?mode=caller`, overrides `port`
+    #[serde(default, alias = "url")]
+    pub(crate) uri: Option<String>,
+
+    /// Port to listen on when no uri is given
+    #[serde(default = "default_srt_port")]
+    pub(crate) port: u16,
+
+    /// Encrypts the stream, must be 10 to 79 characters
+    #[validate(length(min = 10, max = 79, message = "Invalid srt passphrase length"))]
+    #[serde(default)]
+    pub(crate) passphrase: Option<String>,
+
+    /// The stream to send
+    #[serde(default = "default_srt_stream")]
+    pub(crate) stream: StreamConfig,
+}
+
+impl SrtConfig {
+    pub(crate) fn uri(&self) -> String {
+        match self.uri.as_ref() {
+            Some(uri) => uri.clone(),
+            None => format!("srt://:{}?mode=listener", self.port),
+        }
+    }
+}
+
+/// The clock burnt into transcoded video
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct ClockOverlayConfig {
+    /// strftime format of the time
+    #[serde(default = "default_clock_format")]
+    pub(crate) format: String,
+
+    /// Pango font of the text
+    #[serde(default = "default_clock_font")]
+    pub(crate) font: String,
+
+    #[validate(regex(path = *RE_VALIGN, message = "Incorrect valign", code = "valign"))]
+    #[serde(default = "default_clock_valign")]
+    pub(crate) valign: String,
+
+    #[validate(regex(path = *RE_HALIGN, message = "Incorrect halign", code = "halign"))]
+    #[serde(default = "default_clock_halign")]
+    pub(crate) halign: String,
+}
+
+/// The IR mode inside a daily window of local time and outside of it
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct IrScheduleConfig {
+    /// Start of the window as HH:MM
+    #[validate(regex(path = *RE_TIME_OF_DAY, message = "Incorrect time", code = "start"))]
+    pub(crate) start: String,
+
+    /// End of the window as HH:MM, it may be before the start to wrap past midnight
+    #[validate(regex(path = *RE_TIME_OF_DAY, message = "Incorrect time", code = "end"))]
+    pub(crate) end: String,
+
+    /// The mode inside the window
+    #[serde(default = "default_ir_schedule_mode")]
+    pub(crate) mode: IrMode,
+
+    /// The mode outside the window
+    #[serde(default = "default_ir_schedule_otherwise", alias = "else")]
+    pub(crate) otherwise: IrMode,
+}
+
+/// Where and how long the mp4 files of the continuous recording are
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct SegmentConfig {
+    /// Directory for the mp4 files
+    pub(crate) path: String,
+
+    /// Length in seconds of each file
+    #[validate(range(min = 10, message = "Invalid segment duration", code = "duration"))]
+    #[serde(default = "default_segment_duration", alias = "segment_secs")]
+    pub(crate) duration: u64,
+
+    /// The stream to record
+    #[serde(default = "default_record_stream")]
+    pub(crate) stream: StreamConfig,
+}
+
+/// Where the prometheus metrics are served
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct MetricsConfig {
+    #[serde(rename = "bind", default = "default_bind_addr")]
+    pub(crate) bind_addr: String,
+
+    #[serde(default = "default_metrics_port")]
+    pub(crate) bind_port: u16,
+}
+
+/// Where the recordings started with `control/record` are written
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct RecordConfig {
+    /// Directory for the recordings, the working directory if not set
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+
+    /// The stream to record
+    #[serde(default = "default_record_stream")]
+    pub(crate) stream: StreamConfig,
+}
+
+/// Where and how often the time-lapse snapshots are written
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq, Eq)]
+pub(crate) struct TimelapseConfig {
+    /// Directory for the jpegs
+    pub(crate) path: String,
+
+    /// Seconds between the snapshots
+    #[validate(range(min = 1, message = "Invalid timelapse interval", code = "interval"))]
+    #[serde(default = "default_timelapse_interval")]
+    pub(crate) interval: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, Eq, PartialEq)]
 pub(crate) enum SplashPattern {
     #[serde(alias = "smpte")]
@@ -427,6 +1454,10 @@ impl std::fmt::Display for SplashPattern {
     }
 }
 
+fn default_bandwidth_state() -> std::path::PathBuf {
+    std::env::temp_dir().join("neolink_bandwidth.json")
+}
+
 fn default_bind_addr() -> String {
     "0.0.0.0".to_string()
 }
@@ -435,6 +1466,10 @@ fn default_bind_port() -> u16 {
     8554
 }
 
+fn default_metrics_port() -> u16 {
+    9101
+}
+
 fn default_stream() -> StreamConfig {
     StreamConfig::All
 }
@@ -455,10 +1490,18 @@ fn default_channel_id() -> u8 {
     0
 }
 
+fn default_snapshot_quality() -> u8 {
+    75
+}
+
 fn default_update_time() -> bool {
     false
 }
 
+fn default_max_time_drift() -> u64 {
+    5
+}
+
 fn default_motion_timeout() -> f64 {
     1.
 }
@@ -488,22 +1531,205 @@ fn default_pause() -> PauseConfig {
     }
 }
 
+fn default_snapshot_refresh() -> u64 {
+    1000
+}
+
+fn default_snapshot_stream() -> StreamConfig {
+    StreamConfig::Sub
+}
+
+fn default_snapshot_cache() -> SnapshotCacheConfig {
+    SnapshotCacheConfig {
+        enabled: default_false(),
+        refresh: default_snapshot_refresh(),
+        stream: default_snapshot_stream(),
+    }
+}
+
+fn default_unix_socket_stream() -> StreamConfig {
+    StreamConfig::Main
+}
+
+fn default_recording() -> RecordConfig {
+    RecordConfig {
+        path: None,
+        stream: default_record_stream(),
+    }
+}
+
+fn default_clock_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
+fn default_clock_font() -> String {
+    "Sans, 16".to_string()
+}
+
+fn default_clock_valign() -> String {
+    "top".to_string()
+}
+
+fn default_clock_halign() -> String {
+    "right".to_string()
+}
+
+fn default_segment_duration() -> u64 {
+    300
+}
+
+fn default_timelapse_interval() -> u64 {
+    60
+}
+
+fn default_record_stream() -> StreamConfig {
+    StreamConfig::Main
+}
+
+fn default_srt_stream() -> StreamConfig {
+    StreamConfig::Main
+}
+
+fn default_dump_max_size() -> u64 {
+    100
+}
+
+fn default_srt_port() -> u16 {
+    8890
+}
+
 fn default_buffer_size() -> usize {
     25
 }
 
+fn default_mosaic_columns() -> u32 {
+    2
+}
+
+fn default_mosaic_tile_width() -> u32 {
+    640
+}
+
+fn default_mosaic_tile_height() -> u32 {
+    360
+}
+
+fn default_audio_codecs() -> Vec<AudioCodec> {
+    vec![AudioCodec::L16]
+}
+
+fn default_ptz_timeout() -> u64 {
+    30
+}
+
+fn default_reboot_cooldown() -> u64 {
+    300
+}
+
+fn default_ptz_speed() -> u32 {
+    32
+}
+
+fn default_stream_fallback() -> Vec<StreamConfig> {
+    vec![StreamConfig::Sub]
+}
+
+fn default_reconnect_strategy() -> ReconnectStrategy {
+    ReconnectStrategy::Auto
+}
+
+fn default_max_backoff() -> u64 {
+    60
+}
+
+fn default_login_throttle_backoff() -> u64 {
+    300
+}
+
+fn default_clock_jump() -> u64 {
+    0
+}
+
+fn default_loss_gap() -> u64 {
+    1000
+}
+
+fn default_power_cycle_after() -> u32 {
+    5
+}
+
+fn default_power_cycle_cooldown() -> u64 {
+    900
+}
+
+fn default_idle_flush() -> u64 {
+    10
+}
+
+fn default_adaptive_linger() -> u64 {
+    30
+}
+
+fn default_frame_timeout() -> u64 {
+    30
+}
+
+fn default_ir_schedule_mode() -> IrMode {
+    IrMode::On
+}
+
+fn default_ir_schedule_otherwise() -> IrMode {
+    IrMode::Auto
+}
+
 fn default_max_discovery_retries() -> usize {
     10
 }
 
+fn default_siren_duration() -> u64 {
+    10
+}
+
 fn default_2000() -> u64 {
     2000
 }
 
+fn default_5000() -> u64 {
+    5000
+}
+
+fn default_10000() -> u64 {
+    10000
+}
+
+fn default_discovery_topic() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_discovery_features() -> HashSet<Discoveries> {
+    [
+        Discoveries::Motion,
+        Discoveries::Ir,
+        Discoveries::Led,
+        Discoveries::Reboot,
+    ]
+    .into_iter()
+    .collect()
+}
+
 fn default_splash() -> SplashPattern {
     SplashPattern::Snow
 }
 
+fn validate_splash_size(size: &[u32; 2]) -> Result<(), ValidationError> {
+    if size.iter().any(|v| !(16..=4096).contains(v)) {
+        return Err(ValidationError::new(
+            "splash_size must be between 16 and 4096",
+        ));
+    }
+    Ok(())
+}
+
 pub(crate) static RESERVED_NAMES: &[&str] = &["anyone", "anonymous"];
 fn validate_username(name: &str) -> Result<(), ValidationError> {
     if name.trim().is_empty() {
@@ -512,14 +1738,328 @@ fn validate_username(name: &str) -> Result<(), ValidationError> {
     if RESERVED_NAMES.contains(&name) {
         return Err(ValidationError::new("This is a reserved username"));
     }
+    if name.contains(':') {
+        return Err(ValidationError::new("username cannot contain a `:`"));
+    }
     Ok(())
 }
 
+fn validate_config(config: &Config) -> Result<(), ValidationError> {
+    validate_camera_names(config)?;
+    validate_mosaic_names(config)?;
+    validate_user_logins(config)
+}
+
+fn validate_camera_names(config: &Config) -> Result<(), ValidationError> {
+    let mut clashes = vec![];
+    for (i, camera) in config.cameras.iter().enumerate() {
+        let same = config
+            .cameras
+            .iter()
+            .enumerate()
+            .filter(|(_, other)| other.name == camera.name)
+            .collect::<Vec<_>>();
+        // Report each name once, at its first entry
+        if same.len() > 1 && same[0].0 == i {
+            let entries = same
+                .iter()
+                .map(|(j, other)| {
+                    let addr = other
+                        .camera_addr
+                        .as_deref()
+                        .or(other.camera_uid.as_deref())
+                        .unwrap_or("no address");
+                    format!("entry {} ({})", j + 1, addr)
+                })
+                .collect::<Vec<_>>();
+            clashes.push(format!("`{}`: {}", camera.name, entries.join(", ")));
+        }
+    }
+    if clashes.is_empty() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("duplicate_names");
+        error.message = Some(
+            format!(
+                "Cameras share a name, give them unique names or set duplicate_names = \"suffix\". {}",
+                clashes.join("; ")
+            )
+            .into(),
+        );
+        Err(error)
+    }
+}
+
+/// Mosaics are mounted at `/{name}` so they must not land on
+/// a camera's paths or on another mosaic
+fn validate_mosaic_names(config: &Config) -> Result<(), ValidationError> {
+    let mut clashes = vec![];
+    for (i, mosaic) in config.mosaics.iter().enumerate() {
+        if let Some(camera) = config.cameras.iter().find(|camera| {
+            mosaic.name == camera.name || mosaic.name.starts_with(&format!("{}/", camera.name))
+        }) {
+            clashes.push(format!(
+                "mosaic `{}` is on the paths of camera `{}`",
+                mosaic.name, camera.name
+            ));
+        }
+        if config.mosaics[..i]
+            .iter()
+            .any(|other| other.name == mosaic.name)
+        {
+            clashes.push(format!("mosaic `{}` is given more than once", mosaic.name));
+        }
+    }
+    if clashes.is_empty() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("mosaic_names");
+        error.message = Some(
+            format!(
+                "Mosaic names must differ from the camera names. {}",
+                clashes.join("; ")
+            )
+            .into(),
+        );
+        Err(error)
+    }
+}
+
+/// The rtsp server gives each login a single role so the same name and
+/// password cannot be used by more than one camera or by a camera and the
+/// `[[users]]`
+fn validate_user_logins(config: &Config) -> Result<(), ValidationError> {
+    let logins = config
+        .users
+        .iter()
+        .map(|user| (user, "[[users]]".to_string()))
+        .chain(config.cameras.iter().flat_map(|camera| {
+            camera
+                .users
+                .iter()
+                .map(move |user| (user, format!("camera `{}`", camera.name)))
+        }))
+        .collect::<Vec<_>>();
+    let mut clashes = vec![];
+    for (i, (user, place)) in logins.iter().enumerate() {
+        let mut places = vec![place];
+        for (other, other_place) in logins[i + 1..].iter() {
+            if other == user && !places.contains(&other_place) {
+                places.push(other_place);
+            }
+        }
+        // Report each login once, at its first entry
+        if places.len() > 1 && !logins[..i].iter().any(|(other, _)| other == user) {
+            clashes.push(format!(
+                "`{}`: {}",
+                user.name,
+                places
+                    .iter()
+                    .map(|place| place.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+    if clashes.is_empty() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("user_logins");
+        error.message = Some(
+            format!(
+                "The same login is given more than once, put it in [[users]] and list it in the permitted_users of the cameras instead. {}",
+                clashes.join("; ")
+            )
+            .into(),
+        );
+        Err(error)
+    }
+}
+
 fn validate_camera_config(camera_config: &CameraConfig) -> Result<(), ValidationError> {
     match (&camera_config.camera_addr, &camera_config.camera_uid) {
         (None, None) => Err(ValidationError::new(
             "Either camera address or uid must be given",
         )),
+        (None, _) if camera_config.connection == ConnectionMode::Tcp => Err(ValidationError::new(
+            "The camera address must be given for connection = \"tcp\"",
+        )),
+        (_, None)
+            if matches!(
+                camera_config.connection,
+                ConnectionMode::Local | ConnectionMode::Relay
+            ) =>
+        {
+            Err(ValidationError::new(
+                "The camera uid must be given for connection = \"local\" or \"relay\"",
+            ))
+        }
         _ => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str, cameras: &[&str]) -> Config {
+        let mut toml = toml.to_string();
+        for name in cameras {
+            toml.push_str(&format!(
+                "\n[[cameras]]\nname = \"{name}\"\nusername = \"admin\"\naddress = \"127.0.0.1\"\n"
+            ));
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    fn names(config: &Config) -> Vec<&str> {
+        config
+            .cameras
+            .iter()
+            .map(|camera| camera.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_names_suffix() {
+        let mut config = config(
+            "duplicate_names = \"suffix\"",
+            &["Cam", "Cam", "Other", "Cam"],
+        );
+        config.resolve_names();
+        assert_eq!(names(&config), vec!["Cam", "Cam-2", "Other", "Cam-3"]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    // A real camera called `Cam-2` later in the config keeps its name
+    fn test_resolve_names_real_suffix() {
+        let mut config = config("duplicate_names = \"suffix\"", &["Cam", "Cam", "Cam-2"]);
+        config.resolve_names();
+        assert_eq!(names(&config), vec!["Cam", "Cam-3", "Cam-2"]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_names_error() {
+        let mut config = config("", &["Cam", "Other", "Cam"]);
+        config.resolve_names();
+        assert_eq!(names(&config), vec!["Cam", "Other", "Cam"]);
+        let error = validate_config(&config).unwrap_err();
+        assert_eq!(error.code, "duplicate_names");
+        let message = error.message.unwrap();
+        assert!(message.contains("`Cam`: entry 1 (127.0.0.1), entry 3 (127.0.0.1)"));
+    }
+
+    #[test]
+    fn test_validate_mosaic_names() {
+        let mosaic = |name: &str| format!("[[mosaics]]\nname = \"{name}\"\ncameras = [\"Cam\"]\n");
+        assert!(validate_config(&config(&mosaic("All"), &["Cam"])).is_ok());
+        // On the camera's own paths
+        for name in ["Cam", "Cam/main"] {
+            let error = validate_config(&config(&mosaic(name), &["Cam"])).unwrap_err();
+            assert_eq!(error.code, "mosaic_names");
+        }
+        let twice = format!("{}{}", mosaic("All"), mosaic("All"));
+        let error = validate_config(&config(&twice, &["Cam"])).unwrap_err();
+        assert_eq!(error.code, "mosaic_names");
+    }
+
+    #[test]
+    fn test_validate_user_logins() {
+        let camera = |name: &str, user: &str, pass: &str| {
+            format!(
+                "[[cameras]]\nname = \"{name}\"\nusername = \"admin\"\naddress = \"127.0.0.1\"\n[[cameras.users]]\nname = \"{user}\"\npass = \"{pass}\"\n"
+            )
+        };
+        let global = "[[users]]\nname = \"me\"\npass = \"mepass\"\n";
+
+        // The same name with different passwords is fine
+        let toml = format!(
+            "{}{}",
+            camera("Front", "viewer", "one"),
+            camera("Back", "viewer", "two")
+        );
+        assert!(validate_config(&toml::from_str(&toml).unwrap()).is_ok());
+
+        // Two cameras sharing a login
+        let toml = format!(
+            "{}{}",
+            camera("Front", "viewer", "one"),
+            camera("Back", "viewer", "one")
+        );
+        let error = validate_config(&toml::from_str(&toml).unwrap()).unwrap_err();
+        assert_eq!(error.code, "user_logins");
+        assert!(error
+            .message
+            .unwrap()
+            .contains("`viewer`: camera `Front`, camera `Back`"));
+
+        // A camera sharing a login with the [[users]]
+        let toml = format!("{}{}", global, camera("Front", "me", "mepass"));
+        let error = validate_config(&toml::from_str(&toml).unwrap()).unwrap_err();
+        assert_eq!(error.code, "user_logins");
+        assert!(error
+            .message
+            .unwrap()
+            .contains("`me`: [[users]], camera `Front`"));
+    }
+
+    fn expand(text: &str) -> (String, Vec<String>) {
+        let mut value = toml::Value::String(text.to_string());
+        let mut missing = vec![];
+        expand_env_value(&mut value, &mut missing);
+        (value.as_str().unwrap().to_string(), missing)
+    }
+
+    #[test]
+    fn test_expand_env_value() {
+        std::env::set_var("NEOLINK_TEST_PASSWORD", "hunter2");
+        assert_eq!(
+            expand("${NEOLINK_TEST_PASSWORD}"),
+            ("hunter2".to_string(), vec![])
+        );
+        assert_eq!(
+            expand("a${NEOLINK_TEST_PASSWORD}b${NEOLINK_TEST_PASSWORD}"),
+            ("ahunter2bhunter2".to_string(), vec![])
+        );
+        // Not a variable
+        assert_eq!(
+            expand("$NEOLINK_TEST_PASSWORD"),
+            ("$NEOLINK_TEST_PASSWORD".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_expand_env_escape() {
+        std::env::set_var("NEOLINK_TEST_ESCAPED", "hunter2");
+        assert_eq!(
+            expand("$${NEOLINK_TEST_ESCAPED}"),
+            ("${NEOLINK_TEST_ESCAPED}".to_string(), vec![])
+        );
+        assert_eq!(
+            expand("$${NEOLINK_TEST_ESCAPED}${NEOLINK_TEST_ESCAPED}"),
+            ("${NEOLINK_TEST_ESCAPED}hunter2".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_expand_env_missing() {
+        std::env::remove_var("NEOLINK_TEST_MISSING");
+        assert_eq!(
+            expand("${NEOLINK_TEST_MISSING}"),
+            (String::new(), vec!["NEOLINK_TEST_MISSING".to_string()])
+        );
+
+        // Missing variables anywhere in the config are an error naming them
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [[cameras]]
+            password = "${NEOLINK_TEST_MISSING}"
+            "#,
+        )
+        .unwrap();
+        let error = expand_env(&mut value).unwrap_err();
+        assert!(error.to_string().contains("NEOLINK_TEST_MISSING"));
+    }
+}
@@ -4,7 +4,7 @@ use anyhow::{anyhow, Context, Result};
 use gstreamer::{
     parse::launch_full, prelude::*, ClockTime, MessageView, ParseFlags, Pipeline, State,
 };
-use gstreamer_app::AppSrc;
+use gstreamer_app::{AppSink, AppSrc};
 use tokio::{
     sync::{
         self,
@@ -76,8 +76,9 @@ impl Drop for GstSender {
 pub(super) async fn from_input<T: AsRef<Path>>(
     format: VidFormat,
     out_file: T,
+    quality: u8,
 ) -> Result<GstSender> {
-    let pipeline = create_pipeline(format, out_file.as_ref())?;
+    let pipeline = create_pipeline(format, out_file.as_ref(), quality)?;
     output(pipeline).await
 }
 
@@ -132,6 +133,68 @@ async fn output(pipeline: Pipeline) -> Result<GstSender> {
     })
 }
 
+/// Decode a single keyframe into a jpeg
+///
+/// This blocks until gstreamer is done so should be run with `spawn_blocking`
+pub(crate) fn keyframe_to_jpeg(format: VidFormat, keyframe: &[u8], quality: u8) -> Result<Vec<u8>> {
+    gstreamer::init()
+        .context("Unable to start gstreamer ensure it and all plugins are installed")?;
+    let parser = match format {
+        VidFormat::H264 => "h264parse",
+        VidFormat::H265 => "h265parse",
+        VidFormat::None => return Err(anyhow!("Cannot make a jpeg without video")),
+    };
+    let launch_str = format!(
+        "appsrc name=thesource \
+        ! {parser} \
+        ! decodebin \
+        ! videoconvert \
+        ! jpegenc snapshot=TRUE quality={quality} \
+        ! appsink name=thesink"
+    );
+    let pipeline = launch_full(&launch_str, None, ParseFlags::empty())
+        .context("Unable to load gstreamer pipeline ensure all gstramer plugins are installed")?
+        .dynamic_cast::<Pipeline>()
+        .map_err(|_| {
+            anyhow!("Unable to create gstreamer pipeline ensure all gstramer plugins are installed")
+        })?;
+    let source = get_source(&pipeline)?;
+    let sink = pipeline
+        .by_name("thesink")
+        .expect("There shoud be a `thesink`")
+        .dynamic_cast::<AppSink>()
+        .map_err(|_| anyhow!("Cannot find appsink in gstreamer, check your gstreamer plugins"))?;
+
+    pipeline.set_state(State::Playing)?;
+    let res = (|| -> Result<Vec<u8>> {
+        let mut gst_buf = gstreamer::Buffer::with_size(keyframe.len())?;
+        {
+            let gst_buf_mut = gst_buf.get_mut().unwrap();
+            let mut gst_buf_data = gst_buf_mut.map_writable()?;
+            gst_buf_data.copy_from_slice(keyframe);
+        }
+        source
+            .push_buffer(gst_buf)
+            .map_err(|e| anyhow!("Streamer Error: {e:?}"))?;
+        source
+            .end_of_stream()
+            .map_err(|e| anyhow!("Streamer Error: {e:?}"))?;
+        // Blocks until the jpeg is ready or the pipeline stops
+        let sample = sink
+            .pull_sample()
+            .map_err(|_| anyhow!("Keyframe could not be decoded"))?;
+        let buffer = sample
+            .buffer()
+            .ok_or_else(|| anyhow!("Jpeg sample has no buffer"))?;
+        let map = buffer.map_readable()?;
+        Ok(map.as_slice().to_vec())
+    })();
+    pipeline
+        .set_state(State::Null)
+        .context("Error in gstreamer when setting state to Null")?;
+    res
+}
+
 fn start_pipeline(pipeline: Pipeline) -> Result<()> {
     pipeline.set_state(State::Playing)?;
 
@@ -171,7 +234,7 @@ fn get_source(pipeline: &Pipeline) -> Result<AppSrc> {
         .map_err(|_| anyhow!("Cannot find appsource in gstreamer, check your gstreamer plugins"))
 }
 
-fn create_pipeline(format: VidFormat, file_path: &Path) -> Result<Pipeline> {
+fn create_pipeline(format: VidFormat, file_path: &Path, quality: u8) -> Result<Pipeline> {
     gstreamer::init()
         .context("Unable to start gstreamer ensure it and all plugins are installed")?;
     let file_path = file_path.with_extension("jpeg");
@@ -182,8 +245,9 @@ fn create_pipeline(format: VidFormat, file_path: &Path) -> Result<Pipeline> {
                 "appsrc name=thesource \
                 ! h264parse \
                 ! decodebin \
-                ! jpegenc snapshot=TRUE
+                ! jpegenc snapshot=TRUE quality={}
                 ! filesink location={}",
+                quality,
                 file_path.display()
             )
         }
@@ -192,8 +256,9 @@ fn create_pipeline(format: VidFormat, file_path: &Path) -> Result<Pipeline> {
                 "appsrc name=thesource \
                 ! h265parse \
                 ! decodebin \
-                ! jpegenc snapshot=TRUE
+                ! jpegenc snapshot=TRUE quality={}
                 ! filesink location={}",
+                quality,
                 file_path.display()
             )
         }
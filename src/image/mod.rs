@@ -29,6 +29,7 @@ mod gst;
 
 use crate::common::{NeoReactor, StampedData};
 pub(crate) use cmdline::Opt;
+pub(crate) use gst::keyframe_to_jpeg;
 
 /// Entry point for the image subcommand
 ///
@@ -38,7 +39,7 @@ pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
 
     if opt.use_stream {
         let stream_data = camera
-            .stream(StreamKind::Main)
+            .decode_stream(StreamKind::Main)
             .await
             .context("Failed to start video")?;
 
@@ -64,7 +65,8 @@ pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
             }
         };
 
-        let mut sender = gst::from_input(vid_type, &opt.file_path).await?;
+        let quality = camera.config().await?.borrow().snapshot_quality;
+        let mut sender = gst::from_input(vid_type, &opt.file_path, quality).await?;
         sender.send(buf).await?; // Send first iframe
 
         // Keep sending both IFrame or PFrame until finished
@@ -33,7 +33,12 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use env_logger::Env;
 use log::*;
-use std::fs;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::time::{sleep, Duration};
 use validator::Validate;
 
 mod battery;
@@ -49,6 +54,7 @@ mod rtsp;
 mod services;
 mod statusled;
 mod talk;
+mod template;
 mod utils;
 
 use cmdline::{Command, Opt};
@@ -57,10 +63,17 @@ use config::Config;
 
 pub(crate) type AnyResult<T> = Result<T, anyhow::Error>;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // The local offset can only be read while there is a single thread
+    utils::capture_local_offset();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run())
+}
 
+async fn run() -> Result<()> {
     info!(
         "Neolink {} {}",
         env!("NEOLINK_VERSION"),
@@ -70,17 +83,11 @@ async fn main() -> Result<()> {
     let opt = Opt::parse();
 
     let conf_path = opt.config.context("Must supply --config file")?;
-    let config: Config = toml::from_str(
-        &fs::read_to_string(&conf_path)
-            .with_context(|| format!("Failed to read {:?}", conf_path))?,
-    )
-    .with_context(|| format!("Failed to parse the {:?} config file", conf_path))?;
-
-    config
-        .validate()
-        .with_context(|| format!("Failed to validate the {:?} config file", conf_path))?;
+    let config = read_config(&conf_path)?;
 
     let neo_reactor = NeoReactor::new(config.clone()).await;
+    let watcher = tokio::spawn(watch_config(conf_path, neo_reactor.clone()));
+    let metrics = tokio::spawn(common::metrics_main(neo_reactor.clone()));
 
     match opt.cmd {
         None => {
@@ -126,7 +133,66 @@ async fn main() -> Result<()> {
         Some(Command::Services(opts)) => {
             services::main(opts, neo_reactor.clone()).await?;
         }
+        Some(Command::Template(opts)) => {
+            template::main(opts, neo_reactor.clone()).await?;
+        }
     }
 
+    watcher.abort();
+    metrics.abort();
     Ok(())
 }
+
+fn read_config(conf_path: &Path) -> Result<Config> {
+    let mut value: toml::Value = toml::from_str(
+        &fs::read_to_string(conf_path)
+            .with_context(|| format!("Failed to read {:?}", conf_path))?,
+    )
+    .with_context(|| format!("Failed to parse the {:?} config file", conf_path))?;
+    config::expand_env(&mut value)
+        .with_context(|| format!("Failed to fill in the {:?} config file", conf_path))?;
+    let mut config: Config = value
+        .try_into()
+        .with_context(|| format!("Failed to parse the {:?} config file", conf_path))?;
+
+    config.resolve_names();
+    config
+        .validate()
+        .with_context(|| format!("Failed to validate the {:?} config file", conf_path))?;
+    Ok(config)
+}
+
+/// Reload the config whenever the file is saved
+///
+/// The file's modified time is polled rather than using inotify which does
+/// not see changes made to bind mounts from outside of docker. The reactor
+/// passes the new config on to each camera and only those whose settings
+/// changed are restarted. A config that does not parse or validate, such as
+/// one that is half saved, is logged and the current config is kept
+async fn watch_config(conf_path: PathBuf, reactor: NeoReactor) -> Result<()> {
+    const PERIOD: Duration = Duration::from_secs(2);
+    let modified = |path: &Path| -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    };
+    let mut last = modified(&conf_path);
+    loop {
+        sleep(PERIOD).await;
+        let current = modified(&conf_path);
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        match read_config(&conf_path) {
+            Ok(config) => {
+                if *reactor.config().await?.borrow() == config {
+                    continue;
+                }
+                reactor.update_config(config).await?;
+                info!("Reloaded the config from {:?}", conf_path);
+            }
+            Err(e) => {
+                error!("Keeping the current config: {:?}", e);
+            }
+        }
+    }
+}
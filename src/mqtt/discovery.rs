@@ -69,6 +69,16 @@ struct DiscoveryAvaliablity {
     payload_not_available: Option<String>,
 }
 
+/// The camera is only available while both it and neolink are
+///
+/// With `shared_connection` the camera's own last will is not set so
+/// `neolink/status` is the only sign that neolink has gone
+#[derive(Serialize, Debug, Clone)]
+struct DiscoveryAvailabilities {
+    availability: Vec<DiscoveryAvaliablity>,
+    availability_mode: String,
+}
+
 #[derive(Serialize, Debug)]
 struct DiscoveryLight {
     name: String,
@@ -76,7 +86,8 @@ struct DiscoveryLight {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Light specific
     #[serde(skip_serializing_if = "Option::is_none")]
     state_topic: Option<String>,
@@ -109,7 +120,8 @@ struct DiscoveryCamera {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Camera specific
     topic: String,
     #[serde(skip_serializing_if = "Encoding::is_none")]
@@ -123,7 +135,8 @@ struct DiscoverySwitch {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Switch specific
     // - Control
     command_topic: String,
@@ -145,7 +158,8 @@ struct DiscoverySelect {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Switch specific
     // - Control
     command_topic: String,
@@ -162,7 +176,8 @@ struct DiscoveryBinarySensor {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // BinarySensor specific
     payload_off: String,
     payload_on: String,
@@ -177,7 +192,8 @@ struct DiscoveryButton {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Button specific
     command_topic: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -191,7 +207,8 @@ struct DiscoverySensor {
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<String>,
     device: DiscoveryDevice,
-    availability: DiscoveryAvaliablity,
+    #[serde(flatten)]
+    availability: DiscoveryAvailabilities,
     // Button specific
     state_topic: String,
     state_class: String,
@@ -239,10 +256,20 @@ pub(crate) async fn enable_discovery(
         sw_version: Some(env!("CARGO_PKG_VERSION").to_string()),
     };
 
-    let availability = DiscoveryAvaliablity {
-        topic: format!("neolink/{}/status", cam_config.name),
-        payload_available: Some("connected".to_string()),
-        payload_not_available: None,
+    let availability = DiscoveryAvailabilities {
+        availability: vec![
+            DiscoveryAvaliablity {
+                topic: format!("neolink/{}/status/available", cam_config.name),
+                payload_available: Some("online".to_string()),
+                payload_not_available: Some("offline".to_string()),
+            },
+            DiscoveryAvaliablity {
+                topic: "neolink/status".to_string(),
+                payload_available: Some("connected".to_string()),
+                payload_not_available: Some("offline".to_string()),
+            },
+        ],
+        availability_mode: "all".to_string(),
     };
 
     for feature in &discovery_config.features {
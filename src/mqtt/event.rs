@@ -0,0 +1,69 @@
+//! Formats camera events for publishing over MQTT
+//!
+//! Motion is always published as `on`/`off` to `/status/motion`. Other
+//! consumers such as [Frigate](https://frigate.video) expect a JSON payload
+//! which can be added with `event_format` in the `[cameras.mqtt]` config
+//!
+//! The `frigate` format also publishes to `/event` a JSON object of the form
+//!
+//! ```json
+//! {
+//!   "type": "new",
+//!   "camera": "CameraName",
+//!   "label": "motion",
+//!   "score": 1.0,
+//!   "timestamp": 1700000000.123
+//! }
+//! ```
+//!
+//! - `type` is `new` when the event starts and `end` when it finishes
+//! - `camera` is the name of the camera from the config
+//! - `label` is the kind of event, currently always `motion`
+//! - `score` is the confidence of the event, the camera does not report
+//!   one so this is always `1.0`
+//! - `timestamp` is the unix time in seconds that the event was published
+//!
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum EventFormat {
+    #[default]
+    #[serde(alias = "native", alias = "neolink")]
+    Native,
+    #[serde(alias = "frigate", alias = "json")]
+    Frigate,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventType {
+    New,
+    End,
+}
+
+#[derive(Serialize, Debug)]
+struct FrigateEvent<'a> {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    camera: &'a str,
+    label: &'a str,
+    score: f32,
+    timestamp: f64,
+}
+
+/// Serialise an event in the frigate format
+pub(crate) fn frigate_event(camera: &str, label: &str, event_type: EventType) -> Result<String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    Ok(serde_json::to_string(&FrigateEvent {
+        event_type,
+        camera,
+        label,
+        score: 1.0,
+        timestamp,
+    })?)
+}
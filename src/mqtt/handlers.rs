@@ -0,0 +1,149 @@
+//! A registry of handlers for incoming mqtt topics
+//!
+//! Handlers are registered against a topic pattern which uses the
+//! usual mqtt wildcards
+//!
+//! - `+` matches exactly one level of the topic
+//! - `#` matches all remaining levels and must come last
+//!
+//! The levels matched by the wildcards are passed to the handler
+//! in order so that it does not need to parse the topic itself
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+use super::mqttc::{MqttInstance, MqttReply, MqttReplyRef};
+use crate::common::NeoInstance;
+
+/// What a handler is given when its topic matches
+pub(crate) struct HandlerArgs<'a> {
+    pub(crate) msg: MqttReplyRef<'a>,
+    /// The parts of the topic matched by the wildcards
+    pub(crate) captures: Vec<&'a str>,
+    pub(crate) mqtt: &'a MqttInstance,
+    pub(crate) camera: &'a NeoInstance,
+}
+
+type Handler = Box<dyn for<'a> Fn(HandlerArgs<'a>) -> BoxFuture<'a, Result<()>> + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct MqttHandlers {
+    handlers: Vec<(String, Handler)>,
+}
+
+impl MqttHandlers {
+    /// Register a handler for a topic pattern
+    ///
+    /// When several patterns match a topic the first one registered is used
+    pub(crate) fn add<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(HandlerArgs<'a>) -> BoxFuture<'a, Result<()>> + Send + Sync + 'static,
+    {
+        self.handlers.push((pattern.to_string(), Box::new(handler)));
+        self
+    }
+
+    /// Run the handler for this message
+    ///
+    /// Returns `None` if no pattern matches the topic
+    pub(crate) async fn handle(
+        &self,
+        msg: &MqttReply,
+        mqtt: &MqttInstance,
+        camera: &NeoInstance,
+    ) -> Option<Result<()>> {
+        let msg = msg.as_ref();
+        for (pattern, handler) in self.handlers.iter() {
+            if let Some(captures) = topic_matches(pattern, msg.topic) {
+                return Some(
+                    handler(HandlerArgs {
+                        msg,
+                        captures,
+                        mqtt,
+                        camera,
+                    })
+                    .await,
+                );
+            }
+        }
+        None
+    }
+}
+
+/// Match a topic against a pattern returning the levels matched by the wildcards
+pub(crate) fn topic_matches<'a>(pattern: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    let mut captures = vec![];
+    let mut topic_rest = Some(topic);
+    for level in pattern.split('/') {
+        match level {
+            "#" => {
+                captures.push(topic_rest.unwrap_or_default());
+                return Some(captures);
+            }
+            _ => {
+                let (topic_level, next) = match topic_rest?.split_once('/') {
+                    Some((topic_level, next)) => (topic_level, Some(next)),
+                    None => (topic_rest?, None),
+                };
+                if level == "+" {
+                    captures.push(topic_level);
+                } else if level != topic_level {
+                    return None;
+                }
+                topic_rest = next;
+            }
+        }
+    }
+    topic_rest.is_none().then_some(captures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::topic_matches;
+
+    #[test]
+    fn test_exact_match() {
+        assert_eq!(
+            topic_matches("control/led", "control/led"),
+            Some(Vec::<&str>::new())
+        );
+        assert_eq!(topic_matches("control/led", "control/ir"), None);
+        assert_eq!(topic_matches("control/led", "control"), None);
+        assert_eq!(topic_matches("control", "control/led"), None);
+        assert_eq!(topic_matches("control/led", "control/led/extra"), None);
+    }
+
+    #[test]
+    fn test_single_level_wildcard() {
+        assert_eq!(
+            topic_matches("control/stream/+", "control/stream/main"),
+            Some(vec!["main"])
+        );
+        assert_eq!(
+            topic_matches("control/stream/+/ratemode", "control/stream/sub/ratemode"),
+            Some(vec!["sub"])
+        );
+        assert_eq!(
+            topic_matches("+/stream/+/ratemode", "query/stream/sub/ratemode"),
+            Some(vec!["query", "sub"])
+        );
+        assert_eq!(
+            topic_matches("control/stream/+", "control/stream/main/ratemode"),
+            None
+        );
+        assert_eq!(topic_matches("control/stream/+", "control/stream"), None);
+    }
+
+    #[test]
+    fn test_multi_level_wildcard() {
+        assert_eq!(
+            topic_matches("control/ptz/#", "control/ptz/preset"),
+            Some(vec!["preset"])
+        );
+        assert_eq!(
+            topic_matches("control/#", "control/stream/main/ratemode"),
+            Some(vec!["stream/main/ratemode"])
+        );
+        assert_eq!(topic_matches("control/#", "control"), Some(vec![""]));
+        assert_eq!(topic_matches("control/#", "query/battery"), None);
+    }
+}
@@ -9,22 +9,81 @@
 //!
 //! Control messages:
 //!
-//! - `/control/floodlight [on|off]` Turns floodlight (if equipped) on/off
+//! - `/control/floodlight [on|off|0-100]` Turns floodlight (if equipped) on/off or on at a brightness
 //! - `/control/led [on|off]` Turns status LED on/off
 //! - `/control/pir [on|off]` Turns PIR on/off
-//! - `/control/ir [on|off|auto]` Turn IR lights on/off or automatically via light detection
-//! - `/control/reboot` Reboot the camera
-//! - `/control/ptz` [up|down|left|right|in|out] (amount) Control the PTZ movements, amount defaults to 32.0
-//! - `/control/ptz/preset` [id] Move the camera to a known preset
+//! - `/control/ir [on|off|auto]` Turn IR lights on/off or automatically via light detection,
+//!   this holds until the next change of the `ir_schedule`
+//! - `/control/reboot` Reboot the camera, refused for `reboot_cooldown` seconds after the last reboot
+//! - `/control/playsound` [path] Play a WAV/MP3 file from the neolink host on the camera's speaker
+//! - `/control/ptz` [up|down|left|right|in|out] (amount) (speed) Control the PTZ movements, amount defaults to 32.0
+//!    and speed defaults to `ptz_speed`. Moves run one at a time, see `ptz_overlap`
+//! - `/control/ptz stop` Stop straight away, cancelling any running or waiting moves
+//! - `/control/ptz/move` [up|down|left|right|stop] (speed) Move until stopped or `ptz_timeout`
+//! - `/control/ptz/preset` or `/control/preset` [id] Move the camera to a known preset
 //! - `/control/ptz/assign` [id] [name] Assign the current ptz position to an ID and name
+//! - `/control/preset/save` [id] (name) Same as `/control/ptz/assign` with an optional name
+//! - `/control/ptz/info` Query the ptz capabilities of the camera
+//! - `/control/zoom` [in|out|stop|amount] Zoom until stopped or `ptz_timeout`, or to an amount such as 2.5 for 2.5x
+//! - `/control/focus` [position] Focus to a position in the range from `/control/ptz/info`
+//! - `/control/cleanup` Clear all the retained topics that have been published for this camera
+//! - `/control/stream [pause|resume]` Show the splash in place of the camera on the rtsp paths or go back to the camera
+//! - `/control/stream/[main|sub|extern] [on|off]` Start/stop pulling an individual stream
+//! - `/control/stream/[main|sub|extern]/ratemode [cbr|vbr]` Set the bitrate control mode of a stream
+//! - `/control/flip [on|off]` Flip the image upside down on the camera
+//! - `/control/mirror [on|off]` Mirror the image left to right on the camera
+//! - `/control/record [on|off]` Start/stop recording the stream to disk
+//! - `/control/query` Read the led, ir, pir and battery state from the camera and publish them again
+//! - `/control/snapshot` Decode a keyframe of the stream to a jpeg in `/status/snapshot`
+//! - `/control/siren [on|off]` Sound the siren until off or `siren_duration` seconds pass
 //!
 //! Status Messages:
 //!
 //! `/status offline` Sent when the neolink goes offline this is a LastWill message
-//! `/status disconnected` Sent when the camera goes offline
+//! `/status disconnected` Sent when the camera goes offline or is stopped, it is also the
+//!    retained LastWill of the camera so it is sent if neolink dies
+//! `/status connecting` Sent while neolink is trying to connect to the camera
+//! `/status/available [online|offline]` Whether the camera can currently be controlled, used as the
+//!    availability topic of the discovery entities
 //! `/status/battery` Sent in reply to a `/query/battery`
+//! `/status/battery/charging [on|off]` Whether the battery is charging
+//! `/status/power` The power source, charge state and low power warning as JSON
+//!    on cameras that report it
+//! `/status/floodlight [on|off]` Sent when the floodlight changes
+//! `/status/floodlight/brightness` The brightness set by `/control/floodlight`
 //! `/status/pir` Sent in reply to a `/query/pir`
+//! `/status/pir/state [on|off]` Sent once the camera accepts a `/control/pir`
+//! `/status/snapshot` A base64 encoded jpeg sent in reply to a `/control/snapshot`
+//! `/status/led [on|off]` and `/status/ir [on|off|auto]` Sent in reply to a `/control/query`
+//! `/status/siren [on|off]` Sent when the siren is turned on or off
 //! `/status/ptz/preset` Sent in reply to a `/query/ptz/preset`
+//! `/status/ptz/info` Sent in reply to a `/control/ptz/info` as JSON
+//! `/status/schedule` Sent in reply to a `/query/schedule` as JSON
+//! `/status/playsound [playing|done|error]` Progress of the last `/control/playsound`
+//! `/status/reboot/last` Unix time in seconds of the last reboot sent by `/control/reboot`
+//! `/status/reboot` `rebooting` once a reboot is accepted then `rebooted` when the camera is connected again or `failed` if it is not back within 5 minutes
+//! `/status/transport` How the camera is connected as JSON, sent on each connect
+//! `/status/streams` JSON list of the streams the camera offers with their resolution, fps,
+//!    bitrate and codec, sent on each connect. The codec is `null` until the stream has been pulled
+//! `/status/flip [on|off]` Sent in reply to a `/control/flip`
+//! `/status/mirror [on|off]` Sent in reply to a `/control/mirror`
+//! `/status/record [on|off]` Whether the stream is being recorded to disk
+//! `/status/ai/[person|vehicle|pet|face|...] [on|off]` Whether the camera's AI detects that type,
+//!    types the camera reports that are not known are published under their own name
+//! `/status/motion/[person|vehicle|pet|face|...] [on|off]` The same as `/status/ai/<type>`
+//! `/status/motion/snapshot` A base64 encoded jpeg taken when motion starts, when `motion_snapshot` is set
+//! `/status/activity [on|off]` `on` while any motion or AI detection is active and `off` once
+//!    they have all cleared, published with `/status/motion`
+//! `/status/error` Why the camera cannot connect, `throttled` if it is refusing logins, `no camera` if the NVR channel is empty, `power cycle` if too many reconnects have failed, otherwise `none`
+//! `/status/stats/fps` JSON fps of each stream received from the camera every `stats_update` ms
+//! `/status/stats/bitrate` JSON bits per second of each stream received from the camera
+//! `/status/stats/events/today` JSON count of the motion and push notification events since
+//!    midnight, reset at midnight in `utc_offset` or the local time of the host
+//! `/status/stream` Sent in reply to a `/control/stream [pause|resume]`
+//! `/status/stream/[main|sub|extern]` Sent in reply to a `/control/stream`
+//! `/status/stream/[main|sub|extern]/ratemode` Sent in reply to a `/control/stream/*/ratemode`
+//!    or `/query/stream/*/ratemode`
+//! `/event` Motion events as JSON when `event_format = "frigate"` see [`event`]
 //!
 //! Query Messages:
 //!
@@ -33,6 +92,8 @@
 //! `/query/ptz/preset` Request that the camera reports the PTZ presets
 //! `/query/preview` Request that the camera post a base64 encoded jpeg
 //!    of the stream to `/status/preview`
+//! `/query/schedule` Request that the camera reports its recording schedule
+//! `/query/stream/[main|sub|extern]/ratemode` Request that the camera reports the bitrate mode
 //!
 //!
 //! # Usage
@@ -60,38 +121,70 @@
 //! `credentials` are the username and password required to identify with the mqtt server
 //!
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use std::collections::{HashMap, HashSet};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    sync::mpsc::channel as mpsc,
+    sync::{mpsc::channel as mpsc, Mutex},
     task::JoinSet,
-    time::{interval, sleep, Duration, MissedTickBehavior},
+    time::{interval, sleep, timeout, Duration, Instant, MissedTickBehavior},
 };
 use tokio_stream::{wrappers::IntervalStream, StreamExt};
 use tokio_util::sync::CancellationToken;
 use validator::Validate;
 
-use neolink_core::bc_protocol::{Direction as BcDirection, LightState};
+use neolink_core::bc_protocol::{Direction as BcDirection, LightState, PowerStatus, StreamKind};
 
 mod cmdline;
 mod discovery;
+mod event;
+mod handlers;
 mod mqttc;
+mod ptz;
+mod publish;
+mod siren;
+mod stats;
 
 use crate::{
-    common::{MdState, NeoInstance, NeoReactor},
-    config::Config,
+    common::{
+        shutdown, stream_snapshot, ConnectionState, MdState, NeoInstance, NeoReactor, VidFormat,
+        SHUTDOWN_TIMEOUT,
+    },
+    config::{expand_env, Config},
     AnyResult,
 };
 use anyhow::{anyhow, Context, Result};
 pub(crate) use cmdline::Opt;
 pub(crate) use discovery::Discoveries;
+pub(crate) use event::EventFormat;
 use log::*;
 use mqttc::{Mqtt, MqttReplyRef};
+pub(crate) use publish::PublishConfig;
 
 use self::{
     discovery::enable_discovery,
+    event::{frigate_event, EventType},
+    handlers::{HandlerArgs, MqttHandlers},
     mqttc::{MqttInstance, MqttReply},
+    ptz::PtzQueue,
+    publish::ChangeFilter,
+    siren::SirenLatch,
+    stats::{EventTally, StreamRates},
 };
 
+/// When each camera was last rebooted over mqtt, kept across reconnects
+static LAST_REBOOTS: Lazy<Mutex<HashMap<String, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Time for a camera to drop and come back after a reboot
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time allowed for the snapshot of a motion event before it is given up
+const MOTION_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Entry point for the mqtt subcommand
 ///
 /// Opt is the command line options
@@ -110,7 +203,7 @@ pub(crate) async fn main(_: Opt, reactor: NeoReactor) -> Result<()> {
     set.spawn(async move {
         let mut set = JoinSet::<AnyResult<()>>::new();
         let thread_cancel2 = thread_cancel.clone();
-        tokio::select!{
+        let r = tokio::select!{
             _ = thread_cancel.cancelled() => AnyResult::Ok(()),
             v = async {
                 let mut cameras: HashMap<String, CancellationToken> = Default::default();
@@ -133,8 +226,10 @@ pub(crate) async fn main(_: Opt, reactor: NeoReactor) -> Result<()> {
                             let mqtt_instance = thread_instance.subscribe(name).await?;
                             let name = name.clone();
                             set.spawn(async move {
-                                loop {
+                                let mut cleanup_on_shutdown;
+                                let r = loop {
                                     let camera = thread_reactor2.get(&name).await?;
+                                    cleanup_on_shutdown = camera.config().await?.borrow().mqtt.cleanup_on_shutdown;
                                     let mqtt_instance = mqtt_instance.resubscribe().await?;
                                     let r = tokio::select!{
                                         _ = thread_global_cancel.cancelled() => {
@@ -153,7 +248,24 @@ pub(crate) async fn main(_: Opt, reactor: NeoReactor) -> Result<()> {
                                         log::debug!("listen_on_camera stopped: {:?}", r);
                                         continue;
                                     }
+                                };
+                                if cleanup_on_shutdown {
+                                    // Give the broker time to publish the last wills of the
+                                    // dropped connections so that they are also cleared
+                                    sleep(Duration::from_secs(1)).await;
+                                    if let Err(e) = mqtt_instance.clear_retained().await {
+                                        log::warn!("{name}: Failed to clear retained MQTT topics: {e:?}");
+                                    }
+                                }
+                                // Clean stop, say so rather than leaving it to the last wills.
+                                // This is after the cleanup so that the availability is kept
+                                if let Err(e) = mqtt_instance.send_message("status", "disconnected", true).await {
+                                    log::debug!("{name}: Failed to publish disconnected: {e:?}");
                                 }
+                                if let Err(e) = mqtt_instance.send_message("status/available", "offline", true).await {
+                                    log::debug!("{name}: Failed to publish offline: {e:?}");
+                                }
+                                r
                             }) ;
                         }
                     }
@@ -166,6 +278,29 @@ pub(crate) async fn main(_: Opt, reactor: NeoReactor) -> Result<()> {
                     }
                 }
             } => v,
+        };
+        // Let the cameras publish their clean stop but don't wait on a hung one
+        thread_cancel.cancel();
+        if !shutdown(set, async {}, thread_cancel, SHUTDOWN_TIMEOUT).await {
+            log::warn!(
+                "MQTT cameras did not stop within {}s, aborted them",
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+        }
+        r
+    });
+
+    // Stop cleanly on Ctrl-C so that the cameras are marked offline
+    let thread_cancel = global_cancel.clone();
+    set.spawn(async move {
+        tokio::select! {
+            _ = thread_cancel.cancelled() => AnyResult::Ok(()),
+            v = tokio::signal::ctrl_c() => {
+                v?;
+                log::info!("MQTT stopping");
+                thread_cancel.cancel();
+                AnyResult::Ok(())
+            },
         }
     });
 
@@ -204,16 +339,27 @@ pub(crate) async fn main(_: Opt, reactor: NeoReactor) -> Result<()> {
             v = async {
                 while let Ok(msg) = thread_instance.recv().await {
                     if msg.topic == "config" {
-                        let config: Result<Config> = toml::from_str(&msg.message).with_context(|| {
-                            format!("Failed to parse the MQTT {:?} config file", msg.topic)
-                        });
+                        // Filled in from the environment the same as the config file
+                        let config: Result<Config> = toml::from_str::<toml::Value>(&msg.message)
+                            .with_context(|| {
+                                format!("Failed to parse the MQTT {:?} config file", msg.topic)
+                            })
+                            .and_then(|mut value| {
+                                expand_env(&mut value).with_context(|| {
+                                    format!("Failed to fill in the MQTT {:?} config file", msg.topic)
+                                })?;
+                                value.try_into().with_context(|| {
+                                    format!("Failed to parse the MQTT {:?} config file", msg.topic)
+                                })
+                            });
                         if let Err(e) = config {
                             thread_instance
                                 .send_message("config/status", &format!("{:?}", e), false)
                                 .await?;
                             continue;
                         }
-                        let config = config?;
+                        let mut config = config?;
+                        config.resolve_names();
 
                         let validate = config.validate().with_context(|| {
                             format!("Failed to validate the MQTT {:?} config file", msg.topic)
@@ -256,10 +402,13 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
     let mut watch_config = camera.config().await?;
     let camera_name = watch_config.borrow().name.clone();
     let mut config;
+    // Kept outside the loop so that the counts survive config changes
+    let tally = std::sync::Mutex::new(EventTally::new(watch_config.borrow().mqtt.utc_offset));
     let cancel = CancellationToken::new();
     let drop_cancel = cancel.clone().drop_guard();
     let r = loop {
         config = watch_config.borrow().clone().mqtt;
+        tally.lock().unwrap().set_offset(config.utc_offset);
         break tokio::select! {
             v = watch_config.wait_for(|new_config| config != new_config.mqtt) => {
                 v?;
@@ -272,6 +421,7 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                         .await
                         .with_context(|| format!("Failed to publish status for {}", camera_name))?;
                 let _drop_message = mqtt_instance.last_will("status", "disconnected").await?;
+                let _drop_available = mqtt_instance.last_will("status/available", "offline").await?;
                 mqtt_instance
                     .send_message("status/motion", "unknown", true)
                     .await
@@ -281,25 +431,44 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                     .await
                     .with_context(|| format!("Failed to publish push notification unknown for {}", camera_name))?;
                 let _drop_message2 = mqtt_instance.last_will("status/motion", "unknown").await?;
+                if config.enable_motion {
+                    mqtt_instance
+                        .send_message("status/activity", "unknown", true)
+                        .await
+                        .with_context(|| format!("Failed to publish activity unknown for {}", camera_name))?;
+                }
+                let _drop_activity = mqtt_instance.last_will("status/activity", "unknown").await?;
 
                 if let Some(discovery_config) = config.discovery.as_ref() {
                     enable_discovery(discovery_config, &mqtt_instance, &camera).await?;
                 }
 
                 let camera_msg = camera.clone();
+                let handlers = std::sync::Arc::new(mqtt_handlers());
+                let ptz = std::sync::Arc::new(PtzQueue::new());
+                let siren = std::sync::Arc::new(SirenLatch::new());
+                let playsound = std::sync::Arc::new(Mutex::new(()));
                 let mut mqtt_msg = mqtt_instance.resubscribe().await?;
                 let cancel_msg = cancel.clone();
                 let mut set_msg = JoinSet::new();
 
-                let mut camera_watch = camera.camera();
+                let camera_connection = camera.clone();
                 let mqtt_watch = mqtt_instance.resubscribe().await?;
 
+                let mut camera_error = camera.error().await?;
+                let mqtt_error = mqtt_instance.resubscribe().await?;
+                let mut camera_recording = camera.recording().await?;
+                let mqtt_recording = mqtt_instance.resubscribe().await?;
+
                 let camera_floodlight = camera.clone();
                 let mqtt_floodlight = mqtt_instance.resubscribe().await?;
 
                 let camera_motion = camera.clone();
                 let mqtt_motion = mqtt_instance.resubscribe().await?;
 
+                let camera_ai = camera.clone();
+                let mqtt_ai = mqtt_instance.resubscribe().await?;
+
                 let camera_pn = camera.clone();
                 let mqtt_pn = mqtt_instance.resubscribe().await?;
 
@@ -312,6 +481,13 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                 let camera_floodlight_tasks = camera.clone();
                 let mqtt_floodlight_tasks = mqtt_instance.resubscribe().await?;
 
+                let mqtt_stats = mqtt_instance.resubscribe().await?;
+                let mqtt_rates = mqtt_instance.resubscribe().await?;
+
+                let camera_streams = camera.clone();
+                let mut camera_streams_watch = camera.camera();
+                let mqtt_streams = mqtt_instance.resubscribe().await?;
+
                 tokio::select! {
                     _ = cancel.cancelled() => AnyResult::Ok(()),
                     // Handles incomming requests
@@ -323,6 +499,10 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                 while let Ok(msg) = mqtt_msg.recv().await {
                                     let mqtt_msg = mqtt_msg.resubscribe().await?;
                                     let camera_msg = camera_msg.clone();
+                                    let handlers_msg = handlers.clone();
+                                    let ptz_msg = ptz.clone();
+                                    let siren_msg = siren.clone();
+                                    let playsound_msg = playsound.clone();
                                     let tx = tx.clone();
                                     let cancel_msg = cancel_msg.clone();
                                     set_msg.spawn(async move {
@@ -330,7 +510,7 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                             _ = cancel_msg.cancelled() => AnyResult::Ok(()),
                                             v = async {
                                                 // log::debug!("Got message: {msg:?}");
-                                                let res = handle_mqtt_message(msg, &mqtt_msg, &camera_msg).await;
+                                                let res = handle_mqtt_message(msg, &mqtt_msg, &camera_msg, &handlers_msg, &ptz_msg, &siren_msg, &playsound_msg).await;
                                                 if res.is_err() {
                                                     tx.send(res).await?;
                                                 }
@@ -354,25 +534,79 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                     } => v,
                     // Handle camera disconnect/connect
                     v = async {
+                        let mut connection = camera_connection
+                            .connection_state(Duration::from_millis(config.disconnect_grace))
+                            .await?;
                         loop {
-                            camera_watch.wait_for(|cam| cam.upgrade().is_some()).await.with_context(|| {
-                                format!("{}: Online Watch Dropped", camera_name)
-                            })?;
-                            log::trace!("Publish online");
-                            mqtt_watch.send_message("status", "connected", true).await.with_context(|| {
-                                format!("{}: Failed to publish connected", camera_name)
+                            let state = *connection.borrow_and_update();
+                            log::trace!("Publish {state:?}");
+                            mqtt_watch.send_message("status", state.as_str(), true).await.with_context(|| {
+                                format!("{}: Failed to publish connection state", camera_name)
                             })?;
-                            camera_watch.wait_for(|cam| cam.upgrade().is_none()).await.with_context(|| {
-                                format!("{}: Disconnect Watch Dropped", camera_name)
+                            let available = match state {
+                                ConnectionState::Connected => "online",
+                                ConnectionState::Connecting | ConnectionState::Disconnected => "offline",
+                            };
+                            mqtt_watch.send_message("status/available", available, true).await.with_context(|| {
+                                format!("{}: Failed to publish available", camera_name)
                             })?;
-                            mqtt_watch.send_message("status", "disconnected", true).await.with_context(|| {
-                                format!("{}: Failed to publish disconnected", camera_name)
+                            let transport = camera_connection.camera().borrow().upgrade().map(|cam| cam.transport());
+                            if let (ConnectionState::Connected, Some(transport)) = (state, transport) {
+                                mqtt_watch.send_message("status/transport", &serde_json::to_string(&transport)?, true).await.with_context(|| {
+                                    format!("{}: Failed to publish transport", camera_name)
+                                })?;
+                            }
+                            connection.changed().await.with_context(|| {
+                                format!("{}: Connection Watch Dropped", camera_name)
                             })?;
                         }
                     } => {
                         log::debug!("CamConnection returned: {v:?}");
                         v
                     },
+                    // Publish the streams that the camera offers on each connect
+                    v = async {
+                        loop {
+                            camera_streams_watch.wait_for(|cam| cam.upgrade().is_some()).await.with_context(|| {
+                                format!("{}: Online Watch Dropped", camera_name)
+                            })?;
+                            match stream_listing(&camera_streams).await {
+                                Ok(json) => {
+                                    mqtt_streams.send_message("status/streams", &json, true).await.with_context(|| {
+                                        format!("{}: Failed to publish streams", camera_name)
+                                    })?;
+                                }
+                                Err(e) => log::debug!("{}: Could not list the streams: {e:?}", camera_name),
+                            }
+                            camera_streams_watch.wait_for(|cam| cam.upgrade().is_none()).await.with_context(|| {
+                                format!("{}: Disconnect Watch Dropped", camera_name)
+                            })?;
+                        }
+                    } => v,
+                    // Handle connection errors such as an empty NVR channel
+                    v = async {
+                        loop {
+                            let error = camera_error.borrow_and_update().clone();
+                            mqtt_error.send_message("status/error", error.as_deref().unwrap_or("none"), true).await.with_context(|| {
+                                format!("{}: Failed to publish error", camera_name)
+                            })?;
+                            camera_error.changed().await.with_context(|| {
+                                format!("{}: Error Watch Dropped", camera_name)
+                            })?;
+                        }
+                    } => v,
+                    // Handle the recording state
+                    v = async {
+                        loop {
+                            let recording = *camera_recording.borrow_and_update();
+                            mqtt_recording.send_message("status/record", if recording { "on" } else { "off" }, true).await.with_context(|| {
+                                format!("{}: Failed to publish recording", camera_name)
+                            })?;
+                            camera_recording.changed().await.with_context(|| {
+                                format!("{}: Recording Watch Dropped", camera_name)
+                            })?;
+                        }
+                    } => v,
                     // Handle the floodlight
                     v = async {
                         let (tx, mut rx) = mpsc(100);
@@ -427,19 +661,78 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                         let mut md = camera_motion.motion().await?;
                         loop {
                             let v = async {
-                                md.wait_for(|state| matches!(state, MdState::Start(_))).await.with_context(|| {
+                                md.wait_for(|state| matches!(state, MdState::Start(..))).await.with_context(|| {
                                     format!("{}: MdStart Watch Dropped", camera_name)
                                 })?;
                                 mqtt_motion.send_message("status/motion", "on", true).await.with_context(|| {
                                     format!("{}: Failed to publish motion start", camera_name)
                                 })?;
-                                md.wait_for(|state| matches!(state, MdState::Stop(_))).await.with_context(|| {
-                                    format!("{}: MdStop Watch Dropped", camera_name)
-                                })?;
-                                mqtt_motion.send_message("status/motion", "off", true).await.with_context(|| {
-                                    format!("{}: Failed to publish motion stop", camera_name)
+                                if config.event_format == EventFormat::Frigate {
+                                    mqtt_motion.send_message("event", &frigate_event(&camera_name, "motion", EventType::New)?, false).await.with_context(|| {
+                                        format!("{}: Failed to publish motion start event", camera_name)
+                                    })?;
+                                }
+                                // The camera reports its AI detections in the same alarm as
+                                // motion so this is on while either is active
+                                mqtt_motion.send_message("status/activity", "on", true).await.with_context(|| {
+                                    format!("{}: Failed to publish activity start", camera_name)
                                 })?;
-                                AnyResult::Ok(())
+                                tally.lock().unwrap().add_motion();
+                                publish_tally(&tally, &mqtt_motion).await?;
+                                // Taken alongside the wait for the stop so that a slow
+                                // snapshot holds up neither it nor the start
+                                let snapshot = async {
+                                    if !config.motion_snapshot {
+                                        return;
+                                    }
+                                    match timeout(MOTION_SNAPSHOT_TIMEOUT, limited_snapshot(&camera_motion)).await {
+                                        Ok(Ok(jpeg)) => {
+                                            if let Err(e) = mqtt_motion.send_message("status/motion/snapshot", &BASE64.encode(jpeg), true).await {
+                                                log::warn!("{}: Failed to publish the motion snapshot: {e:?}", camera_name);
+                                            }
+                                        }
+                                        Ok(Err(e)) => log::info!("{}: No snapshot of the motion: {e:?}", camera_name),
+                                        Err(_) => log::info!("{}: The snapshot of the motion took too long", camera_name),
+                                    }
+                                };
+                                let stop = async {
+                                    loop {
+                                        md.wait_for(|state| matches!(state, MdState::Stop(_))).await.with_context(|| {
+                                            format!("{}: MdStop Watch Dropped", camera_name)
+                                        })?;
+                                        if config.motion_cooldown == 0 {
+                                            break;
+                                        }
+                                        // Hold the stop until the motion has stayed stopped
+                                        // for the cooldown so that a flapping camera stays on
+                                        match timeout(
+                                            Duration::from_millis(config.motion_cooldown),
+                                            md.wait_for(|state| matches!(state, MdState::Start(..))),
+                                        ).await {
+                                            Ok(v) => {
+                                                v.with_context(|| {
+                                                    format!("{}: MdStart Watch Dropped", camera_name)
+                                                })?;
+                                                log::trace!("{}: Motion started again within the cooldown", camera_name);
+                                            }
+                                            Err(_) => break,
+                                        }
+                                    }
+                                    mqtt_motion.send_message("status/motion", "off", true).await.with_context(|| {
+                                        format!("{}: Failed to publish motion stop", camera_name)
+                                    })?;
+                                    if config.event_format == EventFormat::Frigate {
+                                        mqtt_motion.send_message("event", &frigate_event(&camera_name, "motion", EventType::End)?, false).await.with_context(|| {
+                                            format!("{}: Failed to publish motion stop event", camera_name)
+                                        })?;
+                                    }
+                                    mqtt_motion.send_message("status/activity", "off", true).await.with_context(|| {
+                                        format!("{}: Failed to publish activity stop", camera_name)
+                                    })?;
+                                    AnyResult::Ok(())
+                                };
+                                let (_, stop) = tokio::join!(snapshot, stop);
+                                stop
                             }.await;
                             log::debug!("Motion returned: {v:?}");
                             match v.map_err(|e| e.downcast::<neolink_core::Error>()) {
@@ -450,6 +743,37 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                             }?;
                         }
                     }, if config.enable_motion => v,
+                    // Publish each of the AI types that the camera detects
+                    v = async {
+                        let mut md = camera_ai.motion().await?;
+                        let mut active: HashSet<String> = Default::default();
+                        loop {
+                            let detected = match &*md.borrow_and_update() {
+                                MdState::Start(_, ai_types) => ai_types.iter().map(|ai_type| ai_topic(ai_type)).collect::<HashSet<_>>(),
+                                _ => Default::default(),
+                            };
+                            // Also under the motion topic so automations on motion can
+                            // pick the type without a second topic tree
+                            for ai_type in detected.difference(&active) {
+                                for topic in [format!("status/ai/{ai_type}"), format!("status/motion/{ai_type}")] {
+                                    mqtt_ai.send_message(&topic, "on", true).await.with_context(|| {
+                                        format!("{}: Failed to publish ai {ai_type} start", camera_name)
+                                    })?;
+                                }
+                            }
+                            for ai_type in active.difference(&detected) {
+                                for topic in [format!("status/ai/{ai_type}"), format!("status/motion/{ai_type}")] {
+                                    mqtt_ai.send_message(&topic, "off", true).await.with_context(|| {
+                                        format!("{}: Failed to publish ai {ai_type} stop", camera_name)
+                                    })?;
+                                }
+                            }
+                            active = detected;
+                            md.changed().await.with_context(|| {
+                                format!("{}: AI Watch Dropped", camera_name)
+                            })?;
+                        }
+                    }, if config.enable_motion && config.enable_ai => v,
                     // Handle the SNAP (image preview)
                     v = async {
                         let mut wait = IntervalStream::new({
@@ -458,13 +782,17 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                             i
                         });
                         let v = async {
+                            let mut filter = ChangeFilter::new(config.publish.preview);
                             while wait.next().await.is_some() {
-                                let image = camera_snap.run_passive_task(|cam| {
-                                    Box::pin(async move {
-                                        let image = cam.get_snapshot().await?;
-                                        AnyResult::Ok(image)
-                                    })
-                                }).await;
+                                let image = match camera_snap.cached_snapshot().await? {
+                                    Some(cached) => Ok(cached.to_vec()),
+                                    None => camera_snap.run_passive_task(|cam| {
+                                        Box::pin(async move {
+                                            let image = cam.get_snapshot().await?;
+                                            AnyResult::Ok(image)
+                                        })
+                                    }).await,
+                                };
                                 let image = match image {
                                     Err(e) => match e.downcast::<neolink_core::Error>() {
                                         Ok(neolink_core::Error::CameraServiceUnavailable(_)) => {
@@ -476,12 +804,15 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                     }
                                     n => n,
                                 }?;
-                                mqtt_snap
-                                        .send_message("status/preview", BASE64.encode(image).as_str(), true)
+                                let image = BASE64.encode(image);
+                                if filter.check(&image) {
+                                    mqtt_snap
+                                        .send_message("status/preview", image.as_str(), true)
                                         .await
                                         .with_context(|| {
                                             format!("{}: Failed to publish preview", camera_name)
                                         })?;
+                                }
                             }
                             AnyResult::Ok(())
                         }.await;
@@ -503,6 +834,9 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                         });
 
                         let v = async {
+                            let mut battery_filter = ChangeFilter::new(config.publish.battery);
+                            let mut power_filter = ChangeFilter::new(config.publish.power);
+                            let mut charging_filter = ChangeFilter::new(config.publish.charging);
                             while wait.next().await.is_some() {
                                 let xml = camera_battery.run_passive_task(|cam| {
                                     Box::pin(async move {
@@ -521,12 +855,37 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                     }
                                     n => n,
                                 }?;
-                                mqtt_battery
-                                        .send_message("status/battery_level", format!("{}", xml.battery_percent).as_str(), true)
+                                let level = format!("{}", xml.battery_percent);
+                                if battery_filter.check(&level) {
+                                    mqtt_battery
+                                        .send_message("status/battery_level", level.as_str(), true)
                                         .await
                                         .with_context(|| {
                                             format!("{}: Failed to publish battery", camera_name)
                                         })?;
+                                }
+                                // Complete counts as off, it is only on while charge goes in
+                                let charging = match xml.charge_status.as_str() {
+                                    "charging" => "on",
+                                    _ => "off",
+                                };
+                                if charging_filter.check(charging) {
+                                    mqtt_battery
+                                        .send_message("status/battery/charging", charging, true)
+                                        .await
+                                        .with_context(|| {
+                                            format!("{}: Failed to publish charging", camera_name)
+                                        })?;
+                                }
+                                let power = serde_json::to_string(&PowerStatus::from(&xml))?;
+                                if power_filter.check(&power) {
+                                    mqtt_battery
+                                        .send_message("status/power", &power, true)
+                                        .await
+                                        .with_context(|| {
+                                            format!("{}: Failed to publish power", camera_name)
+                                        })?;
+                                }
                             }
                             AnyResult::Ok(())
                         }.await;
@@ -539,6 +898,17 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                         }?;
                         AnyResult::Ok(())
                     }, if config.enable_battery => v,
+                    // Reset the daily event counts at midnight
+                    v = async {
+                        publish_tally(&tally, &mqtt_stats).await?;
+                        loop {
+                            let wait = tally.lock().unwrap().until_midnight();
+                            sleep(wait).await;
+                            if tally.lock().unwrap().roll() {
+                                publish_tally(&tally, &mqtt_stats).await?;
+                            }
+                        }
+                    } => v,
                     // Handle the push notification messages
                     v = async {
                         let mut pn = camera_pn.push_notifications().await?;
@@ -551,6 +921,8 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                 mqtt_pn.send_message("status/notification", &noti.as_ref().unwrap().message, true).await.with_context(|| {
                                     format!("{}: Failed to publish push notification", camera_name)
                                 })?;
+                                tally.lock().unwrap().add_notification();
+                                publish_tally(&tally, &mqtt_pn).await?;
                                 prev_noti = noti;
                                 AnyResult::Ok(())
                             }.await;
@@ -577,6 +949,8 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                             true => "on".to_string(),
                             false => "off".to_string(),
                         };
+                        let mut filter = ChangeFilter::new(config.publish.floodlight_tasks);
+                        filter.check(&flt_status_txt);
                         mqtt_floodlight_tasks.send_message("status/floodlight_tasks", &flt_status_txt, true).await.with_context(|| {
                             format!("{}: Failed to publish floodlight task notification", camera_name)
                         })?;
@@ -595,13 +969,36 @@ async fn listen_on_camera(camera: NeoInstance, mqtt_instance: MqttInstance) -> R
                                     true => "on".to_string(),
                                     false => "off".to_string(),
                                 };
-                                mqtt_floodlight_tasks.send_message("status/floodlight_tasks", &flt_status_txt, true).await.with_context(|| {
-                                    format!("{}: Failed to publish floodlight task notification", camera_name)
-                                })?;
+                                if filter.check(&flt_status_txt) {
+                                    mqtt_floodlight_tasks.send_message("status/floodlight_tasks", &flt_status_txt, true).await.with_context(|| {
+                                        format!("{}: Failed to publish floodlight task notification", camera_name)
+                                    })?;
+                                }
                             }
                         }
                         AnyResult::Ok(())
                     }, if config.enable_floodlight => v,
+                    // Publish the rates of the streams received from the camera
+                    v = async {
+                        let mut rates = StreamRates::new(&camera_name);
+                        let mut wait = IntervalStream::new({
+                            let mut i = interval(Duration::from_millis(config.stats_update));
+                            i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                            i
+                        });
+                        // The first tick is immediate and has nothing to compare to
+                        wait.next().await;
+                        while wait.next().await.is_some() {
+                            let report = rates.sample();
+                            mqtt_rates.send_message("status/stats/fps", &serde_json::to_string(&report.fps)?, false).await.with_context(|| {
+                                format!("{}: Failed to publish fps", camera_name)
+                            })?;
+                            mqtt_rates.send_message("status/stats/bitrate", &serde_json::to_string(&report.bitrate)?, false).await.with_context(|| {
+                                format!("{}: Failed to publish bitrate", camera_name)
+                            })?;
+                        }
+                        AnyResult::Ok(())
+                    }, if config.enable_stats => v,
                 }?;
                 AnyResult::Ok(())
             } => v,
@@ -618,7 +1015,19 @@ async fn handle_mqtt_message(
     msg: MqttReply,
     mqtt: &MqttInstance,
     camera: &NeoInstance,
+    handlers: &MqttHandlers,
+    ptz: &PtzQueue,
+    siren: &SirenLatch,
+    playsound: &Mutex<()>,
 ) -> Result<()> {
+    if let MqttReplyRef {
+        topic: "control/ir",
+        message: "on" | "off" | "auto",
+    } = msg.as_ref()
+    {
+        // Keep what was asked for until the ir_schedule next changes
+        camera.override_ir().await?;
+    }
     match msg.as_ref() {
         MqttReplyRef {
             topic: _,
@@ -681,6 +1090,53 @@ async fn handle_mqtt_message(
                 .await
                 .with_context(|| "Failed to publish camera status light off")?;
         }
+        MqttReplyRef {
+            topic: "control/floodlight",
+            message,
+        } => {
+            // A brightness in % turns the light on at that level, 0 turns it off
+            let reply = match message.trim().parse::<u32>() {
+                Ok(brightness) if brightness <= 100 => {
+                    let res = camera
+                        .run_task(|cam| {
+                            Box::pin(async move {
+                                if brightness == 0 {
+                                    cam.set_floodlight_manual(false, 180).await?;
+                                    Ok(0)
+                                } else {
+                                    let set = cam.set_floodlight_brightness(brightness).await?;
+                                    cam.set_floodlight_manual(true, 180).await?;
+                                    AnyResult::Ok(set)
+                                }
+                            })
+                        })
+                        .await;
+                    match res {
+                        Ok(set) => {
+                            mqtt.send_message(
+                                "status/floodlight/brightness",
+                                &set.to_string(),
+                                true,
+                            )
+                            .await
+                            .with_context(|| "Failed to publish floodlight brightness")?;
+                            "OK".to_string()
+                        }
+                        Err(e) => {
+                            error!("Failed to set the floodlight brightness: {:?}", e);
+                            format!("FAIL: {e:?}")
+                        }
+                    }
+                }
+                _ => {
+                    error!("Unknown floodlight command {:?}", message);
+                    "FAIL: Expected on, off or a brightness of 0-100".to_string()
+                }
+            };
+            mqtt.send_message("control/floodlight", &reply, false)
+                .await
+                .with_context(|| "Failed to publish floodlight brightness")?;
+        }
         MqttReplyRef {
             topic: "control/led",
             message: "on",
@@ -800,51 +1256,230 @@ async fn handle_mqtt_message(
             topic: "control/reboot",
             ..
         } => {
-            let res = camera
-                .run_task(|cam| {
-                    Box::pin(async move {
-                        cam.reboot().await?;
-                        AnyResult::Ok(())
-                    })
+            let (name, cooldown) = {
+                let config = camera.config().await?;
+                let config = config.borrow();
+                (
+                    config.name.clone(),
+                    Duration::from_secs(config.reboot_cooldown),
+                )
+            };
+            // Watched from before the reboot so that the drop cannot be missed
+            let mut state = camera.connection_state(Duration::ZERO).await?;
+            let mut accepted = false;
+            // Held until the reboot is sent so that two at once cannot both pass the check
+            let mut last_reboots = LAST_REBOOTS.lock().await;
+            let since = last_reboots.get(&name).map(|last| last.elapsed());
+            let reply = match since {
+                Some(since) if since < cooldown => {
+                    warn!(
+                        "{name}: Ignoring reboot, the last was {}s ago and the cooldown is {}s",
+                        since.as_secs(),
+                        cooldown.as_secs()
+                    );
+                    "FAIL: Cooldown".to_string()
+                }
+                _ => {
+                    let res = camera
+                        .run_task(|cam| {
+                            Box::pin(async move {
+                                cam.reboot().await?;
+                                AnyResult::Ok(())
+                            })
+                        })
+                        .await;
+                    if res.is_err() {
+                        error!("Failed to reboot the camera: {:?}", res.err());
+                        "FAIL".to_string()
+                    } else {
+                        accepted = true;
+                        last_reboots.insert(name.clone(), Instant::now());
+                        let timestamp = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs();
+                        mqtt.send_message("status/reboot/last", &timestamp.to_string(), true)
+                            .await
+                            .with_context(|| "Failed to publish the reboot time")?;
+                        "OK".to_string()
+                    }
+                }
+            };
+            drop(last_reboots);
+            mqtt.send_message("control/reboot", &reply, false)
+                .await
+                .with_context(|| "Failed to publish reboot on the camera")?;
+
+            if accepted {
+                mqtt.send_message("status/reboot", "rebooting", true)
+                    .await
+                    .with_context(|| "Failed to publish the reboot status")?;
+                // Keep the camera wanted so that it is connected again after the reboot
+                let _permit = camera.permit().await?;
+                let back = timeout(REBOOT_TIMEOUT, async {
+                    state
+                        .wait_for(|state| *state != ConnectionState::Connected)
+                        .await?;
+                    state
+                        .wait_for(|state| *state == ConnectionState::Connected)
+                        .await?;
+                    AnyResult::Ok(())
                 })
                 .await;
-            let reply = if res.is_err() {
-                error!("Failed to reboot the camera: {:?}", res.err());
+                let status = match back {
+                    Ok(Ok(())) => {
+                        info!("{name}: Camera is back after the reboot");
+                        "rebooted"
+                    }
+                    Ok(Err(e)) => {
+                        warn!("{name}: Lost track of the camera after the reboot: {e:?}");
+                        "failed"
+                    }
+                    Err(_) => {
+                        warn!(
+                            "{name}: Camera did not come back within {}s of the reboot",
+                            REBOOT_TIMEOUT.as_secs()
+                        );
+                        "failed"
+                    }
+                };
+                mqtt.send_message("status/reboot", status, true)
+                    .await
+                    .with_context(|| "Failed to publish the reboot status")?;
+            }
+        }
+        MqttReplyRef {
+            topic: "control/playsound",
+            message,
+        } => {
+            let path = std::path::PathBuf::from(message.trim());
+            let reply = if !path.is_file() {
+                error!("Cannot play sound, {:?} is not a file", path);
                 "FAIL"
             } else {
-                "OK"
-            }
-            .to_string();
-            mqtt.send_message("control/ir", &reply, false)
+                // The camera plays one sound at a time, later requests wait their turn
+                let _playing = match playsound.try_lock() {
+                    Ok(playing) => playing,
+                    Err(_) => {
+                        info!(
+                            "Waiting for the last sound to finish before playing {:?}",
+                            path
+                        );
+                        playsound.lock().await
+                    }
+                };
+                mqtt.send_message("status/playsound", "playing", false)
+                    .await
+                    .with_context(|| "Failed to publish playsound status")?;
+                let (status, reply) = match crate::talk::play_file(camera, &path, 1.0).await {
+                    Ok(()) => ("done", "OK"),
+                    Err(e) => {
+                        error!("Failed to play {:?} on the camera: {:?}", path, e);
+                        ("error", "FAIL")
+                    }
+                };
+                mqtt.send_message("status/playsound", status, false)
+                    .await
+                    .with_context(|| "Failed to publish playsound status")?;
+                reply
+            };
+            mqtt.send_message("control/playsound", reply, false)
                 .await
-                .with_context(|| "Failed to publish reboot on the camera")?;
+                .with_context(|| "Failed to publish playsound reply")?;
         }
         MqttReplyRef {
-            topic: "control/zoom",
+            topic: topic @ ("control/zoom" | "control/focus"),
             message,
         } => {
-            let reply = if let Ok(amount) = message.parse::<f32>() {
-                if let Err(e) = camera
-                    .run_task(|cam| {
-                        Box::pin(async move {
-                            cam.zoom_to((amount * 1000.0) as u32).await?;
-                            AnyResult::Ok(())
-                        })
+            let is_zoom = topic == "control/zoom";
+            let what = if is_zoom { "zoom" } else { "focus" };
+            let timeout = camera.config().await?.borrow().ptz_timeout;
+            let lowercase_message = message.trim().to_lowercase();
+            let info = camera
+                .run_task(|cam| {
+                    Box::pin(async move {
+                        let info = cam.get_ptz_info().await?;
+                        AnyResult::Ok(info)
                     })
-                    .await
-                {
-                    error!("Failed to send PTZ: {:?}", e);
+                })
+                .await;
+            let reply = match info {
+                Err(e) => {
+                    error!("Failed to get ptz info: {:?}", e);
                     format!("FAIL: {e:?}")
-                } else {
-                    "OK".to_string()
                 }
-            } else {
-                "FAIL: Could not convert message to number".to_string()
+                Ok(info)
+                    if (is_zoom && info.zoom_range.is_none())
+                        || (!is_zoom && info.focus_range.is_none()) =>
+                {
+                    error!("Camera does not support {}", what);
+                    format!("FAIL: Camera does not support {what}")
+                }
+                Ok(_) => match lowercase_message.as_str() {
+                    "stop" if is_zoom => {
+                        ptz.stop_zoom();
+                        "OK".to_string()
+                    }
+                    direction @ ("in" | "out") if is_zoom => {
+                        // Keeps going until a stop cancels it or the timeout ends it
+                        match ptz
+                            .zoom_for(camera, direction == "in", Duration::from_secs(timeout))
+                            .await
+                        {
+                            Err(e) => {
+                                error!("Failed to send zoom: {:?}", e);
+                                format!("FAIL: {e:?}")
+                            }
+                            Ok(true) => "OK".to_string(),
+                            Ok(false) => "FAIL: Cancelled".to_string(),
+                        }
+                    }
+                    value => {
+                        // Zoom is given as a multiple, 1.0 is 1x, focus as the raw position
+                        let pos = value.parse::<f32>().ok().filter(|v| *v >= 0.0).map(|v| {
+                            if is_zoom {
+                                (v * 1000.0) as u32
+                            } else {
+                                v as u32
+                            }
+                        });
+                        match pos {
+                            Some(pos) => {
+                                if is_zoom {
+                                    ptz.stop_zoom();
+                                }
+                                let res = camera
+                                    .run_task(|cam| {
+                                        Box::pin(async move {
+                                            if is_zoom {
+                                                cam.zoom_to(pos).await?;
+                                            } else {
+                                                cam.focus_to(pos).await?;
+                                            }
+                                            AnyResult::Ok(())
+                                        })
+                                    })
+                                    .await;
+                                match res {
+                                    Err(e) => {
+                                        error!("Failed to send {}: {:?}", what, e);
+                                        format!("FAIL: {e:?}")
+                                    }
+                                    Ok(()) => "OK".to_string(),
+                                }
+                            }
+                            None if is_zoom => {
+                                "FAIL: Expected in, out, stop or a zoom amount".to_string()
+                            }
+                            None => "FAIL: Could not convert message to number".to_string(),
+                        }
+                    }
+                },
             };
 
-            mqtt.send_message("control/zoom", &reply, false)
+            mqtt.send_message(topic, &reply, false)
                 .await
-                .with_context(|| "Failed to publish zoom on the camera")?;
+                .with_context(|| format!("Failed to publish {what} on the camera"))?;
         }
         MqttReplyRef {
             topic: "control/ptz",
@@ -856,12 +1491,32 @@ async fn handle_mqtt_message(
         } => {
             let lowercase_message = message.to_lowercase();
             let mut words = lowercase_message.split_whitespace();
-            let reply = if let Some(direction_txt) = words.next() {
+            let (default_speed, overlap) = {
+                let config = camera.config().await?;
+                let config = config.borrow();
+                (config.ptz_speed, config.ptz_overlap)
+            };
+            let reply = if let Some("stop") = words.clone().next() {
+                // Stop skips the queue
+                if let Err(e) = ptz.stop(camera, default_speed as f32).await {
+                    error!("Failed to send PTZ stop: {:?}", e);
+                    "FAIL"
+                } else {
+                    "OK"
+                }
+            } else if let Some(direction_txt) = words.next() {
                 // Target amount to move
-                let speed = 32f32;
                 let amount = words.next().unwrap_or("32.0");
+                // Speed to move at, defaults to the camera's `ptz_speed`
+                let speed = words
+                    .next()
+                    .map(|speed| speed.parse::<u32>())
+                    .unwrap_or(Ok(default_speed))
+                    .ok()
+                    .filter(|speed| (1..=100).contains(speed))
+                    .map(|speed| speed as f32);
 
-                if let Ok(amount) = amount.parse::<f32>() {
+                if let (Ok(amount), Some(speed)) = (amount.parse::<f32>(), speed) {
                     let seconds = amount / speed;
                     // range checking on seconds so that you can't sleep for 3.4E+38 seconds
                     let seconds = match seconds {
@@ -884,37 +1539,28 @@ async fn handle_mqtt_message(
                     };
 
                     if let (Some(seconds), Some(bc_direction)) = (seconds, bc_direction) {
-                        // On drop send the stop command again just to make sure it stops
-                        let _drop_command = camera.clone().drop_command(
-                            move |cam| {
-                                Box::pin(async move {
-                                    cam.send_ptz(BcDirection::Stop, speed).await?;
-                                    AnyResult::Ok(())
-                                })
-                            },
-                            Duration::from_millis(100),
-                        );
-                        if let Err(e) = camera
-                            .run_task(|cam| {
-                                Box::pin(async move {
-                                    cam.send_ptz(bc_direction, speed).await?;
-                                    sleep(Duration::from_secs_f32(seconds)).await;
-                                    cam.send_ptz(BcDirection::Stop, speed).await?;
-                                    AnyResult::Ok(())
-                                })
-                            })
+                        match ptz
+                            .move_for(
+                                camera,
+                                bc_direction,
+                                speed,
+                                Duration::from_secs_f32(seconds),
+                                overlap,
+                            )
                             .await
                         {
-                            error!("Failed to send PTZ: {:?}", e);
-                            "FAIL"
-                        } else {
-                            "OK"
+                            Err(e) => {
+                                error!("Failed to send PTZ: {:?}", e);
+                                "FAIL"
+                            }
+                            Ok(true) => "OK",
+                            Ok(false) => "FAIL: Cancelled",
                         }
                     } else {
                         "FAIL"
                     }
                 } else {
-                    error!("No PTZ speed as a valid number");
+                    error!("No PTZ amount or speed as a valid number");
                     "FAIL"
                 }
             } else {
@@ -928,117 +1574,194 @@ async fn handle_mqtt_message(
                 .with_context(|| "Failed to publish ptz on the camera")?;
         }
         MqttReplyRef {
-            topic: "control/ptz/preset",
+            topic: "control/ptz/move",
             message,
         } => {
-            let reply = if let Ok(id) = message.parse::<u8>() {
-                let res = camera
-                    .run_task(|cam| {
-                        Box::pin(async move {
-                            cam.moveto_ptz_preset(id).await?;
-                            AnyResult::Ok(())
-                        })
-                    })
-                    .await;
-                if res.is_err() {
-                    error!("Failed to move to ptz preset: {:?}", res.err());
-                    "FAIL"
-                } else {
-                    "OK"
+            let lowercase_message = message.to_lowercase();
+            let mut words = lowercase_message.split_whitespace();
+            let (default_speed, overlap, timeout) = {
+                let config = camera.config().await?;
+                let config = config.borrow();
+                (config.ptz_speed, config.ptz_overlap, config.ptz_timeout)
+            };
+            let direction = match words.next() {
+                Some("stop") => None,
+                Some("up") => Some(BcDirection::Up),
+                Some("down") => Some(BcDirection::Down),
+                Some("left") => Some(BcDirection::Left),
+                Some("right") => Some(BcDirection::Right),
+                n => {
+                    warn!(
+                        "Unrecognized PTZ move {:?}, expected up/down/left/right/stop",
+                        n
+                    );
+                    mqtt.send_message("control/ptz/move", "FAIL: Unknown direction", false)
+                        .await
+                        .with_context(|| "Failed to publish ptz move")?;
+                    return Ok(());
                 }
-            } else {
-                error!("PTZ preset was not a valid number");
-                "FAIL"
-            }
-            .to_string();
-            mqtt.send_message("control/ir", &reply, false)
-                .await
-                .with_context(|| "Failed to publish ptz move")?;
-        }
-        MqttReplyRef {
-            topic: "control/ptz/assign",
-            message,
-        } => {
-            let mut words = message.split_whitespace();
-            let id = words.next();
-            let name = words.next();
-
-            let reply = if let (Some(Ok(id)), Some(name)) = (id.map(|id| id.parse::<u8>()), name) {
-                let name = name.to_owned();
-                let res = camera
-                    .run_task(|cam| {
-                        let name = name.clone();
-                        Box::pin(async move {
-                            cam.set_ptz_preset(id, name).await?;
-                            AnyResult::Ok(())
-                        })
-                    })
-                    .await;
-                if res.is_err() {
-                    error!("Failed to assign ptz preset: {:?}", res.err());
-                    "FAIL"
-                } else {
-                    "OK"
+            };
+            let speed = words
+                .next()
+                .map(|speed| speed.parse::<u32>())
+                .unwrap_or(Ok(default_speed))
+                .ok()
+                .filter(|speed| (1..=100).contains(speed));
+
+            let reply = match (direction, speed) {
+                (None, _) => match ptz.stop(camera, default_speed as f32).await {
+                    Ok(()) => "OK",
+                    Err(e) => {
+                        error!("Failed to send PTZ stop: {:?}", e);
+                        "FAIL"
+                    }
+                },
+                (Some(_), None) => {
+                    warn!("PTZ speed should be a number from 1 to 100");
+                    "FAIL: Invalid speed"
                 }
-            } else if let (Some(Err(_)), _) = (id.map(|id| id.parse::<u8>()), name) {
-                error!("PTZ preset was not a valid number");
-                "FAIL"
-            } else if let (_, None) = (id.map(|id| id.parse::<u8>()), name) {
-                error!("PTZ preset was not given a name");
-                "FAIL"
-            } else {
-                "FAIL"
-            }
-            .to_string();
-            mqtt.send_message("control/ir", &reply, false)
+                (Some(direction), Some(speed)) => {
+                    // Keeps going until a stop cancels it or the timeout ends it
+                    match ptz
+                        .move_for(
+                            camera,
+                            direction,
+                            speed as f32,
+                            Duration::from_secs(timeout),
+                            overlap,
+                        )
+                        .await
+                    {
+                        Err(e) => {
+                            error!("Failed to send PTZ: {:?}", e);
+                            "FAIL"
+                        }
+                        Ok(true) => "OK",
+                        Ok(false) => "FAIL: Cancelled",
+                    }
+                }
+            };
+
+            mqtt.send_message("control/ptz/move", reply, false)
                 .await
                 .with_context(|| "Failed to publish ptz move")?;
         }
         MqttReplyRef {
-            topic: "control/pir",
-            message: "on",
+            topic: topic @ ("control/ptz/preset" | "control/preset"),
+            message,
         } => {
-            let res = camera
-                .run_task(|cam| {
-                    Box::pin(async move {
-                        cam.pir_set(true).await?;
-                        AnyResult::Ok(())
-                    })
-                })
-                .await;
-            let reply = if res.is_err() {
-                error!("Failed to turn on the pir: {:?}", res.err());
-                "FAIL"
-            } else {
-                "OK"
-            }
-            .to_string();
-            mqtt.send_message("control/pir", &reply, false)
+            let reply = match parse_preset_id(message) {
+                Some(id) => {
+                    let res = camera
+                        .run_task(|cam| {
+                            Box::pin(async move {
+                                // Only recall presets the camera has if it will list them
+                                if let Ok(presets) = cam.get_ptz_preset().await {
+                                    if !presets.preset_list.preset.iter().any(|p| p.id == id) {
+                                        return Ok(false);
+                                    }
+                                }
+                                cam.moveto_ptz_preset(id).await?;
+                                AnyResult::Ok(true)
+                            })
+                        })
+                        .await;
+                    match res {
+                        Ok(true) => "OK".to_string(),
+                        Ok(false) => {
+                            warn!("PTZ preset {id} is not set on the camera");
+                            "FAIL: Unknown preset".to_string()
+                        }
+                        Err(e) => {
+                            error!("Failed to move to ptz preset: {:?}", e);
+                            "FAIL".to_string()
+                        }
+                    }
+                }
+                None => format!("FAIL: Expected a preset id of 0-{PTZ_PRESET_MAX}"),
+            };
+            mqtt.send_message(topic, &reply, false)
                 .await
-                .with_context(|| "Failed to publish pir on")?;
+                .with_context(|| "Failed to publish ptz preset reply")?;
+        }
+        MqttReplyRef {
+            topic: topic @ ("control/ptz/assign" | "control/preset/save"),
+            message,
+        } => {
+            let mut words = message.split_whitespace();
+            let id = words.next().and_then(parse_preset_id);
+            // The name is optional when saving
+            let name = match (words.next(), id) {
+                (Some(name), _) => Some(name.to_owned()),
+                (None, Some(id)) if topic == "control/preset/save" => Some(format!("Preset{id}")),
+                _ => None,
+            };
+
+            let reply = match (id, name) {
+                (Some(id), Some(name)) => {
+                    let res = camera
+                        .run_task(|cam| {
+                            let name = name.clone();
+                            Box::pin(async move {
+                                cam.set_ptz_preset(id, name).await?;
+                                AnyResult::Ok(())
+                            })
+                        })
+                        .await;
+                    if let Err(e) = res {
+                        error!("Failed to assign ptz preset: {:?}", e);
+                        "FAIL".to_string()
+                    } else {
+                        "OK".to_string()
+                    }
+                }
+                (None, _) => format!("FAIL: Expected a preset id of 0-{PTZ_PRESET_MAX}"),
+                (_, None) => {
+                    error!("PTZ preset was not given a name");
+                    "FAIL: Expected a name".to_string()
+                }
+            };
+            mqtt.send_message(topic, &reply, false)
+                .await
+                .with_context(|| "Failed to publish ptz assign reply")?;
         }
         MqttReplyRef {
             topic: "control/pir",
-            message: "off",
+            message: message @ ("on" | "off"),
         } => {
+            let on = message == "on";
             let res = camera
                 .run_task(|cam| {
                     Box::pin(async move {
-                        cam.pir_set(false).await?;
+                        cam.pir_set(on).await?;
                         AnyResult::Ok(())
                     })
                 })
                 .await;
-            let reply = if res.is_err() {
-                error!("Failed to turn off the pir: {:?}", res.err());
-                "FAIL"
-            } else {
-                "OK"
-            }
-            .to_string();
+            let reply = match res.map_err(|e| e.downcast::<neolink_core::Error>()) {
+                Ok(()) => {
+                    // The camera has acknowledged it
+                    mqtt.send_message("status/pir/state", message, true)
+                        .await
+                        .with_context(|| "Failed to publish pir state")?;
+                    "OK".to_string()
+                }
+                Err(Ok(neolink_core::Error::CameraServiceUnavailable(code))) => {
+                    error!("Camera has no pir: code {code}");
+                    "FAIL: Not supported".to_string()
+                }
+                Err(Ok(e)) => {
+                    error!("Failed to turn {message} the pir: {:?}", e);
+                    format!("FAIL: {e}")
+                }
+                Err(Err(e)) => {
+                    error!("Failed to turn {message} the pir: {:?}", e);
+                    format!("FAIL: {e}")
+                }
+            };
             mqtt.send_message("control/pir", &reply, false)
                 .await
-                .with_context(|| "Failed to publish pir off")?;
+                .with_context(|| "Failed to publish pir reply")?;
         }
         MqttReplyRef {
             topic: "control/wakeup",
@@ -1119,21 +1842,59 @@ async fn handle_mqtt_message(
             topic: "control/siren",
             message: "on",
         } => {
-            let res = camera
-                .run_task(|cam| {
-                    Box::pin(async move {
-                        cam.siren().await?;
-                        AnyResult::Ok(())
-                    })
-                })
-                .await;
-            let reply = if let Err(e) = res {
-                error!("Failed to trigger siren: {:?}", e);
-                format!("FAIL: {e:?}")
-            } else {
-                "OK".to_string()
+            let duration = camera.config().await?.borrow().mqtt.siren_duration;
+            match siren.on(camera).await {
+                Ok(cancel) => {
+                    mqtt.send_message("status/siren", "on", true)
+                        .await
+                        .with_context(|| "Failed to publish siren status")?;
+                    mqtt.send_message("control/siren", "OK", false)
+                        .await
+                        .with_context(|| "Failed to publish siren")?;
+                    if let Some(cancel) = cancel {
+                        // Stop it ourselves if no off comes in time
+                        match siren
+                            .stop_after(camera, cancel, Duration::from_secs(duration))
+                            .await
+                        {
+                            Ok(true) => {
+                                info!("Siren turned off after {duration}s");
+                            }
+                            Ok(false) => return Ok(()),
+                            Err(e) => {
+                                error!("Failed to stop the siren: {:?}", e);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    mqtt.send_message("status/siren", "off", true)
+                        .await
+                        .with_context(|| "Failed to publish siren status")?;
+                }
+                Err(e) => {
+                    error!("Failed to trigger siren: {:?}", e);
+                    mqtt.send_message("control/siren", &format!("FAIL: {e:?}"), false)
+                        .await
+                        .with_context(|| "Failed to publish siren")?;
+                }
+            }
+        }
+        MqttReplyRef {
+            topic: "control/siren",
+            message: "off",
+        } => {
+            let reply = match siren.off(camera).await {
+                Ok(()) => {
+                    mqtt.send_message("status/siren", "off", true)
+                        .await
+                        .with_context(|| "Failed to publish siren status")?;
+                    "OK".to_string()
+                }
+                Err(e) => {
+                    error!("Failed to stop siren: {:?}", e);
+                    format!("FAIL: {e:?}")
+                }
             };
-
             mqtt.send_message("control/siren", &reply, false)
                 .await
                 .with_context(|| "Failed to publish siren")?;
@@ -1231,6 +1992,34 @@ async fn handle_mqtt_message(
                 .await
                 .with_context(|| "Failed to publish pir query")?;
         }
+        MqttReplyRef {
+            topic: "control/ptz/info",
+            ..
+        } => {
+            let res = camera
+                .run_task(|cam| {
+                    Box::pin(async move {
+                        let info = cam.get_ptz_info().await?;
+                        AnyResult::Ok(info)
+                    })
+                })
+                .await;
+            let reply = match res.and_then(|info| Ok(serde_json::to_string(&info)?)) {
+                Err(e) => {
+                    error!("Failed to get ptz info: {:?}", e);
+                    "FAIL"
+                }
+                Ok(json) => {
+                    mqtt.send_message("status/ptz/info", &json, false)
+                        .await
+                        .with_context(|| "Failed to publish ptz info")?;
+                    "OK"
+                }
+            };
+            mqtt.send_message("control/ptz/info", reply, false)
+                .await
+                .with_context(|| "Failed to publish ptz info reply")?;
+        }
         MqttReplyRef {
             topic: "query/ptz/preset",
             ..
@@ -1282,14 +2071,19 @@ async fn handle_mqtt_message(
             topic: "query/preview",
             ..
         } => {
-            let res = camera
-                .run_task(|cam| {
-                    Box::pin(async move {
-                        let data = cam.get_snapshot().await?;
-                        AnyResult::Ok(data)
-                    })
-                })
-                .await;
+            let res = match camera.cached_snapshot().await {
+                Ok(Some(cached)) => Ok(cached.to_vec()),
+                _ => {
+                    camera
+                        .run_task(|cam| {
+                            Box::pin(async move {
+                                let data = cam.get_snapshot().await?;
+                                AnyResult::Ok(data)
+                            })
+                        })
+                        .await
+                }
+            };
             let reply = match res {
                 Err(e) => {
                     error!("Failed to get snapshot: {:?}", e);
@@ -1313,7 +2107,529 @@ async fn handle_mqtt_message(
                 .await
                 .with_context(|| "Failed to publish preview query")?;
         }
-        _ => {}
+        MqttReplyRef {
+            topic: "control/cleanup",
+            ..
+        } => {
+            let reply = match mqtt.clear_retained().await {
+                Err(e) => {
+                    error!("Failed to clear retained topics: {:?}", e);
+                    "FAIL"
+                }
+                Ok(()) => "OK",
+            }
+            .to_string();
+            mqtt.send_message("control/cleanup", &reply, false)
+                .await
+                .with_context(|| "Failed to publish cleanup reply")?;
+        }
+        MqttReplyRef {
+            topic: "query/schedule",
+            ..
+        } => {
+            let res = camera
+                .run_task(|cam| {
+                    Box::pin(async move {
+                        let xml = cam.get_record_schedule().await?;
+                        AnyResult::Ok(xml)
+                    })
+                })
+                .await;
+            let reply = match res {
+                Err(e) => {
+                    error!("Failed to get record schedule: {:?}", e);
+                    "FAIL"
+                }
+                Ok(xml) => match serde_json::to_string(&xml) {
+                    Ok(json) => {
+                        mqtt.send_message("status/schedule", &json, true)
+                            .await
+                            .with_context(|| "Failed to publish schedule info")?;
+                        "OK"
+                    }
+                    Err(_) => {
+                        error!("Failed to serialise record schedule");
+                        "FAIL"
+                    }
+                },
+            }
+            .to_string();
+            mqtt.send_message("query/schedule", &reply, false)
+                .await
+                .with_context(|| "Failed to publish schedule query")?;
+        }
+        _ => {
+            if let Some(res) = handlers.handle(&msg, mqtt, camera).await {
+                res?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The handlers for the topics that are matched by pattern
+fn mqtt_handlers() -> MqttHandlers {
+    let mut handlers = MqttHandlers::default();
+    handlers
+        .add("control/stream/+/ratemode", |args| {
+            Box::pin(handle_stream_ratemode(args))
+        })
+        .add("query/stream/+/ratemode", |args| {
+            Box::pin(handle_stream_ratemode(args))
+        })
+        .add("control/stream", |args| Box::pin(handle_stream_pause(args)))
+        .add("control/stream/#", |args| {
+            Box::pin(handle_stream_enable(args))
+        })
+        .add("control/flip", |args| Box::pin(handle_flip_mirror(args)))
+        .add("control/mirror", |args| Box::pin(handle_flip_mirror(args)))
+        .add("control/record", |args| Box::pin(handle_record(args)))
+        .add("control/snapshot", |args| Box::pin(handle_snapshot(args)))
+        .add("control/query", |args| Box::pin(handle_query(args)));
+    handlers
+}
+
+/// `(control|query)/stream/{name}/ratemode`
+async fn handle_stream_ratemode(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, message },
+        captures,
+        mqtt,
+        camera,
+    } = args;
+    let name = captures[0];
+    let mode = match message {
+        "cbr" | "vbr" => Some(message.to_string()),
+        _ => None,
+    };
+    let is_control = topic.starts_with("control/");
+    let reply = match (stream_kind(name), mode) {
+        (Some(stream), mode) if mode.is_some() || !is_control => {
+            let res = camera
+                .run_task(|cam| {
+                    let mode = mode.clone();
+                    Box::pin(async move {
+                        if let Some(mode) = mode {
+                            cam.set_encoder_type(stream, &mode).await?;
+                        }
+                        let mode = cam.get_encoder_type(stream).await?;
+                        AnyResult::Ok(mode)
+                    })
+                })
+                .await;
+            match res {
+                Err(e) => {
+                    error!("Failed to set the {name} stream bitrate mode: {:?}", e);
+                    "FAIL"
+                }
+                Ok(current) => {
+                    mqtt.send_message(&format!("status/stream/{name}/ratemode"), &current, true)
+                        .await
+                        .with_context(|| "Failed to publish stream bitrate mode")?;
+                    if mode.is_some_and(|mode| mode != current) {
+                        // The camera accepted the message but kept its
+                        // mode, most likely it only supports one
+                        "FAIL: Camera did not change the bitrate mode"
+                    } else {
+                        "OK"
+                    }
+                }
+            }
+        }
+        _ => "FAIL: Expected control/stream/[main|sub|extern]/ratemode with cbr or vbr",
+    }
+    .to_string();
+    mqtt.send_message(topic, &reply, false)
+        .await
+        .with_context(|| "Failed to publish stream bitrate mode reply")?;
+    Ok(())
+}
+
+/// `control/flip` and `control/mirror`
+async fn handle_flip_mirror(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, message },
+        mqtt,
+        camera,
+        ..
+    } = args;
+    let is_flip = topic == "control/flip";
+    let name = if is_flip { "flip" } else { "mirror" };
+    let value = match message {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+    let reply = match value {
+        Some(value) => {
+            let res = camera
+                .run_task(|cam| {
+                    Box::pin(async move {
+                        if is_flip {
+                            cam.set_flip(value).await?;
+                        } else {
+                            cam.set_mirror(value).await?;
+                        }
+                        let (flip, mirror) = cam.get_flip_mirror().await?;
+                        AnyResult::Ok(if is_flip { flip } else { mirror })
+                    })
+                })
+                .await;
+            match res {
+                Err(e) => {
+                    error!("Failed to set {name}: {:?}", e);
+                    "FAIL: Camera does not support it"
+                }
+                Ok(current) => {
+                    mqtt.send_message(
+                        &format!("status/{name}"),
+                        if current { "on" } else { "off" },
+                        true,
+                    )
+                    .await
+                    .with_context(|| format!("Failed to publish {name} status"))?;
+                    "OK"
+                }
+            }
+        }
+        None => "FAIL: Expected on or off",
+    }
+    .to_string();
+    mqtt.send_message(topic, &reply, false)
+        .await
+        .with_context(|| format!("Failed to publish {name} reply"))?;
+    Ok(())
+}
+
+/// `control/record`
+///
+/// `status/record` is published by the recording watch in `listen_on_camera`
+async fn handle_record(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, message },
+        mqtt,
+        camera,
+        ..
+    } = args;
+    let reply = match message {
+        "on" | "off" => match camera.set_recording(message == "on").await {
+            Err(e) => {
+                error!("Failed to set recording: {:?}", e);
+                "FAIL"
+            }
+            Ok(()) => "OK",
+        },
+        _ => "FAIL: Expected on or off",
+    };
+    mqtt.send_message(topic, reply, false)
+        .await
+        .with_context(|| "Failed to publish record reply")?;
+    Ok(())
+}
+
+/// `control/snapshot`
+async fn handle_snapshot(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, .. },
+        mqtt,
+        camera,
+        ..
+    } = args;
+    let reply = match limited_snapshot(camera).await {
+        Ok(jpeg) => {
+            mqtt.send_message("status/snapshot", &BASE64.encode(jpeg), true)
+                .await
+                .with_context(|| "Failed to publish snapshot")?;
+            "OK".to_string()
+        }
+        Err(e) => {
+            error!("Failed to take snapshot: {:?}", e);
+            format!("FAIL: {e}")
+        }
+    };
+    mqtt.send_message(topic, &reply, false)
+        .await
+        .with_context(|| "Failed to publish snapshot reply")?;
+    Ok(())
+}
+
+/// `control/query`
+///
+/// Reads the state back from the camera and publishes it again so that
+/// stale retained topics can be corrected
+async fn handle_query(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, .. },
+        mqtt,
+        camera,
+        ..
+    } = args;
+    let mut failed = vec![];
+
+    match camera
+        .run_task(|cam| Box::pin(async move { Ok(cam.get_ledstate().await?) }))
+        .await
+    {
+        Ok(led) => {
+            let ir = match led.state.as_str() {
+                "open" => "on",
+                "close" => "off",
+                _ => "auto",
+            };
+            let led = match led.light_state.as_str() {
+                "open" => "on",
+                _ => "off",
+            };
+            mqtt.send_message("status/ir", ir, true)
+                .await
+                .with_context(|| "Failed to publish ir state")?;
+            mqtt.send_message("status/led", led, true)
+                .await
+                .with_context(|| "Failed to publish led state")?;
+        }
+        Err(e) => {
+            error!("Failed to query the led state: {:?}", e);
+            failed.push("led");
+        }
+    }
+
+    match camera
+        .run_task(|cam| Box::pin(async move { Ok(cam.get_pirstate().await?) }))
+        .await
+        .map_err(|e| e.downcast::<neolink_core::Error>())
+    {
+        Ok(pir) => {
+            let pir = if pir.enable == 1 { "on" } else { "off" };
+            mqtt.send_message("status/pir/state", pir, true)
+                .await
+                .with_context(|| "Failed to publish pir state")?;
+        }
+        // No pir on this camera
+        Err(Ok(neolink_core::Error::CameraServiceUnavailable(_))) => {}
+        Err(e) => {
+            error!("Failed to query the pir state: {:?}", e);
+            failed.push("pir");
+        }
     }
+
+    match camera
+        .run_task(|cam| Box::pin(async move { Ok(cam.battery_info().await?) }))
+        .await
+        .map_err(|e| e.downcast::<neolink_core::Error>())
+    {
+        Ok(xml) => {
+            let charging = match xml.charge_status.as_str() {
+                "charging" => "on",
+                _ => "off",
+            };
+            mqtt.send_message(
+                "status/battery_level",
+                &xml.battery_percent.to_string(),
+                true,
+            )
+            .await
+            .with_context(|| "Failed to publish battery")?;
+            mqtt.send_message("status/battery/charging", charging, true)
+                .await
+                .with_context(|| "Failed to publish charging")?;
+            mqtt.send_message(
+                "status/power",
+                &serde_json::to_string(&PowerStatus::from(&xml))?,
+                true,
+            )
+            .await
+            .with_context(|| "Failed to publish power")?;
+        }
+        // Not a battery camera
+        Err(Ok(neolink_core::Error::CameraServiceUnavailable(_)))
+        | Err(Ok(neolink_core::Error::UnintelligibleReply { .. })) => {}
+        Err(e) => {
+            error!("Failed to query the battery: {:?}", e);
+            failed.push("battery");
+        }
+    }
+
+    let reply = if failed.is_empty() {
+        "OK".to_string()
+    } else {
+        format!("FAIL: {}", failed.join(", "))
+    };
+    mqtt.send_message(topic, &reply, false)
+        .await
+        .with_context(|| "Failed to publish query reply")?;
     Ok(())
 }
+
+/// Largest jpeg that will be published
+const SNAPSHOT_MAX_BYTES: usize = 2 * 1024 * 1024;
+
+/// A snapshot that is small enough to publish
+async fn limited_snapshot(camera: &NeoInstance) -> Result<Vec<u8>> {
+    let jpeg = stream_snapshot(camera).await?;
+    if jpeg.len() > SNAPSHOT_MAX_BYTES {
+        Err(anyhow!(
+            "Snapshot of {} bytes is over the {} byte limit, lower max_decode_resolution or snapshot_quality",
+            jpeg.len(),
+            SNAPSHOT_MAX_BYTES
+        ))
+    } else {
+        Ok(jpeg)
+    }
+}
+
+/// `control/stream`
+async fn handle_stream_pause(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, message },
+        mqtt,
+        camera,
+        ..
+    } = args;
+    let reply = match message {
+        "pause" | "resume" => match camera.set_paused(message == "pause").await {
+            Err(e) => {
+                error!("Failed to {message} the stream: {:?}", e);
+                "FAIL"
+            }
+            Ok(()) => {
+                mqtt.send_message("status/stream", message, true)
+                    .await
+                    .with_context(|| "Failed to publish stream status")?;
+                "OK"
+            }
+        },
+        _ => "FAIL: Expected pause or resume",
+    };
+    mqtt.send_message(topic, reply, false)
+        .await
+        .with_context(|| "Failed to publish stream reply")?;
+    Ok(())
+}
+
+/// `control/stream/{name}`
+async fn handle_stream_enable(args: HandlerArgs<'_>) -> Result<()> {
+    let HandlerArgs {
+        msg: MqttReplyRef { topic, message },
+        captures,
+        mqtt,
+        camera,
+    } = args;
+    let name = captures[0];
+    let stream = stream_kind(name);
+    let enabled = match message {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+    let reply = match (stream, enabled) {
+        (Some(stream), Some(enabled)) => match camera.set_stream_enabled(stream, enabled).await {
+            Err(e) => {
+                error!("Failed to set the {name} stream {message}: {:?}", e);
+                "FAIL"
+            }
+            Ok(()) => {
+                mqtt.send_message(&format!("status/stream/{name}"), message, true)
+                    .await
+                    .with_context(|| "Failed to publish stream status")?;
+                "OK"
+            }
+        },
+        _ => "FAIL: Expected control/stream/[main|sub|extern] with on or off",
+    }
+    .to_string();
+    mqtt.send_message(topic, &reply, false)
+        .await
+        .with_context(|| "Failed to publish stream reply")?;
+    Ok(())
+}
+
+fn stream_kind(name: &str) -> Option<StreamKind> {
+    match name {
+        "main" => Some(StreamKind::Main),
+        "sub" => Some(StreamKind::Sub),
+        "extern" => Some(StreamKind::Extern),
+        _ => None,
+    }
+}
+
+/// A stream offered by the camera as published to `status/streams`
+#[derive(Serialize)]
+struct StreamListing {
+    stream: &'static str,
+    width: u32,
+    height: u32,
+    fps: u32,
+    bitrate: u32,
+    /// Only known once the stream has been pulled
+    codec: Option<&'static str>,
+}
+
+/// JSON list of the streams that the camera offers
+async fn stream_listing(camera: &NeoInstance) -> AnyResult<String> {
+    let info = camera
+        .run_passive_task(|cam| Box::pin(async move { Ok(cam.get_stream_info().await?) }))
+        .await?;
+    let mut streams = vec![];
+    for encode in info
+        .stream_infos
+        .iter()
+        .flat_map(|info| info.encode_tables.iter())
+    {
+        let (kind, stream) = match encode.name.as_str() {
+            "mainStream" => (StreamKind::Main, "main"),
+            "subStream" => (StreamKind::Sub, "sub"),
+            "externStream" => (StreamKind::Extern, "extern"),
+            _ => continue,
+        };
+        let config = camera.stream(kind).await?.config.borrow().clone();
+        streams.push(StreamListing {
+            stream,
+            width: encode.resolution.width,
+            height: encode.resolution.height,
+            fps: config.fps,
+            bitrate: config.bitrate,
+            codec: match config.vid_format {
+                VidFormat::H264 => Some("h264"),
+                VidFormat::H265 => Some("h265"),
+                VidFormat::None => None,
+            },
+        });
+    }
+    Ok(serde_json::to_string(&streams)?)
+}
+
+/// Highest preset id that reolink cameras accept
+const PTZ_PRESET_MAX: u8 = 63;
+
+/// The preset id of a message, warning if it is not a valid id
+fn parse_preset_id(message: &str) -> Option<u8> {
+    match message.trim().parse::<u8>() {
+        Ok(id) if id <= PTZ_PRESET_MAX => Some(id),
+        _ => {
+            warn!("PTZ preset {message:?} is not an id from 0 to {PTZ_PRESET_MAX}");
+            None
+        }
+    }
+}
+
+/// The topic of an AI type, the common types are renamed to match the app
+/// and any others the camera reports are used as they are
+fn ai_topic(ai_type: &str) -> String {
+    match ai_type {
+        "people" | "person" => "person".to_string(),
+        "dog_cat" | "pet" => "pet".to_string(),
+        other => other
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect(),
+    }
+}
+
+/// Publish the events counted so far today
+async fn publish_tally(tally: &std::sync::Mutex<EventTally>, mqtt: &MqttInstance) -> AnyResult<()> {
+    let json = tally.lock().unwrap().to_json()?;
+    mqtt.send_message("status/stats/events/today", &json, true)
+        .await
+        .with_context(|| "Failed to publish the event counts")
+}
@@ -1,15 +1,15 @@
 use crate::{
-    config::{Config, MqttServerConfig},
+    config::{Config, MqttQueuePolicy, MqttServerConfig},
     AnyResult,
 };
 use anyhow::{anyhow, Context, Result};
 use futures::future::FutureExt;
-use log::*;
 use rumqttc::{
     AsyncClient, ConnectReturnCode, Event, Incoming, LastWill, MqttOptions, QoS, TlsConfiguration,
     Transport,
 };
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
 use tokio::task::JoinSet;
 use tokio::{
     sync::{
@@ -23,6 +23,24 @@ use tokio::{
 use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 use tokio_util::sync::CancellationToken;
 
+/// The retained topics of each named instance
+///
+/// This is those published by this process and those the broker holds from
+/// earlier runs, which it sends when we subscribe
+type RetainedTopics = Arc<Mutex<HashMap<String, HashSet<String>>>>;
+
+/// Topics that are published over and over and can be dropped when the
+/// queue is full, the next one replaces it. Everything else is always sent
+const DROPPABLE_TOPICS: &[&str] = &[
+    "status/preview",
+    "status/battery_level",
+    "status/battery/charging",
+    "status/power",
+    "status/stats/fps",
+    "status/stats/bitrate",
+    "status/stats/events/today",
+];
+
 pub(crate) struct Mqtt {
     cancel: CancellationToken,
     outgoing_tx: MpscSender<MqttRequest>,
@@ -35,6 +53,7 @@ impl Mqtt {
         let (outgoing_tx, mut outgoing_rx) = mpsc::<MqttRequest>(100);
         let cancel = CancellationToken::new();
         let mut set = JoinSet::<AnyResult<()>>::new();
+        let retained: RetainedTopics = Default::default();
 
         // Thread that handles the mqttc side
         // including restarting it if the config changes
@@ -57,6 +76,7 @@ impl Mqtt {
                             incomming_tx: thread_incoming_tx.clone(),
                             outgoing_rx: &mut outgoing_rx,
                             outgoing_tx: thread_outgoing_tx.clone(),
+                            retained: retained.clone(),
                             config: mqtt_config.as_ref().unwrap(),
                             cancel: CancellationToken::new(),
                         };
@@ -117,6 +137,7 @@ struct MqttBackend<'a> {
     incomming_tx: BroadcastSender<MqttReply>,
     outgoing_rx: &'a mut MpscReceiver<MqttRequest>,
     outgoing_tx: MpscSender<MqttRequest>,
+    retained: RetainedTopics,
     config: &'a MqttServerConfig,
     cancel: CancellationToken,
 }
@@ -132,37 +153,7 @@ impl<'a> MqttBackend<'a> {
         let max_size = 100 * (1024 * 1024);
         mqttoptions.set_max_packet_size(max_size, max_size);
 
-        // Use TLS if ca path is set
-        if let Some(ca_path) = &self.config.ca {
-            if let Ok(ca) = std::fs::read(ca_path) {
-                // Use client_auth if they have cert and key
-                let client_auth = if let Some((cert_path, key_path)) = &self.config.client_auth {
-                    if let (Ok(cert_buf), Ok(key_buf)) =
-                        (std::fs::read(cert_path), std::fs::read(key_path))
-                    {
-                        Some((cert_buf, key_buf))
-                    } else {
-                        error!("Failed to set client tls");
-                        None
-                    }
-                } else {
-                    None
-                };
-
-                let transport = Transport::Tls(TlsConfiguration::Simple {
-                    ca,
-                    alpn: None,
-                    client_auth,
-                });
-                mqttoptions.set_transport(transport);
-            } else {
-                error!("Failed to set CA");
-            }
-        };
-
-        if let Some((username, password)) = &self.config.credentials {
-            mqttoptions.set_credentials(username, password);
-        }
+        set_up_connection(&mut mqttoptions, self.config)?;
 
         mqttoptions.set_keep_alive(Duration::from_secs(5));
 
@@ -178,6 +169,8 @@ impl<'a> MqttBackend<'a> {
 
         let client = Arc::new(client);
         let send_client = client.clone();
+        // Messages that are waiting to be published and may be dropped if the broker is slow
+        let pending: Arc<Mutex<VecDeque<CancellationToken>>> = Default::default();
         send_client
             .publish(
                 "neolink/status".to_string(),
@@ -194,6 +187,31 @@ impl<'a> MqttBackend<'a> {
                 v = self.outgoing_rx.recv() => {
                     let msg = v.ok_or(anyhow!("All outgoing MQTT channels closed"))?;
 
+                    // Bound the number of messages waiting on the broker
+                    // critical messages such as the connection status are never dropped
+                    let drop_token = CancellationToken::new();
+                    if let Some(topic) = msg.droppable_topic() {
+                        let mut locked_pending = pending.lock().unwrap();
+                        locked_pending.retain(|token| !token.is_cancelled());
+                        if locked_pending.len() >= self.config.max_queue {
+                            match self.config.queue_policy {
+                                MqttQueuePolicy::DropNewest => {
+                                    log::warn!("MQTT queue is full dropping message to {}", topic);
+                                    drop_token.cancel();
+                                }
+                                MqttQueuePolicy::DropOldest => {
+                                    log::warn!("MQTT queue is full dropping oldest message");
+                                    if let Some(oldest) = locked_pending.pop_front() {
+                                        oldest.cancel();
+                                    }
+                                }
+                            }
+                        }
+                        if !drop_token.is_cancelled() {
+                            locked_pending.push_back(drop_token.clone());
+                        }
+                    }
+
                     // Put it on a thread so that we don't block polling
                     let outgoing_tx = self.outgoing_tx.clone();
                     let incomming_tx = self.incomming_tx.clone();
@@ -201,19 +219,28 @@ impl<'a> MqttBackend<'a> {
                     let cancel = self.cancel.clone();
                     let thread_cancel = loop_cancel.clone();
                     let server_config = self.config.clone();
+                    let retained = self.retained.clone();
                     tokio::task::spawn(async move {
+                        let _done = drop_token.clone().drop_guard();
                         tokio::select!{
                             _ = cancel.cancelled() => AnyResult::Ok(()),
                             _ = thread_cancel.cancelled() => AnyResult::Ok(()),
                             v = async {
                                 match msg {
                                     MqttRequest::Send(msg, tx) =>  {
-                                        let v = send_client.publish(
-                                            msg.topic.clone(),
-                                            QoS::AtLeastOnce,
-                                            false,
-                                            (*msg.message).clone(),
-                                        ).await;
+                                        let v = tokio::select! {
+                                            biased;
+                                            _ = drop_token.cancelled() => {
+                                                let _ = tx.send(Ok(()));
+                                                return AnyResult::Ok(());
+                                            },
+                                            v = send_client.publish(
+                                                msg.topic.clone(),
+                                                QoS::AtLeastOnce,
+                                                false,
+                                                (*msg.message).clone(),
+                                            ) => v,
+                                        };
                                         match &v {
                                             Ok(()) => {
                                                 let _ = tx.send(Ok(()));
@@ -226,12 +253,19 @@ impl<'a> MqttBackend<'a> {
                                         v?;
                                     }
                                     MqttRequest::SendRetained(msg, tx) =>  {
-                                        let v = send_client.publish(
-                                            msg.topic.clone(),
-                                            QoS::AtLeastOnce,
-                                            true,
-                                            (*msg.message).clone(),
-                                        ).await;
+                                        let v = tokio::select! {
+                                            biased;
+                                            _ = drop_token.cancelled() => {
+                                                let _ = tx.send(Ok(()));
+                                                return AnyResult::Ok(());
+                                            },
+                                            v = send_client.publish(
+                                                msg.topic.clone(),
+                                                QoS::AtLeastOnce,
+                                                true,
+                                                (*msg.message).clone(),
+                                            ) => v,
+                                        };
                                         match &v {
                                             Ok(()) => {
                                                 let _ = tx.send(Ok(()));
@@ -258,6 +292,7 @@ impl<'a> MqttBackend<'a> {
                                             name,
                                             incomming_rx: BroadcastStream::new(incomming_tx.subscribe()),
                                             outgoing_tx: outgoing_tx.clone(),
+                                            retained,
                                         };
                                         let _ = reply.send(Ok(instance));
                                     },
@@ -284,6 +319,7 @@ impl<'a> MqttBackend<'a> {
                     let incomming_tx = self.incomming_tx.clone();
                     let cancel = self.cancel.clone();
                     let thread_cancel = loop_cancel.clone();
+                    let retained = self.retained.clone();
                     tokio::task::spawn(async move {
                         tokio::select!{
                             _ = cancel.cancelled() => AnyResult::Ok(()),
@@ -312,6 +348,17 @@ impl<'a> MqttBackend<'a> {
                                             .topic
                                             .strip_prefix("neolink/")
                                         {
+                                            if published_message.retain {
+                                                // Left by an earlier run, note it so that it can be cleared
+                                                let name = sub_topic.split('/').next().unwrap_or_default();
+                                                let mut retained = retained.lock().unwrap();
+                                                let topics = retained.entry(name.to_string()).or_default();
+                                                if published_message.payload.is_empty() {
+                                                    topics.remove(&published_message.topic);
+                                                } else {
+                                                    topics.insert(published_message.topic.clone());
+                                                }
+                                            }
                                             let _ = incomming_tx
                                                 .send(MqttReply {
                                                     topic: sub_topic.to_string(),
@@ -347,6 +394,7 @@ impl<'a> Drop for MqttBackend<'a> {
 pub(crate) struct MqttInstance {
     outgoing_tx: MpscSender<MqttRequest>,
     incomming_rx: BroadcastStream<MqttReply>,
+    retained: RetainedTopics,
     name: String,
 }
 
@@ -388,11 +436,22 @@ impl MqttInstance {
         .cloned()
         .collect::<Vec<_>>();
         if retain {
+            let topic = topics.join("/");
+            {
+                let mut retained = self.retained.lock().unwrap();
+                let published = retained.entry(self.name.clone()).or_default();
+                // An empty retained message clears the topic on the broker
+                if message.is_empty() {
+                    published.remove(&topic);
+                } else {
+                    published.insert(topic.clone());
+                }
+            }
             let (tx, rx) = oneshot();
             self.outgoing_tx
                 .send(MqttRequest::SendRetained(
                     MqttReply {
-                        topic: topics.join("/"),
+                        topic,
                         message: Arc::new(message.to_string()),
                     },
                     tx,
@@ -426,6 +485,35 @@ impl MqttInstance {
         Ok(())
     }
 
+    /// Clear the retained topics of this instance by publishing an empty
+    /// retained message to each of them
+    ///
+    /// This is every retained topic under `neolink/<name>/` and any others,
+    /// such as discovery, that were published by this process
+    pub(crate) async fn clear_retained(&self) -> AnyResult<()> {
+        let topics = self
+            .retained
+            .lock()
+            .unwrap()
+            .remove(&self.name)
+            .unwrap_or_default();
+        for topic in topics.iter() {
+            log::debug!("Clearing retained topic {}", topic);
+            let (tx, rx) = oneshot();
+            self.outgoing_tx
+                .send(MqttRequest::SendRetained(
+                    MqttReply {
+                        topic: topic.clone(),
+                        message: Arc::new(String::new()),
+                    },
+                    tx,
+                ))
+                .await?;
+            rx.await??;
+        }
+        Ok(())
+    }
+
     pub(crate) async fn recv(&mut self) -> AnyResult<MqttReply> {
         Ok(loop {
             let mut msg = self
@@ -507,6 +595,63 @@ enum MqttRequest {
     },
 }
 
+impl MqttRequest {
+    /// The topic of the message if it can be dropped when the queue is full
+    ///
+    /// Only the repeating topics in `DROPPABLE_TOPICS` are dropped
+    fn droppable_topic(&self) -> Option<&str> {
+        match self {
+            MqttRequest::Send(msg, _) | MqttRequest::SendRetained(msg, _)
+                if DROPPABLE_TOPICS.iter().any(|droppable| {
+                    msg.topic
+                        .strip_suffix(droppable)
+                        .map(|rest| rest.ends_with('/'))
+                        .unwrap_or(false)
+                }) =>
+            {
+                Some(&msg.topic)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Add the TLS and login of the config to the options
+///
+/// A certificate that cannot be read is an error rather than falling back
+/// to a plain connection that would send the login in the clear
+fn set_up_connection(mqttoptions: &mut MqttOptions, config: &MqttServerConfig) -> AnyResult<()> {
+    if let Some(ca_path) = &config.ca {
+        let ca = std::fs::read(ca_path)
+            .with_context(|| format!("Failed to read the mqtt ca {:?}", ca_path))?;
+        // Use client_auth if they have cert and key
+        let client_auth = match &config.client_auth {
+            Some((cert_path, key_path)) => Some((
+                std::fs::read(cert_path).with_context(|| {
+                    format!("Failed to read the mqtt client certificate {:?}", cert_path)
+                })?,
+                std::fs::read(key_path).with_context(|| {
+                    format!("Failed to read the mqtt client key {:?}", key_path)
+                })?,
+            )),
+            None => None,
+        };
+        mqttoptions.set_transport(Transport::Tls(TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth,
+        }));
+    } else if config.use_tls {
+        // Trust the system's certificates
+        mqttoptions.set_transport(Transport::tls_with_default_config());
+    }
+
+    if let Some((username, password)) = &config.credentials {
+        mqttoptions.set_credentials(username, password);
+    }
+    Ok(())
+}
+
 pub(crate) struct LastWillMqtt {
     cancel: CancellationToken,
 }
@@ -517,6 +662,12 @@ impl LastWillMqtt {
         topic: String,
         message: String,
     ) -> AnyResult<Self> {
+        let cancel = CancellationToken::new();
+        if config.shared_connection {
+            // The will of the shared connection on `neolink/status` covers this
+            log::trace!("MQTT Last Will on {topic} left to the shared connection");
+            return Ok(LastWillMqtt { cancel });
+        }
         log::trace!("Run MQTT Last Will");
         let mut mqttoptions = MqttOptions::new(
             format!("NeolinkLastWill_{}", topic),
@@ -526,37 +677,7 @@ impl LastWillMqtt {
         let max_size = 100 * (1024 * 1024);
         mqttoptions.set_max_packet_size(max_size, max_size);
 
-        // Use TLS if ca path is set
-        if let Some(ca_path) = &config.ca {
-            if let Ok(ca) = std::fs::read(ca_path) {
-                // Use client_auth if they have cert and key
-                let client_auth = if let Some((cert_path, key_path)) = &config.client_auth {
-                    if let (Ok(cert_buf), Ok(key_buf)) =
-                        (std::fs::read(cert_path), std::fs::read(key_path))
-                    {
-                        Some((cert_buf, key_buf))
-                    } else {
-                        error!("Failed to set client tls");
-                        None
-                    }
-                } else {
-                    None
-                };
-
-                let transport = Transport::Tls(TlsConfiguration::Simple {
-                    ca,
-                    alpn: None,
-                    client_auth,
-                });
-                mqttoptions.set_transport(transport);
-            } else {
-                error!("Failed to set CA");
-            }
-        };
-
-        if let Some((username, password)) = &config.credentials {
-            mqttoptions.set_credentials(username, password);
-        }
+        set_up_connection(&mut mqttoptions, config)?;
 
         mqttoptions.set_keep_alive(Duration::from_secs(5));
 
@@ -565,7 +686,6 @@ impl LastWillMqtt {
 
         let (client, mut connection) = AsyncClient::new(mqttoptions, 100);
         let client = Arc::new(client);
-        let cancel = CancellationToken::new();
         let thread_cancel = cancel.clone();
 
         tokio::task::spawn(async move {
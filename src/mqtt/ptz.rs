@@ -0,0 +1,176 @@
+//! Serialises the PTZ moves of a camera
+//!
+//! MQTT messages are handled concurrently so without this a `left` quickly
+//! followed by a `right` would interleave their start and stop commands and
+//! can leave the camera moving. Moves are run one at a time with a short
+//! settle between them. With `ptz_overlap = "cancel"` a new move ends the
+//! one in progress rather than waiting for it to finish.
+//!
+//! A `stop` never waits, it is sent straight away and cancels the move in
+//! progress along with any that are waiting.
+//!
+//! The camera only takes a zoom position so a continuous zoom is run as a
+//! series of small steps. It runs beside the moves and is ended by a zoom
+//! `stop`, a PTZ `stop`, the end of the range or its timeout
+use std::sync::Mutex;
+use tokio::{
+    sync::Mutex as AsyncMutex,
+    time::{sleep, sleep_until, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{common::NeoInstance, config::PtzOverlap, AnyResult};
+use neolink_core::bc_protocol::Direction as BcDirection;
+
+/// Time between the stop of one move and the start of the next
+const SETTLE: Duration = Duration::from_millis(250);
+/// Steps of a continuous zoom from one end of the range to the other
+const ZOOM_STEPS: u32 = 20;
+/// Time between the steps of a continuous zoom
+const ZOOM_STEP_TIME: Duration = Duration::from_millis(200);
+
+pub(super) struct PtzQueue {
+    /// Held while a move runs, holds the time the last move ended
+    running: AsyncMutex<Option<Instant>>,
+    /// Cancelled to end the running and waiting moves
+    cancel: Mutex<CancellationToken>,
+    /// Cancelled to end the continuous zoom
+    zoom_cancel: Mutex<CancellationToken>,
+}
+
+impl PtzQueue {
+    pub(super) fn new() -> Self {
+        Self {
+            running: AsyncMutex::new(None),
+            cancel: Mutex::new(CancellationToken::new()),
+            zoom_cancel: Mutex::new(CancellationToken::new()),
+        }
+    }
+
+    fn cancel_all(&self) {
+        let mut cancel = self.cancel.lock().unwrap();
+        cancel.cancel();
+        *cancel = CancellationToken::new();
+        self.stop_zoom();
+    }
+
+    fn restart_zoom(&self) -> CancellationToken {
+        let mut cancel = self.zoom_cancel.lock().unwrap();
+        cancel.cancel();
+        *cancel = CancellationToken::new();
+        cancel.clone()
+    }
+
+    /// Move in a direction for a time then stop
+    ///
+    /// Returns false if the move was cancelled before it started
+    pub(super) async fn move_for(
+        &self,
+        camera: &NeoInstance,
+        direction: BcDirection,
+        speed: f32,
+        duration: Duration,
+        overlap: PtzOverlap,
+    ) -> AnyResult<bool> {
+        if overlap == PtzOverlap::Cancel {
+            self.cancel_all();
+        }
+        let cancel = self.cancel.lock().unwrap().clone();
+        let mut last_end = tokio::select! {
+            _ = cancel.cancelled() => return Ok(false),
+            v = self.running.lock() => v,
+        };
+        if let Some(last_end) = *last_end {
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(false),
+                _ = sleep_until(last_end + SETTLE) => {},
+            }
+        }
+
+        // On drop send the stop command again just to make sure it stops
+        let _drop_command = camera.clone().drop_command(
+            move |cam| {
+                Box::pin(async move {
+                    cam.send_ptz(BcDirection::Stop, speed).await?;
+                    AnyResult::Ok(())
+                })
+            },
+            Duration::from_millis(100),
+        );
+        let r = camera
+            .run_task(|cam| {
+                let cancel = cancel.clone();
+                Box::pin(async move {
+                    cam.send_ptz(direction, speed).await?;
+                    tokio::select! {
+                        _ = cancel.cancelled() => {},
+                        _ = tokio::time::sleep(duration) => {},
+                    }
+                    cam.send_ptz(BcDirection::Stop, speed).await?;
+                    AnyResult::Ok(())
+                })
+            })
+            .await;
+        *last_end = Some(Instant::now());
+        r.map(|_| true)
+    }
+
+    /// Zoom in or out until stopped, the end of the range or the duration
+    ///
+    /// Returns false if a `stop` or another zoom ended it
+    pub(super) async fn zoom_for(
+        &self,
+        camera: &NeoInstance,
+        zoom_in: bool,
+        duration: Duration,
+    ) -> AnyResult<bool> {
+        let cancel = self.restart_zoom();
+        let deadline = Instant::now() + duration;
+        camera
+            .run_task(|cam| {
+                let cancel = cancel.clone();
+                Box::pin(async move {
+                    let range = cam.get_zoom().await?.zoom;
+                    let step = (range.max_pos.saturating_sub(range.min_pos) / ZOOM_STEPS).max(1);
+                    let mut pos = range.cur_pos;
+                    loop {
+                        let next = if zoom_in {
+                            pos.saturating_add(step).min(range.max_pos)
+                        } else {
+                            pos.saturating_sub(step).max(range.min_pos)
+                        };
+                        if next == pos {
+                            // Reached the end of the range
+                            return AnyResult::Ok(true);
+                        }
+                        cam.zoom_to(next).await?;
+                        pos = next;
+                        tokio::select! {
+                            _ = cancel.cancelled() => return Ok(false),
+                            _ = sleep_until(deadline) => return Ok(true),
+                            _ = sleep(ZOOM_STEP_TIME) => {},
+                        }
+                    }
+                })
+            })
+            .await
+    }
+
+    /// End the continuous zoom where it is
+    pub(super) fn stop_zoom(&self) {
+        self.restart_zoom();
+    }
+
+    /// Stop straight away, ending any running or waiting moves
+    pub(super) async fn stop(&self, camera: &NeoInstance, speed: f32) -> AnyResult<()> {
+        self.cancel_all();
+        camera
+            .run_task(|cam| {
+                Box::pin(async move {
+                    cam.send_ptz(BcDirection::Stop, speed).await?;
+                    AnyResult::Ok(())
+                })
+            })
+            .await
+    }
+}
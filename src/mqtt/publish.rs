@@ -0,0 +1,130 @@
+//! Controls how often the polled status topics are published
+//!
+//! Each polled topic can either be published on every poll, which keeps
+//! it fresh for automations that watch the time of the last message, or
+//! only when its value changes, which keeps the broker traffic down.
+//! Boolean like topics default to on change and numeric ones to always
+//!
+//! ```toml
+//! [cameras.mqtt.publish]
+//! battery = "on_change"
+//! floodlight_tasks = "always"
+//! ```
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PublishMode {
+    /// Publish every poll
+    #[serde(alias = "always", alias = "poll")]
+    Always,
+    /// Publish only when the value changes
+    #[serde(alias = "on_change", alias = "on-change", alias = "change")]
+    OnChange,
+}
+
+/// The publish mode of each polled topic
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub(crate) struct PublishConfig {
+    /// `/status/preview`
+    #[serde(default = "default_always")]
+    pub(crate) preview: PublishMode,
+    /// `/status/battery_level`
+    #[serde(default = "default_always")]
+    pub(crate) battery: PublishMode,
+    /// `/status/power`
+    #[serde(default = "default_on_change")]
+    pub(crate) power: PublishMode,
+    /// `/status/battery/charging`
+    #[serde(default = "default_on_change")]
+    pub(crate) charging: PublishMode,
+    /// `/status/floodlight_tasks`
+    #[serde(default = "default_on_change")]
+    pub(crate) floodlight_tasks: PublishMode,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            preview: PublishMode::Always,
+            battery: PublishMode::Always,
+            power: PublishMode::OnChange,
+            charging: PublishMode::OnChange,
+            floodlight_tasks: PublishMode::OnChange,
+        }
+    }
+}
+
+const fn default_always() -> PublishMode {
+    PublishMode::Always
+}
+
+const fn default_on_change() -> PublishMode {
+    PublishMode::OnChange
+}
+
+/// Decides if a polled value should be published
+pub(super) struct ChangeFilter {
+    mode: PublishMode,
+    last: Option<String>,
+}
+
+impl ChangeFilter {
+    pub(super) fn new(mode: PublishMode) -> Self {
+        Self { mode, last: None }
+    }
+
+    /// Returns true if the value should be published
+    pub(super) fn check(&mut self, value: &str) -> bool {
+        match self.mode {
+            PublishMode::Always => true,
+            PublishMode::OnChange => {
+                if self.last.as_deref() == Some(value) {
+                    false
+                } else {
+                    self.last = Some(value.to_string());
+                    true
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_always() {
+        let mut filter = ChangeFilter::new(PublishMode::Always);
+        assert!(filter.check("50"));
+        assert!(filter.check("50"));
+        assert!(filter.check("51"));
+    }
+
+    #[test]
+    fn test_on_change() {
+        let mut filter = ChangeFilter::new(PublishMode::OnChange);
+        // The first value is always published
+        assert!(filter.check("on"));
+        assert!(!filter.check("on"));
+        assert!(filter.check("off"));
+        assert!(!filter.check("off"));
+        assert!(filter.check("on"));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config: PublishConfig = toml::from_str("").unwrap();
+        assert_eq!(config, PublishConfig::default());
+        assert_eq!(config.preview, PublishMode::Always);
+        assert_eq!(config.battery, PublishMode::Always);
+        assert_eq!(config.power, PublishMode::OnChange);
+        assert_eq!(config.charging, PublishMode::OnChange);
+        assert_eq!(config.floodlight_tasks, PublishMode::OnChange);
+
+        let config: PublishConfig =
+            toml::from_str("battery = \"on_change\"\npower = \"always\"").unwrap();
+        assert_eq!(config.battery, PublishMode::OnChange);
+        assert_eq!(config.power, PublishMode::Always);
+    }
+}
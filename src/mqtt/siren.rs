@@ -0,0 +1,89 @@
+//! Keeps the siren from being left on
+//!
+//! An `on` sounds the siren until an `off` is sent or `siren_duration`
+//! passes, whichever is first. A second `on` while it is sounding restarts
+//! the time rather than stacking another stop
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::{common::NeoInstance, AnyResult};
+
+pub(super) struct SirenLatch {
+    /// Cancelled to end the wait of the last `on`
+    cancel: Mutex<CancellationToken>,
+}
+
+impl SirenLatch {
+    pub(super) fn new() -> Self {
+        Self {
+            cancel: Mutex::new(CancellationToken::new()),
+        }
+    }
+
+    fn restart(&self) -> CancellationToken {
+        let mut cancel = self.cancel.lock().unwrap();
+        cancel.cancel();
+        *cancel = CancellationToken::new();
+        cancel.clone()
+    }
+
+    /// Turn the siren on
+    ///
+    /// Returns the token to pass to `stop_after`. Falls back to a single
+    /// play on cameras without the manual mode and returns None if so, there
+    /// is then nothing to stop
+    pub(super) async fn on(&self, camera: &NeoInstance) -> AnyResult<Option<CancellationToken>> {
+        let cancel = self.restart();
+        let manual = camera
+            .run_task(|cam| {
+                Box::pin(async move {
+                    match cam.siren_manual(true).await {
+                        Ok(()) => AnyResult::Ok(true),
+                        Err(e) => {
+                            log::debug!("Manual siren not supported, playing once: {e:?}");
+                            cam.siren().await?;
+                            Ok(false)
+                        }
+                    }
+                })
+            })
+            .await?;
+        Ok(manual.then_some(cancel))
+    }
+
+    /// Wait for the duration to pass then stop the siren
+    ///
+    /// Returns false without stopping if an `on` or `off` came first
+    pub(super) async fn stop_after(
+        &self,
+        camera: &NeoInstance,
+        cancel: CancellationToken,
+        duration: Duration,
+    ) -> AnyResult<bool> {
+        tokio::select! {
+            _ = cancel.cancelled() => Ok(false),
+            _ = sleep(duration) => {
+                self.send_off(camera).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Stop the siren straight away
+    pub(super) async fn off(&self, camera: &NeoInstance) -> AnyResult<()> {
+        self.restart();
+        self.send_off(camera).await
+    }
+
+    async fn send_off(&self, camera: &NeoInstance) -> AnyResult<()> {
+        camera
+            .run_task(|cam| {
+                Box::pin(async move {
+                    cam.siren_manual(false).await?;
+                    AnyResult::Ok(())
+                })
+            })
+            .await
+    }
+}
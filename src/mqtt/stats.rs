@@ -0,0 +1,243 @@
+//! A daily tally of the events of a camera and the rates of its streams
+//!
+//! The counts reset at midnight in the configured utc offset
+//! or the local time of the host if none is configured
+use serde::Serialize;
+use std::collections::BTreeMap;
+use time::{Date, OffsetDateTime, UtcOffset};
+use tokio::time::{Duration, Instant};
+
+use crate::{common::stream_totals, utils::local_offset};
+
+#[derive(Debug)]
+pub(super) struct EventTally {
+    offset: UtcOffset,
+    date: Date,
+    motion: u32,
+    notification: u32,
+}
+
+/// What is published to `status/stats/events/today`
+#[derive(Serialize)]
+struct TallyReport {
+    date: String,
+    motion: u32,
+    notification: u32,
+}
+
+impl EventTally {
+    pub(super) fn new(offset_minutes: Option<i32>) -> Self {
+        let offset = to_offset(offset_minutes);
+        Self {
+            offset,
+            date: OffsetDateTime::now_utc().to_offset(offset).date(),
+            motion: 0,
+            notification: 0,
+        }
+    }
+
+    /// Use a new offset, the counts are kept unless this moves us into a new day
+    pub(super) fn set_offset(&mut self, offset_minutes: Option<i32>) {
+        self.offset = to_offset(offset_minutes);
+        self.roll();
+    }
+
+    /// Reset the counts if the day has changed
+    ///
+    /// Returns true if they were reset
+    pub(super) fn roll(&mut self) -> bool {
+        self.roll_at(OffsetDateTime::now_utc())
+    }
+
+    fn roll_at(&mut self, now: OffsetDateTime) -> bool {
+        let today = now.to_offset(self.offset).date();
+        if today != self.date {
+            self.date = today;
+            self.motion = 0;
+            self.notification = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(super) fn add_motion(&mut self) {
+        self.roll();
+        self.motion += 1;
+    }
+
+    pub(super) fn add_notification(&mut self) {
+        self.roll();
+        self.notification += 1;
+    }
+
+    pub(super) fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&TallyReport {
+            date: self.date.to_string(),
+            motion: self.motion,
+            notification: self.notification,
+        })
+    }
+
+    /// Time until the counts should next reset
+    pub(super) fn until_midnight(&self) -> Duration {
+        self.until_midnight_at(OffsetDateTime::now_utc())
+    }
+
+    fn until_midnight_at(&self, now: OffsetDateTime) -> Duration {
+        let now = now.to_offset(self.offset);
+        let midnight = now
+            .date()
+            .next_day()
+            .map(|day| day.midnight().assume_offset(self.offset));
+        match midnight {
+            Some(midnight) => Duration::from_secs_f64((midnight - now).as_seconds_f64().max(1.0)),
+            None => Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Works out the fps and bitrate of the camera's streams
+///
+/// These come from the frames counted as they arrive from the camera and
+/// so show what is received rather than what the clients are sent
+pub(super) struct StreamRates {
+    name: String,
+    last: Instant,
+    totals: BTreeMap<String, (u64, u64)>,
+}
+
+/// What is published to `status/stats/fps` and `status/stats/bitrate`
+pub(super) struct RatesReport {
+    pub(super) fps: BTreeMap<String, f64>,
+    pub(super) bitrate: BTreeMap<String, u64>,
+}
+
+impl StreamRates {
+    pub(super) fn new(name: &str) -> Self {
+        let mut me = Self {
+            name: name.to_string(),
+            last: Instant::now(),
+            totals: Default::default(),
+        };
+        me.sample();
+        me
+    }
+
+    /// The rates since the last sample
+    ///
+    /// A stream that was not started at the last sample reports zero until
+    /// the next one
+    pub(super) fn sample(&mut self) -> RatesReport {
+        let secs = self.last.elapsed().as_secs_f64().max(1.0);
+        self.last = Instant::now();
+        let mut report = RatesReport {
+            fps: Default::default(),
+            bitrate: Default::default(),
+        };
+        for (stream, frames, bytes) in stream_totals(&self.name) {
+            let (prev_frames, prev_bytes) = self
+                .totals
+                .insert(stream.clone(), (frames, bytes))
+                .unwrap_or((frames, bytes));
+            let fps = frames.saturating_sub(prev_frames) as f64 / secs;
+            report
+                .fps
+                .insert(stream.clone(), (fps * 10.0).round() / 10.0);
+            report.bitrate.insert(
+                stream,
+                (bytes.saturating_sub(prev_bytes) as f64 * 8.0 / secs) as u64,
+            );
+        }
+        report
+    }
+}
+
+fn to_offset(offset_minutes: Option<i32>) -> UtcOffset {
+    match offset_minutes {
+        Some(minutes) => UtcOffset::from_whole_seconds(minutes * 60).unwrap_or(UtcOffset::UTC),
+        None => local_offset(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    fn at(day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::from_calendar_date(2024, Month::March, day)
+            .unwrap()
+            .with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn new_tally(offset_minutes: i32, now: OffsetDateTime) -> EventTally {
+        let offset = to_offset(Some(offset_minutes));
+        EventTally {
+            offset,
+            date: now.to_offset(offset).date(),
+            motion: 0,
+            notification: 0,
+        }
+    }
+
+    #[test]
+    fn test_roll_same_day() {
+        let mut tally = new_tally(0, at(10, 1, 0));
+        tally.motion = 3;
+        tally.notification = 2;
+        assert!(!tally.roll_at(at(10, 23, 59)));
+        assert_eq!(tally.motion, 3);
+        assert_eq!(tally.notification, 2);
+    }
+
+    #[test]
+    fn test_roll_new_day() {
+        let mut tally = new_tally(0, at(10, 23, 59));
+        tally.motion = 3;
+        tally.notification = 2;
+        assert!(tally.roll_at(at(11, 0, 0)));
+        assert_eq!(tally.motion, 0);
+        assert_eq!(tally.notification, 0);
+        assert_eq!(tally.date, at(11, 0, 0).date());
+    }
+
+    #[test]
+    fn test_roll_uses_offset() {
+        // 22:30 utc is already the next day at +02:00
+        let mut tally = new_tally(120, at(10, 21, 0));
+        tally.motion = 1;
+        assert!(tally.roll_at(at(10, 22, 30)));
+        assert_eq!(tally.motion, 0);
+
+        // and still the same day at -05:00
+        let mut tally = new_tally(-300, at(10, 12, 0));
+        tally.motion = 1;
+        assert!(!tally.roll_at(at(11, 4, 0)));
+        assert_eq!(tally.motion, 1);
+    }
+
+    #[test]
+    fn test_until_midnight() {
+        let tally = new_tally(0, at(10, 23, 0));
+        assert_eq!(
+            tally.until_midnight_at(at(10, 23, 0)),
+            Duration::from_secs(60 * 60)
+        );
+
+        let tally = new_tally(120, at(10, 21, 0));
+        assert_eq!(
+            tally.until_midnight_at(at(10, 21, 0)),
+            Duration::from_secs(60 * 60)
+        );
+    }
+
+    #[test]
+    fn test_until_midnight_at_least_a_second() {
+        let tally = new_tally(0, at(10, 0, 0));
+        let just_before = at(10, 23, 59) + time::Duration::milliseconds(59_999);
+        assert_eq!(tally.until_midnight_at(just_before), Duration::from_secs(1));
+    }
+}
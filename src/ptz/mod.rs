@@ -92,7 +92,7 @@ pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
                 CmdDirection::Down => Direction::Down,
                 CmdDirection::Stop => Direction::Stop,
             };
-            let speed = speed.unwrap_or(32) as f32;
+            let speed = speed.unwrap_or(camera.config().await?.borrow().ptz_speed) as f32;
             let seconds = amount as f32 / speed;
             let duration = Duration::from_secs_f32(seconds);
             camera
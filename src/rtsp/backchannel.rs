@@ -0,0 +1,128 @@
+//! Sends the audio of an rtsp client to the camera's speaker
+//!
+//! Clients that ask for the ONVIF backchannel, with the header
+//! `Require: www.onvif.org/ver20/backchannel`, are offered an extra sendonly
+//! PCMU audio stream. What they send on it is decoded and encoded to the
+//! adpcm that the camera's talk command expects. Each burst of audio is sent
+//! as its own talk so that the speaker is free for the app once the client
+//! goes quiet
+use gstreamer::{FlowError, FlowSuccess};
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use neolink_core::bc::xml::TalkConfig;
+use tokio::{
+    sync::mpsc::unbounded_channel,
+    time::{timeout, Duration},
+};
+
+use crate::{common::NeoInstance, talk::talk_setup, AnyResult};
+
+/// Name of the appsink at the end of the backchannel
+const SINK_NAME: &str = "backchannel_sink";
+/// Quiet time that ends a burst of talk
+const QUIET: Duration = Duration::from_secs(1);
+
+/// The camera's talk settings for the backchannel
+#[derive(Clone)]
+pub(super) struct TalkBack {
+    name: String,
+    camera: NeoInstance,
+    talk_config: TalkConfig,
+    block_size: u16,
+    sample_rate: u16,
+}
+
+impl TalkBack {
+    /// Get the talk settings of the camera
+    ///
+    /// None if the camera cannot talk, the backchannel is then not offered
+    /// rather than failing the stream
+    pub(super) async fn new(camera: &NeoInstance) -> Option<Self> {
+        let name = camera.config().await.ok()?.borrow().name.clone();
+        match talk_setup(camera).await {
+            Ok((talk_config, block_size, sample_rate)) => Some(Self {
+                name,
+                camera: camera.clone(),
+                talk_config,
+                block_size,
+                sample_rate,
+            }),
+            Err(e) => {
+                log::info!("{name}: Not offering the rtsp backchannel: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// The pipeline from the client's rtp to the camera's adpcm
+    ///
+    /// Every element is named with `backchannel` so that they are left in
+    /// place when the rest of the media is rebuilt. It is a separate branch
+    /// from the audio sent to the clients and so the silence fallback there
+    /// is not affected
+    pub(super) fn launch(&self) -> String {
+        format!(
+            "( capsfilter name=depay_backchannel caps=\"application/x-rtp,media=audio,payload=0,clock-rate=8000,encoding-name=PCMU\" \
+            ! rtppcmudepay name=backchannel_depay \
+            ! mulawdec name=backchannel_decoder \
+            ! audioconvert name=backchannel_convert \
+            ! audioresample name=backchannel_resample \
+            ! capsfilter name=backchannel_rawcaps caps=\"audio/x-raw,rate={},channels=1\" \
+            ! adpcmenc name=backchannel_encoder blockalign={} layout=dvi \
+            ! appsink name={SINK_NAME} async=false sync=false )",
+            self.sample_rate, self.block_size
+        )
+    }
+
+    /// Send the audio from the sink to the camera until the client leaves
+    pub(super) async fn run(&self, sink: AppSink) -> AnyResult<()> {
+        let (tx, mut rx) = unbounded_channel();
+        sink.set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| FlowError::Error)?;
+                    tx.send(map.as_slice().to_vec())
+                        .map_err(|_| FlowError::Eos)?;
+                    Ok(FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        while let Some(first) = rx.recv().await {
+            log::info!("{}: Talking from an rtsp client", self.name);
+            let (talk_tx, talk_rx) = crossbeam_channel::unbounded();
+            let _ = talk_tx.send(first);
+            let talk = self.camera.run_task(|cam| {
+                let talk_rx = talk_rx.clone();
+                let talk_config = self.talk_config.clone();
+                Box::pin(async move {
+                    cam.talk_stream(talk_rx, talk_config).await?;
+                    AnyResult::Ok(())
+                })
+            });
+            let rx = &mut rx;
+            let feed = async move {
+                while let Ok(Some(data)) = timeout(QUIET, rx.recv()).await {
+                    if talk_tx.send(data).is_err() {
+                        break;
+                    }
+                }
+                // Dropping the sender ends the talk
+            };
+            let (talk, _) = tokio::join!(talk, feed);
+            match talk {
+                Ok(()) => log::debug!("{}: Rtsp client stopped talking", self.name),
+                Err(e) => log::warn!("{}: Could not talk from the rtsp client: {e:?}", self.name),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The appsink of the backchannel if this client asked for it
+pub(super) fn find_sink(bin: &gstreamer::Bin) -> Option<AppSink> {
+    use gstreamer::prelude::*;
+    bin.by_name(SINK_NAME)
+        .and_then(|sink| sink.dynamic_cast::<AppSink>().ok())
+}
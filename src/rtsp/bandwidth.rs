@@ -0,0 +1,344 @@
+//! Keeps the video of all cameras inside a bandwidth budget
+//!
+//! The main stream of each camera is metered. When the total goes over
+//! `bandwidth_budget` the camera with the lowest `priority` that is
+//! streaming has its main stream bitrate lowered one step on the camera's
+//! encoder. When the total drops well below the budget the highest priority
+//! camera that was lowered is raised one step back towards where it was.
+//! This repeats every few seconds so the cameras settle inside the budget
+//!
+//! The original bitrates are written to `bandwidth_state` so that cameras
+//! left lowered by a crash are put back after the next start
+use anyhow::anyhow;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    task::JoinSet,
+    time::{interval, timeout, Duration, Instant, MissedTickBehavior},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    common::{NeoInstance, NeoReactor},
+    config::Config,
+    AnyResult,
+};
+use neolink_core::bc_protocol::StreamKind;
+
+/// How often the usage is checked and one camera adjusted
+const PERIOD: Duration = Duration::from_secs(10);
+/// Lowered cameras are only raised once the usage is under this part of the budget
+const RAISE_BELOW: f64 = 0.8;
+/// Time allowed to restore the cameras when stopping
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A camera being metered
+struct Meter {
+    instance: NeoInstance,
+    priority: u8,
+    bytes: Arc<AtomicU64>,
+}
+
+/// Keep the cameras inside the budget until cancelled, then put them back
+pub(super) async fn bandwidth_main(
+    reactor: NeoReactor,
+    cancel: CancellationToken,
+) -> AnyResult<()> {
+    let mut config = reactor.config().await?;
+    let mut lowered = Lowered::load(config.borrow().bandwidth_state.clone());
+    let r = tokio::select! {
+        _ = cancel.cancelled() => AnyResult::Ok(()),
+        v = async {
+            loop {
+                let current = config.borrow_and_update().clone();
+                lowered.set_path(current.bandwidth_state.clone());
+                match current.bandwidth_budget {
+                    Some(budget) => {
+                        tokio::select! {
+                            v = config.changed() => v?,
+                            v = manage(&reactor, &current, budget, &mut lowered) => v?,
+                        }
+                    }
+                    None => {
+                        restore_all(&reactor, &mut lowered).await;
+                        config.changed().await?;
+                    }
+                }
+            }
+        } => v,
+    };
+    if timeout(RESTORE_TIMEOUT, restore_all(&reactor, &mut lowered))
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Could not restore every camera's bitrate in time, they will be restored on the next start"
+        );
+    }
+    r
+}
+
+/// The original main stream bitrate of the cameras that have been lowered
+///
+/// Every change is written to the state file
+struct Lowered {
+    path: PathBuf,
+    bitrates: HashMap<String, u32>,
+}
+
+impl Lowered {
+    fn load(path: PathBuf) -> Self {
+        let bitrates = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                log::warn!("Ignoring the bandwidth state in {}: {e}", path.display());
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        };
+        Self { path, bitrates }
+    }
+
+    fn set_path(&mut self, path: PathBuf) {
+        if path != self.path {
+            let _ = std::fs::remove_file(&self.path);
+            self.path = path;
+            self.save();
+        }
+    }
+
+    fn insert(&mut self, name: &str, original: u32) {
+        if !self.bitrates.contains_key(name) {
+            self.bitrates.insert(name.to_string(), original);
+            self.save();
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        if self.bitrates.remove(name).is_some() {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = save_state(&self.path, &self.bitrates) {
+            log::warn!(
+                "Could not save the bandwidth state to {}: {e:?}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+fn save_state(path: &Path, bitrates: &HashMap<String, u32>) -> AnyResult<()> {
+    if bitrates.is_empty() {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    } else {
+        std::fs::write(path, serde_json::to_string(bitrates)?)?;
+        Ok(())
+    }
+}
+
+async fn manage(
+    reactor: &NeoReactor,
+    config: &Config,
+    budget: u32,
+    lowered: &mut Lowered,
+) -> AnyResult<()> {
+    log::info!("Keeping the camera streams under {budget} kbps");
+    let mut meters: HashMap<String, Meter> = Default::default();
+    let mut set = JoinSet::new();
+    for camera in config.cameras.iter().filter(|camera| camera.enabled) {
+        let instance = reactor.get(&camera.name).await?;
+        let bytes = Arc::new(AtomicU64::new(0));
+        set.spawn(meter(instance.clone(), bytes.clone()));
+        meters.insert(
+            camera.name.clone(),
+            Meter {
+                instance,
+                priority: camera.priority,
+                bytes,
+            },
+        );
+    }
+
+    let mut ticker = interval(PERIOD);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut last = Instant::now();
+    loop {
+        ticker.tick().await;
+        let secs = last.elapsed().as_secs_f64().max(1.0);
+        last = Instant::now();
+        let usage = meters
+            .iter()
+            .map(|(name, meter)| {
+                let bytes = meter.bytes.swap(0, Ordering::Relaxed);
+                (name.clone(), (bytes as f64 * 8.0 / 1000.0 / secs) as u32)
+            })
+            .collect::<HashMap<_, _>>();
+        let total: u32 = usage.values().sum();
+        log::debug!("Camera streams are using {total} of {budget} kbps");
+
+        if total > budget {
+            // Lowest priority first, only those that are streaming
+            let mut candidates = meters
+                .iter()
+                .filter(|(name, _)| usage.get(*name).copied().unwrap_or(0) > 0)
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|(_, meter)| meter.priority);
+            for (name, meter) in candidates {
+                match step(&meter.instance, Direction::Down, None).await {
+                    Ok(Some((from, to))) => {
+                        log::info!(
+                            "{name}: Streams are using {total} of {budget} kbps, lowering the main stream from {from} to {to} kbps"
+                        );
+                        lowered.insert(name, from);
+                        break;
+                    }
+                    Ok(None) => continue, // Already at its lowest
+                    Err(e) => {
+                        log::debug!("{name}: Could not lower the bitrate: {e:?}");
+                        continue;
+                    }
+                }
+            }
+        } else if (total as f64) < budget as f64 * RAISE_BELOW {
+            // Highest priority first
+            let mut candidates = lowered
+                .bitrates
+                .keys()
+                .filter_map(|name| meters.get(name).map(|meter| (name.clone(), meter)))
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|(_, meter)| std::cmp::Reverse(meter.priority));
+            if let Some((name, meter)) = candidates.into_iter().next() {
+                let original = lowered.bitrates[&name];
+                match step(&meter.instance, Direction::Up, Some(original)).await {
+                    Ok(Some((from, to))) => {
+                        log::info!(
+                            "{name}: Streams are using {total} of {budget} kbps, raising the main stream from {from} to {to} kbps"
+                        );
+                        if to >= original {
+                            lowered.remove(&name);
+                        }
+                    }
+                    Ok(None) => {
+                        lowered.remove(&name);
+                    }
+                    Err(e) => log::debug!("{name}: Could not raise the bitrate: {e:?}"),
+                }
+            }
+        }
+    }
+}
+
+/// Count the bytes of the main stream without asking for it to be pulled
+async fn meter(instance: NeoInstance, bytes: Arc<AtomicU64>) -> AnyResult<()> {
+    let mut stream = instance.stream(StreamKind::Main).await?;
+    stream.deactivate().await?;
+    let mut frames = BroadcastStream::new(stream.vid.resubscribe());
+    while let Some(frame) = frames.next().await {
+        if let Ok(frame) = frame {
+            bytes.fetch_add(frame.data.len() as u64, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Up,
+    Down,
+}
+
+/// Move the main stream bitrate one step through the camera's bitrate table
+///
+/// Returns the old and new bitrate or None if it is already at the end
+async fn step(
+    instance: &NeoInstance,
+    direction: Direction,
+    limit: Option<u32>,
+) -> AnyResult<Option<(u32, u32)>> {
+    instance
+        .run_task(|cam| {
+            Box::pin(async move {
+                let table = cam
+                    .get_stream_info()
+                    .await?
+                    .stream_infos
+                    .iter()
+                    .flat_map(|info| info.encode_tables.iter())
+                    .find(|encode| encode.name == StreamKind::Main.to_string())
+                    .map(|encode| {
+                        encode
+                            .bitrate_table
+                            .split(',')
+                            .filter_map(|c| c.trim().parse::<u32>().ok())
+                            .collect::<Vec<_>>()
+                    })
+                    .ok_or_else(|| anyhow!("Camera has no bitrate table for the main stream"))?;
+                let mut compression = cam.get_compression().await?;
+                let from = compression.main_stream.bit_rate;
+                let to = match direction {
+                    Direction::Down => table.iter().copied().filter(|b| *b < from).max(),
+                    Direction::Up => table
+                        .iter()
+                        .copied()
+                        .filter(|b| *b > from && limit.map(|l| *b <= l).unwrap_or(true))
+                        .min(),
+                };
+                match to {
+                    Some(to) => {
+                        compression.main_stream.bit_rate = to;
+                        cam.set_compression(compression).await?;
+                        Ok(Some((from, to)))
+                    }
+                    None => Ok(None),
+                }
+            })
+        })
+        .await
+}
+
+/// Put every lowered camera back to its original bitrate
+///
+/// Those that cannot be reached are kept to be tried again
+async fn restore_all(reactor: &NeoReactor, lowered: &mut Lowered) {
+    let pending = lowered
+        .bitrates
+        .iter()
+        .map(|(name, original)| (name.clone(), *original))
+        .collect::<Vec<_>>();
+    for (name, original) in pending {
+        let res = async {
+            let instance = reactor.get(&name).await?;
+            instance
+                .run_task(|cam| {
+                    Box::pin(async move {
+                        let mut compression = cam.get_compression().await?;
+                        compression.main_stream.bit_rate = original;
+                        cam.set_compression(compression).await?;
+                        AnyResult::Ok(())
+                    })
+                })
+                .await
+        }
+        .await;
+        match res {
+            Ok(()) => {
+                log::info!("{name}: Restored the main stream to {original} kbps");
+                lowered.remove(&name);
+            }
+            Err(e) => log::warn!("{name}: Could not restore the main stream bitrate: {e:?}"),
+        }
+    }
+}
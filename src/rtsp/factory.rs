@@ -1,14 +1,286 @@
 use anyhow::{anyhow, Context, Result};
-use gstreamer::{prelude::*, Bin, Caps, Element, ElementFactory, GhostPad};
-use gstreamer_app::{AppSrc, AppSrcCallbacks, AppStreamType};
-use tokio::sync::mpsc::{channel as mpsc, Receiver as MpscReceiver};
+use gstreamer::{glib::WeakRef, prelude::*, Bin, Caps, Element, ElementFactory, GhostPad};
+use gstreamer_app::{AppSink, AppSrc, AppSrcCallbacks, AppStreamType};
+use gstreamer_rtsp::RTSPLowerTrans;
+use gstreamer_rtsp_server::prelude::*;
+use neolink_core::bc_protocol::StreamKind;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    sync::mpsc::{channel as mpsc, Receiver as MpscReceiver},
+    time::Duration,
+};
 
 use crate::{
     common::{AudFormat, StreamConfig, VidFormat},
-    rtsp::gst::NeoMediaFactory,
+    config::{
+        AudioCodec, CameraConfig, ClockOverlayConfig, RtspTransport, StreamPreset, TimestampSource,
+    },
+    rtsp::{backchannel::find_sink, gst::NeoMediaFactory},
     AnyResult,
 };
 
+/// Camera settings that change how the pipeline is built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct FactoryOptions {
+    /// Fill gaps in the aac with silence
+    pub(super) audio_fallback: bool,
+    /// Max size of the video rtp packets, None for the gstreamer default
+    pub(super) mtu: Option<u32>,
+    /// Buffer sizes and related settings from the stream's preset
+    pub(super) tuning: Tuning,
+    /// What the audio is encoded as for the clients
+    pub(super) audio_codec: AudioCodec,
+    /// Where the buffer times come from
+    pub(super) timestamps: TimestampSource,
+    /// Offer the clients a backchannel to the camera's speaker
+    pub(super) talk_back: bool,
+    /// Send H265 to the clients as H264
+    pub(super) transcode: bool,
+    /// Clock to burn into the video when it is transcoded
+    pub(super) clock: Option<ClockOverlayConfig>,
+    /// Shown in place of the video while the camera sends none
+    pub(super) splash: Option<Splash>,
+    /// Also serve the audio alone at `/audio` under each path
+    pub(super) audio_mount: bool,
+    /// Which transports the clients may receive the rtp on
+    pub(super) transport: RtspTransport,
+}
+
+impl FactoryOptions {
+    pub(super) fn new(config: &CameraConfig, kind: StreamKind) -> Self {
+        let presets = &config.presets;
+        let preset = match kind {
+            StreamKind::Main => presets.main,
+            StreamKind::Sub => presets.sub,
+            StreamKind::Extern => presets.extern_,
+        };
+        let mut tuning = Tuning::from(preset);
+        if let Some(secs) = presets.buffer_secs {
+            tuning.buffer_secs = secs;
+        }
+        if let Some(ms) = presets.queue_ms {
+            tuning.queue_time = Duration::from_millis(ms);
+        }
+        if let Some(bytes) = presets.buffer_bytes {
+            tuning.min_buffer = bytes;
+        }
+        if presets.leaky_queues {
+            tuning.leaky = true;
+            if presets.queue_ms.is_none() {
+                tuning.queue_time = tuning.queue_time.min(LEAKY_QUEUE_TIME);
+            }
+        }
+        Self {
+            audio_fallback: config.audio_fallback,
+            mtu: config.rtp_mtu,
+            tuning,
+            audio_codec: pick_audio_codec(&config.audio_codecs),
+            timestamps: config.timestamps,
+            talk_back: config.talk_back,
+            transcode: config.transcode_h265 && can_transcode(&config.name),
+            clock: config.clock_overlay.clone(),
+            splash: config.use_splash.then(|| Splash::new(config)),
+            audio_mount: config.audio_mount,
+            transport: config.rtsp_transport,
+        }
+    }
+}
+
+/// Whether the elements to transcode H265 into H264 are installed
+///
+/// Logs the plugins that are missing, the H265 is then passed through
+fn can_transcode(name: &str) -> bool {
+    for kind in ["avdec_h265", "videoconvert", "x264enc"] {
+        if let Err(e) = make_element(kind, "transcode_check") {
+            log::warn!("{name}: Cannot transcode H265, passing it through: {e:?}");
+            return false;
+        }
+    }
+    true
+}
+
+/// The first codec that has all of its elements installed
+///
+/// L16 is used if none of them do
+fn pick_audio_codec(codecs: &[AudioCodec]) -> AudioCodec {
+    codecs
+        .iter()
+        .copied()
+        .find(|codec| {
+            audio_codec_elements(*codec).iter().all(|alternatives| {
+                alternatives
+                    .iter()
+                    .any(|kind| ElementFactory::find(kind).is_some())
+            })
+        })
+        .unwrap_or(AudioCodec::L16)
+}
+
+/// The elements after the decoder for each codec
+///
+/// Each entry lists alternatives, the first that is installed is used
+fn audio_codec_elements(codec: AudioCodec) -> &'static [&'static [&'static str]] {
+    match codec {
+        AudioCodec::L16 => &[&["audioconvert"], &["rtpL16pay"]],
+        AudioCodec::Opus => &[
+            &["audioconvert"],
+            &["audioresample"],
+            &["opusenc"],
+            &["rtpopuspay"],
+        ],
+        AudioCodec::Aac => &[
+            &["audioconvert"],
+            &["audioresample"],
+            &["fdkaacenc", "avenc_aac"],
+            &["rtpmp4gpay"],
+        ],
+        AudioCodec::Pcmu => &[
+            &["audioconvert"],
+            &["audioresample"],
+            &["capsfilter"],
+            &["mulawenc"],
+            &["rtppcmupay"],
+        ],
+        AudioCodec::Pcma => &[
+            &["audioconvert"],
+            &["audioresample"],
+            &["capsfilter"],
+            &["alawenc"],
+            &["rtppcmapay"],
+        ],
+    }
+}
+
+/// Make and add to the bin the elements that encode the decoded audio
+///
+/// They are linked together and returned in order, the first needs
+/// linking to the decoder. The payloader is `pay0` when there is no video
+fn build_audio_encoder(
+    bin: &Bin,
+    codec: AudioCodec,
+    stream_config: &StreamConfig,
+) -> Result<Vec<Element>> {
+    log::debug!("Encoding audio as {codec:?}");
+    let groups = audio_codec_elements(codec);
+    let mut elements = vec![];
+    for (i, alternatives) in groups.iter().enumerate() {
+        // The payloader must be named payN for the rtsp server to find it
+        let name = if i + 1 == groups.len() {
+            match stream_config.vid_format {
+                VidFormat::None => "pay0".to_string(),
+                _ => "pay1".to_string(),
+            }
+        } else {
+            format!("audencoder{i}")
+        };
+        let element = alternatives
+            .iter()
+            .find(|kind| ElementFactory::find(kind).is_some())
+            .map(|kind| make_element(kind, &name))
+            .unwrap_or_else(|| make_element(alternatives[0], &name))?;
+        if alternatives[0] == "capsfilter" {
+            // G.711 players expect 8kHz mono
+            element.set_property(
+                "caps",
+                Caps::builder("audio/x-raw")
+                    .field("rate", 8000i32)
+                    .field("channels", 1i32)
+                    .build(),
+            );
+        }
+        elements.push(element);
+    }
+    bin.add_many(&elements)?;
+    Element::link_many(&elements)?;
+    Ok(elements)
+}
+
+/// Most time held in a leaky queue unless `queue_ms` is set
+const LEAKY_QUEUE_TIME: Duration = Duration::from_secs(1);
+
+/// The pipeline settings behind a [`StreamPreset`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Tuning {
+    /// Seconds of stream at its bitrate that the queues hold
+    buffer_secs: u32,
+    /// Smallest size of the queues in bytes
+    min_buffer: u32,
+    /// The appsrc holds this many times the queue size
+    appsrc_factor: u64,
+    /// Max time of data in the queues
+    queue_time: Duration,
+    stream_type: AppStreamType,
+    /// Passed to the video payloader, None for the gstreamer default
+    config_interval: Option<i32>,
+    /// The queues drop their oldest data when full
+    leaky: bool,
+}
+
+impl From<StreamPreset> for Tuning {
+    fn from(preset: StreamPreset) -> Self {
+        match preset {
+            StreamPreset::LowLatency => Self {
+                buffer_secs: 2,
+                min_buffer: 512 * 1024,
+                appsrc_factor: 1,
+                queue_time: Duration::from_secs(1),
+                stream_type: AppStreamType::Stream,
+                // Resend SPS/PPS with every keyframe so clients can start sooner
+                config_interval: Some(-1),
+                leaky: false,
+            },
+            StreamPreset::Balanced => Self {
+                buffer_secs: 15,
+                min_buffer: 4 * 1024 * 1024,
+                appsrc_factor: 3,
+                queue_time: Duration::from_secs(5),
+                stream_type: AppStreamType::Seekable,
+                config_interval: None,
+                leaky: false,
+            },
+            StreamPreset::HighQuality => Self {
+                buffer_secs: 30,
+                min_buffer: 8 * 1024 * 1024,
+                appsrc_factor: 3,
+                queue_time: Duration::from_secs(10),
+                stream_type: AppStreamType::Seekable,
+                config_interval: None,
+                leaky: false,
+            },
+        }
+    }
+}
+
+impl Tuning {
+    fn buffer_size(&self, bitrate: u32) -> u32 {
+        std::cmp::max(
+            bitrate.saturating_mul(self.buffer_secs) / 8u32,
+            self.min_buffer,
+        )
+    }
+
+    /// Apply the buffer settings to an appsrc
+    fn apply(&self, source: &AppSrc, buffer_size: u32) {
+        source.set_max_bytes(buffer_size as u64 * self.appsrc_factor);
+        source.set_stream_type(self.stream_type);
+    }
+
+    fn make_queue(&self, name: &str, buffer_size: u32) -> AnyResult<Element> {
+        let queue = make_timed_queue(name, buffer_size, self.queue_time)?;
+        if self.leaky {
+            // Drop stale frames rather than fall further behind
+            queue.set_property_from_str("leaky", "downstream");
+        }
+        Ok(queue)
+    }
+
+    fn apply_payloader(&self, payload: &Element) {
+        if let Some(interval) = self.config_interval {
+            payload.set_property("config-interval", interval);
+        }
+    }
+}
+
 pub(super) struct ClientSourceData {
     pub(super) app: AppSrc,
 }
@@ -16,98 +288,248 @@ pub(super) struct ClientSourceData {
 pub(super) struct ClientData {
     pub(super) vid: Option<ClientSourceData>,
     pub(super) aud: Option<ClientSourceData>,
+    /// The audio from the client when it asked for the backchannel
+    pub(super) aud_back: Option<AppSink>,
+}
+
+/// What the splash screen shows while the stream is not ready
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Splash {
+    pattern: String,
+    text: String,
+    font: String,
+    size: [u32; 2],
+}
+
+impl Default for Splash {
+    fn default() -> Self {
+        Self {
+            pattern: "snow".to_string(),
+            text: "Stream not Ready".to_string(),
+            font: "Sans, 16".to_string(),
+            size: [896, 512],
+        }
+    }
+}
+
+impl Splash {
+    pub(super) fn new(config: &CameraConfig) -> Self {
+        let default = Self::default();
+        Self {
+            pattern: config.splash_pattern.to_string(),
+            text: config.splash_text.clone().unwrap_or(default.text),
+            font: config.splash_font.clone().unwrap_or(default.font),
+            size: config.splash_size.unwrap_or(default.size),
+        }
+    }
+
+    /// The same splash captioned to show the stream is paused
+    pub(super) fn paused(self) -> Self {
+        Self {
+            text: "Paused".to_string(),
+            ..self
+        }
+    }
+}
+
+/// The splashes being shown by a dummy factory
+///
+/// The splash plays until it is ended so that a client of a camera that is
+/// not ready keeps waiting. Once the stream is mounted in its place the
+/// splash is ended and the client's player connects again to get the stream
+#[derive(Clone, Default)]
+pub(super) struct SplashClients(Arc<Mutex<Vec<(String, WeakRef<Element>)>>>);
+
+impl SplashClients {
+    fn add(&self, path: &str, source: &Element) {
+        let mut sources = self.0.lock().unwrap();
+        sources.retain(|(_, source)| source.upgrade().is_some());
+        sources.push((path.to_string(), source.downgrade()));
+    }
+
+    /// End the splashes on the paths
+    pub(super) fn end(&self, paths: &[String]) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(path, source)| match source.upgrade() {
+                Some(source) if paths.contains(path) => {
+                    log::debug!("Ending the splash at {path}");
+                    source.send_event(gstreamer::event::Eos::new());
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            });
+    }
 }
 
 pub(super) async fn make_dummy_factory(
     use_splash: bool,
-    pattern: String,
-) -> AnyResult<NeoMediaFactory> {
-    NeoMediaFactory::new_with_callback(move |element| {
+    splash: Splash,
+) -> AnyResult<(NeoMediaFactory, SplashClients)> {
+    let clients = SplashClients::default();
+    let thread_clients = clients.clone();
+    let factory = NeoMediaFactory::new_with_path_callback(move |element, path| {
         clear_bin(&element)?;
         if !use_splash {
             Ok(None)
         } else {
-            build_unknown(&element, &pattern)?;
+            let source = build_unknown(&element, &splash)?;
+            thread_clients.add(path, &source);
             Ok(Some(element))
         }
     })
-    .await
+    .await?;
+    Ok((factory, clients))
 }
 
+/// Make the factory of a camera's stream
+///
+/// When `backchannel` is given it is the launch line of the audio
+/// backchannel that is offered to the clients. A `vid_format` of
+/// `VidFormat::None` with audio makes an audio only factory
 pub(super) async fn make_factory(
     stream_config: &StreamConfig,
+    options: FactoryOptions,
+    backchannel: Option<String>,
 ) -> AnyResult<(NeoMediaFactory, MpscReceiver<ClientData>)> {
     let (client_tx, client_rx) = mpsc(100);
+    let transport = options.transport;
     let factory = {
         let stream_config = stream_config.clone();
 
         NeoMediaFactory::new_with_callback(move |element| {
             clear_bin(&element)?;
-            let vid = match stream_config.vid_format {
-                VidFormat::None => {
-                    // This should not be reachable
-                    log::debug!("Building unknown during normal make factory");
-                    build_unknown(&element, "black")?;
-                    AnyResult::Ok(None)
-                }
-                VidFormat::H264 => {
-                    let app = build_h264(&element, &stream_config)?;
-                    app.set_callbacks(
-                        AppSrcCallbacks::builder()
-                            .seek_data(move |_, _seek_pos| true)
-                            .build(),
-                    );
-                    AnyResult::Ok(Some(app))
-                }
-                VidFormat::H265 => {
-                    let app = build_h265(&element, &stream_config)?;
-
-                    app.set_callbacks(
-                        AppSrcCallbacks::builder()
-                            .seek_data(move |_, _seek_pos| true)
-                            .build(),
-                    );
-                    AnyResult::Ok(Some(app))
-                }
-            }?;
-            let aud = if matches!(stream_config.vid_format, VidFormat::None) {
-                None
-            } else {
-                match stream_config.aud_format {
-                    AudFormat::None => AnyResult::Ok(None),
-                    AudFormat::Aac => {
-                        let app = build_aac(&element, &stream_config)?;
-                        app.set_callbacks(
-                            AppSrcCallbacks::builder()
-                                .seek_data(move |_, _seek_pos| true)
-                                .build(),
-                        );
-                        AnyResult::Ok(Some(app))
-                    }
-                    AudFormat::Adpcm(block_size) => {
-                        let app = build_adpcm(&element, block_size, &stream_config)?;
-                        app.set_callbacks(
-                            AppSrcCallbacks::builder()
-                                .seek_data(move |_, _seek_pos| true)
-                                .build(),
-                        );
-                        AnyResult::Ok(Some(app))
-                    }
-                }?
-            };
+            let (vid, aud) = build_stream(&element, &stream_config, &options)?;
+
+            let aud_back = element
+                .clone()
+                .dynamic_cast::<Bin>()
+                .ok()
+                .and_then(|bin| find_sink(&bin));
 
             client_tx.blocking_send(ClientData {
                 vid: vid.map(|app| ClientSourceData { app }),
                 aud: aud.map(|app| ClientSourceData { app }),
+                aud_back,
             })?;
             Ok(Some(element))
         })
         .await
     }?;
+    if let Some(launch) = backchannel {
+        factory.set_backchannel(&launch);
+    }
+    match transport {
+        // Leave the gstreamer default which also allows multicast
+        RtspTransport::Both => {}
+        RtspTransport::Tcp => factory.set_protocols(RTSPLowerTrans::TCP),
+        RtspTransport::Udp => factory.set_protocols(RTSPLowerTrans::UDP),
+    }
+
+    Ok((factory, client_rx))
+}
+
+/// Make a factory that decodes each of the inputs and composes
+/// them into a grid of `columns` wide with each tile of size `tile`
+///
+/// Inputs that are `None` are shown as an offline tile
+///
+/// The receiver gets an appsrc per input, in the same order, for
+/// each new client. Offline inputs have no appsrc
+pub(super) async fn make_mosaic_factory(
+    inputs: Vec<Option<StreamConfig>>,
+    columns: u32,
+    tile: [u32; 2],
+) -> AnyResult<(NeoMediaFactory, MpscReceiver<Vec<Option<AppSrc>>>)> {
+    let (client_tx, client_rx) = mpsc(100);
+    let factory = NeoMediaFactory::new_with_callback(move |element| {
+        clear_bin(&element)?;
+        let apps = build_mosaic(&element, &inputs, columns, tile)?;
+        for app in apps.iter().flatten() {
+            app.set_callbacks(
+                AppSrcCallbacks::builder()
+                    .seek_data(move |_, _seek_pos| true)
+                    .build(),
+            );
+        }
+        client_tx.blocking_send(apps)?;
+        Ok(Some(element))
+    })
+    .await?;
 
     Ok((factory, client_rx))
 }
 
+/// Check that the pipeline of this stream can be built by building
+/// it once into a throwaway bin
+///
+/// This is the same build that [`make_factory`] runs for each client
+/// so a stream that passes can be served
+pub(super) fn check_factory(
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> AnyResult<()> {
+    let bin = Bin::builder().name("check").build();
+    build_stream(bin.upcast_ref(), stream_config, options)?;
+    Ok(())
+}
+
+/// Builds the video and audio of the stream into the media's bin
+fn build_stream(
+    element: &Element,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> AnyResult<(Option<AppSrc>, Option<AppSrc>)> {
+    let vid = match stream_config.vid_format {
+        VidFormat::None if !matches!(stream_config.aud_format, AudFormat::None) => {
+            log::debug!("Building audio only");
+            None
+        }
+        VidFormat::None => {
+            // This should not be reachable
+            log::debug!("Building unknown during normal make factory");
+            build_unknown(
+                element,
+                &Splash {
+                    pattern: "black".to_string(),
+                    ..Default::default()
+                },
+            )?;
+            None
+        }
+        VidFormat::H264 => {
+            if options.clock.is_some() {
+                log::debug!("The clock overlay is only drawn on transcoded H265");
+            }
+            Some(build_h264(element, stream_config, options)?)
+        }
+        VidFormat::H265 => {
+            if options.transcode {
+                Some(build_h265_transcode(element, stream_config, options)?)
+            } else {
+                Some(build_h265(element, stream_config, options)?)
+            }
+        }
+    };
+    let aud = match stream_config.aud_format {
+        AudFormat::None => None,
+        AudFormat::Aac => Some(build_aac(element, stream_config, options)?),
+        AudFormat::Adpcm(block_size) => {
+            Some(build_adpcm(element, block_size, stream_config, options)?)
+        }
+    };
+    for app in vid.iter().chain(aud.iter()) {
+        app.set_callbacks(
+            AppSrcCallbacks::builder()
+                .seek_data(move |_, _seek_pos| true)
+                .build(),
+        );
+    }
+    Ok((vid, aud))
+}
+
 fn clear_bin(bin: &Element) -> Result<()> {
     let bin = bin
         .clone()
@@ -116,28 +538,33 @@ fn clear_bin(bin: &Element) -> Result<()> {
     // Clear the autogenerated ones
     log::debug!("Clearing old elements");
     for element in bin.iterate_elements().into_iter().flatten() {
+        // The backchannel is added by the onvif factory only for the
+        // clients that ask for it, it is kept as it is
+        if element.name().contains("backchannel") {
+            continue;
+        }
         bin.remove(&element)?;
     }
 
     Ok(())
 }
 
-fn build_unknown(bin: &Element, pattern: &str) -> Result<()> {
+/// Build the splash, returns its source which plays until sent an EOS
+fn build_unknown(bin: &Element, splash: &Splash) -> Result<Element> {
     let bin = bin
         .clone()
         .dynamic_cast::<Bin>()
         .map_err(|_| anyhow!("Media source's element should be a bin"))?;
     log::debug!("Building Unknown Pipeline");
     let source = make_element("videotestsrc", "testvidsrc")?;
-    source.set_property_from_str("pattern", pattern);
-    source.set_property("num-buffers", 500i32); // Send buffers then EOS
+    source.set_property_from_str("pattern", &splash.pattern);
     let queue = make_queue("queue0", 1024 * 1024 * 4)?;
 
     let overlay = make_element("textoverlay", "overlay")?;
-    overlay.set_property("text", "Stream not Ready");
+    overlay.set_property("text", &splash.text);
     overlay.set_property_from_str("valignment", "top");
     overlay.set_property_from_str("halignment", "left");
-    overlay.set_property("font-desc", "Sans, 16");
+    overlay.set_property("font-desc", &splash.font);
     let encoder = make_element("jpegenc", "encoder")?;
     let payload = make_element("rtpjpegpay", "pay0")?;
 
@@ -146,18 +573,145 @@ fn build_unknown(bin: &Element, pattern: &str) -> Result<()> {
         &queue,
         &Caps::builder("video/x-raw")
             .field("format", "YUY2")
-            .field("width", 896i32)
-            .field("height", 512i32)
+            .field("width", splash.size[0] as i32)
+            .field("height", splash.size[1] as i32)
             .field("framerate", gstreamer::Fraction::new(25, 1))
             .build(),
     )?;
     Element::link_many([&queue, &overlay, &encoder, &payload])?;
 
+    Ok(source)
+}
+
+/// Frame rate of the splash that stands in for the camera
+const FALLBACK_FPS: i32 = 5;
+/// Time without video from the camera before the splash is shown
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Link the video from `tail` to `next` through a switch to the splash
+///
+/// While the camera sends no video, such as when it reconnects, the clients
+/// are sent the splash and they are switched back to the camera when its
+/// video returns. The splash is encoded in the same codec and size as the
+/// camera so the clients stay connected without a new DESCRIBE. If the
+/// elements for this are missing the video is linked straight through
+fn link_video_fallback(
+    bin: &Bin,
+    tail: &Element,
+    next: &Element,
+    format: &VidFormat,
+    stream_config: &StreamConfig,
+    splash: Option<&Splash>,
+) -> Result<()> {
+    let splash = match splash {
+        Some(splash) => splash,
+        None => {
+            tail.link(next)?;
+            return Ok(());
+        }
+    };
+    let (primary, switch, splash_elements) =
+        match build_video_fallback(format, stream_config, splash) {
+            Ok(elements) => elements,
+            Err(e) => {
+                log::debug!("Not showing the splash when the camera stops: {e:?}");
+                tail.link(next)?;
+                return Ok(());
+            }
+        };
+    bin.add_many([&primary, &switch])?;
+    bin.add_many(&splash_elements)?;
+    // The camera must be linked first so that the switch prefers it
+    Element::link_many([tail, &primary, &switch, next])?;
+    Element::link_many(&splash_elements)?;
+    if let Some(last) = splash_elements.last() {
+        last.link(&switch)?;
+    }
     Ok(())
 }
 
-fn build_h264(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
-    let buffer_size = buffer_size(stream_config.bitrate);
+/// Make the elements of the video fallback without adding them
+///
+/// Returns the capsfilter for the camera's video, the switch and the
+/// elements that make the splash in order
+fn build_video_fallback(
+    format: &VidFormat,
+    stream_config: &StreamConfig,
+    splash: &Splash,
+) -> Result<(Element, Element, Vec<Element>)> {
+    let (encoder_kind, parser_kind, media) = match format {
+        VidFormat::H264 => ("x264enc", "h264parse", "video/x-h264"),
+        VidFormat::H265 => ("x265enc", "h265parse", "video/x-h265"),
+        VidFormat::None => return Err(anyhow!("No video to stand in for")),
+    };
+    // Both sides of the switch must be in the same format
+    let encoded_caps = Caps::builder(media)
+        .field("stream-format", "byte-stream")
+        .field("alignment", "au")
+        .build();
+
+    let primary = make_element("capsfilter", "fallback_primary_caps")?;
+    primary.set_property("caps", &encoded_caps);
+    let switch = make_element("fallbackswitch", "vidfallbackswitch")?;
+    switch.set_property(
+        "timeout",
+        std::convert::TryInto::<u64>::try_into(FALLBACK_TIMEOUT.as_nanos()).unwrap_or(0),
+    );
+    switch.set_property("immediate-fallback", true);
+
+    let source = make_element("videotestsrc", "fallback_src")?;
+    source.set_property_from_str("pattern", &splash.pattern);
+    source.set_property("is-live", true);
+    let raw_caps = make_element("capsfilter", "fallback_raw_caps")?;
+    let [width, height] = stream_config.resolution;
+    raw_caps.set_property(
+        "caps",
+        Caps::builder("video/x-raw")
+            .field("width", width as i32)
+            .field("height", height as i32)
+            .field("framerate", gstreamer::Fraction::new(FALLBACK_FPS, 1))
+            .build(),
+    );
+    let overlay = make_element("textoverlay", "fallback_overlay")?;
+    overlay.set_property("text", &splash.text);
+    overlay.set_property_from_str("valignment", "top");
+    overlay.set_property_from_str("halignment", "left");
+    overlay.set_property("font-desc", &splash.font);
+    let convert = make_element("videoconvert", "fallback_convert")?;
+    let encoder = make_element(encoder_kind, "fallback_encoder")?;
+    encoder.set_property_from_str("tune", "zerolatency");
+    encoder.set_property_from_str("speed-preset", "ultrafast");
+    // A keyframe every second so the switch to it is quick
+    match format {
+        VidFormat::H265 => encoder.set_property("key-int-max", FALLBACK_FPS),
+        _ => encoder.set_property("key-int-max", FALLBACK_FPS as u32),
+    }
+    let parser = make_element(parser_kind, "fallback_parser")?;
+    let splash_caps = make_element("capsfilter", "fallback_splash_caps")?;
+    splash_caps.set_property("caps", &encoded_caps);
+
+    Ok((
+        primary,
+        switch,
+        vec![
+            source,
+            raw_caps,
+            overlay,
+            convert,
+            encoder,
+            parser,
+            splash_caps,
+        ],
+    ))
+}
+
+fn build_h264(
+    bin: &Element,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> Result<AppSrc> {
+    let tuning = options.tuning;
+    let buffer_size = tuning.buffer_size(stream_config.bitrate);
     log::debug!("buffer_size: {buffer_size}");
     let bin = bin
         .clone()
@@ -172,19 +726,31 @@ fn build_h264(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     source.set_block(false);
     source.set_min_latency(0);
     source.set_property("emit-signals", false);
-    source.set_max_bytes(buffer_size as u64 * 3);
-    source.set_do_timestamp(true);
-    source.set_stream_type(AppStreamType::Seekable);
+    source.set_do_timestamp(options.timestamps == TimestampSource::Arrival);
+    tuning.apply(&source, buffer_size);
 
     let source = source
         .dynamic_cast::<Element>()
         .map_err(|_| anyhow!("Cannot cast back"))?;
-    let queue = make_queue("source_queue", buffer_size)?;
+    let queue = tuning.make_queue("source_queue", buffer_size)?;
     let parser = make_element("h264parse", "parser")?;
     let stamper = make_element("h264timestamper", "stamper")?;
     let payload = make_element("rtph264pay", "pay0")?;
+    if let Some(mtu) = options.mtu {
+        payload.set_property("mtu", mtu);
+    }
+    tuning.apply_payloader(&payload);
     bin.add_many([&source, &queue, &parser, &stamper, &payload])?;
-    Element::link_many([&source, &queue, &parser, &stamper, &payload])?;
+    Element::link_many([&source, &queue, &parser])?;
+    link_video_fallback(
+        &bin,
+        &parser,
+        &stamper,
+        &VidFormat::H264,
+        stream_config,
+        options.splash.as_ref(),
+    )?;
+    Element::link_many([&stamper, &payload])?;
 
     let source = source
         .dynamic_cast::<AppSrc>()
@@ -192,8 +758,13 @@ fn build_h264(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     Ok(source)
 }
 
-fn build_h265(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
-    let buffer_size = buffer_size(stream_config.bitrate);
+fn build_h265(
+    bin: &Element,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> Result<AppSrc> {
+    let tuning = options.tuning;
+    let buffer_size = tuning.buffer_size(stream_config.bitrate);
     log::debug!("buffer_size: {buffer_size}");
     let bin = bin
         .clone()
@@ -207,19 +778,113 @@ fn build_h265(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     source.set_block(false);
     source.set_min_latency(0);
     source.set_property("emit-signals", false);
-    source.set_max_bytes(buffer_size as u64 * 3);
-    source.set_do_timestamp(true);
-    source.set_stream_type(AppStreamType::Seekable);
+    source.set_do_timestamp(options.timestamps == TimestampSource::Arrival);
+    tuning.apply(&source, buffer_size);
 
     let source = source
         .dynamic_cast::<Element>()
         .map_err(|_| anyhow!("Cannot cast back"))?;
-    let queue = make_queue("source_queue", buffer_size)?;
+    let queue = tuning.make_queue("source_queue", buffer_size)?;
     let parser = make_element("h265parse", "parser")?;
     let stamper = make_element("h265timestamper", "stamper")?;
     let payload = make_element("rtph265pay", "pay0")?;
+    if let Some(mtu) = options.mtu {
+        payload.set_property("mtu", mtu);
+    }
+    tuning.apply_payloader(&payload);
     bin.add_many([&source, &queue, &parser, &stamper, &payload])?;
-    Element::link_many([&source, &queue, &parser, &stamper, &payload])?;
+    Element::link_many([&source, &queue, &parser])?;
+    link_video_fallback(
+        &bin,
+        &parser,
+        &stamper,
+        &VidFormat::H265,
+        stream_config,
+        options.splash.as_ref(),
+    )?;
+    Element::link_many([&stamper, &payload])?;
+
+    let source = source
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot convert appsrc"))?;
+    Ok(source)
+}
+
+/// Decode the H265 and encode it as H264 for clients that cannot play H265
+///
+/// This is heavy on the CPU, the encoder is set up for low latency
+fn build_h265_transcode(
+    bin: &Element,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> Result<AppSrc> {
+    let tuning = options.tuning;
+    let buffer_size = tuning.buffer_size(stream_config.bitrate);
+    log::debug!("buffer_size: {buffer_size}");
+    let bin = bin
+        .clone()
+        .dynamic_cast::<Bin>()
+        .map_err(|_| anyhow!("Media source's element should be a bin"))?;
+    log::debug!("Building H265 to H264 Pipeline");
+    let source = make_element("appsrc", "vidsrc")?
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot cast to appsrc."))?;
+    source.set_is_live(true);
+    source.set_block(false);
+    source.set_min_latency(0);
+    source.set_property("emit-signals", false);
+    source.set_do_timestamp(options.timestamps == TimestampSource::Arrival);
+    tuning.apply(&source, buffer_size);
+
+    let source = source
+        .dynamic_cast::<Element>()
+        .map_err(|_| anyhow!("Cannot cast back"))?;
+    let queue = tuning.make_queue("source_queue", buffer_size)?;
+    let parser = make_element("h265parse", "parser")?;
+    let decoder = make_element("avdec_h265", "decoder")?;
+    let convert = make_element("videoconvert", "convert")?;
+    let clock = match options.clock.as_ref() {
+        Some(clock) => {
+            let overlay = make_element("clockoverlay", "clock")?;
+            overlay.set_property("time-format", &clock.format);
+            overlay.set_property("font-desc", &clock.font);
+            overlay.set_property_from_str("valignment", &clock.valign);
+            overlay.set_property_from_str("halignment", &clock.halign);
+            overlay.set_property("shaded-background", true);
+            Some(overlay)
+        }
+        None => None,
+    };
+    let encoder = make_element("x264enc", "encoder")?;
+    encoder.set_property_from_str("tune", "zerolatency");
+    encoder.set_property_from_str("speed-preset", "ultrafast");
+    if stream_config.bitrate > 0 {
+        // x264enc is in kbit/s
+        encoder.set_property("bitrate", std::cmp::max(stream_config.bitrate / 1000, 1));
+    }
+    if stream_config.fps > 0 {
+        // A keyframe every two seconds so clients can join quickly
+        encoder.set_property("key-int-max", stream_config.fps * 2);
+    }
+    let payload = make_element("rtph264pay", "pay0")?;
+    if let Some(mtu) = options.mtu {
+        payload.set_property("mtu", mtu);
+    }
+    tuning.apply_payloader(&payload);
+    let mut elements = vec![source.clone(), queue, parser, decoder, convert];
+    elements.extend(clock);
+    elements.push(encoder.clone());
+    bin.add_many(&elements)?;
+    bin.add(&payload)?;
+    Element::link_many(&elements)?;
+    link_video_fallback(
+        &bin,
+        &encoder,
+        &payload,
+        &VidFormat::H264,
+        stream_config,
+        options.splash.as_ref(),
+    )?;
 
     let source = source
         .dynamic_cast::<AppSrc>()
@@ -227,8 +892,13 @@ fn build_h265(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     Ok(source)
 }
 
-fn build_aac(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
-    let buffer_size = buffer_size(stream_config.bitrate);
+fn build_aac(
+    bin: &Element,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> Result<AppSrc> {
+    let tuning = options.tuning;
+    let buffer_size = tuning.buffer_size(stream_config.bitrate);
     log::debug!("buffer_size: {buffer_size}");
     let bin = bin
         .clone()
@@ -243,15 +913,14 @@ fn build_aac(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     source.set_block(false);
     source.set_min_latency(0);
     source.set_property("emit-signals", false);
-    source.set_max_bytes(buffer_size as u64 * 3);
-    source.set_do_timestamp(true);
-    source.set_stream_type(AppStreamType::Seekable);
+    source.set_do_timestamp(options.timestamps == TimestampSource::Arrival);
+    tuning.apply(&source, buffer_size);
 
     let source = source
         .dynamic_cast::<Element>()
         .map_err(|_| anyhow!("Cannot cast back"))?;
 
-    let queue = make_queue("audqueue", buffer_size)?;
+    let queue = tuning.make_queue("audqueue", buffer_size)?;
     let parser = make_element("aacparse", "audparser")?;
     let decoder = match make_element("faad", "auddecoder_faad") {
         Ok(ele) => Ok(ele),
@@ -261,17 +930,21 @@ fn build_aac(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     // The fallback
     let silence = make_element("audiotestsrc", "audsilence")?;
     silence.set_property_from_str("wave", "silence");
-    let fallback_switch = make_element("fallbackswitch", "audfallbackswitch");
-    if let Ok(fallback_switch) = fallback_switch.as_ref() {
+    // Without the plugin the audio gaps as it does with the fallback off
+    let fallback_switch = if options.audio_fallback {
+        make_element("fallbackswitch", "audfallbackswitch").ok()
+    } else {
+        None
+    };
+    if let Some(fallback_switch) = fallback_switch.as_ref() {
         fallback_switch.set_property("timeout", 3u64 * 1_000_000_000u64);
         fallback_switch.set_property("immediate-fallback", true);
     }
 
-    let encoder = make_element("audioconvert", "audencoder")?;
-    let payload = make_element("rtpL16pay", "pay1")?;
+    let encoder = build_audio_encoder(&bin, options.audio_codec, stream_config)?[0].clone();
 
-    bin.add_many([&source, &queue, &parser, &decoder, &encoder, &payload])?;
-    if let Ok(fallback_switch) = fallback_switch.as_ref() {
+    bin.add_many([&source, &queue, &parser, &decoder])?;
+    if let Some(fallback_switch) = fallback_switch.as_ref() {
         bin.add_many([&silence, fallback_switch])?;
         Element::link_many([
             &source,
@@ -280,11 +953,10 @@ fn build_aac(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
             &decoder,
             fallback_switch,
             &encoder,
-            &payload,
         ])?;
         Element::link_many([&silence, fallback_switch])?;
     } else {
-        Element::link_many([&source, &queue, &parser, &decoder, &encoder, &payload])?;
+        Element::link_many([&source, &queue, &parser, &decoder, &encoder])?;
     }
 
     let source = source
@@ -293,8 +965,112 @@ fn build_aac(bin: &Element, stream_config: &StreamConfig) -> Result<AppSrc> {
     Ok(source)
 }
 
-fn build_adpcm(bin: &Element, block_size: u32, stream_config: &StreamConfig) -> Result<AppSrc> {
-    let buffer_size = buffer_size(stream_config.bitrate);
+fn build_mosaic(
+    bin: &Element,
+    inputs: &[Option<StreamConfig>],
+    columns: u32,
+    tile: [u32; 2],
+) -> Result<Vec<Option<AppSrc>>> {
+    let bin = bin
+        .clone()
+        .dynamic_cast::<Bin>()
+        .map_err(|_| anyhow!("Media source's element should be a bin"))?;
+    log::debug!("Building Mosaic Pipeline");
+    // Original command line
+    // compositor name=mixer background=black
+    //  ! videoconvert
+    //  ! x264enc tune=zerolatency speed-preset=ultrafast
+    //  ! rtph264pay name=pay0
+    // appsrc name=vidsrc0 ! queue ! h264parse ! avdec_h264 ! mixer.sink_0
+    // appsrc name=vidsrc1 ! queue ! h264parse ! avdec_h264 ! mixer.sink_1
+    // videotestsrc pattern=black is-live=true ! textoverlay text=Offline ! mixer.sink_2
+    let mixer = make_element("compositor", "mixer")?;
+    mixer.set_property_from_str("background", "black");
+    let convert = make_element("videoconvert", "mixconvert")?;
+    let encoder = make_element("x264enc", "mixencoder")?;
+    encoder.set_property_from_str("tune", "zerolatency");
+    encoder.set_property_from_str("speed-preset", "ultrafast");
+    let payload = make_element("rtph264pay", "pay0")?;
+    bin.add_many([&mixer, &convert, &encoder, &payload])?;
+    Element::link_many([&mixer, &convert, &encoder, &payload])?;
+
+    let mut sources = vec![];
+    for (i, input) in inputs.iter().enumerate() {
+        // Place the tile on the grid, the compositor handles the scaling
+        let sink = mixer
+            .request_pad_simple("sink_%u")
+            .ok_or(anyhow!("Compositor is missing its sink pads"))?;
+        let pos = i as u32;
+        sink.set_property("xpos", ((pos % columns) * tile[0]) as i32);
+        sink.set_property("ypos", ((pos / columns) * tile[1]) as i32);
+        sink.set_property("width", tile[0] as i32);
+        sink.set_property("height", tile[1] as i32);
+
+        let (parser, decoder, bitrate) = match input {
+            Some(input) if input.vid_format == VidFormat::H264 => {
+                ("h264parse", "avdec_h264", input.bitrate)
+            }
+            Some(input) if input.vid_format == VidFormat::H265 => {
+                ("h265parse", "avdec_h265", input.bitrate)
+            }
+            _ => {
+                let placeholder = make_element("videotestsrc", &format!("offline{i}"))?;
+                placeholder.set_property_from_str("pattern", "black");
+                placeholder.set_property("is-live", true);
+                let overlay = make_element("textoverlay", &format!("offline_text{i}"))?;
+                overlay.set_property("text", "Offline");
+                bin.add_many([&placeholder, &overlay])?;
+                Element::link_many([&placeholder, &overlay])?;
+                overlay
+                    .static_pad("src")
+                    .ok_or(anyhow!("Textoverlay is missing its pad"))?
+                    .link(&sink)?;
+                sources.push(None);
+                continue;
+            }
+        };
+        let buffer_size = buffer_size(bitrate);
+        let source = make_element("appsrc", &format!("vidsrc{i}"))?
+            .dynamic_cast::<AppSrc>()
+            .map_err(|_| anyhow!("Cannot cast to appsrc."))?;
+        source.set_is_live(true);
+        source.set_block(false);
+        source.set_min_latency(0);
+        source.set_property("emit-signals", false);
+        source.set_max_bytes(buffer_size as u64 * 3);
+        source.set_do_timestamp(true);
+        source.set_stream_type(AppStreamType::Seekable);
+
+        let source = source
+            .dynamic_cast::<Element>()
+            .map_err(|_| anyhow!("Cannot cast back"))?;
+        let queue = make_queue(&format!("mosaic_queue{i}"), buffer_size)?;
+        let parser = make_element(parser, &format!("parser{i}"))?;
+        let decoder = make_element(decoder, &format!("decoder{i}"))?;
+        bin.add_many([&source, &queue, &parser, &decoder])?;
+        Element::link_many([&source, &queue, &parser, &decoder])?;
+        decoder
+            .static_pad("src")
+            .ok_or(anyhow!("Decoder is missing its pad"))?
+            .link(&sink)?;
+
+        sources.push(Some(
+            source
+                .dynamic_cast::<AppSrc>()
+                .map_err(|_| anyhow!("Cannot convert appsrc"))?,
+        ));
+    }
+    Ok(sources)
+}
+
+fn build_adpcm(
+    bin: &Element,
+    block_size: u32,
+    stream_config: &StreamConfig,
+    options: &FactoryOptions,
+) -> Result<AppSrc> {
+    let tuning = options.tuning;
+    let buffer_size = tuning.buffer_size(stream_config.bitrate);
     log::debug!("buffer_size: {buffer_size}");
     let bin = bin
         .clone()
@@ -315,31 +1091,21 @@ fn build_adpcm(bin: &Element, block_size: u32, stream_config: &StreamConfig) ->
     source.set_block(false);
     source.set_min_latency(0);
     source.set_property("emit-signals", false);
-    source.set_max_bytes(buffer_size as u64 * 3);
-    source.set_do_timestamp(true);
-    source.set_stream_type(AppStreamType::Seekable);
-
-    source.set_caps(Some(
-        &Caps::builder("audio/x-adpcm")
-            .field("layout", "div")
-            .field("block_align", block_size as i32)
-            .field("channels", 1i32)
-            .field("rate", 8000i32)
-            .build(),
-    ));
+    source.set_do_timestamp(options.timestamps == TimestampSource::Arrival);
+    tuning.apply(&source, buffer_size);
+
+    source.set_caps(Some(&adpcm_caps(block_size)));
 
     let source = source
         .dynamic_cast::<Element>()
         .map_err(|_| anyhow!("Cannot cast back"))?;
 
-    let queue = make_queue("audqueue", buffer_size)?;
+    let queue = tuning.make_queue("audqueue", buffer_size)?;
     let decoder = make_element("decodebin", "auddecoder")?;
-    let encoder = make_element("audioconvert", "audencoder")?;
-    let payload = make_element("rtpL16pay", "pay1")?;
+    let encoder = build_audio_encoder(&bin, options.audio_codec, stream_config)?[0].clone();
 
-    bin.add_many([&source, &queue, &decoder, &encoder, &payload])?;
+    bin.add_many([&source, &queue, &decoder])?;
     Element::link_many([&source, &queue, &decoder])?;
-    Element::link_many([&encoder, &payload])?;
     decoder.connect_pad_added(move |_element, pad| {
         let sink_pad = encoder
             .static_pad("sink")
@@ -354,9 +1120,19 @@ fn build_adpcm(bin: &Element, block_size: u32, stream_config: &StreamConfig) ->
     Ok(source)
 }
 
+/// The caps of the camera's adpcm which is the DVI/IMA layout
+fn adpcm_caps(block_size: u32) -> Caps {
+    Caps::builder("audio/x-adpcm")
+        .field("layout", "dvi")
+        .field("block_align", block_size as i32)
+        .field("channels", 1i32)
+        .field("rate", 8000i32)
+        .build()
+}
+
 // Convenice funcion to make an element or provide a message
 // about what plugin is missing
-fn make_element(kind: &str, name: &str) -> AnyResult<Element> {
+pub(super) fn make_element(kind: &str, name: &str) -> AnyResult<Element> {
     ElementFactory::make_with_name(kind, Some(name)).with_context(|| {
         let plugin = match kind {
             "appsrc" => "app (gst-plugins-base)",
@@ -369,6 +1145,17 @@ fn make_element(kind: &str, name: &str) -> AnyResult<Element> {
             "rtpjitterbuffer" => "rtp (gst-plugins-good)",
             "aacparse" => "audioparsers (gst-plugins-good)",
             "rtpL16pay" => "rtp (gst-plugins-good)",
+            "audioresample" => "audioresample (gst-plugins-base)",
+            "opusenc" => "opus (gst-plugins-base)",
+            "rtpopuspay" => "rtp (gst-plugins-good)",
+            "fdkaacenc" => "fdkaac (gst-plugins-bad)",
+            "avenc_aac" => "libav (gst-libav)",
+            "rtpmp4gpay" => "rtp (gst-plugins-good)",
+            "capsfilter" => "coreelements (gstreamer)",
+            "mulawenc" => "mulaw (gst-plugins-good)",
+            "alawenc" => "alaw (gst-plugins-good)",
+            "rtppcmupay" => "rtp (gst-plugins-good)",
+            "rtppcmapay" => "rtp (gst-plugins-good)",
             "x264enc" => "x264 (gst-plugins-ugly)",
             "x265enc" => "x265 (gst-plugins-bad)",
             "avdec_h264" => "libav (gst-libav)",
@@ -377,6 +1164,15 @@ fn make_element(kind: &str, name: &str) -> AnyResult<Element> {
             "imagefreeze" => "imagefreeze (gst-plugins-good)",
             "audiotestsrc" => "audiotestsrc (gst-plugins-base)",
             "decodebin" => "playback (gst-plugins-good)",
+            "compositor" => "compositor (gst-plugins-base)",
+            "videoconvert" => "videoconvertscale (gst-plugins-base)",
+            "clockoverlay" => "pango (gst-plugins-base)",
+            "mpegtsmux" => "mpegtsmux (gst-plugins-bad)",
+            "srtsink" => "srt (gst-plugins-bad)",
+            "splitmuxsink" => "multifile (gst-plugins-good)",
+            "queue" => "coreelements (gstreamer)",
+            "fallbackswitch" => "fallbackswitch (gst-plugins-rs)",
+            "textoverlay" => "pango (gst-plugins-base)",
             _ => "Unknown",
         };
         format!(
@@ -434,14 +1230,17 @@ fn make_dbl_queue(name: &str, buffer_size: u32) -> AnyResult<Element> {
 }
 
 fn make_queue(name: &str, buffer_size: u32) -> AnyResult<Element> {
+    make_timed_queue(name, buffer_size, Duration::from_secs(5))
+}
+
+fn make_timed_queue(name: &str, buffer_size: u32, max_time: Duration) -> AnyResult<Element> {
     let queue = make_element("queue", &format!("queue1_{}", name))?;
     queue.set_property("max-size-bytes", buffer_size);
     queue.set_property("max-size-buffers", 0u32);
     queue.set_property("max-size-time", 0u64);
     queue.set_property(
         "max-size-time",
-        std::convert::TryInto::<u64>::try_into(tokio::time::Duration::from_secs(5).as_nanos())
-            .unwrap_or(0),
+        std::convert::TryInto::<u64>::try_into(max_time.as_nanos()).unwrap_or(0),
     );
     Ok(queue)
 }
@@ -449,3 +1248,62 @@ fn make_queue(name: &str, buffer_size: u32) -> AnyResult<Element> {
 fn buffer_size(bitrate: u32) -> u32 {
     std::cmp::max(bitrate * 15u32 / 8u32, 4u32 * 1024u32 * 1024u32)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gstreamer::{parse, Buffer, Pipeline, State};
+    use gstreamer_app::AppSink;
+
+    #[test]
+    // The caps given to the camera's adpcm must let adpcmdec decode it
+    //
+    // They once said `div` instead of `dvi` which gave no audio
+    fn test_adpcm_caps_decode() {
+        gstreamer::init().unwrap();
+        // As sent by the camera, 4 bytes of header then two samples a byte
+        const BLOCK_SIZE: usize = 244;
+
+        let caps = adpcm_caps(BLOCK_SIZE as u32);
+        let structure = caps.structure(0).unwrap();
+        assert_eq!(structure.get::<&str>("layout").unwrap(), "dvi");
+
+        let pipeline = parse::launch(
+            "appsrc name=src format=time \
+            ! adpcmdec \
+            ! audioconvert \
+            ! audio/x-raw,format=S16LE \
+            ! appsink name=sink sync=false",
+        )
+        .unwrap()
+        .dynamic_cast::<Pipeline>()
+        .unwrap();
+        let source = pipeline
+            .by_name("src")
+            .unwrap()
+            .dynamic_cast::<AppSrc>()
+            .unwrap();
+        source.set_caps(Some(&caps));
+        let sink = pipeline
+            .by_name("sink")
+            .unwrap()
+            .dynamic_cast::<AppSink>()
+            .unwrap();
+        pipeline.set_state(State::Playing).unwrap();
+
+        // Silence, a zero sample and step index followed by zero steps
+        source
+            .push_buffer(Buffer::from_slice(vec![0u8; BLOCK_SIZE]))
+            .unwrap();
+        source.end_of_stream().unwrap();
+
+        let mut decoded = 0;
+        while let Ok(sample) = sink.pull_sample() {
+            decoded += sample.buffer().unwrap().size();
+        }
+        pipeline.set_state(State::Null).unwrap();
+
+        // The header sample and two per byte after it, at 2 bytes each
+        assert_eq!(decoded, (1 + (BLOCK_SIZE - 4) * 2) * 2);
+    }
+}
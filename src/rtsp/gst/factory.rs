@@ -13,8 +13,8 @@ use gstreamer::{
 use gstreamer_rtsp::RTSPUrl;
 use gstreamer_rtsp_server::prelude::*;
 use gstreamer_rtsp_server::subclass::prelude::*;
-use gstreamer_rtsp_server::RTSPMediaFactory;
 use gstreamer_rtsp_server::RTSPTransportMode;
+use gstreamer_rtsp_server::{RTSPMediaFactory, RTSPOnvifMediaFactory};
 use gstreamer_rtsp_server::{RTSP_PERM_MEDIA_FACTORY_ACCESS, RTSP_PERM_MEDIA_FACTORY_CONSTRUCT};
 use log::*;
 use std::collections::HashSet;
@@ -23,7 +23,7 @@ use tokio::sync::Mutex;
 
 glib::wrapper! {
     /// The wrapped RTSPMediaFactory
-    pub(crate) struct NeoMediaFactory(ObjectSubclass<NeoMediaFactoryImpl>) @extends RTSPMediaFactory;
+    pub(crate) struct NeoMediaFactory(ObjectSubclass<NeoMediaFactoryImpl>) @extends RTSPOnvifMediaFactory, RTSPMediaFactory;
 }
 
 impl Default for NeoMediaFactory {
@@ -48,12 +48,28 @@ impl NeoMediaFactory {
     pub(crate) async fn new_with_callback<F>(callback: F) -> AnyResult<Self>
     where
         F: Fn(Element) -> AnyResult<Option<Element>> + Send + Sync + 'static,
+    {
+        Self::new_with_path_callback(move |element, _| callback(element)).await
+    }
+
+    /// The same as `new_with_callback` but also passing the path the client asked for
+    pub(crate) async fn new_with_path_callback<F>(callback: F) -> AnyResult<Self>
+    where
+        F: Fn(Element, &str) -> AnyResult<Option<Element>> + Send + Sync + 'static,
     {
         let factory = Self::new();
         factory.imp().set_callback(callback).await;
         Ok(factory)
     }
 
+    /// Offer the clients that ask for it an audio backchannel
+    ///
+    /// The onvif factory adds the elements of `launch` to the media of those
+    /// clients, one of them must be named `depay_backchannel`
+    pub(crate) fn set_backchannel(&self, launch: &str) {
+        self.set_backchannel_launch(Some(launch));
+    }
+
     pub(crate) fn add_permitted_roles<T: AsRef<str>>(&self, permitted_roles: &HashSet<T>) {
         for permitted_role in permitted_roles {
             let s = permitted_role.as_ref();
@@ -99,7 +115,8 @@ unsafe impl Sync for NeoMediaFactory {}
 
 pub(crate) struct NeoMediaFactoryImpl {
     #[allow(clippy::type_complexity)]
-    call_back: Arc<Mutex<Option<Arc<dyn Fn(Element) -> AnyResult<Option<Element>> + Send + Sync>>>>,
+    call_back:
+        Arc<Mutex<Option<Arc<dyn Fn(Element, &str) -> AnyResult<Option<Element>> + Send + Sync>>>>,
 }
 
 impl Default for NeoMediaFactoryImpl {
@@ -115,14 +132,14 @@ impl Default for NeoMediaFactoryImpl {
 impl NeoMediaFactoryImpl {
     async fn set_callback<F>(&self, callback: F)
     where
-        F: Fn(Element) -> AnyResult<Option<Element>> + Send + Sync + 'static,
+        F: Fn(Element, &str) -> AnyResult<Option<Element>> + Send + Sync + 'static,
     {
         self.call_back.lock().await.replace(Arc::new(callback));
     }
-    fn build_pipeline(&self, media: Element) -> AnyResult<Option<Element>> {
+    fn build_pipeline(&self, media: Element, path: &str) -> AnyResult<Option<Element>> {
         match self.call_back.blocking_lock().as_ref() {
             Some(call) => {
-                let new_media = call(media);
+                let new_media = call(media, path);
                 match new_media {
                     Ok(new_media) => Ok(new_media),
                     Err(e) => {
@@ -137,10 +154,19 @@ impl NeoMediaFactoryImpl {
 }
 
 impl ObjectImpl for NeoMediaFactoryImpl {}
+impl RTSPOnvifMediaFactoryImpl for NeoMediaFactoryImpl {}
 impl RTSPMediaFactoryImpl for NeoMediaFactoryImpl {
     fn create_element(&self, url: &RTSPUrl) -> Option<Element> {
-        self.parent_create_element(url)
-            .and_then(|orig| self.build_pipeline(orig).expect("Could not build pipeline"))
+        let path = url
+            .decode_path_components()
+            .iter()
+            .map(|component| component.as_str())
+            .collect::<Vec<_>>()
+            .join("/");
+        self.parent_create_element(url).and_then(|orig| {
+            self.build_pipeline(orig, &path)
+                .expect("Could not build pipeline")
+        })
     }
 }
 
@@ -148,5 +174,5 @@ impl RTSPMediaFactoryImpl for NeoMediaFactoryImpl {
 impl ObjectSubclass for NeoMediaFactoryImpl {
     const NAME: &'static str = "NeoMediaFactory";
     type Type = super::NeoMediaFactory;
-    type ParentType = RTSPMediaFactory;
+    type ParentType = RTSPOnvifMediaFactory;
 }
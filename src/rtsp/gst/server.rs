@@ -13,7 +13,8 @@ use gstreamer_rtsp_server::{
     gio::{TlsAuthenticationMode, TlsCertificate},
     prelude::*,
     subclass::prelude::*,
-    RTSPAuth, RTSPFilterResult, RTSPServer, RTSPToken, RTSP_TOKEN_MEDIA_FACTORY_ROLE,
+    RTSPAuth, RTSPClient, RTSPFilterResult, RTSPOnvifClient, RTSPServer, RTSPToken,
+    RTSP_TOKEN_MEDIA_FACTORY_ROLE,
 };
 use log::*;
 use std::{
@@ -141,8 +142,17 @@ impl NeoRtspServer {
         self.imp().set_up_tls(config)
     }
 
-    pub(crate) async fn add_user(&self, username: &str, password: &str) -> AnyResult<()> {
-        self.imp().add_user(username, password).await
+    /// Add a login that is given `role`
+    ///
+    /// The role is what the factories permit, it is the username for the
+    /// `[[users]]` and [`camera_role`] for the users of a single camera
+    pub(crate) async fn add_user(
+        &self,
+        role: &str,
+        username: &str,
+        password: &str,
+    ) -> AnyResult<()> {
+        self.imp().add_user(role, username, password).await
     }
 
     pub(crate) async fn remove_user(&self, username: &str) -> AnyResult<()> {
@@ -154,6 +164,14 @@ impl NeoRtspServer {
     }
 }
 
+/// The role of a user that may only watch `camera`
+///
+/// A `:` cannot be part of a basic auth username so these never clash with
+/// the names of the `[[users]]`
+pub(crate) fn camera_role(camera: &str, username: &str) -> String {
+    format!("{camera}:{username}")
+}
+
 unsafe impl Send for NeoRtspServer {}
 unsafe impl Sync for NeoRtspServer {}
 
@@ -165,7 +183,20 @@ pub(crate) struct NeoRtspServerImpl {
 }
 
 impl ObjectImpl for NeoRtspServerImpl {}
-impl RTSPServerImpl for NeoRtspServerImpl {}
+impl RTSPServerImpl for NeoRtspServerImpl {
+    /// Use the onvif client, it accepts the `Require` header that clients
+    /// send to ask for the audio backchannel
+    fn create_client(&self) -> Option<RTSPClient> {
+        let server = self.obj();
+        let client = Object::new::<RTSPOnvifClient>();
+        client.set_session_pool(server.session_pool().as_ref());
+        client.set_mount_points(server.mount_points().as_ref());
+        client.set_auth(server.auth().as_ref());
+        client.set_thread_pool(server.thread_pool().as_ref());
+        client.set_content_length_limit(server.content_length_limit());
+        Some(client.upcast())
+    }
+}
 
 #[object_subclass]
 impl ObjectSubclass for NeoRtspServerImpl {
@@ -208,14 +239,19 @@ impl NeoRtspServerImpl {
         Ok(())
     }
 
-    pub(crate) async fn add_user(&self, username: &str, password: &str) -> AnyResult<()> {
+    pub(crate) async fn add_user(
+        &self,
+        role: &str,
+        username: &str,
+        password: &str,
+    ) -> AnyResult<()> {
         let mut locked_users = self.users.write().await;
         let auth = self.obj().auth().unwrap();
 
-        let token = RTSPToken::new(&[(RTSP_TOKEN_MEDIA_FACTORY_ROLE, &username)]);
+        let token = RTSPToken::new(&[(RTSP_TOKEN_MEDIA_FACTORY_ROLE, &role)]);
         let basic = RTSPAuth::make_basic(username, password);
 
-        if let Some(old_basic) = locked_users.get(username) {
+        if let Some(old_basic) = locked_users.get(role) {
             if basic.as_str() == old_basic {
                 // Password is the same
                 return Ok(());
@@ -227,7 +263,7 @@ impl NeoRtspServerImpl {
 
         auth.add_basic(basic.as_str(), &token);
 
-        locked_users.insert(username.to_string(), basic.to_string());
+        locked_users.insert(role.to_string(), basic.to_string());
         Ok(())
     }
 
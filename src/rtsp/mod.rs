@@ -67,18 +67,28 @@ use tokio_stream::wrappers::IntervalStream;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 
+mod backchannel;
+mod bandwidth;
 mod cmdline;
 mod factory;
 mod gst;
+mod mosaic;
+mod segments;
+mod srt;
 mod stream;
+mod unix;
 
 use crate::common::{NeoInstance, NeoReactor};
+use bandwidth::bandwidth_main;
 use factory::*;
+use segments::segments_main;
+use srt::srt_main;
 use stream::*;
+use unix::unix_socket_main;
 
-use super::config::UserConfig;
+use super::config::{Config, UserConfig};
 pub(crate) use cmdline::Opt;
-use gst::NeoRtspServer;
+use gst::{camera_role, NeoRtspServer};
 
 type AnyResult<T> = anyhow::Result<T, anyhow::Error>;
 
@@ -119,12 +129,12 @@ pub(crate) async fn main(_opt: Opt, reactor: NeoReactor) -> Result<()> {
         tokio::select! {
             _ = thread_cancel.cancelled() => AnyResult::Ok(()),
             v = async {
-                let mut curr_users = HashSet::new();
+                let mut curr_users = HashMap::new();
                 loop {
 
-                    curr_users = thread_config.wait_for(|new_config|
-                        new_config.users.iter().cloned().collect::<HashSet<_>>() != curr_users
-                    ).await?.users.iter().cloned().collect::<HashSet<_>>();
+                    curr_users = rtsp_users(&thread_config.wait_for(|new_config|
+                        rtsp_users(new_config) != curr_users
+                    ).await?);
 
                     let config = thread_config.borrow().clone();
                     if let Err(e) = apply_users(&thread_rtsp, &curr_users).await {
@@ -195,6 +205,79 @@ pub(crate) async fn main(_opt: Opt, reactor: NeoReactor) -> Result<()> {
         }
     });
 
+    // Startup and stop mosaics as they change in the config
+    let mut thread_config = reactor.config().await?;
+    let thread_cancel = global_cancel.clone();
+    let thread_rtsp = rtsp.clone();
+    let thread_reactor = reactor.clone();
+    set.spawn(async move {
+        tokio::select! {
+            _ = thread_cancel.cancelled() => AnyResult::Ok(()),
+            v = async {
+                let mut curr_mosaics = vec![];
+                loop {
+                    curr_mosaics = thread_config
+                        .wait_for(|config| config.mosaics != curr_mosaics)
+                        .await
+                        .with_context(|| "Mosaic Config Watcher")?
+                        .mosaics
+                        .clone();
+
+                    let mut mosaics = JoinSet::<AnyResult<()>>::new();
+                    for mosaic_config in curr_mosaics.iter().cloned() {
+                        let thread_rtsp2 = thread_rtsp.clone();
+                        let thread_reactor2 = thread_reactor.clone();
+                        log::info!("{}: Mosaic Starting", mosaic_config.name);
+                        mosaics.spawn(async move {
+                            let name = mosaic_config.name.clone();
+                            if let Err(e) =
+                                mosaic::mosaic_main(mosaic_config, thread_reactor2, &thread_rtsp2)
+                                    .await
+                            {
+                                log::error!("{name}: Mosaic stopped: {e:?}");
+                            }
+                            AnyResult::Ok(())
+                        });
+                    }
+                    // Keep them running until the config changes
+                    let prev_mosaics = curr_mosaics.clone();
+                    thread_config
+                        .wait_for(|config| config.mosaics != prev_mosaics)
+                        .await?;
+                    mosaics.abort_all();
+                    while mosaics.join_next().await.is_some() {}
+                    for mosaic_config in prev_mosaics.iter() {
+                        if let Some(mounts) = thread_rtsp.mount_points() {
+                            mounts.remove_factory(&format!("/{}", mosaic_config.name));
+                        }
+                    }
+                }
+            } => v,
+        }
+    });
+
+    // Keeps the cameras inside the bandwidth budget
+    let thread_cancel = global_cancel.clone();
+    let thread_reactor = reactor.clone();
+    // It restores the cameras itself when cancelled
+    set.spawn(bandwidth_main(thread_reactor, thread_cancel));
+
+    // Stop cleanly on Ctrl-C so that the lowered cameras are restored
+    let thread_cancel = global_cancel.clone();
+    let thread_rtsp = rtsp.clone();
+    set.spawn(async move {
+        tokio::select! {
+            _ = thread_cancel.cancelled() => AnyResult::Ok(()),
+            v = tokio::signal::ctrl_c() => {
+                v?;
+                log::info!("RTSP stopping");
+                thread_cancel.cancel();
+                thread_rtsp.quit().await?;
+                AnyResult::Ok(())
+            },
+        }
+    });
+
     let rtsp_config = reactor.config().await?.borrow().clone();
     info!(
         "Starting RTSP Server at {}:{}",
@@ -230,24 +313,63 @@ pub(crate) async fn main(_opt: Opt, reactor: NeoReactor) -> Result<()> {
     Ok(())
 }
 
+/// The users of the config by their role
+///
+/// This is the `[[users]]` and the `[[cameras.users]]` of the enabled cameras
+fn rtsp_users(config: &Config) -> HashMap<String, UserConfig> {
+    config
+        .users
+        .iter()
+        .map(|user| (user.name.clone(), user.clone()))
+        .chain(
+            config
+                .cameras
+                .iter()
+                .filter(|camera| camera.enabled)
+                .flat_map(|camera| {
+                    camera
+                        .users
+                        .iter()
+                        .map(|user| (camera_role(&camera.name, &user.name), user.clone()))
+                }),
+        )
+        .collect()
+}
+
 /// This keeps the users in rtsp and the config in sync
-async fn apply_users(rtsp: &NeoRtspServer, curr_users: &HashSet<UserConfig>) -> AnyResult<()> {
+async fn apply_users(
+    rtsp: &NeoRtspServer,
+    curr_users: &HashMap<String, UserConfig>,
+) -> AnyResult<()> {
     // Add those missing
-    for user in curr_users.iter() {
-        log::debug!("Adding user {} to rtsp server", user.name);
-        rtsp.add_user(&user.name, &user.pass).await?;
+    for (role, user) in curr_users.iter() {
+        log::debug!("Adding user {} to rtsp server", role);
+        rtsp.add_user(role, &user.name, &user.pass).await?;
     }
     // Remove unused
     let rtsp_users = rtsp.get_users().await?;
-    for user in rtsp_users {
-        if !curr_users.iter().any(|a| a.name == user) {
-            log::debug!("Removing user {} from rtsp server", user);
-            rtsp.remove_user(&user).await?;
+    for role in rtsp_users {
+        if !curr_users.contains_key(&role) {
+            log::debug!("Removing user {} from rtsp server", role);
+            rtsp.remove_user(&role).await?;
         }
     }
     Ok(())
 }
 
+/// The `[[users]]` known to the rtsp server
+///
+/// These are the users that can watch any camera that does not limit them
+/// with `permitted_users`. The users of a single camera are left out
+async fn shared_users(rtsp: &NeoRtspServer) -> AnyResult<HashSet<String>> {
+    Ok(rtsp
+        .get_users()
+        .await?
+        .into_iter()
+        .filter(|a| a != "anyone" && a != "anonymous" && !a.contains(':'))
+        .collect())
+}
+
 /// Top level camera entry point
 ///
 /// It checks which streams are supported and then starts them
@@ -291,22 +413,40 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
         AnyResult::Ok(())
     });
 
+    set.spawn(unix_socket_main(camera.clone()));
+    set.spawn(srt_main(camera.clone()));
+    set.spawn(segments_main(camera.clone()));
+
     log::debug!("{name}: Camera Main::Loop");
 
     let mut camera_config = camera.config().await?.clone();
+    let mut paused = camera.paused().await?;
     loop {
         let prev_stream_config = camera_config.borrow_and_update().stream;
         let prev_stream_users = camera_config.borrow().permitted_users.clone();
+        let own_users = camera_config
+            .borrow()
+            .users
+            .iter()
+            .map(|user| camera_role(&name, &user.name))
+            .collect::<HashSet<_>>();
         let active_streams = prev_stream_config
             .as_stream_kinds()
             .drain(..)
             .collect::<HashSet<_>>();
         let use_splash = camera_config.borrow().use_splash;
-        let splash_pattern = camera_config.borrow().splash_pattern.to_string();
+        let splash = Splash::new(&camera_config.borrow());
+        // A paused camera only has the splash on its paths
+        let is_paused = *paused.borrow_and_update();
+        let (dummy_use_splash, dummy_splash) = if is_paused {
+            (true, splash.clone().paused())
+        } else {
+            (use_splash, splash.clone())
+        };
 
         // This select is for changes to camera_config.stream
         break tokio::select! {
-            v = camera_config.wait_for(|config| config.stream != prev_stream_config || config.permitted_users != prev_stream_users || config.use_splash != use_splash) => {
+            v = camera_config.wait_for(|config| config.stream != prev_stream_config || config.permitted_users != prev_stream_users || config.users.iter().map(|user| camera_role(&name, &user.name)).collect::<HashSet<_>>() != own_users || config.use_splash != use_splash || Splash::new(config) != splash) => {
                 if let Err(e) = v {
                     AnyResult::Err(e.into())
                 } else {
@@ -314,15 +454,29 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
                     continue;
                 }
             },
+            v = paused.wait_for(|paused| *paused != is_paused) => {
+                if let Err(e) = v {
+                    AnyResult::Err(e.into())
+                } else {
+                    if is_paused {
+                        log::info!("{name}: Stream resumed");
+                    } else {
+                        log::info!("{name}: Stream paused");
+                    }
+                    continue;
+                }
+            },
             v = async {
                 // This select handles enabling the right stream
                 log::debug!("{name}: Camera Main::Select Stream");
                 // and setting up the users
-                let all_users = rtsp.get_users().await?.iter().filter(|a| *a != "anyone" && *a != "anonymous").cloned().collect::<HashSet<_>>();
-                let permitted_users: HashSet<String> = match &prev_stream_users {
+                let all_users = shared_users(rtsp).await?;
+                let mut permitted_users: HashSet<String> = match &prev_stream_users {
                     // If in the camera config there is the user "anyone", or if none is specified but users
                     // are defined at all, then we add all users to the camera's allowed list.
                     Some(p) if p.iter().any(|u| u == "anyone") => all_users,
+                    // A camera with its own users only lets those in
+                    None if !own_users.is_empty() => HashSet::new(),
                     None if !all_users.is_empty() => all_users,
 
                     // The user specified permitted_users
@@ -331,9 +485,10 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
                     // The user didn't specify permitted_users, and there are none defined anyway
                     None => ["anonymous".to_string()].iter().cloned().collect(),
                 };
+                permitted_users.extend(own_users.iter().cloned());
 
                 // Create the dummy factory
-                let dummy_factory = make_dummy_factory(use_splash, splash_pattern).await?;
+                let (dummy_factory, splash_clients) = make_dummy_factory(dummy_use_splash, dummy_splash.clone()).await?;
                 dummy_factory.add_permitted_roles(&permitted_users);
                 let mut supported_streams_1 = supported_streams.clone();
                 let mut supported_streams_2 = supported_streams.clone();
@@ -367,8 +522,8 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
                         }
                         log::debug!("{}: Preparing at {}", name, paths.join(", "));
 
-                        supported_streams_1.wait_for(|ss| ss.contains(&StreamKind::Main)).await?;
-                        stream_main(camera.stream(StreamKind::Main).await?, camera.clone(), rtsp, &permitted_users, &paths).await
+                        supported_streams_1.wait_for(|ss| !is_paused && ss.contains(&StreamKind::Main)).await?;
+                        stream_with_fallback(StreamKind::Main, camera.clone(), rtsp, &permitted_users, &paths, &splash_clients).await
                     }, if active_streams.contains(&StreamKind::Main) => v,
                     v = async {
                         log::debug!("{name}: Camera Main::Select Sub");
@@ -402,8 +557,8 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
                         }
                         log::debug!("{}: Preparing at {}", name, paths.join(", "));
 
-                        supported_streams_2.wait_for(|ss| ss.contains(&StreamKind::Sub)).await?;
-                        stream_main(camera.stream(StreamKind::Sub).await?,camera.clone(), rtsp, &permitted_users, &paths).await
+                        supported_streams_2.wait_for(|ss| !is_paused && ss.contains(&StreamKind::Sub)).await?;
+                        stream_with_fallback(StreamKind::Sub, camera.clone(), rtsp, &permitted_users, &paths, &splash_clients).await
                     }, if active_streams.contains(&StreamKind::Sub) => v,
                     v = async {
                         log::debug!("{name}: Camera Main::Select Extern");
@@ -436,8 +591,8 @@ async fn camera_main(camera: NeoInstance, rtsp: &NeoRtspServer) -> Result<()> {
                         }
                         log::debug!("{}: Preparing at {}", name, paths.join(", "));
 
-                        supported_streams_3.wait_for(|ss| ss.contains(&StreamKind::Extern)).await?;
-                        stream_main(camera.stream(StreamKind::Extern).await?,camera.clone(), rtsp, &permitted_users, &paths).await
+                        supported_streams_3.wait_for(|ss| !is_paused && ss.contains(&StreamKind::Extern)).await?;
+                        stream_with_fallback(StreamKind::Extern, camera.clone(), rtsp, &permitted_users, &paths, &splash_clients).await
                     }, if active_streams.contains(&StreamKind::Extern) => v,
                     else => {
                         // all disabled just wait here until config is changed
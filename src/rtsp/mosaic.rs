@@ -0,0 +1,192 @@
+//! Serves several cameras composed into a single grid
+//!
+//! Each camera's low resolution stream is decoded and placed on
+//! a tile of the grid, the grid is then reencoded as h264. This
+//! requires much more cpu than the normal streams so is opt-in
+//! with the `[[mosaics]]` config
+use anyhow::{anyhow, Result};
+use futures::future::select_all;
+use std::collections::HashSet;
+use tokio::{
+    task::JoinSet,
+    time::{timeout, Duration},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use super::{factory::*, gst::NeoRtspServer, shared_users, stream::*};
+use crate::{
+    common::{NeoReactor, StreamConfig, StreamInstance},
+    config::{MosaicConfig, TimestampSource},
+    AnyResult,
+};
+
+/// How long to wait for each camera's stream before it is
+/// shown as an offline tile instead
+const INPUT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Top level mosaic entry point
+///
+/// Restarts the mosaic whenever one of the input streams changes format
+/// or an offline input comes online
+pub(super) async fn mosaic_main(
+    config: MosaicConfig,
+    reactor: NeoReactor,
+    rtsp: &NeoRtspServer,
+) -> Result<()> {
+    let name = config.name.clone();
+    if config.cameras.is_empty() {
+        return Err(anyhow!("{name}: Mosaic has no cameras"));
+    }
+
+    let mut instances = vec![];
+    for camera_name in config.cameras.iter() {
+        let camera = reactor.get(camera_name).await?;
+        let kind = camera
+            .low_stream()
+            .await?
+            .ok_or(anyhow!(
+                "{name}: {camera_name} has no stream for the mosaic"
+            ))?
+            .name;
+        instances.push(camera.decode_stream(kind).await?);
+    }
+
+    let all_users = shared_users(rtsp).await?;
+    let permitted_users: HashSet<String> = match &config.permitted_users {
+        Some(p) if p.iter().any(|u| u == "anyone") => all_users,
+        None if !all_users.is_empty() => all_users,
+        Some(p) => p.iter().cloned().collect(),
+        None => ["anonymous".to_string()].iter().cloned().collect(),
+    };
+
+    loop {
+        // The stream must be active to learn its format
+        // after that it is only pulled while there are clients
+        //
+        // Offline inputs are kept active so that the camera keeps trying
+        // and we can reload once they are ready
+        let mut inputs = vec![];
+        for (instance, camera_name) in instances.iter_mut().zip(config.cameras.iter()) {
+            instance.activate().await?;
+            let mut watcher = instance.config.clone();
+            match timeout(INPUT_TIMEOUT, watcher.wait_for(|config| config.vid_ready())).await {
+                Ok(stream_config) => {
+                    let stream_config = stream_config?.clone();
+                    instance.deactivate().await?;
+                    inputs.push(Some(stream_config));
+                }
+                Err(_) => {
+                    log::warn!("{name}: {camera_name} is not ready. Showing it as offline");
+                    inputs.push(None);
+                }
+            }
+        }
+
+        let mut watchers = instances
+            .iter()
+            .map(|instance| instance.config.clone())
+            .collect::<Vec<_>>();
+        let changed = select_all(
+            watchers
+                .iter_mut()
+                .zip(inputs.iter())
+                .map(|(watcher, input)| {
+                    Box::pin(async move {
+                        match input {
+                            Some(input) => watcher.wait_for(|config| config != input).await?,
+                            None => watcher.wait_for(|config| config.vid_ready()).await?,
+                        };
+                        AnyResult::Ok(())
+                    })
+                }),
+        );
+
+        let result = tokio::select! {
+            (v, _, _) = changed => {
+                v.map(|_| true)
+            },
+            v = mosaic_run(&config, &mut instances, &inputs, rtsp, &permitted_users) => v.map(|_| false),
+        };
+        for (instance, input) in instances.iter_mut().zip(inputs.iter()) {
+            if input.is_none() {
+                instance.deactivate().await?;
+            }
+        }
+        if result? {
+            log::info!("{name}: Mosaic input changed. Reloading");
+            continue;
+        }
+        break Ok(());
+    }
+}
+
+/// Mounts the mosaic and pushes the camera data into each new client
+async fn mosaic_run(
+    config: &MosaicConfig,
+    instances: &mut [StreamInstance],
+    inputs: &[Option<StreamConfig>],
+    rtsp: &NeoRtspServer,
+    users: &HashSet<String>,
+) -> AnyResult<()> {
+    let mounts = rtsp
+        .mount_points()
+        .ok_or(anyhow!("RTSP server lacks mount point"))?;
+    let (factory, mut client_rx) = make_mosaic_factory(
+        inputs.to_vec(),
+        config.columns,
+        [config.tile_width, config.tile_height],
+    )
+    .await?;
+    factory.add_permitted_roles(users);
+    let path = format!("/{}", config.name);
+    mounts.add_factory(&path, factory);
+    log::info!("{}: Mosaic available at {}", config.name, path);
+
+    let stream_cancel = CancellationToken::new();
+    let drop_guard = stream_cancel.clone().drop_guard();
+    let mut set = JoinSet::new();
+    while let Some(apps) = client_rx.recv().await {
+        log::debug!("{}: New mosaic media", config.name);
+        for ((app, instance), input) in apps
+            .into_iter()
+            .zip(instances.iter_mut())
+            .zip(inputs.iter())
+        {
+            // Offline inputs are a placeholder tile with nothing to push
+            let (app, input) = match (app, input) {
+                (Some(app), Some(input)) => (app, input),
+                _ => continue,
+            };
+            let vid_rx = BroadcastStream::new(instance.vid.resubscribe()).filter(|f| f.is_ok());
+            let mut activator = instance.activator_handle().await;
+            let framerate = Duration::from_millis(1000u64 / std::cmp::max(input.fps as u64, 5u64));
+            let thread_stream_cancel = stream_cancel.clone();
+            set.spawn(async move {
+                activator.activate().await?;
+                let r = tokio::select! {
+                    _ = thread_stream_cancel.cancelled() => AnyResult::Ok(()),
+                    v = send_to_appsrc(
+                        frametime_stream(
+                            ensure_order(
+                                wait_for_keyframe(vid_rx)
+                            ),
+                            framerate
+                        ),
+                        &app,
+                        // Each input is on its own clock so keep them in step by arrival
+                        TimestampSource::Arrival
+                    ) => v,
+                };
+                drop(activator);
+                let _ = app.end_of_stream();
+                log::debug!("Mosaic Thread End: {:?}", r);
+                r
+            });
+        }
+    }
+    stream_cancel.cancel();
+    drop(drop_guard);
+    while set.join_next().await.is_some() {}
+    AnyResult::Ok(())
+}
@@ -0,0 +1,213 @@
+//! Records the video of a camera to a series of mp4 files
+//!
+//! The stream is fed to `splitmuxsink` which starts a new file at the first
+//! keyframe after each `duration` so every file plays on its own. A leaky
+//! queue sits before the muxer so a slow disk drops recorded frames rather
+//! than holding up the camera's stream. This runs alongside the rtsp server
+//! and pulls the camera's stream for as long as it is set
+use anyhow::{anyhow, Context};
+use gstreamer::{prelude::*, ClockTime, MessageView, Pipeline, State};
+use gstreamer_app::AppSrc;
+use std::path::PathBuf;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use super::{factory::make_element, stream::*};
+use crate::{
+    common::{NeoInstance, VidFormat},
+    config::SegmentConfig,
+    utils::now_local,
+    AnyResult,
+};
+
+/// Time allowed for the last file to be finished when stopping
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs the recording restarting it if its config changes
+pub(super) async fn segments_main(camera: NeoInstance) -> AnyResult<()> {
+    let mut config = camera.config().await?;
+    let name = config.borrow().name.clone();
+    loop {
+        let segments = config.borrow_and_update().segments.clone();
+        tokio::select! {
+            v = config.wait_for(|config| config.segments != segments) => {
+                v?;
+            },
+            v = async {
+                match segments.as_ref() {
+                    Some(segments) => segments_run(&name, &camera, segments).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                match v {
+                    Ok(()) => {
+                        // Stream ended or changed format, start again
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                    Err(e) => {
+                        // Wait for the user to fix the config
+                        log::error!("{name}: Mp4 recording stopped: {e:?}");
+                        config.wait_for(|config| config.segments != segments).await?;
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn segments_run(name: &str, camera: &NeoInstance, segments: &SegmentConfig) -> AnyResult<()> {
+    let kind = segments
+        .stream
+        .as_stream_kinds()
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No stream selected for the mp4 recording"))?;
+
+    let dir = PathBuf::from(&segments.path);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let mut stream = camera.stream(kind).await?;
+    stream.activate().await?;
+    let (format, fps) = {
+        let config = stream.config.wait_for(|config| config.vid_ready()).await?;
+        (config.vid_format.clone(), config.fps)
+    };
+
+    let timestamps = camera.config().await?.borrow().timestamps;
+    let (pipeline, appsrc) = build_pipeline(name, &format, &dir, segments.duration)?;
+    let _stop = NullOnDrop(pipeline.clone());
+    pipeline.set_state(State::Playing)?;
+    log::info!(
+        "{name}: Recording the {kind} stream to {} in {}s mp4 files",
+        dir.display(),
+        segments.duration
+    );
+
+    let mut bus = pipeline
+        .bus()
+        .ok_or_else(|| anyhow!("Pipeline without bus"))?
+        .stream();
+    let framerate = Duration::from_millis(1000u64 / std::cmp::max(fps as u64, 5u64));
+    let frames = BroadcastStream::new(stream.vid.resubscribe()).filter(|f| f.is_ok()); // Filter to ignore lagged
+    let r = tokio::select! {
+        v = async {
+            while let Some(msg) = bus.next().await {
+                match msg.view() {
+                    MessageView::Error(err) => {
+                        return Err(anyhow!("Error from gstreamer: {:?}", err.error()));
+                    }
+                    MessageView::Eos(..) => break,
+                    _ => {}
+                }
+            }
+            AnyResult::Ok(())
+        } => v,
+        v = stream.config.wait_for(|config| config.vid_format != format) => {
+            v?;
+            log::info!("{name}: Video format changed, starting a new mp4 recording");
+            AnyResult::Ok(())
+        },
+        v = send_to_appsrc(
+            frametime_stream(
+                ensure_order(
+                    wait_for_keyframe(frames)
+                ),
+                framerate
+            ),
+            &appsrc,
+            timestamps
+        ) => v,
+    };
+    stream.deactivate().await?;
+
+    // Finish the last file, mp4 is unplayable without its index
+    if appsrc.end_of_stream().is_ok() {
+        let finished = timeout(FINISH_TIMEOUT, async {
+            while let Some(msg) = bus.next().await {
+                if let MessageView::Eos(..) | MessageView::Error(..) = msg.view() {
+                    break;
+                }
+            }
+        })
+        .await;
+        if finished.is_err() {
+            log::warn!("{name}: The last mp4 file was not finished in time");
+        }
+    }
+    r
+}
+
+fn build_pipeline(
+    name: &str,
+    format: &VidFormat,
+    dir: &std::path::Path,
+    duration: u64,
+) -> AnyResult<(Pipeline, AppSrc)> {
+    gstreamer::init()
+        .context("Unable to start gstreamer ensure it and all plugins are installed")?;
+    let parser = match format {
+        VidFormat::H264 => make_element("h264parse", "segments_parser")?,
+        VidFormat::H265 => make_element("h265parse", "segments_parser")?,
+        VidFormat::None => return Err(anyhow!("Cannot record mp4 without video")),
+    };
+
+    let source = make_element("appsrc", "segments_source")?
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot cast to appsrc."))?;
+    source.set_is_live(true);
+    source.set_block(false);
+    source.set_min_latency(0);
+    source.set_property("emit-signals", false);
+    source.set_format(gstreamer::Format::Time);
+    let source = source
+        .dynamic_cast::<gstreamer::Element>()
+        .map_err(|_| anyhow!("Cannot cast back"))?;
+
+    // Drop frames rather than block when the disk cannot keep up
+    let queue = make_element("queue", "segments_queue")?;
+    queue.set_property_from_str("leaky", "downstream");
+    queue.set_property("max-size-bytes", 0u32);
+    queue.set_property("max-size-buffers", 0u32);
+    queue.set_property("max-size-time", ClockTime::from_seconds(5).nseconds());
+
+    let sink = make_element("splitmuxsink", "segments_sink")?;
+    sink.set_property(
+        "max-size-time",
+        ClockTime::from_seconds(duration).nseconds(),
+    );
+    let dir = dir.to_path_buf();
+    let name = name.to_string();
+    sink.connect("format-location", false, move |_| {
+        let now = now_local();
+        let time = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let path = dir.join(format!("{name}-{time}.mp4"));
+        log::debug!("{name}: Starting mp4 file {}", path.display());
+        Some(path.to_string_lossy().to_string().to_value())
+    });
+
+    let pipeline = Pipeline::new();
+    pipeline.add_many([&source, &parser, &queue, &sink])?;
+    gstreamer::Element::link_many([&source, &parser, &queue, &sink])?;
+
+    let source = source
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot convert appsrc"))?;
+    Ok((pipeline, source))
+}
+
+/// Stops the pipeline when dropped
+struct NullOnDrop(Pipeline);
+
+impl Drop for NullOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.set_state(State::Null);
+    }
+}
@@ -0,0 +1,168 @@
+//! Sends the video of a camera over SRT
+//!
+//! SRT copes with packet loss far better than rtsp over udp so it is
+//! useful for viewing over the internet. The stream is muxed into mpeg-ts
+//! and handed to `srtsink` which, depending on the uri, either listens for
+//! viewers or calls out to a server such as mediamtx. This runs alongside
+//! the rtsp server and pulls the camera's stream for as long as it is set
+use anyhow::{anyhow, Context};
+use gstreamer::{prelude::*, MessageView, Pipeline, State};
+use gstreamer_app::AppSrc;
+use tokio::time::{sleep, Duration};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use super::{factory::make_element, stream::*};
+use crate::{
+    common::{NeoInstance, VidFormat},
+    config::SrtConfig,
+    AnyResult,
+};
+
+/// Runs the srt output restarting it if its config changes
+pub(super) async fn srt_main(camera: NeoInstance) -> AnyResult<()> {
+    let mut config = camera.config().await?;
+    let name = config.borrow().name.clone();
+    loop {
+        let srt = config.borrow_and_update().srt.clone();
+        tokio::select! {
+            v = config.wait_for(|config| config.srt != srt) => {
+                v?;
+            },
+            v = async {
+                match srt.as_ref() {
+                    Some(srt) => srt_run(&name, &camera, srt).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                match v {
+                    Ok(()) => {
+                        // Stream ended or changed format, start again
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                    Err(e) => {
+                        // Wait for the user to fix the config
+                        log::error!("{name}: SRT output stopped: {e:?}");
+                        config.wait_for(|config| config.srt != srt).await?;
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn srt_run(name: &str, camera: &NeoInstance, srt: &SrtConfig) -> AnyResult<()> {
+    let kind = srt
+        .stream
+        .as_stream_kinds()
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No stream selected for srt"))?;
+
+    let mut stream = camera.stream(kind).await?;
+    stream.activate().await?;
+    let (format, fps) = {
+        let config = stream.config.wait_for(|config| config.vid_ready()).await?;
+        (config.vid_format.clone(), config.fps)
+    };
+
+    let timestamps = camera.config().await?.borrow().timestamps;
+    let uri = srt.uri();
+    let (pipeline, appsrc) = build_pipeline(&format, &uri, srt.passphrase.as_deref())?;
+    let _stop = NullOnDrop(pipeline.clone());
+    pipeline.set_state(State::Playing)?;
+    log::info!("{name}: {kind} stream available over SRT at {uri}");
+
+    let mut bus = pipeline
+        .bus()
+        .ok_or_else(|| anyhow!("Pipeline without bus"))?
+        .stream();
+    let framerate = Duration::from_millis(1000u64 / std::cmp::max(fps as u64, 5u64));
+    let frames = BroadcastStream::new(stream.vid.resubscribe()).filter(|f| f.is_ok()); // Filter to ignore lagged
+    let r = tokio::select! {
+        v = async {
+            while let Some(msg) = bus.next().await {
+                match msg.view() {
+                    MessageView::Error(err) => {
+                        return Err(anyhow!("Error from gstreamer: {:?}", err.error()));
+                    }
+                    MessageView::Eos(..) => break,
+                    _ => {}
+                }
+            }
+            AnyResult::Ok(())
+        } => v,
+        v = stream.config.wait_for(|config| config.vid_format != format) => {
+            v?;
+            log::info!("{name}: Video format changed, restarting SRT output");
+            AnyResult::Ok(())
+        },
+        v = send_to_appsrc(
+            frametime_stream(
+                ensure_order(
+                    wait_for_keyframe(frames)
+                ),
+                framerate
+            ),
+            &appsrc,
+            timestamps
+        ) => v,
+    };
+    stream.deactivate().await?;
+    r
+}
+
+fn build_pipeline(
+    format: &VidFormat,
+    uri: &str,
+    passphrase: Option<&str>,
+) -> AnyResult<(Pipeline, AppSrc)> {
+    gstreamer::init()
+        .context("Unable to start gstreamer ensure it and all plugins are installed")?;
+    let parser = match format {
+        VidFormat::H264 => make_element("h264parse", "srt_parser")?,
+        VidFormat::H265 => make_element("h265parse", "srt_parser")?,
+        VidFormat::None => return Err(anyhow!("Cannot send srt without video")),
+    };
+    // Resend the parameter sets with every keyframe so viewers can join at any time
+    parser.set_property("config-interval", -1i32);
+
+    let source = make_element("appsrc", "srt_source")?
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot cast to appsrc."))?;
+    source.set_is_live(true);
+    source.set_block(false);
+    source.set_min_latency(0);
+    source.set_property("emit-signals", false);
+    source.set_format(gstreamer::Format::Time);
+    let source = source
+        .dynamic_cast::<gstreamer::Element>()
+        .map_err(|_| anyhow!("Cannot cast back"))?;
+
+    let muxer = make_element("mpegtsmux", "srt_muxer")?;
+    let sink = make_element("srtsink", "srt_sink")?;
+    sink.set_property("uri", uri);
+    if let Some(passphrase) = passphrase {
+        sink.set_property("passphrase", passphrase);
+    }
+    // Don't hold up the pipeline when there are no viewers
+    sink.set_property("wait-for-connection", false);
+    sink.set_property("sync", false);
+
+    let pipeline = Pipeline::new();
+    pipeline.add_many([&source, &parser, &muxer, &sink])?;
+    gstreamer::Element::link_many([&source, &parser, &muxer, &sink])?;
+
+    let source = source
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| anyhow!("Cannot convert appsrc"))?;
+    Ok((pipeline, source))
+}
+
+/// Stops the pipeline, and closes the srt socket, when dropped
+struct NullOnDrop(Pipeline);
+
+impl Drop for NullOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.set_state(State::Null);
+    }
+}
@@ -1,24 +1,31 @@
 use anyhow::{anyhow, Result};
+use futures::future::select_all;
 use gstreamer::{prelude::*, ClockTime, FlowError};
 use gstreamer_app::AppSrc;
-use gstreamer_rtsp_server::prelude::*;
-use std::collections::HashSet;
+use gstreamer_rtsp_server::{prelude::*, RTSPMountPoints};
+use std::collections::{HashSet, VecDeque};
 use std::sync::Arc;
 use tokio::{
-    sync::{broadcast::channel as broadcast, watch::channel as watch},
+    sync::{
+        broadcast::{channel as broadcast, Sender as BroadcastSender},
+        mpsc::channel as mpsc,
+        watch::{channel as watch, Receiver as WatchReceiver},
+    },
     task::JoinSet,
     time::{interval, sleep, Duration},
 };
 use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
 
-use crate::common::{Permit, StampedData, UseCounter};
+use crate::common::{stream_metrics, Permit, StampedData, UseCounter};
 use crate::{
-    common::{NeoInstance, StreamConfig, StreamInstance},
+    common::{AudFormat, NeoInstance, StreamConfig, StreamInstance, VidFormat},
+    config::TimestampSource,
     AnyResult,
 };
+use neolink_core::bc_protocol::StreamKind;
 
-use super::{factory::*, gst::NeoRtspServer};
+use super::{backchannel::TalkBack, factory::*, gst::NeoRtspServer};
 
 #[derive(Clone)]
 struct PauseAffectors {
@@ -27,18 +34,131 @@ struct PauseAffectors {
     client: bool,
 }
 
+/// Serve the stream, if it cannot be built then try the streams in
+/// `stream_fallback` in order on the same paths
+///
+/// If none of them can be built the splash that is already
+/// mounted on the paths is left in place
+///
+/// The choice is made again when the fallbacks or the pipeline settings
+/// of the streams that failed change, or while nothing could be built,
+/// when the format of one of the streams changes
+pub(super) async fn stream_with_fallback(
+    kind: StreamKind,
+    camera: NeoInstance,
+    rtsp: &NeoRtspServer,
+    users: &HashSet<String>,
+    paths: &[String],
+    splash_clients: &SplashClients,
+) -> Result<()> {
+    let mut camera_config = camera.config().await?;
+    loop {
+        let config = camera_config.borrow_and_update().clone();
+        let name = config.name.clone();
+        let mut kinds = vec![kind];
+        for fallback in config
+            .stream_fallback
+            .iter()
+            .flat_map(|stream| stream.as_stream_kinds())
+        {
+            if !kinds.contains(&fallback) {
+                kinds.push(fallback);
+            }
+        }
+
+        let mut failed = vec![];
+        let mut selected = None;
+        for stream_kind in kinds {
+            let mut stream_instance = camera.stream(stream_kind).await?;
+            stream_instance
+                .config
+                .wait_for(|config| config.vid_ready())
+                .await?;
+            let stream_config = stream_instance.config.borrow().clone();
+            match check_factory(&stream_config, &FactoryOptions::new(&config, stream_kind)) {
+                Ok(()) => {
+                    if stream_kind != kind {
+                        log::warn!("{name}: Serving the {stream_kind} in place of the {kind}");
+                    }
+                    selected = Some(stream_instance);
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("{name}: Unable to build the {stream_kind}: {e:?}");
+                    failed.push((stream_kind, stream_config, stream_instance));
+                }
+            }
+        }
+
+        let failed_options = failed
+            .iter()
+            .map(|(stream_kind, _, _)| (*stream_kind, FactoryOptions::new(&config, *stream_kind)))
+            .collect::<Vec<_>>();
+        let selection_changed = camera_config.wait_for(|new_conf| {
+            new_conf.stream_fallback != config.stream_fallback
+                || failed_options.iter().any(|(stream_kind, options)| {
+                    FactoryOptions::new(new_conf, *stream_kind) != *options
+                })
+        });
+
+        match selected {
+            Some(stream_instance) => {
+                // Only the chosen stream is pulled while serving
+                drop(failed);
+                tokio::select! {
+                    v = selection_changed => {
+                        v?;
+                    },
+                    v = stream_main(stream_instance, camera.clone(), rtsp, users, paths, splash_clients) => return v,
+                }
+            }
+            None => {
+                log::warn!(
+                    "{name}: No stream could be built for the {kind}. Serving the splash only"
+                );
+                // Keep the failed streams so that a change in their format is seen
+                let format_changed = select_all(failed.iter_mut().map(
+                    |(_, stream_config, stream_instance)| {
+                        let stream_config = stream_config.clone();
+                        Box::pin(async move {
+                            stream_instance
+                                .config
+                                .wait_for(|config| config.vid_ready() && config != &stream_config)
+                                .await?;
+                            AnyResult::Ok(())
+                        })
+                    },
+                ));
+                tokio::select! {
+                    v = selection_changed => {
+                        v?;
+                    },
+                    (v, _, _) = format_changed => {
+                        v?;
+                    },
+                }
+            }
+        }
+        log::info!("{name}: Stream settings changed. Choosing the {kind} stream again");
+    }
+}
+
 /// This handles the stream by activating and deacivating it as required
-pub(super) async fn stream_main(
+async fn stream_main(
     mut stream_instance: StreamInstance,
     camera: NeoInstance,
     rtsp: &NeoRtspServer,
     users: &HashSet<String>,
     paths: &[String],
+    splash_clients: &SplashClients,
 ) -> Result<()> {
     let mut camera_config = camera.config().await?.clone();
     let name = camera_config.borrow().name.clone();
+    let kind = stream_instance.name;
 
     let mut curr_pause;
+    let mut curr_factory_options;
+    let mut curr_adaptive;
     loop {
         let this_loop_cancel = CancellationToken::new();
         let _drop_guard = this_loop_cancel.clone().drop_guard();
@@ -70,7 +190,72 @@ pub(super) async fn stream_main(
             v?;
         }
 
+        // Some cameras send a few frames in the wrong format after connecting.
+        // Keep pulling until the format has not changed for the whole warmup
+        // so that clients do not get the wrong format
+        let warmup = Duration::from_millis(camera_config.borrow().stream_warmup);
+        if !warmup.is_zero() {
+            log::debug!("{}: Warming up stream for {:?}", &name, warmup);
+            loop {
+                let current = stream_instance.config.borrow().clone();
+                match tokio::time::timeout(
+                    warmup,
+                    stream_instance.config.wait_for(|config| config != &current),
+                )
+                .await
+                {
+                    Ok(v) => {
+                        v?;
+                        log::debug!("{}: Stream format changed during warmup", &name);
+                    }
+                    Err(_) => break,
+                }
+            }
+            log::debug!("{}: Stream warmed up", &name);
+        }
+
         curr_pause = camera_config.borrow().pause.clone();
+        curr_factory_options = FactoryOptions::new(&camera_config.borrow(), kind);
+        curr_adaptive = {
+            let config = camera_config.borrow();
+            (config.adaptive_stream, config.adaptive_linger)
+        };
+        // The sub stream that new clients of the main stream start on
+        let upgrade_from = if curr_adaptive.0 && kind == StreamKind::Main {
+            if curr_pause.on_motion || curr_pause.on_disconnect {
+                log::info!(
+                    "{}: The pause settings take the place of the adaptive stream",
+                    &name
+                );
+                None
+            } else {
+                Some(camera.stream(StreamKind::Sub).await?)
+            }
+        } else {
+            None
+        };
+        log::debug!(
+            "{}: Sending {} audio as {:?}",
+            &name,
+            kind,
+            curr_factory_options.audio_codec
+        );
+        if let Some(preferred) = camera_config.borrow().audio_codecs.first() {
+            if *preferred != curr_factory_options.audio_codec {
+                log::info!(
+                    "{}: The gstreamer elements for {:?} audio are missing, using {:?}",
+                    &name,
+                    preferred,
+                    curr_factory_options.audio_codec
+                );
+            }
+        }
+
+        let talk_back = if curr_factory_options.talk_back {
+            TalkBack::new(&camera).await
+        } else {
+            None
+        };
 
         let last_stream_config = stream_instance.config.borrow().clone();
         let mut thread_stream_config = stream_instance.config.clone();
@@ -89,7 +274,7 @@ pub(super) async fn stream_main(
         let client_count = client_counter.create_deactivated().await?;
 
         // Client count affector
-        if curr_pause.on_motion {
+        if curr_pause.on_disconnect {
             let thread_name = name.clone();
             let client_count = client_counter.create_deactivated().await?;
             let thread_pause_affector_tx = pause_affector_tx.clone();
@@ -133,7 +318,7 @@ pub(super) async fn stream_main(
                         log::debug!("{}: Activating Motion Pause", &thread_name);
                         loop {
                             motion
-                                .wait_for(|md| matches!(md, crate::common::MdState::Start(_)))
+                                .wait_for(|md| matches!(md, crate::common::MdState::Start(..)))
                                 .await?;
                             log::info!("{}: Enabling Motion", thread_name);
                             thread_pause_affector_tx.send_modify(|current| {
@@ -233,6 +418,42 @@ pub(super) async fn stream_main(
             });
         }
 
+        if upgrade_from.is_some() {
+            // Pull the main stream only while it has clients, the sub stream
+            // is held for as long as `upgrade_from` is
+            let cancel = this_loop_cancel.clone();
+            let mut main_activator = stream_instance.activator_handle().await;
+            main_activator.deactivate().await?;
+            stream_instance.deactivate().await?;
+            let client_count = client_counter.create_deactivated().await?;
+            let linger = Duration::from_secs(curr_adaptive.1);
+            let thread_name = name.clone();
+            set.spawn(async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => AnyResult::Ok(()),
+                    v = async {
+                        loop {
+                            client_count.aquired_users().await?;
+                            log::info!("{}: Switching to the main stream", thread_name);
+                            main_activator.activate().await?;
+                            loop {
+                                client_count.dropped_users().await?;
+                                tokio::select! {
+                                    v = client_count.aquired_users() => {
+                                        v?;
+                                        continue;
+                                    },
+                                    _ = sleep(linger) => break,
+                                }
+                            }
+                            log::info!("{}: No clients, back to the sub stream", thread_name);
+                            main_activator.deactivate().await?;
+                        }
+                    } => v,
+                }
+            });
+        }
+
         // This thread jsut keeps it active for 5s after an initial start to build the buffer
         let cancel = this_loop_cancel.clone();
         let mut init_activator = stream_instance.activator_handle().await;
@@ -280,6 +501,10 @@ pub(super) async fn stream_main(
             v = thread_stream_config.wait_for(|new_conf| new_conf != &last_stream_config) => {
                 let v = v?;
                 // If stream config changes we reload the stream
+                if v.resolution != last_stream_config.resolution || v.vid_format != last_stream_config.vid_format {
+                    // The old sdp no longer matches the video, the clients must reconnect
+                    log::info!("{}: Video changed to {}x{} {:?}. Renegotiating RTSP clients", &name, v.resolution[0], v.resolution[1], v.vid_format);
+                }
                 log::info!("{}: Stream Configuration Changed. Reloading Streams", &name);
                 log::trace!("    From {:?} to {:?}", last_stream_config, v.clone());
                 continue;
@@ -290,32 +515,58 @@ pub(super) async fn stream_main(
                 log::info!("{}: Pause Configuration Changed. Reloading Streams", &name);
                 continue;
             },
-            v = stream_run(&name, &stream_instance, rtsp, &last_stream_config, users, paths, client_count) => v,
+            v = camera_config.wait_for(|new_conf| (new_conf.adaptive_stream, new_conf.adaptive_linger) != curr_adaptive ) => {
+                v?;
+                log::info!("{}: Adaptive Stream Configuration Changed. Reloading Streams", &name);
+                continue;
+            },
+            v = camera_config.wait_for(|new_conf| FactoryOptions::new(new_conf, kind) != curr_factory_options ) => {
+                v?;
+                log::info!("{}: Pipeline Configuration Changed. Reloading Streams", &name);
+                continue;
+            },
+            v = stream_run(&name, &stream_instance, rtsp, &last_stream_config, curr_factory_options.clone(), talk_back.as_ref(), upgrade_from.as_ref(), users, paths, splash_clients, client_count) => v,
         };
     }
 }
 
 /// This handles the stream itself by creating the factory and pushing messages into it
+#[allow(clippy::too_many_arguments)]
 async fn stream_run(
     name: &str,
     stream_instance: &StreamInstance,
     rtsp: &NeoRtspServer,
     stream_config: &StreamConfig,
+    factory_options: FactoryOptions,
+    talk_back: Option<&TalkBack>,
+    upgrade_from: Option<&StreamInstance>,
     users: &HashSet<String>,
     paths: &[String],
+    splash_clients: &SplashClients,
     client_count: Permit,
 ) -> AnyResult<()> {
     let vidstream = stream_instance.vid.resubscribe();
     let audstream = stream_instance.aud.resubscribe();
     let vid_history = stream_instance.vid_history.clone();
     let aud_history = stream_instance.aud_history.clone();
+    // The sub stream can only stand in if the client's decoder is the same
+    let upgrade_from = upgrade_from
+        .filter(|sub| sub.config.borrow().vid_format == stream_config.vid_format)
+        .map(|sub| (sub.vid.resubscribe(), sub.vid_history.clone()));
 
     // Finally ready to create the factory and connect the stream
     let mounts = rtsp
         .mount_points()
         .ok_or(anyhow!("RTSP server lacks mount point"))?;
+    let timestamps = factory_options.timestamps;
+    let audio_mount = factory_options.audio_mount;
     // Create the factory
-    let (factory, mut client_rx) = make_factory(stream_config).await?;
+    let (factory, mut client_rx) = make_factory(
+        stream_config,
+        factory_options.clone(),
+        talk_back.map(|talk_back| talk_back.launch()),
+    )
+    .await?;
 
     factory.add_permitted_roles(users);
 
@@ -324,16 +575,70 @@ async fn stream_run(
         mounts.add_factory(path, factory.clone());
     }
     log::info!("{}: Available at {}", name, paths.join(", "));
+    // Clients waiting on the splash connect again to get the stream
+    splash_clients.end(paths);
+
+    // The same audio without the video
+    let audio_paths = paths
+        .iter()
+        .map(|path| format!("{path}/audio"))
+        .collect::<Vec<_>>();
+    let _audio_mounts = RemoveOnDrop(mounts.clone(), audio_paths.clone());
+    let mut audio_rx = if audio_mount && !matches!(stream_config.aud_format, AudFormat::None) {
+        let audio_config = StreamConfig {
+            vid_format: VidFormat::None,
+            ..stream_config.clone()
+        };
+        let (audio_factory, audio_rx) = make_factory(&audio_config, factory_options, None).await?;
+        audio_factory.add_permitted_roles(users);
+        for path in audio_paths.iter() {
+            mounts.add_factory(path, audio_factory.clone());
+        }
+        log::info!("{}: Audio available at {}", name, audio_paths.join(", "));
+        audio_rx
+    } else {
+        if audio_mount {
+            log::info!(
+                "{name}: No audio in the {} stream to serve alone",
+                stream_instance.name
+            );
+        }
+        // Nothing is sent on this, it just ends
+        mpsc(1).1
+    };
 
     let stream_cancel = CancellationToken::new();
     let drop_guard = stream_cancel.clone().drop_guard();
+    // Ends the clients of this factory when the stream is rebuilt
+    let mut clients = EndOnDrop::default();
     let mut set = JoinSet::new();
-    // Wait for new media client data to come in from the factory
-    while let Some(mut client_data) = client_rx.recv().await {
+    // Wait for new media client data to come in from the factories
+    loop {
+        let mut client_data = tokio::select! {
+            v = client_rx.recv() => match v {
+                Some(client_data) => client_data,
+                None => break,
+            },
+            Some(client_data) = audio_rx.recv() => client_data,
+        };
         log::debug!("New media");
         // New media created
         let vid = client_data.vid.take().map(|data| data.app);
         let aud = client_data.aud.take().map(|data| data.app);
+        clients.0.retain(|app| check_live(app).is_ok());
+        clients.0.extend(vid.iter().chain(aud.iter()).cloned());
+
+        // Audio the client sends back for the camera's speaker
+        if let (Some(sink), Some(talk_back)) = (client_data.aud_back.take(), talk_back) {
+            let talk_back = talk_back.clone();
+            let thread_stream_cancel = stream_cancel.clone();
+            set.spawn(async move {
+                tokio::select! {
+                    _ = thread_stream_cancel.cancelled() => AnyResult::Ok(()),
+                    v = talk_back.run(sink) => v,
+                }
+            });
+        }
 
         // This is the data that gets sent to gstreamer thread
         // It represents the combination of the camera stream and the appsrc seek messages
@@ -347,12 +652,25 @@ async fn stream_run(
         let thread_vid_data_tx = vid_data_tx.clone();
         let thread_stream_cancel = stream_cancel.clone();
         let thread_vid_history = vid_history.clone();
+        let thread_upgrade_from = upgrade_from
+            .as_ref()
+            .map(|(vid, history)| (vid.resubscribe(), history.clone()));
         set.spawn(async move {
             let r = tokio::select! {
                 _ = thread_stream_cancel.cancelled() => AnyResult::Ok(()),
                 v = async {
-                    // Send Initial
-                    {
+                    let main_ready = thread_vid_history.borrow().iter().any(|data| data.keyframe);
+                    if let (Some((sub_vid, sub_history)), false) = (thread_upgrade_from, main_ready) {
+                        // Start on the sub stream until the main stream catches up
+                        start_on_sub(
+                            BroadcastStream::new(sub_vid),
+                            &sub_history,
+                            &mut vidstream,
+                            &thread_vid_data_tx,
+                        )
+                        .await?;
+                    } else {
+                        // Send Initial
                         let history = thread_vid_history.borrow();
                         // let last_ts = history.back().map(|s| s.ts);
                         for data in history.iter() {
@@ -428,6 +746,14 @@ async fn stream_run(
         let vid_data_rx = BroadcastStream::new(vid_data_rx).filter(|f| f.is_ok()); // Filter to ignore lagged
         let thread_vid = vid.clone();
         let mut thread_client_count = client_count.subscribe();
+        let client_metrics = stream_metrics(name, stream_instance.name).client();
+        // The audio keeps the stream going for the audio only clients
+        let mut aud_client = vid.is_none().then(|| {
+            (
+                client_count.subscribe(),
+                stream_metrics(name, stream_instance.name).client(),
+            )
+        });
         log::debug!("stream_config.fps: {}", stream_config.fps);
         // let fallback_time = Duration::from_secs(3);
         let framerate =
@@ -452,11 +778,13 @@ async fn stream_run(
                         //     fallback_time,
                         //     framerate,
                         // ),
-                        &thread_vid) => {
+                        &thread_vid,
+                        timestamps) => {
                         v
                     },
                 };
                 drop(thread_client_count);
+                drop(client_metrics);
                 let _ = thread_vid.end_of_stream();
                 log::debug!("Vid Thread End: {:?}", r);
                 r
@@ -471,6 +799,9 @@ async fn stream_run(
             Duration::from_millis(1000u64 / std::cmp::max(stream_config.fps as u64, 5u64));
         if let Some(thread_aud) = thread_aud {
             set.spawn(async move {
+                if let Some((client_count, _)) = aud_client.as_mut() {
+                    client_count.activate().await?;
+                }
                 let r = tokio::select! {
                     _ = thread_stream_cancel.cancelled() => {
                         AnyResult::Ok(())
@@ -483,10 +814,11 @@ async fn stream_run(
                                 )
                             ),
                             aud_framerate
-                        ), &thread_aud) => {
+                        ), &thread_aud, timestamps) => {
                         v
                     },
                 };
+                drop(aud_client);
                 let _ = thread_aud.end_of_stream();
                 log::debug!("Aud Thread End: {:?}", r);
                 r
@@ -504,6 +836,71 @@ async fn stream_run(
     AnyResult::Ok(())
 }
 
+/// Send the sub stream until the first keyframe of the main stream
+///
+/// The main stream is only pulled while it has clients and so a new client
+/// would otherwise wait for the camera's next keyframe. The keyframe carries
+/// the main stream's parameters and the resolution changes from there
+async fn start_on_sub<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
+    mut sub: BroadcastStream<StampedData>,
+    sub_history: &WatchReceiver<VecDeque<StampedData>>,
+    main: &mut T,
+    tx: &BroadcastSender<StampedData>,
+) -> AnyResult<()> {
+    {
+        let history = sub_history.borrow();
+        for data in history.iter() {
+            tx.send(data.clone())?;
+        }
+    }
+    loop {
+        tokio::select! {
+            frame = main.next() => match frame {
+                Some(Ok(data)) if data.keyframe => {
+                    tx.send(data)?;
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            frame = sub.next() => match frame {
+                Some(Ok(data)) => {
+                    tx.send(data)?;
+                }
+                Some(Err(_)) => {}
+                // Nothing more to stand in with, wait on the main stream
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Takes the factories off the paths when dropped
+struct RemoveOnDrop(RTSPMountPoints, Vec<String>);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        for path in self.1.iter() {
+            self.0.remove_factory(path);
+        }
+    }
+}
+
+/// Sends EOS to the appsrcs when dropped
+///
+/// This ends the clients of an old factory, for example after a change
+/// of resolution, so that they reconnect and get the new sdp
+#[derive(Default)]
+struct EndOnDrop(Vec<AppSrc>);
+
+impl Drop for EndOnDrop {
+    fn drop(&mut self) {
+        for app in self.0.drain(..) {
+            let _ = app.end_of_stream();
+        }
+    }
+}
+
 fn check_live(app: &AppSrc) -> Result<()> {
     // log::debug!("Checking Live: {:?}", app.bus());
     app.bus().ok_or(anyhow!("App source is closed"))?;
@@ -530,7 +927,7 @@ fn get_runtime(app: &AppSrc) -> Option<Duration> {
 }
 
 // This ensures we start at a keyframe
-fn wait_for_keyframe<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
+pub(super) fn wait_for_keyframe<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
     mut stream: T,
 ) -> impl Stream<Item = AnyResult<StampedData>> + Unpin {
     Box::pin(async_stream::stream! {
@@ -577,7 +974,7 @@ fn hold_stream<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
 // Take a stream of stamped data and reorder it
 // in case they are out of order
 // this also releases frames in waves of keyframe so it should replace `hold_stream`
-fn ensure_order<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
+pub(super) fn ensure_order<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
     mut stream: T,
 ) -> impl Stream<Item = AnyResult<StampedData>> + Unpin {
     Box::pin(async_stream::stream! {
@@ -609,7 +1006,7 @@ fn ensure_order<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
 
 // Take a stream of stamped data pause until
 // it is time to display it
-fn frametime_stream<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
+pub(super) fn frametime_stream<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
     mut stream: T,
     expected_frame_rate: Duration,
 ) -> impl Stream<Item = AnyResult<StampedData>> + Unpin {
@@ -682,12 +1079,65 @@ fn repeat_keyframe<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
     })
 }
 
+/// Works out the presentation time of each buffer
+///
+/// With camera timestamps the first frame is pinned to the pipeline's
+/// running time and later frames are placed relative to it using the
+/// camera's times. If the camera's times go backwards or drift too far
+/// from the running time they are pinned again to the arrival time
+struct Stamper {
+    source: TimestampSource,
+    /// Camera time and running time of the pinned frame
+    anchor: Option<(Duration, Duration)>,
+    last_camera: Option<Duration>,
+}
+
+impl Stamper {
+    const MAX_DRIFT: Duration = Duration::from_secs(2);
+
+    fn new(source: TimestampSource) -> Self {
+        Self {
+            source,
+            anchor: None,
+            last_camera: None,
+        }
+    }
+
+    fn stamp(&mut self, camera: Duration, runtime: Duration) -> Duration {
+        if self.source == TimestampSource::Arrival {
+            return runtime;
+        }
+        let monotonic = self.last_camera.map(|last| camera > last).unwrap_or(true);
+        self.last_camera = Some(camera);
+        if let Some((anchor_camera, anchor_runtime)) = self.anchor {
+            if monotonic && camera >= anchor_camera {
+                let time = anchor_runtime + (camera - anchor_camera);
+                let drift = if time > runtime {
+                    time - runtime
+                } else {
+                    runtime - time
+                };
+                if drift <= Self::MAX_DRIFT {
+                    return time;
+                }
+                log::debug!("Camera timestamps drifted by {drift:?}, resyncing");
+            } else {
+                log::debug!("Camera timestamps went backwards, resyncing");
+            }
+        }
+        self.anchor = Some((camera, runtime));
+        runtime
+    }
+}
+
 /// Takes a stream and sends it to an appsrc
-async fn send_to_appsrc<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
+pub(super) async fn send_to_appsrc<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
     mut stream: T,
     appsrc: &AppSrc,
+    timestamps: TimestampSource,
 ) -> AnyResult<()> {
     let mut rt = Duration::ZERO;
+    let mut stamper = Stamper::new(timestamps);
     let mut wait_for_iframe = true;
     while let Some(Ok(data)) = stream.next().await {
         check_live(appsrc)?; // Stop if appsrc is dropped
@@ -707,7 +1157,8 @@ async fn send_to_appsrc<E, T: Stream<Item = Result<StampedData, E>> + Unpin>(
             {
                 let gst_buf_mut = gst_buf.get_mut().unwrap();
                 // log::debug!("Setting PTS: {ts:?}, Runtime: {ts:?}");
-                let time = ClockTime::from_useconds(rt.as_micros() as u64);
+                let time = stamper.stamp(data.ts, rt);
+                let time = ClockTime::from_useconds(time.as_micros() as u64);
                 gst_buf_mut.set_dts(time);
                 gst_buf_mut.set_pts(time);
                 let mut gst_buf_data = gst_buf_mut.map_writable().unwrap();
@@ -0,0 +1,112 @@
+//! Serves the raw video of a camera on a unix socket
+//!
+//! The gstreamer rtsp server can only listen on tcp so rather than rtsp
+//! each client that connects to the socket is sent the elementary
+//! h264/h265 stream, in annex b format, starting from the next keyframe.
+//! This is meant for processes on the same host such as a sidecar container
+use anyhow::anyhow;
+use std::{
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+use tokio::{io::AsyncWriteExt, net::UnixListener, task::JoinSet};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use super::stream::wait_for_keyframe;
+use crate::{common::NeoInstance, config::UnixSocketConfig, AnyResult};
+
+/// Serves the socket restarting it if its config changes
+pub(super) async fn unix_socket_main(camera: NeoInstance) -> AnyResult<()> {
+    let mut config = camera.config().await?;
+    let name = config.borrow().name.clone();
+    loop {
+        let socket = config.borrow_and_update().unix_socket.clone();
+        tokio::select! {
+            v = config.wait_for(|config| config.unix_socket != socket) => {
+                v?;
+            },
+            v = async {
+                match socket.as_ref() {
+                    Some(socket) => unix_socket_run(&name, &camera, socket).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                if let Err(e) = v {
+                    // Wait for the user to fix the config
+                    log::error!("{name}: Unix socket stopped: {e:?}");
+                    config.wait_for(|config| config.unix_socket != socket).await?;
+                }
+            },
+        }
+    }
+}
+
+async fn unix_socket_run(
+    name: &str,
+    camera: &NeoInstance,
+    socket: &UnixSocketConfig,
+) -> AnyResult<()> {
+    let kind = socket
+        .stream
+        .as_stream_kinds()
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No stream selected for the unix socket"))?;
+
+    let path = PathBuf::from(&socket.path);
+    // A socket left over from a previous run would stop us binding, anything
+    // else at the path is left alone
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{} already exists and is not a socket",
+                path.display()
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(&path)?;
+    let _cleanup = RemoveOnDrop(path.clone());
+    log::info!(
+        "{name}: Raw {kind} video available on unix socket {}",
+        path.display()
+    );
+
+    let mut set = JoinSet::new();
+    loop {
+        let mut client = tokio::select! {
+            // Tidy up the finished clients
+            Some(_) = set.join_next() => continue,
+            v = listener.accept() => v?.0,
+        };
+        log::debug!("{name}: New unix socket client");
+        let mut instance = camera.stream(kind).await?;
+        let thread_name = name.to_string();
+        set.spawn(async move {
+            instance.activate().await?;
+            let mut frames = wait_for_keyframe(BroadcastStream::new(instance.vid.resubscribe()));
+            let r = async {
+                while let Some(frame) = frames.next().await {
+                    client.write_all(&frame?.data).await?;
+                }
+                AnyResult::Ok(())
+            }
+            .await;
+            // Usually just the client going away
+            log::debug!("{thread_name}: Unix socket client ended: {r:?}");
+            instance.deactivate().await?;
+            AnyResult::Ok(())
+        });
+    }
+}
+
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let path: &Path = &self.0;
+        let _ = std::fs::remove_file(path);
+    }
+}
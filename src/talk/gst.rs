@@ -6,6 +6,7 @@ use gstreamer::{
     MessageView, ParseFlags, Pipeline, ResourceError, State,
 };
 use gstreamer_app::{AppSink, AppSinkCallbacks};
+use std::path::Path;
 use tokio::task::JoinSet;
 
 use byte_slice_cast::*;
@@ -21,6 +22,28 @@ pub(super) fn from_input(
     input(pipeline)
 }
 
+/// Read the audio from a file
+///
+/// The location is set on the `filesrc` directly so that the path is
+/// not parsed as part of the pipeline
+#[allow(clippy::type_complexity)]
+pub(super) fn from_file(
+    path: &Path,
+    volume: f32,
+    block_align: u16,
+    sample_rate: u16,
+) -> Result<(JoinSet<AnyResult<()>>, Receiver<Vec<u8>>)> {
+    let location = path
+        .to_str()
+        .ok_or_else(|| anyhow!("File path not UTF8 complient"))?;
+    let pipeline = create_pipeline("filesrc name=thesource", volume, block_align, sample_rate)?;
+    pipeline
+        .by_name("thesource")
+        .ok_or_else(|| anyhow!("There should be a `thesource`"))?
+        .set_property("location", location);
+    input(pipeline)
+}
+
 #[allow(clippy::type_complexity)]
 fn input(pipeline: Pipeline) -> Result<(JoinSet<AnyResult<()>>, Receiver<Vec<u8>>)> {
     let appsink = get_sink(&pipeline)?;
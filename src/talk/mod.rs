@@ -12,12 +12,18 @@
 /// ```
 ///
 use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::Receiver;
 use neolink_core::bc::xml::TalkConfig;
+use std::path::Path;
+use tokio::task::JoinSet;
 
 mod cmdline;
 mod gst;
 
-use crate::common::NeoReactor;
+use crate::{
+    common::{NeoInstance, NeoReactor},
+    AnyResult,
+};
 pub(crate) use cmdline::Opt;
 
 /// Entry point for the talk subcommand
@@ -25,6 +31,33 @@ pub(crate) use cmdline::Opt;
 /// Opt is the command line options
 pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
     let camera = reactor.get(&opt.camera).await?;
+    let (talk_config, block_size, sample_rate) = talk_setup(&camera).await?;
+
+    let (set, rx) = match (&opt.file_path, &opt.microphone) {
+        (Some(path), false) => gst::from_file(path, opt.volume, block_size, sample_rate)
+            .with_context(|| format!("Failed to setup gst with the file: {:?}", path))?,
+        (None, true) => gst::from_input(&opt.input_src, opt.volume, block_size, sample_rate)
+            .context("Failed to setup gst using the microphone")?,
+        _ => unreachable!(),
+    };
+
+    send_talk(&camera, set, rx, talk_config).await
+}
+
+/// Decode an audio file and play it on the camera's speaker
+///
+/// Returns once the whole file has been sent
+pub(crate) async fn play_file(camera: &NeoInstance, path: &Path, volume: f32) -> Result<()> {
+    let (talk_config, block_size, sample_rate) = talk_setup(camera).await?;
+    let (set, rx) = gst::from_file(path, volume, block_size, sample_rate)
+        .with_context(|| format!("Failed to setup gst with the file: {:?}", path))?;
+
+    send_talk(camera, set, rx, talk_config).await
+}
+
+/// Get the talk config from the camera's talk ability along with
+/// the adpcm block size and sample rate it expects
+pub(crate) async fn talk_setup(camera: &NeoInstance) -> Result<(TalkConfig, u16, u16)> {
     let config = camera.config().await?.borrow().clone();
     let name = config.name.clone();
 
@@ -70,22 +103,16 @@ pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
         ));
     }
 
-    let (mut set, rx) = match (&opt.file_path, &opt.microphone) {
-        (Some(path), false) => gst::from_input(
-            &format!(
-                "filesrc location={}",
-                path.to_str().expect("File path not UTF8 complient")
-            ),
-            opt.volume,
-            block_size,
-            sample_rate,
-        )
-        .with_context(|| format!("Failed to setup gst with the file: {:?}", path))?,
-        (None, true) => gst::from_input(&opt.input_src, opt.volume, block_size, sample_rate)
-            .context("Failed to setup gst using the microphone")?,
-        _ => unreachable!(),
-    };
+    Ok((talk_config, block_size, sample_rate))
+}
 
+/// Send the adpcm data from gstreamer to the camera until it runs out
+async fn send_talk(
+    camera: &NeoInstance,
+    mut set: JoinSet<AnyResult<()>>,
+    rx: Receiver<Vec<u8>>,
+    talk_config: TalkConfig,
+) -> Result<()> {
     camera
         .run_task(|cam| {
             let rx = rx.clone();
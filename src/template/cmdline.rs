@@ -0,0 +1,8 @@
+use clap::Parser;
+
+/// The template command will print a `[[cameras]]` config block for the camera
+#[derive(Parser, Debug)]
+pub struct Opt {
+    /// The name of the camera. Must be a name in the config
+    pub camera: String,
+}
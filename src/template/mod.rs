@@ -0,0 +1,114 @@
+///
+/// # Neolink Template
+///
+/// This module handles the template subcommand
+///
+/// The subcommand connects to the camera and prints a `[[cameras]]`
+/// block with the detected streams and model info filled in. The
+/// password is replaced with a placeholder so the output can be
+/// shared safely.
+///
+/// # Usage
+///
+/// ```bash
+/// neolink template --config=config.toml CameraName >> config.toml
+/// ```
+///
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+mod cmdline;
+
+use crate::{common::NeoReactor, config::StreamConfig};
+pub(crate) use cmdline::Opt;
+
+const PASSWORD_PLACEHOLDER: &str = "CHANGE_ME";
+
+#[derive(Serialize, Debug)]
+struct Template {
+    cameras: Vec<CameraTemplate>,
+}
+
+#[derive(Serialize, Debug)]
+struct CameraTemplate {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<String>,
+    username: String,
+    password: String,
+    stream: StreamConfig,
+    channel_id: u8,
+}
+
+/// Entry point for the template subcommand
+///
+/// Opt is the command line options
+pub(crate) async fn main(opt: Opt, reactor: NeoReactor) -> Result<()> {
+    let camera = reactor.get(&opt.camera).await?;
+    let config = camera.config().await?.borrow().clone();
+
+    let (version, stream_info) = camera
+        .run_task(|cam| {
+            Box::pin(async move {
+                let version = cam
+                    .version()
+                    .await
+                    .context("Unable to get camera version info")?;
+                let stream_info = cam
+                    .get_stream_info()
+                    .await
+                    .context("Unable to get camera stream info")?;
+                Ok((version, stream_info))
+            })
+        })
+        .await?;
+
+    let encodes = stream_info
+        .stream_infos
+        .iter()
+        .flat_map(|info| info.encode_tables.iter())
+        .collect::<Vec<_>>();
+    let has = |name: &str| encodes.iter().any(|encode| encode.name == name);
+    let stream = match (has("mainStream"), has("subStream"), has("externStream")) {
+        (true, true, true) => StreamConfig::All,
+        (true, true, false) => StreamConfig::Both,
+        (true, false, _) => StreamConfig::Main,
+        (false, true, _) => StreamConfig::Sub,
+        (false, false, true) => StreamConfig::Extern,
+        (false, false, false) => StreamConfig::None,
+    };
+
+    let template = Template {
+        cameras: vec![CameraTemplate {
+            name: config.name.clone(),
+            address: config.camera_addr.clone(),
+            uid: config.camera_uid.clone(),
+            username: config.username.clone(),
+            password: PASSWORD_PLACEHOLDER.to_string(),
+            stream,
+            channel_id: config.channel_id,
+        }],
+    };
+
+    println!("# Model: {}", version.model.as_deref().unwrap_or("Unknown"));
+    println!("# Hardware: {}", version.hardwareVersion);
+    println!("# Firmware: {}", version.firmwareVersion);
+    for encode in encodes.iter() {
+        println!(
+            "# {}: {}x{} framerates [{}] bitrates [{}]",
+            encode.name,
+            encode.resolution.width,
+            encode.resolution.height,
+            encode.framerate_table,
+            encode.bitrate_table
+        );
+    }
+    print!(
+        "{}",
+        toml::to_string(&template).context("Unable to serialise the template")?
+    );
+
+    Ok(())
+}
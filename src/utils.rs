@@ -2,16 +2,56 @@
 //!
 use log::*;
 
-use super::config::CameraConfig;
+use super::config::{CameraConfig, ConnectionMode};
 use anyhow::{anyhow, Context, Error, Result};
 use neolink_core::bc_protocol::{
     BcCamera, BcCameraOpt, ConnectionProtocol, Credentials, DiscoveryMethods, MaxEncryption,
 };
+use once_cell::sync::{Lazy, OnceCell};
 use std::{
+    collections::HashMap,
     fmt::{Display, Error as FmtError, Formatter},
     net::{IpAddr, ToSocketAddrs},
     str::FromStr,
+    sync::{Arc, Weak},
 };
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::Mutex;
+
+/// Logged in NVR connections keyed on their address and username
+///
+/// Each channel holds the connection alive so once all channels
+/// are dropped the entry can no longer be upgraded. Each NVR has its own
+/// lock so that logging in to one does not hold up the others
+type NvrSlot = Arc<Mutex<Weak<BcCamera>>>;
+static NVR_CONNECTIONS: Lazy<std::sync::Mutex<HashMap<String, NvrSlot>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// The host's offset from utc as found at startup
+static LOCAL_OFFSET: OnceCell<UtcOffset> = OnceCell::new();
+
+/// Find the host's offset from utc
+///
+/// The `time` crate refuses to read it once other threads are running, so
+/// this is called before the runtime starts. A daylight saving change is
+/// picked up on the next restart
+pub(crate) fn capture_local_offset() {
+    let offset = UtcOffset::current_local_offset().unwrap_or_else(|e| {
+        warn!("Could not find the local time offset, using UTC: {e}");
+        UtcOffset::UTC
+    });
+    let _ = LOCAL_OFFSET.set(offset);
+}
+
+/// The host's offset from utc
+pub(crate) fn local_offset() -> UtcOffset {
+    LOCAL_OFFSET.get().copied().unwrap_or(UtcOffset::UTC)
+}
+
+/// The host's local time
+pub(crate) fn now_local() -> OffsetDateTime {
+    OffsetDateTime::now_utc().to_offset(local_offset())
+}
 
 pub(crate) fn timeout<F>(future: F) -> tokio::time::Timeout<F>
 where
@@ -87,20 +127,29 @@ impl AddressOrUid {
             }
         }?;
 
+        let (protocol, discovery) = match camera_config.connection {
+            ConnectionMode::Auto => (ConnectionProtocol::TcpUdp, camera_config.discovery),
+            ConnectionMode::Tcp => (ConnectionProtocol::Tcp, DiscoveryMethods::None),
+            ConnectionMode::Local => (ConnectionProtocol::Udp, DiscoveryMethods::Local),
+            // Debug is the discovery that only uses the relay
+            ConnectionMode::Relay => (ConnectionProtocol::Udp, DiscoveryMethods::Debug),
+        };
+
         let options = BcCameraOpt {
             name: camera_config.name.clone(),
             channel_id: camera_config.channel_id,
             addrs,
             port,
             uid: camera_config.camera_uid.clone(),
-            protocol: ConnectionProtocol::TcpUdp,
-            discovery: camera_config.discovery,
+            protocol,
+            discovery,
             credentials: Credentials {
                 username: camera_config.username.clone(),
                 password: camera_config.password.clone(),
             },
             debug: camera_config.debug,
             max_discovery_retries: camera_config.max_discovery_retries,
+            bind: camera_config.bind_addr,
         };
 
         trace!("Camera Info: {:?}", options);
@@ -110,15 +159,68 @@ impl AddressOrUid {
 }
 
 pub(crate) async fn connect_and_login(camera_config: &CameraConfig) -> Result<BcCamera> {
+    if camera_config.nvr_relay {
+        return connect_nvr_channel(camera_config).await;
+    }
+    login_camera(camera_config).await
+}
+
+/// Get a channel of an NVR reusing the connection if another channel
+/// already has it open
+async fn connect_nvr_channel(camera_config: &CameraConfig) -> Result<BcCamera> {
+    let key = format!(
+        "{}/{}/{}",
+        camera_config.camera_addr.as_deref().unwrap_or_default(),
+        camera_config.camera_uid.as_deref().unwrap_or_default(),
+        camera_config.username
+    );
+    let slot = NVR_CONNECTIONS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .clone();
+    // Held while connecting so that the channels do not each open their own
+    let mut connection = slot.lock().await;
+    let nvr = match connection.upgrade() {
+        Some(nvr) => {
+            info!("{}: Using the existing NVR connection", camera_config.name);
+            nvr
+        }
+        None => {
+            let nvr = Arc::new(login_camera(camera_config).await?);
+            *connection = Arc::downgrade(&nvr);
+            nvr
+        }
+    };
+    drop(connection);
+
+    let camera = timeout(nvr.channel(camera_config.channel_id))
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to open channel {} for {}",
+                camera_config.channel_id, camera_config.name
+            )
+        })??;
+    info!(
+        "{}: Using channel {} of the NVR",
+        camera_config.name, camera_config.channel_id
+    );
+    Ok(camera)
+}
+
+async fn login_camera(camera_config: &CameraConfig) -> Result<BcCamera> {
     let camera_addr = AddressOrUid::new(
         &camera_config.camera_addr,
         &camera_config.camera_uid,
         &camera_config.discovery,
     )
     .unwrap();
+    let mode = camera_config.connection.as_str();
     info!(
-        "{}: Connecting to camera at {}",
-        camera_config.name, camera_addr
+        "{}: Connecting to camera at {} (connection = {})",
+        camera_config.name, camera_addr, mode
     );
 
     let camera = camera_addr
@@ -126,8 +228,8 @@ pub(crate) async fn connect_and_login(camera_config: &CameraConfig) -> Result<Bc
         .await
         .with_context(|| {
             format!(
-                "Failed to connect to camera {} at {} on channel {}",
-                camera_config.name, camera_addr, camera_config.channel_id
+                "Failed to connect to camera {} at {} on channel {} (connection = {})",
+                camera_config.name, camera_addr, camera_config.channel_id, mode
             )
         })?;
 